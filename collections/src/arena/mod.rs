@@ -0,0 +1,350 @@
+//! Allocators for the nodes of node-based collections.
+//!
+//! A structure generic over `A: NodeAlloc` gets the storage of its nodes
+//! from `A`. `Heap` passes every request to the global allocator. `Arena`
+//! carves nodes out of large chunks, reuses freed nodes through caches kept
+//! per thread, and returns all of its chunks when dropped, so that a
+//! structure dropped with its arena does not free its nodes one by one.
+
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub trait NodeAlloc: Default + Send + Sync {
+    /// Whether dropping the allocator frees everything it allocated, so
+    /// that an owner dropped along with it only has to drop its values.
+    const FREES_ON_DROP: bool;
+
+    /// Uninitialized storage for `layout`, which must not be zero-sized.
+    fn alloc(&self, layout: Layout) -> NonNull<u8>;
+
+    /// Frees storage returned by `alloc` for the same `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `alloc` on this allocator with `layout` and not
+    /// have been freed.
+    unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+/// Every node from the global allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Heap;
+
+impl NodeAlloc for Heap {
+    const FREES_ON_DROP: bool = false;
+
+    fn alloc(&self, layout: Layout) -> NonNull<u8> {
+        let ptr = unsafe { alloc::alloc(layout) };
+        NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+    }
+
+    unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout) {
+        alloc::dealloc(ptr.as_ptr(), layout);
+    }
+}
+
+const CHUNK_SIZE: usize = 64 << 10;
+const ALIGN: usize = 16;
+/// Slots moved at a time between a thread cache and the shared depot.
+const BATCH: usize = 32;
+/// Arenas a thread keeps caches for. The free slots in the cache of an
+/// arena dropped from the list are lost to it until it is dropped.
+const CACHED_ARENAS: usize = 8;
+
+/// Size classes: multiples of 16 bytes up to 256, then powers of two up to
+/// 4096. Larger or more aligned requests go to the global allocator.
+const CLASSES: usize = 20;
+const MAX_SIZE: usize = 4096;
+
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size();
+    if layout.align() > ALIGN || size > MAX_SIZE {
+        None
+    } else if size <= 256 {
+        Some(size.max(1).div_ceil(16) - 1)
+    } else {
+        Some(16 + (size.next_power_of_two().trailing_zeros() as usize - 9))
+    }
+}
+
+fn class_size(class: usize) -> usize {
+    if class < 16 {
+        (class + 1) * 16
+    } else {
+        512 << (class - 16)
+    }
+}
+
+/// Memory an `Arena` holds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub chunks: usize,
+    /// Bytes of the chunks and of the requests too large for them.
+    pub reserved_bytes: usize,
+    /// Allocations not freed yet.
+    pub live: usize,
+}
+
+struct Depot {
+    /// Free slots of each class given back by the thread caches.
+    free: Vec<Vec<NonNull<u8>>>,
+    /// Part of the last chunk of each class not handed out yet.
+    bump: Vec<(usize, usize)>,
+    chunks: Vec<NonNull<u8>>,
+    /// Requests too large for a class, by address.
+    large: HashMap<usize, Layout>,
+    large_bytes: usize,
+}
+
+/// Slab allocator with size classes. Each class carves slots out of its
+/// own 64 KiB chunks; a freed slot goes to a cache of the freeing thread,
+/// which the next allocations of the class on that thread reuse, and
+/// batches of slots move between the caches and a shared depot when a
+/// cache runs empty or grows past twice the batch size.
+pub struct Arena {
+    id: u64,
+    depot: Mutex<Depot>,
+    live: AtomicUsize,
+}
+
+// The depot only holds addresses of memory the arena owns.
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+struct ThreadCache {
+    arena: u64,
+    classes: Vec<Vec<NonNull<u8>>>,
+}
+
+thread_local! {
+    static CACHES: RefCell<Vec<ThreadCache>> = const { RefCell::new(Vec::new()) };
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            depot: Mutex::new(Depot {
+                free: vec![Vec::new(); CLASSES],
+                bump: vec![(0, 0); CLASSES],
+                chunks: Vec::new(),
+                large: HashMap::new(),
+                large_bytes: 0,
+            }),
+            live: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        let depot = self.depot.lock().unwrap();
+        ArenaStats {
+            chunks: depot.chunks.len(),
+            reserved_bytes: depot.chunks.len() * CHUNK_SIZE + depot.large_bytes,
+            live: self.live.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Calls `f` on the cache of this arena for the current thread.
+    fn with_cache<R>(&self, f: impl FnOnce(&mut ThreadCache) -> R) -> R {
+        CACHES.with(|caches| {
+            let mut caches = caches.borrow_mut();
+            let i = match caches.iter().position(|c| c.arena == self.id) {
+                Some(i) => i,
+                None => {
+                    if caches.len() == CACHED_ARENAS {
+                        caches.remove(0);
+                    }
+                    caches.push(ThreadCache {
+                        arena: self.id,
+                        classes: vec![Vec::new(); CLASSES],
+                    });
+                    caches.len() - 1
+                }
+            };
+            f(&mut caches[i])
+        })
+    }
+
+    /// Moves a batch of free slots of `class` into `cache`.
+    fn refill(&self, class: usize, cache: &mut Vec<NonNull<u8>>) {
+        let mut depot = self.depot.lock().unwrap();
+        let depot = &mut *depot;
+        let free = &mut depot.free[class];
+        cache.extend(free.drain(free.len().saturating_sub(BATCH)..));
+        let size = class_size(class);
+        while cache.len() < BATCH {
+            let (next, end) = &mut depot.bump[class];
+            if *next + size > *end {
+                let layout = Layout::from_size_align(CHUNK_SIZE, ALIGN).unwrap();
+                let chunk = Heap.alloc(layout);
+                depot.chunks.push(chunk);
+                *next = chunk.as_ptr() as usize;
+                *end = *next + CHUNK_SIZE;
+            }
+            cache.push(NonNull::new(*next as *mut u8).unwrap());
+            *next += size;
+        }
+    }
+
+    fn alloc_large(&self, layout: Layout) -> NonNull<u8> {
+        let ptr = Heap.alloc(layout);
+        let mut depot = self.depot.lock().unwrap();
+        depot.large.insert(ptr.as_ptr() as usize, layout);
+        depot.large_bytes += layout.size();
+        ptr
+    }
+}
+
+impl NodeAlloc for Arena {
+    const FREES_ON_DROP: bool = true;
+
+    fn alloc(&self, layout: Layout) -> NonNull<u8> {
+        self.live.fetch_add(1, Ordering::Relaxed);
+        let Some(class) = class_of(layout) else {
+            return self.alloc_large(layout);
+        };
+        self.with_cache(|cache| {
+            let slots = &mut cache.classes[class];
+            if slots.is_empty() {
+                self.refill(class, slots);
+            }
+            slots.pop().unwrap()
+        })
+    }
+
+    unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        let Some(class) = class_of(layout) else {
+            let mut depot = self.depot.lock().unwrap();
+            depot.large.remove(&(ptr.as_ptr() as usize));
+            depot.large_bytes -= layout.size();
+            drop(depot);
+            Heap.free(ptr, layout);
+            return;
+        };
+        self.with_cache(|cache| {
+            let slots = &mut cache.classes[class];
+            slots.push(ptr);
+            if slots.len() >= 2 * BATCH {
+                let mut depot = self.depot.lock().unwrap();
+                depot.free[class].extend(slots.drain(BATCH..));
+            }
+        })
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let _ = CACHES.try_with(|caches| caches.borrow_mut().retain(|c| c.arena != self.id));
+        let depot = self.depot.get_mut().unwrap();
+        let chunk = Layout::from_size_align(CHUNK_SIZE, ALIGN).unwrap();
+        for ptr in depot.chunks.drain(..) {
+            unsafe { Heap.free(ptr, chunk) };
+        }
+        for (addr, layout) in depot.large.drain() {
+            unsafe { Heap.free(NonNull::new(addr as *mut u8).unwrap(), layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn fill(ptr: NonNull<u8>, layout: Layout, byte: u8) {
+        unsafe { std::ptr::write_bytes(ptr.as_ptr(), byte, layout.size()) };
+    }
+
+    fn check(ptr: NonNull<u8>, layout: Layout, byte: u8) {
+        let bytes = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+        assert!(bytes.iter().all(|b| *b == byte));
+    }
+
+    #[test]
+    fn size_classes() {
+        for size in 1..=MAX_SIZE {
+            let class = class_of(Layout::from_size_align(size, 8).unwrap()).unwrap();
+            assert!(class_size(class) >= size);
+            assert!(class == 0 || class_size(class - 1) < size);
+        }
+        assert_eq!(
+            class_of(Layout::from_size_align(MAX_SIZE + 1, 8).unwrap()),
+            None
+        );
+        assert_eq!(class_of(Layout::from_size_align(64, 64).unwrap()), None);
+    }
+
+    #[test]
+    fn alloc_free() {
+        let arena = Arena::new();
+        let mut live = Vec::new();
+        let mut x: u64 = 1;
+        for i in 0..20000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            if x >> 62 == 0 && !live.is_empty() {
+                let (ptr, layout, byte) = live.swap_remove((x >> 20) as usize % live.len());
+                check(ptr, layout, byte);
+                unsafe { arena.free(ptr, layout) };
+            } else {
+                // Mostly small nodes, some past the largest class.
+                let size = 1 + (x >> 33) as usize % 600 + (x >> 60 == 3) as usize * 5000;
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let ptr = arena.alloc(layout);
+                assert_eq!(ptr.as_ptr() as usize % 8, 0);
+                fill(ptr, layout, i as u8);
+                live.push((ptr, layout, i as u8));
+            }
+        }
+        assert_eq!(arena.stats().live, live.len());
+        for (ptr, layout, byte) in live.drain(..) {
+            check(ptr, layout, byte);
+            unsafe { arena.free(ptr, layout) };
+        }
+        let stats = arena.stats();
+        assert_eq!(stats.live, 0);
+        assert!(stats.chunks > 0);
+        assert_eq!(stats.reserved_bytes, stats.chunks * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn threads() {
+        let arena = Arc::new(Arena::new());
+        let layout = Layout::new::<[u64; 4]>();
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let arena = arena.clone();
+                std::thread::spawn(move || {
+                    for round in 0..50 {
+                        let ptrs: Vec<_> = (0..100).map(|_| arena.alloc(layout)).collect();
+                        for ptr in &ptrs {
+                            fill(*ptr, layout, t * 64 + round);
+                        }
+                        for ptr in ptrs {
+                            check(ptr, layout, t * 64 + round);
+                            unsafe { arena.free(ptr, layout) };
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(arena.stats().live, 0);
+    }
+}
@@ -0,0 +1,956 @@
+use std::fmt;
+use std::mem::size_of;
+
+use crate::memory::{sum_with_heap, HeapSize, Memory, MemoryUsage};
+
+struct Leaf<V> {
+    key: Box<[u8]>,
+    value: V,
+}
+
+/// Part of an inner node shared by all sizes.
+struct Header<V> {
+    /// Compressed path: bytes every key below has after the byte leading
+    /// to this node.
+    prefix: Vec<u8>,
+    /// Entry whose key ends right after `prefix`, which sorts before every
+    /// child.
+    value: Option<Box<Leaf<V>>>,
+}
+
+struct Inner<V, C> {
+    header: Header<V>,
+    children: C,
+}
+
+/// Up to 4 children with sorted key bytes.
+struct Node4<V> {
+    len: u8,
+    keys: [u8; 4],
+    children: [Option<Node<V>>; 4],
+}
+
+/// Up to 16 children with sorted key bytes.
+struct Node16<V> {
+    len: u8,
+    keys: [u8; 16],
+    children: [Option<Node<V>>; 16],
+}
+
+/// Up to 48 children, found through a 256-entry index of slot + 1.
+struct Node48<V> {
+    len: u8,
+    index: [u8; 256],
+    children: [Option<Node<V>>; 48],
+}
+
+/// One child slot per key byte.
+struct Node256<V> {
+    len: u16,
+    children: [Option<Node<V>>; 256],
+}
+
+enum Node<V> {
+    Leaf(Box<Leaf<V>>),
+    N4(Box<Inner<V, Node4<V>>>),
+    N16(Box<Inner<V, Node16<V>>>),
+    N48(Box<Inner<V, Node48<V>>>),
+    N256(Box<Inner<V, Node256<V>>>),
+}
+
+/// Children of an inner node keyed by the next byte of the key.
+trait Children<V> {
+    fn len(&self) -> usize;
+    fn is_full(&self) -> bool;
+    fn get(&self, b: u8) -> Option<&Node<V>>;
+    fn get_mut(&mut self, b: u8) -> Option<&mut Option<Node<V>>>;
+    /// Adds child `b`, which must be absent, to a node that is not full.
+    fn add(&mut self, b: u8, node: Node<V>);
+    fn remove(&mut self, b: u8) -> Option<Node<V>>;
+    /// First child with a key byte after `after`, or the first one.
+    fn next(&self, after: Option<u8>) -> Option<(u8, &Node<V>)>;
+    fn last(&self) -> Option<&Node<V>>;
+    /// Removes every child in key order.
+    fn take_all(&mut self) -> Vec<(u8, Node<V>)>;
+}
+
+/// Sorted-array children of `Node4` and `Node16`.
+macro_rules! sorted_children {
+    ($node:ident, $cap:expr) => {
+        impl<V> $node<V> {
+            fn new() -> Self {
+                $node {
+                    len: 0,
+                    keys: [0; $cap],
+                    children: std::array::from_fn(|_| None),
+                }
+            }
+
+            fn position(&self, b: u8) -> Option<usize> {
+                self.keys[..self.len as usize].iter().position(|x| *x == b)
+            }
+        }
+
+        impl<V> Children<V> for $node<V> {
+            fn len(&self) -> usize {
+                self.len as usize
+            }
+
+            fn is_full(&self) -> bool {
+                self.len as usize == $cap
+            }
+
+            fn get(&self, b: u8) -> Option<&Node<V>> {
+                self.children[self.position(b)?].as_ref()
+            }
+
+            fn get_mut(&mut self, b: u8) -> Option<&mut Option<Node<V>>> {
+                let i = self.position(b)?;
+                Some(&mut self.children[i])
+            }
+
+            fn add(&mut self, b: u8, node: Node<V>) {
+                let len = self.len as usize;
+                let i = self.keys[..len].partition_point(|x| *x < b);
+                self.keys.copy_within(i..len, i + 1);
+                self.children[i..=len].rotate_right(1);
+                self.keys[i] = b;
+                self.children[i] = Some(node);
+                self.len += 1;
+            }
+
+            fn remove(&mut self, b: u8) -> Option<Node<V>> {
+                let i = self.position(b)?;
+                let len = self.len as usize;
+                let node = self.children[i].take();
+                self.keys.copy_within(i + 1..len, i);
+                self.children[i..len].rotate_left(1);
+                self.len -= 1;
+                node
+            }
+
+            fn next(&self, after: Option<u8>) -> Option<(u8, &Node<V>)> {
+                let len = self.len as usize;
+                let i = match after {
+                    Some(b) => self.keys[..len].partition_point(|x| *x <= b),
+                    None => 0,
+                };
+                Some((*self.keys[..len].get(i)?, self.children[i].as_ref()?))
+            }
+
+            fn last(&self) -> Option<&Node<V>> {
+                self.children[(self.len as usize).checked_sub(1)?].as_ref()
+            }
+
+            fn take_all(&mut self) -> Vec<(u8, Node<V>)> {
+                let len = std::mem::take(&mut self.len) as usize;
+                (0..len)
+                    .filter_map(|i| Some((self.keys[i], self.children[i].take()?)))
+                    .collect()
+            }
+        }
+    };
+}
+
+sorted_children!(Node4, 4);
+sorted_children!(Node16, 16);
+
+impl<V> Node48<V> {
+    fn new() -> Self {
+        Node48 {
+            len: 0,
+            index: [0; 256],
+            children: std::array::from_fn(|_| None),
+        }
+    }
+}
+
+impl<V> Children<V> for Node48<V> {
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == 48
+    }
+
+    fn get(&self, b: u8) -> Option<&Node<V>> {
+        match self.index[b as usize] {
+            0 => None,
+            i => self.children[i as usize - 1].as_ref(),
+        }
+    }
+
+    fn get_mut(&mut self, b: u8) -> Option<&mut Option<Node<V>>> {
+        match self.index[b as usize] {
+            0 => None,
+            i => Some(&mut self.children[i as usize - 1]),
+        }
+    }
+
+    fn add(&mut self, b: u8, node: Node<V>) {
+        let i = self.children.iter().position(|x| x.is_none()).unwrap();
+        self.children[i] = Some(node);
+        self.index[b as usize] = i as u8 + 1;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, b: u8) -> Option<Node<V>> {
+        let i = std::mem::take(&mut self.index[b as usize]);
+        if i == 0 {
+            return None;
+        }
+        self.len -= 1;
+        self.children[i as usize - 1].take()
+    }
+
+    fn next(&self, after: Option<u8>) -> Option<(u8, &Node<V>)> {
+        let from = after.map_or(0, |b| b as usize + 1);
+        (from..256).find_map(|b| Some((b as u8, self.get(b as u8)?)))
+    }
+
+    fn last(&self) -> Option<&Node<V>> {
+        (0..=255u8).rev().find_map(|b| self.get(b))
+    }
+
+    fn take_all(&mut self) -> Vec<(u8, Node<V>)> {
+        (0..=255u8)
+            .filter_map(|b| Some((b, self.remove(b)?)))
+            .collect()
+    }
+}
+
+impl<V> Node256<V> {
+    fn new() -> Self {
+        Node256 {
+            len: 0,
+            children: std::array::from_fn(|_| None),
+        }
+    }
+}
+
+impl<V> Children<V> for Node256<V> {
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    fn get(&self, b: u8) -> Option<&Node<V>> {
+        self.children[b as usize].as_ref()
+    }
+
+    fn get_mut(&mut self, b: u8) -> Option<&mut Option<Node<V>>> {
+        let slot = &mut self.children[b as usize];
+        slot.is_some().then_some(slot)
+    }
+
+    fn add(&mut self, b: u8, node: Node<V>) {
+        self.children[b as usize] = Some(node);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, b: u8) -> Option<Node<V>> {
+        let node = self.children[b as usize].take()?;
+        self.len -= 1;
+        Some(node)
+    }
+
+    fn next(&self, after: Option<u8>) -> Option<(u8, &Node<V>)> {
+        let from = after.map_or(0, |b| b as usize + 1);
+        (from..256).find_map(|b| Some((b as u8, self.children[b].as_ref()?)))
+    }
+
+    fn last(&self) -> Option<&Node<V>> {
+        self.children.iter().rev().find_map(|x| x.as_ref())
+    }
+
+    fn take_all(&mut self) -> Vec<(u8, Node<V>)> {
+        (0..=255u8)
+            .filter_map(|b| Some((b, self.remove(b)?)))
+            .collect()
+    }
+}
+
+/// Children counts at which a node shrinks to the next smaller size. They
+/// are below the capacity of the smaller size so that a node at the limit
+/// does not switch back and forth.
+const SHRINK_16: usize = 3;
+const SHRINK_48: usize = 12;
+const SHRINK_256: usize = 40;
+
+impl<V> Node<V> {
+    fn leaf(key: &[u8], value: V) -> Self {
+        Node::Leaf(Box::new(Leaf {
+            key: key.into(),
+            value,
+        }))
+    }
+
+    fn node4(prefix: Vec<u8>) -> Self {
+        Node::N4(Box::new(Inner {
+            header: Header {
+                prefix,
+                value: None,
+            },
+            children: Node4::new(),
+        }))
+    }
+
+    fn inner(&self) -> Option<(&Header<V>, &dyn Children<V>)> {
+        match self {
+            Node::Leaf(_) => None,
+            Node::N4(x) => Some((&x.header, &x.children)),
+            Node::N16(x) => Some((&x.header, &x.children)),
+            Node::N48(x) => Some((&x.header, &x.children)),
+            Node::N256(x) => Some((&x.header, &x.children)),
+        }
+    }
+
+    fn inner_mut(&mut self) -> Option<(&mut Header<V>, &mut dyn Children<V>)> {
+        match self {
+            Node::Leaf(_) => None,
+            Node::N4(x) => Some((&mut x.header, &mut x.children)),
+            Node::N16(x) => Some((&mut x.header, &mut x.children)),
+            Node::N48(x) => Some((&mut x.header, &mut x.children)),
+            Node::N256(x) => Some((&mut x.header, &mut x.children)),
+        }
+    }
+
+    /// Places `leaf` in a new inner node whose keys end at or continue
+    /// after `depth`.
+    fn place(&mut self, leaf: Box<Leaf<V>>, depth: usize) {
+        let (header, children) = self.inner_mut().unwrap();
+        match leaf.key.get(depth) {
+            Some(b) => children.add(*b, Node::Leaf(leaf)),
+            None => header.value = Some(leaf),
+        }
+    }
+
+    /// Moves the header and children of an inner node into a node sized
+    /// for `len` children.
+    fn resize(self, len: usize) -> Self {
+        let (header, children) = match self {
+            Node::Leaf(_) => unreachable!(),
+            Node::N4(mut x) => (x.header, x.children.take_all()),
+            Node::N16(mut x) => (x.header, x.children.take_all()),
+            Node::N48(mut x) => (x.header, x.children.take_all()),
+            Node::N256(mut x) => (x.header, x.children.take_all()),
+        };
+        fn fill<V, C: Children<V>>(mut c: C, children: Vec<(u8, Node<V>)>) -> C {
+            for (b, node) in children {
+                c.add(b, node);
+            }
+            c
+        }
+        match len {
+            0..=4 => Node::N4(Box::new(Inner {
+                header,
+                children: fill(Node4::new(), children),
+            })),
+            5..=16 => Node::N16(Box::new(Inner {
+                header,
+                children: fill(Node16::new(), children),
+            })),
+            17..=48 => Node::N48(Box::new(Inner {
+                header,
+                children: fill(Node48::new(), children),
+            })),
+            _ => Node::N256(Box::new(Inner {
+                header,
+                children: fill(Node256::new(), children),
+            })),
+        }
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Node counts and memory of an `Art`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArtStats {
+    pub leaves: usize,
+    pub node4: usize,
+    pub node16: usize,
+    pub node48: usize,
+    pub node256: usize,
+    /// Bytes of nodes, leaves, keys and prefixes, but not of memory owned
+    /// by the values.
+    pub bytes: usize,
+}
+
+/// Ordered map from byte strings on an adaptive radix tree. Inner nodes
+/// branch on one key byte and come in four sizes, growing and shrinking
+/// with their number of children. Runs of bytes without a branch are
+/// compressed into the prefix of the node below (path compression), and a
+/// subtree holding a single key is just a leaf with the whole key (lazy
+/// expansion), so the depth of a key is at most the number of bytes at
+/// which it branches from other keys.
+pub struct Art<V> {
+    root: Option<Node<V>>,
+    len: usize,
+}
+
+impl<V> Default for Art<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Art<V> {
+    pub fn new() -> Self {
+        Art { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let mut node = self.root.as_ref()?;
+        let mut depth = 0;
+        loop {
+            let (header, children) = match node {
+                Node::Leaf(leaf) => return (*leaf.key == *key).then_some(&leaf.value),
+                _ => node.inner().unwrap(),
+            };
+            if !key[depth..].starts_with(&header.prefix) {
+                return None;
+            }
+            depth += header.prefix.len();
+            let Some(b) = key.get(depth) else {
+                return header.value.as_ref().map(|leaf| &leaf.value);
+            };
+            node = children.get(*b)?;
+            depth += 1;
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        let mut node = self.root.as_mut()?;
+        let mut depth = 0;
+        loop {
+            if let Node::Leaf(leaf) = node {
+                return (*leaf.key == *key).then_some(&mut leaf.value);
+            }
+            let (header, children) = node.inner_mut().unwrap();
+            if !key[depth..].starts_with(&header.prefix) {
+                return None;
+            }
+            depth += header.prefix.len();
+            let Some(b) = key.get(depth) else {
+                return header.value.as_mut().map(|leaf| &mut leaf.value);
+            };
+            node = children.get_mut(*b)?.as_mut()?;
+            depth += 1;
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        let ret = Self::insert_at(&mut self.root, key, 0, value);
+        if ret.is_none() {
+            self.len += 1;
+        }
+        ret
+    }
+
+    fn insert_at(slot: &mut Option<Node<V>>, key: &[u8], depth: usize, value: V) -> Option<V> {
+        let Some(node) = slot else {
+            *slot = Some(Node::leaf(key, value));
+            return None;
+        };
+        if let Node::Leaf(leaf) = node {
+            if *leaf.key == *key {
+                return Some(std::mem::replace(&mut leaf.value, value));
+            }
+            // Expand the leaf into a node branching where the keys differ.
+            let p = depth + common_prefix(&leaf.key[depth..], &key[depth..]);
+            let mut inner = Node::node4(key[depth..p].to_vec());
+            let Some(Node::Leaf(leaf)) = slot.take() else {
+                unreachable!()
+            };
+            inner.place(leaf, p);
+            inner.place(
+                Box::new(Leaf {
+                    key: key.into(),
+                    value,
+                }),
+                p,
+            );
+            *slot = Some(inner);
+            return None;
+        }
+
+        let (header, children) = node.inner_mut().unwrap();
+        let p = common_prefix(&header.prefix, &key[depth..]);
+        if p < header.prefix.len() {
+            // Split the compressed path where the key leaves it.
+            let mut inner = Node::node4(header.prefix[..p].to_vec());
+            let b = header.prefix[p];
+            header.prefix.drain(..=p);
+            let node = slot.take().unwrap();
+            inner.inner_mut().unwrap().1.add(b, node);
+            inner.place(
+                Box::new(Leaf {
+                    key: key.into(),
+                    value,
+                }),
+                depth + p,
+            );
+            *slot = Some(inner);
+            return None;
+        }
+
+        let depth = depth + p;
+        let Some(&b) = key.get(depth) else {
+            return match &mut header.value {
+                Some(leaf) => Some(std::mem::replace(&mut leaf.value, value)),
+                None => {
+                    header.value = Some(Box::new(Leaf {
+                        key: key.into(),
+                        value,
+                    }));
+                    None
+                }
+            };
+        };
+        if let Some(child) = children.get_mut(b) {
+            return Self::insert_at(child, key, depth + 1, value);
+        }
+        if children.is_full() {
+            let len = children.len() + 1;
+            *slot = Some(slot.take().unwrap().resize(len));
+        }
+        let (_, children) = slot.as_mut().unwrap().inner_mut().unwrap();
+        children.add(b, Node::leaf(key, value));
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let ret = Self::remove_at(&mut self.root, key, 0);
+        if ret.is_some() {
+            self.len -= 1;
+        }
+        ret
+    }
+
+    fn remove_at(slot: &mut Option<Node<V>>, key: &[u8], depth: usize) -> Option<V> {
+        let node = slot.as_mut()?;
+        let Some((header, children)) = node.inner_mut() else {
+            let Some(Node::Leaf(leaf)) = slot.take_if(|x| {
+                let Node::Leaf(leaf) = x else { unreachable!() };
+                *leaf.key == *key
+            }) else {
+                return None;
+            };
+            return Some(leaf.value);
+        };
+        if !key[depth..].starts_with(&header.prefix) {
+            return None;
+        }
+        let depth = depth + header.prefix.len();
+        let ret = match key.get(depth) {
+            None => header.value.take()?.value,
+            Some(&b) => match children.get(b)? {
+                Node::Leaf(leaf) if *leaf.key == *key => {
+                    let Some(Node::Leaf(leaf)) = children.remove(b) else {
+                        unreachable!()
+                    };
+                    leaf.value
+                }
+                Node::Leaf(_) => return None,
+                // An inner node keeps at least two entries, so it is never
+                // emptied by the removal.
+                _ => Self::remove_at(children.get_mut(b).unwrap(), key, depth + 1)?,
+            },
+        };
+        Self::shrink(slot);
+        Some(ret)
+    }
+
+    /// Restores the invariants of an inner node that lost an entry: a node
+    /// left with a single entry collapses into it, and the others move to
+    /// a smaller size when they get sparse enough.
+    fn shrink(slot: &mut Option<Node<V>>) {
+        let node = slot.as_mut().unwrap();
+        let (header, children) = node.inner_mut().unwrap();
+        let len = children.len();
+        match (len, header.value.is_some()) {
+            (0, _) => *slot = header.value.take().map(Node::Leaf),
+            (1, false) => {
+                let prefix = std::mem::take(&mut header.prefix);
+                let (b, mut child) = children.take_all().pop().unwrap();
+                if let Some((header, _)) = child.inner_mut() {
+                    let mut path = prefix;
+                    path.push(b);
+                    path.append(&mut header.prefix);
+                    header.prefix = path;
+                }
+                *slot = Some(child);
+            }
+            _ => {
+                let shrink = match node {
+                    Node::N16(_) => len <= SHRINK_16,
+                    Node::N48(_) => len <= SHRINK_48,
+                    Node::N256(_) => len <= SHRINK_256,
+                    _ => false,
+                };
+                if shrink {
+                    *slot = Some(slot.take().unwrap().resize(len));
+                }
+            }
+        }
+    }
+
+    pub fn first(&self) -> Option<(&[u8], &V)> {
+        self.iter().next()
+    }
+
+    pub fn last(&self) -> Option<(&[u8], &V)> {
+        let mut node = self.root.as_ref()?;
+        loop {
+            let (header, children) = match node {
+                Node::Leaf(leaf) => return Some((&leaf.key, &leaf.value)),
+                _ => node.inner().unwrap(),
+            };
+            // Every inner node has a child or an entry of its own.
+            match children.last() {
+                Some(child) => node = child,
+                None => return header.value.as_ref().map(|leaf| (&*leaf.key, &leaf.value)),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        if let Some(root) = &self.root {
+            iter.push(root, None);
+        }
+        iter
+    }
+
+    /// Iterates in key order over the entries with keys at or after `start`.
+    pub fn range_from(&self, start: &[u8]) -> Iter<'_, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        if let Some(root) = &self.root {
+            iter.seek(root, start, 0);
+        }
+        iter
+    }
+
+    /// Iterates in key order over the entries whose keys start with
+    /// `prefix`.
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a [u8], &'a V)> {
+        self.range_from(prefix)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    pub fn stats(&self) -> ArtStats {
+        fn walk<V>(node: &Node<V>, stats: &mut ArtStats) {
+            let leaf_bytes = |leaf: &Leaf<V>| size_of::<Leaf<V>>() + leaf.key.len();
+            let (header, children) = match node {
+                Node::Leaf(leaf) => {
+                    stats.leaves += 1;
+                    stats.bytes += leaf_bytes(leaf);
+                    return;
+                }
+                _ => node.inner().unwrap(),
+            };
+            let (count, size) = match node {
+                Node::N4(_) => (&mut stats.node4, size_of::<Inner<V, Node4<V>>>()),
+                Node::N16(_) => (&mut stats.node16, size_of::<Inner<V, Node16<V>>>()),
+                Node::N48(_) => (&mut stats.node48, size_of::<Inner<V, Node48<V>>>()),
+                Node::N256(_) => (&mut stats.node256, size_of::<Inner<V, Node256<V>>>()),
+                Node::Leaf(_) => unreachable!(),
+            };
+            *count += 1;
+            stats.bytes += size + header.prefix.capacity();
+            if let Some(leaf) = &header.value {
+                stats.leaves += 1;
+                stats.bytes += leaf_bytes(leaf);
+            }
+            let mut after = None;
+            while let Some((b, child)) = children.next(after) {
+                walk(child, stats);
+                after = Some(b);
+            }
+        }
+        let mut stats = ArtStats::default();
+        if let Some(root) = &self.root {
+            walk(root, &mut stats);
+        }
+        stats
+    }
+}
+
+enum Frame<'a, V> {
+    Leaf(&'a Leaf<V>),
+    Inner {
+        header: &'a Header<V>,
+        children: &'a dyn Children<V>,
+        /// Whether the entry of the header is still to be returned.
+        value: bool,
+        /// Key byte of the last child visited.
+        after: Option<u8>,
+    },
+}
+
+/// Depth-first walk over the tree in key order.
+pub struct Iter<'a, V> {
+    stack: Vec<Frame<'a, V>>,
+}
+
+impl<'a, V> Iter<'a, V> {
+    /// Pushes `node` to be walked from its first entry, or from the child
+    /// after `after` without its own entry.
+    fn push(&mut self, node: &'a Node<V>, after: Option<u8>) {
+        self.stack.push(match node {
+            Node::Leaf(leaf) => Frame::Leaf(leaf),
+            _ => {
+                let (header, children) = node.inner().unwrap();
+                Frame::Inner {
+                    header,
+                    children,
+                    value: after.is_none(),
+                    after,
+                }
+            }
+        });
+    }
+
+    /// Pushes the parts of `node`, reached at `depth`, with keys at or
+    /// after `start`.
+    fn seek(&mut self, node: &'a Node<V>, start: &[u8], depth: usize) {
+        let (header, children) = match node {
+            Node::Leaf(leaf) => {
+                if *leaf.key >= *start {
+                    self.push(node, None);
+                }
+                return;
+            }
+            _ => node.inner().unwrap(),
+        };
+        let rest = &start[depth..];
+        let n = std::cmp::min(header.prefix.len(), rest.len());
+        match header.prefix[..n].cmp(&rest[..n]) {
+            std::cmp::Ordering::Less => {}
+            std::cmp::Ordering::Greater => self.push(node, None),
+            std::cmp::Ordering::Equal => match rest.get(n) {
+                // `start` ends within or right after the prefix, so every
+                // key below is at or after it.
+                None => self.push(node, None),
+                Some(&b) if n < header.prefix.len() => {
+                    if header.prefix[n] > b {
+                        self.push(node, None);
+                    }
+                }
+                Some(&b) => {
+                    self.push(node, Some(b));
+                    if let Some(child) = children.get(b) {
+                        self.seek(child, start, depth + n + 1);
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a [u8], &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match self.stack.last_mut()? {
+                Frame::Leaf(leaf) => {
+                    let leaf = *leaf;
+                    self.stack.pop();
+                    return Some((&leaf.key, &leaf.value));
+                }
+                Frame::Inner {
+                    header,
+                    children,
+                    value,
+                    after,
+                } => {
+                    if std::mem::take(value) {
+                        if let Some(leaf) = &header.value {
+                            return Some((&leaf.key, &leaf.value));
+                        }
+                    }
+                    let next = children.next(*after);
+                    if let Some((b, _)) = next {
+                        *after = Some(b);
+                    }
+                    next
+                }
+            };
+            match next {
+                Some((_, child)) => self.push(child, None),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl<'a, V> IntoIterator for &'a Art<V> {
+    type Item = (&'a [u8], &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: AsRef<[u8]>, V> FromIterator<(K, V)> for Art<V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut art = Art::new();
+        art.extend(iter);
+        art
+    }
+}
+
+impl<K: AsRef<[u8]>, V> Extend<(K, V)> for Art<V> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k.as_ref(), v);
+        }
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for Art<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(k, v)| (String::from_utf8_lossy(k), v)))
+            .finish()
+    }
+}
+
+impl<V: HeapSize> MemoryUsage for Art<V> {
+    fn memory_usage(&self) -> Memory {
+        let keys =
+            self.len * size_of::<Box<[u8]>>() + self.iter().map(|(k, _)| k.len()).sum::<usize>();
+        Memory {
+            // Leaves hold their key and value, prefixes are part of the nodes.
+            nodes: self.stats().bytes - keys - self.len * size_of::<V>(),
+            keys,
+            values: sum_with_heap(self.iter().map(|(_, v)| v)),
+            overhead: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut art = Art::new();
+        assert_eq!(art.insert(b"user12", 1), None);
+        assert_eq!(art.insert(b"user1", 2), None);
+        assert_eq!(art.insert(b"user123", 3), None);
+        assert_eq!(art.insert(b"user1", 4), Some(2));
+        assert_eq!(art.insert(b"", 5), None);
+        assert_eq!(art.len(), 4);
+        assert_eq!(art.get(b"user1"), Some(&4));
+        assert_eq!(art.get(b"user"), None);
+        assert_eq!(art.get(b"user1234"), None);
+        assert_eq!(art.get(b""), Some(&5));
+        *art.get_mut(b"user123").unwrap() += 10;
+        let keys: Vec<&[u8]> = art.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, [&b""[..], b"user1", b"user12", b"user123"]);
+        assert_eq!(art.remove(b"user12"), Some(1));
+        assert_eq!(art.remove(b"user12"), None);
+        assert_eq!(art.remove(b"user1"), Some(4));
+        assert_eq!(art.remove(b""), Some(5));
+        assert_eq!(art.get(b"user123"), Some(&13));
+        assert_eq!(art.len(), 1);
+        assert_eq!(
+            art.stats(),
+            ArtStats {
+                leaves: 1,
+                bytes: size_of::<Leaf<i32>>() + 7,
+                ..ArtStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        let mut art = Art::new();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..50000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            // Short keys over a small alphabet, so that many are prefixes
+            // of others, and a wide first byte to fill up large nodes.
+            let len = (x >> 60) as usize % 5;
+            let mut key = vec![(x >> 20) as u8];
+            key.extend((0..len).map(|i| b'a' + ((x >> (30 + 3 * i)) & 3) as u8));
+            if x & 3 == 0 {
+                assert_eq!(art.remove(&key), map.remove(&key));
+            } else {
+                assert_eq!(art.insert(&key, x), map.insert(key, x));
+            }
+        }
+        assert_eq!(art.len(), map.len());
+        assert!(art.iter().eq(map.iter().map(|(k, v)| (&k[..], v))));
+        for start in [&b""[..], b"\x10", b"\x10ab", b"\x80b", b"\xff\xff"] {
+            assert!(art
+                .range_from(start)
+                .eq(map.range(start.to_vec()..).map(|(k, v)| (&k[..], v))));
+        }
+        assert!(art.prefix(b"\x42a").eq(map
+            .range(b"\x42a".to_vec()..b"\x42b".to_vec())
+            .map(|(k, v)| (&k[..], v))));
+        let stats = art.stats();
+        assert_eq!(stats.leaves, map.len());
+        assert!(stats.node256 > 0);
+
+        let keys: Vec<Vec<u8>> = map.keys().cloned().collect();
+        for key in keys {
+            assert_eq!(art.remove(&key), map.remove(&key));
+        }
+        assert!(art.is_empty());
+        assert_eq!(art.stats(), ArtStats::default());
+    }
+
+    #[test]
+    fn ycsb_keys() {
+        let keys: Vec<String> = (0..10000u64)
+            .map(|i| format!("user{}", i.wrapping_mul(0x9e3779b97f4a7c15)))
+            .collect();
+        let art: Art<usize> = keys.iter().enumerate().map(|(i, k)| (k, i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(art.get(key.as_bytes()), Some(&i));
+        }
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert!(art
+            .iter()
+            .map(|(k, _)| k)
+            .eq(sorted.iter().map(|k| k.as_bytes())));
+    }
+}
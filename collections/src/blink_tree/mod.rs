@@ -0,0 +1,804 @@
+use std::borrow::Borrow;
+use std::mem::size_of;
+use std::ops::Bound;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+
+use crate::memory::{size_with_heap, HeapSize, Memory, MemoryUsage};
+use crate::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use crate::sync::{hint, thread};
+
+/// Entries of a leaf and children of an internal node.
+#[cfg(not(loom))]
+const CAPACITY: usize = 32;
+#[cfg(loom)]
+const CAPACITY: usize = 4;
+
+/// Restarts of one operation between yields of the thread.
+const YIELD_RESTARTS: u32 = 16;
+
+/// An optimistic read saw a node change under it; the operation starts over
+/// from the root.
+struct Restart;
+
+/// Node of the tree. Every field is atomic so that readers can look at a node
+/// without locking it; they check afterwards that its version did not move.
+///
+/// The version is even when the node is unlocked. Writers lock it by moving
+/// it to the next odd number and unlock it by moving it to the next even one.
+struct Node<K, V> {
+    version: AtomicU64,
+    leaf: bool,
+    len: AtomicUsize,
+    /// Key of each entry of a leaf. In an internal node `keys[i]` is the
+    /// first key of child `i + 1`.
+    keys: [Atomic<K>; CAPACITY],
+    values: [Atomic<V>; CAPACITY],
+    children: [Atomic<Node<K, V>>; CAPACITY],
+    /// Right sibling of a leaf.
+    next: Atomic<Node<K, V>>,
+}
+
+impl<K, V> Node<K, V> {
+    fn new(leaf: bool) -> Self {
+        Node {
+            version: AtomicU64::new(0),
+            leaf,
+            len: AtomicUsize::new(0),
+            keys: std::array::from_fn(|_| Atomic::null()),
+            values: std::array::from_fn(|_| Atomic::null()),
+            children: std::array::from_fn(|_| Atomic::null()),
+            next: Atomic::null(),
+        }
+    }
+
+    fn read_lock(&self) -> Result<u64, Restart> {
+        let version = self.version.load(Ordering::Acquire);
+        if version & 1 == 1 {
+            hint::spin_loop();
+            return Err(Restart);
+        }
+        Ok(version)
+    }
+
+    /// Checks that nothing read since `read_lock` returned `version` was
+    /// changed by a writer.
+    fn validate(&self, version: u64) -> Result<(), Restart> {
+        fence(Ordering::Acquire);
+        if self.version.load(Ordering::Relaxed) != version {
+            return Err(Restart);
+        }
+        Ok(())
+    }
+
+    /// Locks the node if it is still at `version`.
+    fn upgrade(&self, version: u64) -> Result<(), Restart> {
+        self.version
+            .compare_exchange(version, version + 1, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|_| Restart)
+    }
+
+    fn unlock(&self) {
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Length read optimistically, which may be garbage until validated.
+    fn len(&self) -> Result<usize, Restart> {
+        let len = self.len.load(Ordering::Acquire);
+        if len > CAPACITY {
+            return Err(Restart);
+        }
+        Ok(len)
+    }
+
+    fn key<'g>(&self, i: usize, guard: &'g Guard) -> Result<&'g K, Restart> {
+        unsafe { self.keys[i].load(Ordering::Acquire, guard).as_ref() }.ok_or(Restart)
+    }
+
+    fn value<'g>(&self, i: usize, guard: &'g Guard) -> Result<&'g V, Restart> {
+        unsafe { self.values[i].load(Ordering::Acquire, guard).as_ref() }.ok_or(Restart)
+    }
+
+    /// Index of the child covering `key`.
+    fn child_index<Q>(&self, key: &Q, guard: &Guard) -> Result<usize, Restart>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let len = self.len()?;
+        if len == 0 {
+            return Err(Restart);
+        }
+        let (mut lo, mut hi) = (0, len - 1);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.key(mid, guard)?.borrow() <= key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// Position of `key` in a leaf, or where it would be inserted.
+    fn search<Q>(&self, key: &Q, guard: &Guard) -> Result<Result<usize, usize>, Restart>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (mut lo, mut hi) = (0, self.len()?);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.key(mid, guard)?.borrow().cmp(key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(lo))
+    }
+
+    /// Moves the slots `from..len` of `src` to the start of `dst`, which must
+    /// be a new node of the same kind.
+    fn move_slots(src: &Self, dst: &Self, from: usize, len: usize, guard: &Guard) {
+        for i in from..len {
+            let j = i - from;
+            dst.keys[j].store(
+                src.keys[i].load(Ordering::Relaxed, guard),
+                Ordering::Relaxed,
+            );
+            dst.values[j].store(
+                src.values[i].load(Ordering::Relaxed, guard),
+                Ordering::Relaxed,
+            );
+            dst.children[j].store(
+                src.children[i].load(Ordering::Relaxed, guard),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Shifts the slots `at..len` of `slots` one to the right.
+    fn shift_right<T>(slots: &[Atomic<T>], at: usize, len: usize, guard: &Guard) {
+        for i in (at..len).rev() {
+            slots[i + 1].store(slots[i].load(Ordering::Relaxed, guard), Ordering::Release);
+        }
+    }
+
+    /// Shifts the slots `at + 1..len` of `slots` one to the left.
+    fn shift_left<T>(slots: &[Atomic<T>], at: usize, len: usize, guard: &Guard) {
+        for i in at + 1..len {
+            slots[i - 1].store(slots[i].load(Ordering::Relaxed, guard), Ordering::Release);
+        }
+    }
+}
+
+/// Concurrent ordered map on a B-link tree with optimistic lock coupling.
+///
+/// Readers never lock: they note the version of each node, read it, and
+/// check the version again before trusting what they read, starting over
+/// from the root when a writer got in between. Writers lock only the nodes
+/// they change, splitting full nodes on the way down so that a split never
+/// has to go back up. Leaves link to their right sibling for scans.
+///
+/// Nodes are not merged when entries are removed; the space is reused by
+/// later inserts into the same key range. Removed keys and values are freed
+/// through epoch-based reclamation once no reader can see them, and nodes
+/// when the tree is dropped.
+pub struct BLinkTree<K, V> {
+    root: Atomic<Node<K, V>>,
+    len: AtomicUsize,
+}
+
+impl<K, V> Default for BLinkTree<K, V>
+where
+    K: Ord + Clone + Send + Sync,
+    V: Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> BLinkTree<K, V>
+where
+    K: Ord + Clone + Send + Sync,
+    V: Send + Sync,
+{
+    pub fn new() -> Self {
+        BLinkTree {
+            root: Atomic::new(Node::new(true)),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of entries; concurrent writers may make it stale immediately.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `f` until it completes without a restart, yielding now and then
+    /// in case the writer holding it up is waiting for a CPU.
+    fn retry<'g, T>(guard: &'g Guard, mut f: impl FnMut(&'g Guard) -> Result<T, Restart>) -> T {
+        let mut restarts = 0;
+        loop {
+            if let Ok(x) = f(guard) {
+                return x;
+            }
+            restarts += 1;
+            if restarts % YIELD_RESTARTS == 0 {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// The root, read-locked, after checking it is still the root.
+    fn root<'g>(&self, guard: &'g Guard) -> Result<(Shared<'g, Node<K, V>>, u64), Restart> {
+        let root = self.root.load(Ordering::Acquire, guard);
+        let version = unsafe { root.deref() }.read_lock()?;
+        if self.root.load(Ordering::Acquire, guard) != root {
+            return Err(Restart);
+        }
+        Ok((root, version))
+    }
+
+    /// Read-locks child `i` of `node`, then checks that `node` did not
+    /// change so that the child still covers the keys it was chosen for.
+    fn child<'g>(
+        node: &Node<K, V>,
+        version: u64,
+        i: usize,
+        guard: &'g Guard,
+    ) -> Result<(&'g Node<K, V>, u64), Restart> {
+        let child =
+            unsafe { node.children[i].load(Ordering::Acquire, guard).as_ref() }.ok_or(Restart)?;
+        let child_version = child.read_lock()?;
+        node.validate(version)?;
+        Ok((child, child_version))
+    }
+
+    /// The leaf covering `key`, or the first leaf for `None`, read-locked.
+    fn leaf<'g, Q>(
+        &self,
+        key: Option<&Q>,
+        guard: &'g Guard,
+    ) -> Result<(&'g Node<K, V>, u64), Restart>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (root, mut version) = self.root(guard)?;
+        let mut node = unsafe { root.deref() };
+        while !node.leaf {
+            let i = match key {
+                Some(key) => node.child_index(key, guard)?,
+                None => 0,
+            };
+            (node, version) = Self::child(node, version, i, guard)?;
+        }
+        Ok((node, version))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        Self::retry(&epoch::pin(), |guard| {
+            let (leaf, version) = self.leaf(Some(key), guard)?;
+            let value = match leaf.search(key, guard)? {
+                Ok(i) => Some(leaf.value(i, guard)?.clone()),
+                Err(_) => None,
+            };
+            leaf.validate(version)?;
+            Ok(value)
+        })
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Self::retry(&epoch::pin(), |guard| {
+            let (leaf, version) = self.leaf(Some(key), guard)?;
+            let found = leaf.search(key, guard)?.is_ok();
+            leaf.validate(version)?;
+            Ok(found)
+        })
+    }
+
+    /// Inserts `value` under `key`, replacing any previous value. Returns
+    /// whether the key is new.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let mut entry = Some((key, value));
+        Self::retry(&epoch::pin(), |guard| self.try_insert(&mut entry, guard))
+    }
+
+    fn try_insert(&self, entry: &mut Option<(K, V)>, guard: &Guard) -> Result<bool, Restart> {
+        let key = &entry.as_ref().unwrap().0;
+        let (root, mut version) = self.root(guard)?;
+        let mut node = unsafe { root.deref() };
+        let mut parent: Option<(&Node<K, V>, u64, usize)> = None;
+        loop {
+            if node.len()? == CAPACITY {
+                self.split(node, version, parent, guard)?;
+                return Err(Restart);
+            }
+            if node.leaf {
+                break;
+            }
+            let i = node.child_index(key, guard)?;
+            let (child, child_version) = Self::child(node, version, i, guard)?;
+            parent = Some((node, version, i));
+            (node, version) = (child, child_version);
+        }
+
+        let pos = node.search(key, guard)?;
+        node.upgrade(version)?;
+        match pos {
+            Ok(i) => {
+                let (_, value) = entry.take().unwrap();
+                let old = node.values[i].swap(Owned::new(value), Ordering::Release, guard);
+                node.unlock();
+                unsafe { guard.defer_destroy(old) };
+                Ok(false)
+            }
+            Err(i) => {
+                let (key, value) = entry.take().unwrap();
+                let len = node.len.load(Ordering::Relaxed);
+                Node::<K, V>::shift_right(&node.keys, i, len, guard);
+                Node::<K, V>::shift_right(&node.values, i, len, guard);
+                node.keys[i].store(Owned::new(key), Ordering::Release);
+                node.values[i].store(Owned::new(value), Ordering::Release);
+                node.len.store(len + 1, Ordering::Release);
+                node.unlock();
+                self.len.fetch_add(1, Ordering::Relaxed);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Splits the full `node` in half under the locks of the node and of its
+    /// parent, which is not full as it was split on the way down otherwise.
+    /// `parent` holds the index of `node` among its children.
+    fn split(
+        &self,
+        node: &Node<K, V>,
+        version: u64,
+        parent: Option<(&Node<K, V>, u64, usize)>,
+        guard: &Guard,
+    ) -> Result<(), Restart> {
+        match parent {
+            Some((parent, parent_version, _)) => {
+                parent.upgrade(parent_version)?;
+                if let Err(e) = node.upgrade(version) {
+                    parent.unlock();
+                    return Err(e);
+                }
+            }
+            None => node.upgrade(version)?,
+        }
+
+        let len = node.len.load(Ordering::Relaxed);
+        let mid = len / 2;
+        let right = Owned::new(Node::new(node.leaf));
+        let sep = if node.leaf {
+            Node::move_slots(node, &right, mid, len, guard);
+            right.len.store(len - mid, Ordering::Relaxed);
+            right
+                .next
+                .store(node.next.load(Ordering::Relaxed, guard), Ordering::Relaxed);
+            let first = unsafe { node.keys[mid].load(Ordering::Relaxed, guard).deref() };
+            Owned::new(first.clone()).into_shared(guard)
+        } else {
+            // Child `mid` starts the right node and the key before it moves
+            // up to the parent.
+            Node::move_slots(node, &right, mid, len, guard);
+            right.len.store(len - mid, Ordering::Relaxed);
+            node.keys[mid - 1].load(Ordering::Relaxed, guard)
+        };
+        let right = right.into_shared(guard);
+        if node.leaf {
+            node.next.store(right, Ordering::Release);
+        }
+        node.len.store(mid, Ordering::Release);
+
+        match parent {
+            Some((parent, _, i)) => {
+                let len = parent.len.load(Ordering::Relaxed);
+                Node::<K, V>::shift_right(&parent.keys, i, len - 1, guard);
+                Node::<K, V>::shift_right(&parent.children, i + 1, len, guard);
+                parent.keys[i].store(sep, Ordering::Release);
+                parent.children[i + 1].store(right, Ordering::Release);
+                parent.len.store(len + 1, Ordering::Release);
+                parent.unlock();
+            }
+            None => {
+                let root = Owned::new(Node::new(false));
+                root.keys[0].store(sep, Ordering::Relaxed);
+                root.children[0].store(Shared::from(node as *const _), Ordering::Relaxed);
+                root.children[1].store(right, Ordering::Relaxed);
+                root.len.store(2, Ordering::Relaxed);
+                self.root.store(root, Ordering::Release);
+            }
+        }
+        node.unlock();
+        Ok(())
+    }
+
+    /// Replaces the value of `key` with `f` of the current one. Returns
+    /// whether the key was found.
+    pub fn update<Q, F>(&self, key: &Q, f: F) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        F: FnOnce(&V) -> V,
+    {
+        let mut f = Some(f);
+        Self::retry(&epoch::pin(), |guard| {
+            let (leaf, version) = self.leaf(Some(key), guard)?;
+            let Ok(i) = leaf.search(key, guard)? else {
+                leaf.validate(version)?;
+                return Ok(false);
+            };
+            leaf.upgrade(version)?;
+            let old = leaf.values[i].load(Ordering::Relaxed, guard);
+            let value = (f.take().unwrap())(unsafe { old.deref() });
+            leaf.values[i].store(Owned::new(value), Ordering::Release);
+            leaf.unlock();
+            unsafe { guard.defer_destroy(old) };
+            Ok(true)
+        })
+    }
+
+    /// Removes `key`, returning whether it was found.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Self::retry(&epoch::pin(), |guard| {
+            let (leaf, version) = self.leaf(Some(key), guard)?;
+            let Ok(i) = leaf.search(key, guard)? else {
+                leaf.validate(version)?;
+                return Ok(false);
+            };
+            leaf.upgrade(version)?;
+            let len = leaf.len.load(Ordering::Relaxed);
+            let old_key = leaf.keys[i].load(Ordering::Relaxed, guard);
+            let old_value = leaf.values[i].load(Ordering::Relaxed, guard);
+            Node::<K, V>::shift_left(&leaf.keys, i, len, guard);
+            Node::<K, V>::shift_left(&leaf.values, i, len, guard);
+            leaf.len.store(len - 1, Ordering::Release);
+            leaf.unlock();
+            unsafe {
+                guard.defer_destroy(old_key);
+                guard.defer_destroy(old_value);
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            Ok(true)
+        })
+    }
+
+    /// Up to `limit` entries in key order from `start`. Each leaf is read
+    /// consistently, but entries changed concurrently in leaves not yet
+    /// reached may or may not be seen.
+    pub fn range<Q>(&self, start: Bound<&Q>, limit: usize) -> Vec<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        let guard = &epoch::pin();
+        let start_key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let (mut leaf, _) = Self::retry(guard, |guard| self.leaf(start_key, guard));
+        let mut ret: Vec<(K, V)> = Vec::new();
+        let mut buf = Vec::new();
+        while ret.len() < limit {
+            // Leaves are never removed and a split only moves entries to
+            // the right, so a leaf that changed is simply read again.
+            let next = Self::retry(guard, |guard| {
+                buf.clear();
+                let version = leaf.read_lock()?;
+                let next = Self::read_leaf(leaf, start, ret.last(), &mut buf, guard)?;
+                leaf.validate(version)?;
+                Ok(next)
+            });
+            ret.extend(buf.drain(..).take(limit - ret.len()));
+            match unsafe { next.as_ref() } {
+                Some(next) => leaf = next,
+                None => break,
+            }
+        }
+        ret
+    }
+
+    /// Clones the entries of `leaf` after `start` and after the `last` one
+    /// returned into `buf`, returning the next leaf.
+    fn read_leaf<'g, Q>(
+        leaf: &Node<K, V>,
+        start: Bound<&Q>,
+        last: Option<&(K, V)>,
+        buf: &mut Vec<(K, V)>,
+        guard: &'g Guard,
+    ) -> Result<Shared<'g, Node<K, V>>, Restart>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        for i in 0..leaf.len()? {
+            let key = leaf.key(i, guard)?;
+            let after = match last {
+                Some((last, _)) => key > last,
+                None => match start {
+                    Bound::Included(start) => key.borrow() >= start,
+                    Bound::Excluded(start) => key.borrow() > start,
+                    Bound::Unbounded => true,
+                },
+            };
+            if after {
+                buf.push((key.clone(), leaf.value(i, guard)?.clone()));
+            }
+        }
+        Ok(leaf.next.load(Ordering::Acquire, guard))
+    }
+}
+
+impl<K, V> Drop for BLinkTree<K, V> {
+    fn drop(&mut self) {
+        unsafe fn free<K, V>(node: Shared<'_, Node<K, V>>, guard: &Guard) {
+            let node = node.into_owned();
+            let len = node.len.load(Ordering::Relaxed);
+            if node.leaf {
+                for i in 0..len {
+                    drop(node.keys[i].load(Ordering::Relaxed, guard).into_owned());
+                    drop(node.values[i].load(Ordering::Relaxed, guard).into_owned());
+                }
+            } else {
+                for i in 0..len {
+                    if i > 0 {
+                        drop(node.keys[i - 1].load(Ordering::Relaxed, guard).into_owned());
+                    }
+                    free(node.children[i].load(Ordering::Relaxed, guard), guard);
+                }
+            }
+        }
+        unsafe {
+            let guard = epoch::unprotected();
+            free(self.root.load(Ordering::Relaxed, guard), guard);
+        }
+    }
+}
+
+/// Walks the nodes without locking them, so that writes running at the same
+/// time may be missed or counted twice.
+impl<K: HeapSize, V: HeapSize> MemoryUsage for BLinkTree<K, V> {
+    fn memory_usage(&self) -> Memory {
+        fn size<T: HeapSize>(slot: &Atomic<T>, guard: &Guard) -> usize {
+            unsafe { slot.load(Ordering::Acquire, guard).as_ref() }.map_or(0, size_with_heap)
+        }
+        fn walk<K: HeapSize, V: HeapSize>(node: &Node<K, V>, guard: &Guard, memory: &mut Memory) {
+            memory.nodes += size_of::<Node<K, V>>();
+            let len = std::cmp::min(node.len.load(Ordering::Acquire), CAPACITY);
+            for i in 0..len {
+                if node.leaf {
+                    memory.keys += size(&node.keys[i], guard);
+                    memory.values += size(&node.values[i], guard);
+                    continue;
+                }
+                if i + 1 < len {
+                    memory.overhead += size(&node.keys[i], guard);
+                }
+                if let Some(child) =
+                    unsafe { node.children[i].load(Ordering::Acquire, guard).as_ref() }
+                {
+                    walk(child, guard, memory);
+                }
+            }
+        }
+        let guard = epoch::pin();
+        let mut memory = Memory::default();
+        if let Some(root) = unsafe { self.root.load(Ordering::Acquire, &guard).as_ref() } {
+            walk(root, &guard, &mut memory);
+        }
+        memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn matches_btreemap() {
+        let tree = BLinkTree::new();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..50000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % 5000;
+            match x & 3 {
+                0 => assert_eq!(tree.remove(&key), map.remove(&key).is_some()),
+                1 => assert_eq!(
+                    tree.update(&key, |v| v + 1),
+                    map.get_mut(&key).map(|v| *v += 1).is_some()
+                ),
+                _ => assert_eq!(tree.insert(key, x), map.insert(key, x).is_none()),
+            }
+        }
+        assert_eq!(tree.len(), map.len());
+        for key in 0..5000 {
+            assert_eq!(tree.get(&key), map.get(&key).copied());
+        }
+        let all: Vec<(u64, u64)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(tree.range(Bound::Unbounded, usize::MAX), all);
+        let from: Vec<(u64, u64)> = map
+            .range((Bound::Excluded(1000), Bound::Unbounded))
+            .take(100)
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        assert_eq!(tree.range(Bound::Excluded(&1000), 100), from);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_reads() {
+        const THREADS: u64 = 8;
+        const KEYS: u64 = 20000;
+        let tree = Arc::new(BLinkTree::new());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for i in 0..KEYS {
+                        let key = i * THREADS + t;
+                        assert!(tree.insert(key, key));
+                        assert_eq!(tree.get(&key), Some(key));
+                        if i % 3 == 0 {
+                            assert!(tree.remove(&key));
+                        }
+                        if i % 1000 == 0 {
+                            let scan = tree.range(Bound::Included(&(key / 2)), 100);
+                            assert!(scan.windows(2).all(|x| x[0].0 < x[1].0));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let keys: Vec<u64> = tree
+            .range(Bound::Unbounded, usize::MAX)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        let expected: Vec<u64> = (0..KEYS * THREADS)
+            .filter(|key| !(key / THREADS).is_multiple_of(3))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.len(), expected.len());
+    }
+}
+
+#[cfg(all(test, loom))]
+mod model {
+    use super::*;
+    use crate::sync::model::{self, Tracked};
+    use loom::sync::Arc;
+
+    /// Tree of one full leaf, so that the next insert splits it.
+    fn full_leaf() -> BLinkTree<u64, u64> {
+        let tree = BLinkTree::new();
+        for key in 0..CAPACITY as u64 {
+            tree.insert(key * 10, key * 10);
+        }
+        tree
+    }
+
+    fn keys(tree: &BLinkTree<u64, u64>) -> Vec<u64> {
+        let entries = tree.range(Bound::Unbounded, usize::MAX);
+        entries.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn get_during_split() {
+        model::check(|| {
+            let tree = Arc::new(full_leaf());
+            let writer = {
+                let tree = tree.clone();
+                thread::spawn(move || assert!(tree.insert(15, 15)))
+            };
+            assert_eq!(tree.get(&20), Some(20));
+            assert!(matches!(tree.get(&15), None | Some(15)));
+            writer.join().unwrap();
+            assert_eq!(tree.get(&15), Some(15));
+            assert_eq!(keys(&tree), [0, 10, 15, 20, 30]);
+        });
+    }
+
+    #[test]
+    fn concurrent_splits() {
+        model::check(|| {
+            let tree = Arc::new(full_leaf());
+            let writer = {
+                let tree = tree.clone();
+                thread::spawn(move || assert!(tree.insert(25, 25)))
+            };
+            assert!(tree.insert(5, 5));
+            writer.join().unwrap();
+            assert_eq!(keys(&tree), [0, 5, 10, 20, 25, 30]);
+            assert_eq!(tree.len(), 6);
+        });
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        model::check(|| {
+            let tree = Arc::new(full_leaf());
+            let remover = {
+                let tree = tree.clone();
+                thread::spawn(move || assert!(tree.remove(&10)))
+            };
+            assert!(tree.insert(5, 5));
+            assert!(!tree.remove(&7));
+            remover.join().unwrap();
+            assert_eq!(keys(&tree), [0, 5, 20, 30]);
+            assert_eq!(tree.len(), 4);
+        });
+    }
+
+    #[test]
+    fn scan_during_split() {
+        model::check(|| {
+            let tree = Arc::new(full_leaf());
+            let writer = {
+                let tree = tree.clone();
+                thread::spawn(move || assert!(tree.insert(35, 35)))
+            };
+            // Keys present for the whole scan are all seen, in order.
+            let scan = keys(&tree);
+            assert!(scan == [0, 10, 20, 30] || scan == [0, 10, 20, 30, 35]);
+            writer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn reclamation() {
+        model::check(|| {
+            let tree = Arc::new(BLinkTree::new());
+            for key in 0..3 {
+                tree.insert(key, Tracked::new(key));
+            }
+            let writer = {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    assert!(tree.remove(&1));
+                    assert!(!tree.insert(2, Tracked::new(20)));
+                })
+            };
+            // A value read during the writes is the old or the new one.
+            assert!(matches!(tree.get(&1).map(|v| v.value), None | Some(1)));
+            assert!(matches!(tree.get(&2).map(|v| v.value), Some(2 | 20)));
+            writer.join().unwrap();
+            assert_eq!(tree.get(&2).map(|v| v.value), Some(20));
+            assert_eq!(tree.len(), 2);
+        });
+    }
+}
@@ -0,0 +1,850 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+
+use crate::memory::{spare, sum_with_heap, HeapSize, Memory, MemoryUsage};
+
+const NIL: usize = usize::MAX;
+const DEFAULT_FANOUT: usize = 64;
+
+enum Kind<V> {
+    /// Child `i` holds the keys before `keys[i]` and at or after
+    /// `keys[i - 1]`.
+    Internal(Vec<usize>),
+    Leaf {
+        values: Vec<V>,
+        next: usize,
+    },
+}
+
+struct Node<K, V> {
+    keys: Vec<K>,
+    kind: Kind<V>,
+}
+
+impl<K, V> Node<K, V> {
+    fn leaf() -> Self {
+        Node {
+            keys: Vec::new(),
+            kind: Kind::Leaf {
+                values: Vec::new(),
+                next: NIL,
+            },
+        }
+    }
+
+    fn children(&self) -> &Vec<usize> {
+        match &self.kind {
+            Kind::Internal(children) => children,
+            Kind::Leaf { .. } => unreachable!(),
+        }
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<usize> {
+        match &mut self.kind {
+            Kind::Internal(children) => children,
+            Kind::Leaf { .. } => unreachable!(),
+        }
+    }
+
+    fn values(&self) -> &Vec<V> {
+        match &self.kind {
+            Kind::Leaf { values, .. } => values,
+            Kind::Internal(_) => unreachable!(),
+        }
+    }
+
+    fn values_mut(&mut self) -> &mut Vec<V> {
+        match &mut self.kind {
+            Kind::Leaf { values, .. } => values,
+            Kind::Internal(_) => unreachable!(),
+        }
+    }
+
+    /// Entries of a leaf or children of an internal node.
+    fn size(&self) -> usize {
+        match &self.kind {
+            Kind::Internal(children) => children.len(),
+            Kind::Leaf { values, .. } => values.len(),
+        }
+    }
+}
+
+/// Index of the child of an internal node with `keys` that covers `key`.
+fn child_index<K, Q>(keys: &[K], key: &Q) -> usize
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    keys.partition_point(|k| k.borrow() <= key)
+}
+
+/// Ordered map on a B+tree. Entries live in the leaves, which are linked
+/// left to right so that scans walk them without going back up the tree.
+/// Internal nodes hold copies of separator keys, hence `K: Clone`.
+///
+/// Nodes hold at most `fanout` entries or children and, except for the root
+/// and the right edge left by `append_sorted`, at least `fanout / 2`.
+pub struct BPlusTree<K, V> {
+    nodes: Vec<Node<K, V>>,
+    free: Vec<usize>,
+    root: usize,
+    fanout: usize,
+    len: usize,
+}
+
+impl<K: Ord + Clone, V> Default for BPlusTree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone, V> BPlusTree<K, V> {
+    pub fn new() -> Self {
+        Self::with_fanout(DEFAULT_FANOUT)
+    }
+
+    /// Creates a tree whose nodes hold up to `fanout` entries or children.
+    /// Panics if `fanout` is below 4.
+    pub fn with_fanout(fanout: usize) -> Self {
+        assert!(fanout >= 4, "fanout below 4");
+        BPlusTree {
+            nodes: vec![Node::leaf()],
+            free: Vec::new(),
+            root: 0,
+            fanout,
+            len: 0,
+        }
+    }
+
+    /// Builds a tree of packed nodes from entries in strictly increasing key
+    /// order, see `append_sorted`. Panics if the keys are not.
+    pub fn from_sorted<I>(fanout: usize, iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut tree = Self::with_fanout(fanout);
+        tree.append_sorted(iter);
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    /// Number of levels, 1 for a tree that is a single leaf.
+    pub fn height(&self) -> usize {
+        let mut x = self.root;
+        let mut height = 1;
+        while let Kind::Internal(children) = &self.nodes[x].kind {
+            x = children[0];
+            height += 1;
+        }
+        height
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::with_fanout(self.fanout);
+    }
+
+    fn alloc(&mut self, node: Node<K, V>) -> usize {
+        match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, i: usize) -> Node<K, V> {
+        self.free.push(i);
+        std::mem::replace(&mut self.nodes[i], Node::leaf())
+    }
+
+    /// Two distinct nodes borrowed at once.
+    fn pair_mut(&mut self, a: usize, b: usize) -> (&mut Node<K, V>, &mut Node<K, V>) {
+        debug_assert_ne!(a, b);
+        if a < b {
+            let (x, y) = self.nodes.split_at_mut(b);
+            (&mut x[a], &mut y[0])
+        } else {
+            let (x, y) = self.nodes.split_at_mut(a);
+            (&mut y[0], &mut x[b])
+        }
+    }
+
+    /// Leaf that covers `key`, and the internal nodes above it with the
+    /// index of the child taken when `path` is given.
+    fn descend<Q>(&self, key: &Q, mut path: Option<&mut Vec<(usize, usize)>>) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut x = self.root;
+        while let Kind::Internal(children) = &self.nodes[x].kind {
+            let i = child_index(&self.nodes[x].keys, key);
+            if let Some(path) = path.as_deref_mut() {
+                path.push((x, i));
+            }
+            x = children[i];
+        }
+        x
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<(usize, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let x = self.descend(key, None);
+        let i = self.nodes[x]
+            .keys
+            .binary_search_by(|k| k.borrow().cmp(key))
+            .ok()?;
+        Some((x, i))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (x, i) = self.find(key)?;
+        Some(&self.nodes[x].values()[i])
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (x, i) = self.find(key)?;
+        Some(&mut self.nodes[x].values_mut()[i])
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut path = Vec::new();
+        let x = self.descend(&key, Some(&mut path));
+        let Node { keys, kind } = &mut self.nodes[x];
+        let Kind::Leaf { values, .. } = kind else {
+            unreachable!()
+        };
+        match keys.binary_search(&key) {
+            Ok(i) => return Some(std::mem::replace(&mut values[i], value)),
+            Err(i) => {
+                keys.insert(i, key);
+                values.insert(i, value);
+            }
+        }
+        self.len += 1;
+        if keys.len() > self.fanout {
+            self.split(x, path);
+        }
+        None
+    }
+
+    /// Splits the overflowing node `x` in half and inserts the new right
+    /// half into the parent, splitting up the `path` as needed.
+    fn split(&mut self, mut x: usize, mut path: Vec<(usize, usize)>) {
+        loop {
+            let Node { keys, kind } = &mut self.nodes[x];
+            let at = keys.len() / 2;
+            let mut right_keys = keys.split_off(at);
+            let (sep, right) = match kind {
+                Kind::Leaf { values, next } => {
+                    let right = Kind::Leaf {
+                        values: values.split_off(at),
+                        next: *next,
+                    };
+                    (right_keys[0].clone(), right)
+                }
+                Kind::Internal(children) => {
+                    let right = Kind::Internal(children.split_off(at + 1));
+                    (right_keys.remove(0), right)
+                }
+            };
+            let r = self.alloc(Node {
+                keys: right_keys,
+                kind: right,
+            });
+            if let Kind::Leaf { next, .. } = &mut self.nodes[x].kind {
+                *next = r;
+            }
+            let Some((p, i)) = path.pop() else {
+                self.root = self.alloc(Node {
+                    keys: vec![sep],
+                    kind: Kind::Internal(vec![x, r]),
+                });
+                return;
+            };
+            let parent = &mut self.nodes[p];
+            parent.keys.insert(i, sep);
+            parent.children_mut().insert(i + 1, r);
+            if parent.size() <= self.fanout {
+                return;
+            }
+            x = p;
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut path = Vec::new();
+        let mut x = self.descend(key, Some(&mut path));
+        let node = &mut self.nodes[x];
+        let i = node.keys.binary_search_by(|k| k.borrow().cmp(key)).ok()?;
+        node.keys.remove(i);
+        let value = node.values_mut().remove(i);
+        self.len -= 1;
+
+        while let Some((p, i)) = path.pop() {
+            if self.nodes[x].size() >= self.fanout / 2 {
+                break;
+            }
+            self.rebalance(p, i);
+            x = p;
+        }
+        let root = &self.nodes[self.root];
+        if root.size() == 1 {
+            if let Kind::Internal(children) = &root.kind {
+                let child = children[0];
+                self.release(self.root);
+                self.root = child;
+            }
+        }
+        Some(value)
+    }
+
+    /// Refills child `i` of `p` from a sibling, merging the two when their
+    /// entries fit in one node.
+    fn rebalance(&mut self, p: usize, i: usize) {
+        let min = self.fanout / 2;
+        let l = if i > 0 { i - 1 } else { i };
+        let children = self.nodes[p].children();
+        let (left, right) = (children[l], children[l + 1]);
+        if self.nodes[left].size() + self.nodes[right].size() <= self.fanout {
+            self.merge(p, l);
+        } else if l < i {
+            while self.nodes[right].size() < min {
+                self.move_right(p, l);
+            }
+        } else {
+            while self.nodes[left].size() < min {
+                self.move_left(p, l);
+            }
+        }
+    }
+
+    /// Merges child `l + 1` of `p` into child `l`.
+    fn merge(&mut self, p: usize, l: usize) {
+        let parent = &mut self.nodes[p];
+        let sep = parent.keys.remove(l);
+        let r = parent.children_mut().remove(l + 1);
+        let left = parent.children()[l];
+        let right = self.release(r);
+        let Node { keys, kind } = &mut self.nodes[left];
+        match (kind, right.kind) {
+            (
+                Kind::Leaf { values, next },
+                Kind::Leaf {
+                    values: right_values,
+                    next: right_next,
+                },
+            ) => {
+                keys.extend(right.keys);
+                values.extend(right_values);
+                *next = right_next;
+            }
+            (Kind::Internal(children), Kind::Internal(right_children)) => {
+                keys.push(sep);
+                keys.extend(right.keys);
+                children.extend(right_children);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Moves the last entry or child of child `l` of `p` to child `l + 1`.
+    fn move_right(&mut self, p: usize, l: usize) {
+        let children = self.nodes[p].children();
+        let (left, right) = (children[l], children[l + 1]);
+        let (left, right) = self.pair_mut(left, right);
+        let key = left.keys.pop().unwrap();
+        let sep = match (&mut left.kind, &mut right.kind) {
+            (Kind::Leaf { values, .. }, Kind::Leaf { values: to, .. }) => {
+                to.insert(0, values.pop().unwrap());
+                right.keys.insert(0, key);
+                right.keys[0].clone()
+            }
+            (Kind::Internal(children), Kind::Internal(to)) => {
+                to.insert(0, children.pop().unwrap());
+                key
+            }
+            _ => unreachable!(),
+        };
+        let old = std::mem::replace(&mut self.nodes[p].keys[l], sep);
+        let right = self.nodes[p].children()[l + 1];
+        let right = &mut self.nodes[right];
+        if let Kind::Internal(_) = right.kind {
+            right.keys.insert(0, old);
+        }
+    }
+
+    /// Moves the first entry or child of child `l + 1` of `p` to child `l`.
+    fn move_left(&mut self, p: usize, l: usize) {
+        let children = self.nodes[p].children();
+        let (left, right) = (children[l], children[l + 1]);
+        let (left, right) = self.pair_mut(left, right);
+        let key = right.keys.remove(0);
+        let sep = match (&mut left.kind, &mut right.kind) {
+            (Kind::Leaf { values, .. }, Kind::Leaf { values: from, .. }) => {
+                values.push(from.remove(0));
+                left.keys.push(key);
+                right.keys[0].clone()
+            }
+            (Kind::Internal(children), Kind::Internal(from)) => {
+                children.push(from.remove(0));
+                key
+            }
+            _ => unreachable!(),
+        };
+        let old = std::mem::replace(&mut self.nodes[p].keys[l], sep);
+        let left = self.nodes[p].children()[l];
+        let left = &mut self.nodes[left];
+        if let Kind::Internal(_) = left.kind {
+            left.keys.push(old);
+        }
+    }
+
+    /// Appends entries in strictly increasing key order after every key in
+    /// the tree; panics if they are not. Nodes on the right edge are filled
+    /// up before a new one is started instead of being split in half, so
+    /// the leaves left behind are full and the internal nodes one child
+    /// short of full, while the right edge itself may be left less than
+    /// half full.
+    pub fn append_sorted<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut edge = vec![self.root];
+        while let Kind::Internal(children) = &self.nodes[*edge.last().unwrap()].kind {
+            edge.push(*children.last().unwrap());
+        }
+        for (key, value) in iter {
+            let x = *edge.last().unwrap();
+            let leaf = &mut self.nodes[x];
+            assert!(
+                leaf.keys.last().is_none_or(|last| *last < key),
+                "keys not in increasing order"
+            );
+            self.len += 1;
+            if leaf.keys.len() < self.fanout {
+                leaf.keys.push(key);
+                leaf.values_mut().push(value);
+                continue;
+            }
+            let sep = key.clone();
+            let r = self.alloc(Node {
+                keys: vec![key],
+                kind: Kind::Leaf {
+                    values: vec![value],
+                    next: NIL,
+                },
+            });
+            if let Kind::Leaf { next, .. } = &mut self.nodes[x].kind {
+                *next = r;
+            }
+            self.push_edge(&mut edge, sep, r);
+        }
+    }
+
+    /// Adds `x`, the new right sibling of the last node of `edge`, to the
+    /// parent, or to a new parent with the last child of the full one.
+    fn push_edge(&mut self, edge: &mut Vec<usize>, mut sep: K, mut x: usize) {
+        let mut level = edge.len() - 1;
+        edge[level] = x;
+        while level > 0 {
+            let parent = &mut self.nodes[edge[level - 1]];
+            if parent.size() < self.fanout {
+                parent.keys.push(sep);
+                parent.children_mut().push(x);
+                return;
+            }
+            let parent_sep = parent.keys.pop().unwrap();
+            let last = parent.children_mut().pop().unwrap();
+            x = self.alloc(Node {
+                keys: vec![sep],
+                kind: Kind::Internal(vec![last, x]),
+            });
+            sep = parent_sep;
+            level -= 1;
+            edge[level] = x;
+        }
+        self.root = self.alloc(Node {
+            keys: vec![sep],
+            kind: Kind::Internal(vec![self.root, x]),
+        });
+        edge.insert(0, self.root);
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        let mut x = self.root;
+        while let Kind::Internal(children) = &self.nodes[x].kind {
+            x = *children.last().unwrap();
+        }
+        let node = &self.nodes[x];
+        Some((node.keys.last()?, node.values().last()?))
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut x = self.root;
+        while let Kind::Internal(children) = &self.nodes[x].kind {
+            x = children[0];
+        }
+        Iter {
+            tree: self,
+            leaf: x,
+            pos: 0,
+        }
+    }
+
+    /// Iterates over the entries with keys in `range`, in key order.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V, Q, R>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let iter = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                let leaf = self.descend(key, None);
+                let keys = &self.nodes[leaf].keys;
+                let pos = match range.start_bound() {
+                    Bound::Included(_) => keys.partition_point(|k| k.borrow() < key),
+                    _ => keys.partition_point(|k| k.borrow() <= key),
+                };
+                Iter {
+                    tree: self,
+                    leaf,
+                    pos,
+                }
+            }
+            Bound::Unbounded => self.iter(),
+        };
+        Range {
+            iter,
+            range,
+            _key: PhantomData,
+        }
+    }
+}
+
+/// Walks the leaf chain from one position.
+pub struct Iter<'a, K, V> {
+    tree: &'a BPlusTree<K, V>,
+    leaf: usize,
+    pos: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.leaf != NIL {
+            let node = &self.tree.nodes[self.leaf];
+            let Kind::Leaf { values, next } = &node.kind else {
+                unreachable!()
+            };
+            if self.pos < node.keys.len() {
+                self.pos += 1;
+                return Some((&node.keys[self.pos - 1], &values[self.pos - 1]));
+            }
+            self.leaf = *next;
+            self.pos = 0;
+        }
+        None
+    }
+}
+
+pub struct Range<'a, K, V, Q: ?Sized, R> {
+    iter: Iter<'a, K, V>,
+    range: R,
+    _key: PhantomData<fn(&Q)>,
+}
+
+impl<'a, K, V, Q, R> Iterator for Range<'a, K, V, Q, R>
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.iter.next()?;
+        let past_end = match self.range.end_bound() {
+            Bound::Included(end) => k.borrow() > end,
+            Bound::Excluded(end) => k.borrow() >= end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.iter.leaf = NIL;
+            return None;
+        }
+        Some((k, v))
+    }
+}
+
+impl<'a, K: Ord + Clone, V> IntoIterator for &'a BPlusTree<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Ord + Clone, V> FromIterator<(K, V)> for BPlusTree<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut tree = BPlusTree::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<K: Ord + Clone, V> Extend<(K, V)> for BPlusTree<K, V> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord + Clone + fmt::Debug, V: fmt::Debug> fmt::Debug for BPlusTree<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> MemoryUsage for BPlusTree<K, V> {
+    fn memory_usage(&self) -> Memory {
+        let node = size_of::<Node<K, V>>();
+        let mut memory = Memory {
+            nodes: (self.nodes.len() - self.free.len()) * node,
+            overhead: self.free.len() * node
+                + spare(&self.nodes)
+                + self.free.capacity() * size_of::<usize>(),
+            ..Memory::default()
+        };
+        for n in &self.nodes {
+            memory.overhead += spare(&n.keys);
+            match &n.kind {
+                Kind::Internal(children) => {
+                    memory.nodes += children.capacity() * size_of::<usize>();
+                    memory.overhead += sum_with_heap(&n.keys);
+                }
+                Kind::Leaf { values, .. } => {
+                    memory.keys += sum_with_heap(&n.keys);
+                    memory.values += sum_with_heap(values);
+                    memory.overhead += spare(values);
+                }
+            }
+        }
+        memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Checks the key bounds of every subtree and the node sizes, returning
+    /// the number of entries below `x`.
+    fn check<K: Ord + Clone, V>(
+        tree: &BPlusTree<K, V>,
+        x: usize,
+        lo: Option<&K>,
+        hi: Option<&K>,
+    ) -> usize {
+        let node = &tree.nodes[x];
+        assert!(node.size() <= tree.fanout);
+        assert!(node.keys.windows(2).all(|w| w[0] < w[1]));
+        assert!(lo.is_none_or(|lo| node.keys.first().is_none_or(|k| lo <= k)));
+        assert!(hi.is_none_or(|hi| node.keys.last().is_none_or(|k| k < hi)));
+        match &node.kind {
+            Kind::Leaf { values, .. } => {
+                assert_eq!(node.keys.len(), values.len());
+                assert!(x == tree.root || !values.is_empty());
+                values.len()
+            }
+            Kind::Internal(children) => {
+                assert_eq!(children.len(), node.keys.len() + 1);
+                assert!(children.len() >= 2);
+                (0..children.len())
+                    .map(|i| {
+                        let lo = if i == 0 { lo } else { Some(&node.keys[i - 1]) };
+                        let hi = node.keys.get(i).or(hi);
+                        check(tree, children[i], lo, hi)
+                    })
+                    .sum()
+            }
+        }
+    }
+
+    fn check_tree<K: Ord + Clone, V>(tree: &BPlusTree<K, V>) {
+        assert_eq!(check(tree, tree.root, None, None), tree.len());
+        assert_eq!(tree.iter().count(), tree.len());
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut tree = BPlusTree::with_fanout(4);
+        assert_eq!(tree.insert(2, "b"), None);
+        assert_eq!(tree.insert(1, "a"), None);
+        assert_eq!(tree.insert(2, "B"), Some("b"));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(&2), Some(&"B"));
+        assert_eq!(tree.get(&3), None);
+        assert_eq!(tree.first(), Some((&1, &"a")));
+        assert_eq!(tree.last(), Some((&2, &"B")));
+        assert_eq!(tree.remove(&1), Some("a"));
+        assert_eq!(tree.remove(&1), None);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        for fanout in [4, 5, 16, 64] {
+            let mut tree = BPlusTree::with_fanout(fanout);
+            let mut map = BTreeMap::new();
+            let mut x: u64 = 1;
+            for _ in 0..20000 {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let key = (x >> 33) % 1000;
+                if x & 3 == 0 {
+                    assert_eq!(tree.remove(&key), map.remove(&key));
+                } else {
+                    assert_eq!(tree.insert(key, x), map.insert(key, x));
+                }
+            }
+            check_tree(&tree);
+            assert!(tree.iter().eq(map.iter()));
+            assert!(tree.range(100..=200).eq(map.range(100..=200)));
+            assert!(tree
+                .range((Bound::Excluded(500), Bound::Unbounded))
+                .eq(map.range((Bound::Excluded(500), Bound::Unbounded))));
+            for key in 0..1000 {
+                tree.remove(&key);
+            }
+            check_tree(&tree);
+            assert!(tree.is_empty());
+            assert_eq!(tree.height(), 1);
+        }
+    }
+
+    #[test]
+    fn sorted_load() {
+        let mut tree = BPlusTree::from_sorted(8, (0..1000).map(|i| (i * 2, i)));
+        check_tree(&tree);
+        // 125 full leaves, 18 nodes of up to 7 leaves, 3 above them and the
+        // root.
+        assert_eq!(tree.height(), 4);
+        tree.append_sorted((1000..1100).map(|i| (i * 2, i)));
+        check_tree(&tree);
+        for i in (0..1100).step_by(3) {
+            assert_eq!(tree.remove(&(i * 2)), Some(i));
+            tree.insert(i * 2 + 1, i);
+        }
+        check_tree(&tree);
+        assert_eq!(tree.len(), 1100);
+        let keys: Vec<i32> = tree.range(10..20).map(|(k, _)| *k).collect();
+        assert_eq!(keys, [10, 13, 14, 16, 19]);
+    }
+
+    #[test]
+    fn appends_between_updates() {
+        for fanout in [4, 5, 8] {
+            let mut tree = BPlusTree::with_fanout(fanout);
+            let mut map = BTreeMap::new();
+            let mut x: u64 = 7;
+            let mut next = 1;
+            for round in 0..5000 {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let key = (x >> 33) % (next * 10);
+                match x % 3 {
+                    0 => {
+                        let n = (x >> 40) % 10 + 1;
+                        let batch: Vec<(u64, u64)> =
+                            (next..next + n).map(|k| (k * 10, round)).collect();
+                        next += n;
+                        map.extend(batch.iter().copied());
+                        tree.append_sorted(batch);
+                    }
+                    1 => assert_eq!(tree.remove(&key), map.remove(&key)),
+                    _ => assert_eq!(tree.insert(key, round), map.insert(key, round)),
+                }
+                if round % 500 == 0 {
+                    check_tree(&tree);
+                }
+            }
+            check_tree(&tree);
+            assert!(tree.iter().eq(map.iter()));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "keys not in increasing order")]
+    fn sorted_load_rejects_unsorted() {
+        BPlusTree::from_sorted(8, [(1, ()), (1, ())]);
+    }
+
+    #[test]
+    fn borrowed_keys() {
+        let tree: BPlusTree<String, usize> = ["b", "a", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.to_string(), i))
+            .collect();
+        assert_eq!(tree.get("c"), Some(&2));
+        let keys: Vec<&String> = tree
+            .range::<str, _>((Bound::Included("b"), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["b", "c"]);
+    }
+}
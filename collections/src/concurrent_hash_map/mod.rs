@@ -0,0 +1,643 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::mem::size_of;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+
+use crate::memory::{size_with_heap, HeapSize, Memory, MemoryUsage};
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::sync::{Mutex, MutexGuard};
+
+const DEFAULT_STRIPES: usize = 64;
+#[cfg(not(loom))]
+const MIN_BUCKETS: usize = 16;
+#[cfg(loom)]
+const MIN_BUCKETS: usize = 2;
+
+/// Under loom, hashes must not change between the runs of a model.
+#[cfg(not(loom))]
+type Hasher = std::hash::RandomState;
+#[cfg(loom)]
+type Hasher = std::hash::BuildHasherDefault<std::hash::DefaultHasher>;
+/// Buckets each write moves to the next table while one is being filled.
+const MIGRATE_BATCH: usize = 8;
+/// Tag of a bucket head whose entries have moved to the next table.
+const MOVED: usize = 1;
+
+/// Key and value, replaced as a whole by a write. While the table grows an
+/// entry is linked from a node of each table.
+struct Entry<K, V> {
+    key: K,
+    value: V,
+}
+
+/// Link of a bucket chain. Writes swap the entry of a linked node.
+struct Node<K, V> {
+    hash: u64,
+    entry: Atomic<Entry<K, V>>,
+    next: Atomic<Node<K, V>>,
+}
+
+impl<K, V> Node<K, V> {
+    fn entry<'g>(&self, guard: &'g Guard) -> &'g Entry<K, V> {
+        unsafe { self.entry.load(Ordering::Acquire, guard).deref() }
+    }
+}
+
+struct Table<K, V> {
+    /// Power-of-two number of chains, each owned by the lock stripe of its
+    /// index.
+    buckets: Box<[Atomic<Node<K, V>>]>,
+    /// Table of twice the buckets being filled from this one.
+    next: Atomic<Table<K, V>>,
+    /// Buckets handed out for migration.
+    claimed: AtomicUsize,
+    /// Buckets done migrating.
+    migrated: AtomicUsize,
+}
+
+impl<K, V> Table<K, V> {
+    fn new(buckets: usize) -> Self {
+        Table {
+            buckets: (0..buckets).map(|_| Atomic::null()).collect(),
+            next: Atomic::null(),
+            claimed: AtomicUsize::new(0),
+            migrated: AtomicUsize::new(0),
+        }
+    }
+
+    fn bucket(&self, hash: u64) -> &Atomic<Node<K, V>> {
+        &self.buckets[hash as usize & (self.buckets.len() - 1)]
+    }
+}
+
+/// Concurrent hash map with separate chaining. Readers take no locks: they
+/// walk a bucket chain under an epoch guard, and removed entries and
+/// replaced values are freed once no reader can see them. Writers lock one
+/// of a fixed set of stripes, chosen by the low bits of the hash, so writes
+/// to keys of different stripes run in parallel.
+///
+/// The table doubles once it holds more than 3/4 entries per bucket, but
+/// without stopping writers: the larger table is filled a few buckets at a
+/// time by the writes that follow, which link the same entries from new
+/// nodes rather than copying them. A moved bucket is marked in the old
+/// table, which sends readers and writers of its keys on to the new one,
+/// and the new table replaces the old once every bucket has moved. Each
+/// bucket splits into two buckets of the same stripe, so a migration holds
+/// only one lock.
+pub struct ConcurrentHashMap<K, V> {
+    table: Atomic<Table<K, V>>,
+    stripes: Box<[Mutex<()>]>,
+    len: AtomicUsize,
+    resizes: AtomicU64,
+    hasher: Hasher,
+}
+
+impl<K, V> Default for ConcurrentHashMap<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> ConcurrentHashMap<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+{
+    pub fn new() -> Self {
+        Self::with_stripes(DEFAULT_STRIPES)
+    }
+
+    /// Map with `stripes` write locks, rounded up to a power of two.
+    pub fn with_stripes(stripes: usize) -> Self {
+        assert!(stripes > 0, "a map needs at least one stripe");
+        let stripes = stripes.next_power_of_two();
+        ConcurrentHashMap {
+            table: Atomic::new(Table::new(std::cmp::max(stripes, MIN_BUCKETS))),
+            stripes: (0..stripes).map(|_| Mutex::new(())).collect(),
+            len: AtomicUsize::new(0),
+            resizes: AtomicU64::new(0),
+            hasher: Hasher::default(),
+        }
+    }
+
+    /// Number of entries; concurrent writers may make it stale immediately.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of buckets of the current table.
+    pub fn capacity(&self) -> usize {
+        let guard = &epoch::pin();
+        unsafe { self.table.load(Ordering::Acquire, guard).deref() }
+            .buckets
+            .len()
+    }
+
+    /// Times the table started to grow.
+    pub fn resizes(&self) -> u64 {
+        self.resizes.load(Ordering::Relaxed)
+    }
+
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    /// Head of the chain of `hash`, following moved buckets to the table
+    /// they moved to.
+    fn head<'g>(&self, hash: u64, guard: &'g Guard) -> Shared<'g, Node<K, V>> {
+        let mut table = unsafe { self.table.load(Ordering::Acquire, guard).deref() };
+        loop {
+            let head = table.bucket(hash).load(Ordering::Acquire, guard);
+            if head.tag() != MOVED {
+                return head;
+            }
+            table = unsafe { table.next.load(Ordering::Acquire, guard).deref() };
+        }
+    }
+
+    /// Locks the stripe of `hash` and returns its bucket, which cannot move
+    /// while the lock is held.
+    fn lock<'g>(
+        &self,
+        hash: u64,
+        guard: &'g Guard,
+    ) -> (MutexGuard<'_, ()>, &'g Atomic<Node<K, V>>) {
+        let lock = self.stripes[hash as usize & (self.stripes.len() - 1)]
+            .lock()
+            .unwrap();
+        let mut table = unsafe { self.table.load(Ordering::Acquire, guard).deref() };
+        loop {
+            let bucket = table.bucket(hash);
+            if bucket.load(Ordering::Acquire, guard).tag() != MOVED {
+                return (lock, bucket);
+            }
+            table = unsafe { table.next.load(Ordering::Acquire, guard).deref() };
+        }
+    }
+
+    /// The link pointing to the node of `key` in the chain of `bucket`, or
+    /// `None` if the chain has no such key. The caller holds the lock of the
+    /// bucket, so the link keeps pointing to the node.
+    fn find<'g, Q>(
+        bucket: &'g Atomic<Node<K, V>>,
+        hash: u64,
+        key: &Q,
+        guard: &'g Guard,
+    ) -> Option<&'g Atomic<Node<K, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let mut link = bucket;
+        loop {
+            let node = link.load(Ordering::Acquire, guard);
+            let n = unsafe { node.as_ref() }?;
+            if n.hash == hash && n.entry(guard).key.borrow() == key {
+                return Some(link);
+            }
+            link = &n.next;
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let guard = &epoch::pin();
+        let hash = self.hash(key);
+        let mut node = self.head(hash, guard);
+        while let Some(n) = unsafe { node.as_ref() } {
+            if n.hash == hash {
+                let entry = n.entry(guard);
+                if entry.key.borrow() == key {
+                    return Some(entry.value.clone());
+                }
+            }
+            node = n.next.load(Ordering::Acquire, guard);
+        }
+        None
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let guard = &epoch::pin();
+        let hash = self.hash(key);
+        let mut node = self.head(hash, guard);
+        while let Some(n) = unsafe { node.as_ref() } {
+            if n.hash == hash && n.entry(guard).key.borrow() == key {
+                return true;
+            }
+            node = n.next.load(Ordering::Acquire, guard);
+        }
+        false
+    }
+
+    /// Inserts `value` under `key`, replacing any previous value. Returns
+    /// whether the key is new.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let guard = &epoch::pin();
+        let hash = self.hash(&key);
+        let (lock, bucket) = self.lock(hash, guard);
+        let new = match Self::find(bucket, hash, &key, guard) {
+            Some(link) => {
+                let node = unsafe { link.load(Ordering::Relaxed, guard).deref() };
+                let old =
+                    node.entry
+                        .swap(Owned::new(Entry { key, value }), Ordering::AcqRel, guard);
+                unsafe { guard.defer_destroy(old) };
+                false
+            }
+            None => {
+                let head = bucket.load(Ordering::Relaxed, guard);
+                bucket.store(
+                    Owned::new(Node {
+                        hash,
+                        entry: Atomic::new(Entry { key, value }),
+                        next: Atomic::from(head),
+                    }),
+                    Ordering::Release,
+                );
+                true
+            }
+        };
+        drop(lock);
+        if new {
+            self.len.fetch_add(1, Ordering::Relaxed);
+            self.grow(guard);
+        }
+        self.help_migrate(guard);
+        new
+    }
+
+    /// Replaces the value of `key` with `f` of the current one. Returns
+    /// whether the key was found.
+    pub fn update<Q, F>(&self, key: &Q, f: F) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        K: Clone,
+        F: FnOnce(&V) -> V,
+    {
+        let guard = &epoch::pin();
+        let hash = self.hash(key);
+        let (lock, bucket) = self.lock(hash, guard);
+        let Some(link) = Self::find(bucket, hash, key, guard) else {
+            return false;
+        };
+        let node = unsafe { link.load(Ordering::Relaxed, guard).deref() };
+        let old = node.entry.load(Ordering::Relaxed, guard);
+        let entry = unsafe { old.deref() };
+        node.entry.store(
+            Owned::new(Entry {
+                key: entry.key.clone(),
+                value: f(&entry.value),
+            }),
+            Ordering::Release,
+        );
+        unsafe { guard.defer_destroy(old) };
+        drop(lock);
+        self.help_migrate(guard);
+        true
+    }
+
+    /// Removes `key`, returning whether it was found.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let guard = &epoch::pin();
+        let hash = self.hash(key);
+        let (lock, bucket) = self.lock(hash, guard);
+        let Some(link) = Self::find(bucket, hash, key, guard) else {
+            return false;
+        };
+        let old = link.load(Ordering::Relaxed, guard);
+        let n = unsafe { old.deref() };
+        link.store(n.next.load(Ordering::Relaxed, guard), Ordering::Release);
+        unsafe {
+            guard.defer_destroy(n.entry.load(Ordering::Relaxed, guard));
+            guard.defer_destroy(old);
+        }
+        drop(lock);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.help_migrate(guard);
+        true
+    }
+
+    /// Starts filling a table of twice the buckets once the current one is
+    /// too full, unless one is being filled already.
+    fn grow(&self, guard: &Guard) {
+        let table = unsafe { self.table.load(Ordering::Acquire, guard).deref() };
+        if self.len() * 4 <= table.buckets.len() * 3
+            || !table.next.load(Ordering::Acquire, guard).is_null()
+        {
+            return;
+        }
+        let next = Owned::new(Table::new(table.buckets.len() * 2));
+        if table
+            .next
+            .compare_exchange(
+                Shared::null(),
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            )
+            .is_ok()
+        {
+            self.resizes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves up to `MIGRATE_BATCH` buckets to the table being filled, and
+    /// makes it the current table after the last one.
+    fn help_migrate(&self, guard: &Guard) {
+        let shared = self.table.load(Ordering::Acquire, guard);
+        let table = unsafe { shared.deref() };
+        let next = table.next.load(Ordering::Acquire, guard);
+        if next.is_null() {
+            return;
+        }
+        let next = unsafe { next.deref() };
+        let len = table.buckets.len();
+        for _ in 0..MIGRATE_BATCH {
+            let i = table.claimed.fetch_add(1, Ordering::Relaxed);
+            if i >= len {
+                return;
+            }
+            self.migrate(table, next, i, guard);
+            if table.migrated.fetch_add(1, Ordering::AcqRel) + 1 == len {
+                self.table
+                    .store(Shared::from(next as *const Table<K, V>), Ordering::Release);
+                unsafe { guard.defer_destroy(shared) };
+                return;
+            }
+        }
+    }
+
+    /// Links the entries of bucket `i` into its two buckets of `next`, then
+    /// marks it moved. Readers still on the old chain see the same entries.
+    fn migrate(&self, table: &Table<K, V>, next: &Table<K, V>, i: usize, guard: &Guard) {
+        let _lock = self.stripes[i & (self.stripes.len() - 1)].lock().unwrap();
+        let head = table.buckets[i].load(Ordering::Acquire, guard);
+        let mut node = head;
+        while let Some(n) = unsafe { node.as_ref() } {
+            let bucket = next.bucket(n.hash);
+            bucket.store(
+                Owned::new(Node {
+                    hash: n.hash,
+                    entry: Atomic::from(n.entry.load(Ordering::Relaxed, guard)),
+                    next: Atomic::from(bucket.load(Ordering::Relaxed, guard)),
+                }),
+                Ordering::Release,
+            );
+            node = n.next.load(Ordering::Acquire, guard);
+        }
+        table.buckets[i].store(Shared::null().with_tag(MOVED), Ordering::Release);
+        node = head;
+        while let Some(n) = unsafe { node.as_ref() } {
+            let next = n.next.load(Ordering::Relaxed, guard);
+            unsafe { guard.defer_destroy(node) };
+            node = next;
+        }
+    }
+}
+
+impl<K, V> Drop for ConcurrentHashMap<K, V> {
+    fn drop(&mut self) {
+        unsafe fn free<K, V>(table: Shared<'_, Table<K, V>>, guard: &Guard) {
+            let Some(t) = table.as_ref() else {
+                return;
+            };
+            // Each entry is linked from one chain: moved buckets are empty
+            // in the old table.
+            for bucket in t.buckets.iter() {
+                let mut node = bucket.load(Ordering::Relaxed, guard);
+                while let Some(n) = node.as_ref() {
+                    let next = n.next.load(Ordering::Relaxed, guard);
+                    drop(n.entry.load(Ordering::Relaxed, guard).into_owned());
+                    drop(node.into_owned());
+                    node = next;
+                }
+            }
+            free(t.next.load(Ordering::Relaxed, guard), guard);
+            drop(table.into_owned());
+        }
+        unsafe {
+            let guard = epoch::unprotected();
+            free(self.table.load(Ordering::Relaxed, guard), guard);
+        }
+    }
+}
+
+/// Walks the buckets without locking them, so that writes running at the
+/// same time may be missed or counted twice.
+impl<K: HeapSize, V: HeapSize> MemoryUsage for ConcurrentHashMap<K, V> {
+    fn memory_usage(&self) -> Memory {
+        let guard = epoch::pin();
+        let mut memory = Memory {
+            overhead: self.stripes.len() * size_of::<Mutex<()>>(),
+            ..Memory::default()
+        };
+        let node =
+            size_of::<Node<K, V>>() + size_of::<Entry<K, V>>() - size_of::<K>() - size_of::<V>();
+        let mut table = self.table.load(Ordering::Acquire, &guard);
+        while let Some(t) = unsafe { table.as_ref() } {
+            memory.nodes +=
+                size_of::<Table<K, V>>() + t.buckets.len() * size_of::<Atomic<Node<K, V>>>();
+            for bucket in t.buckets.iter() {
+                let mut n = bucket.load(Ordering::Acquire, &guard);
+                while let Some(x) = unsafe { n.as_ref() } {
+                    let entry = x.entry(&guard);
+                    memory.nodes += node;
+                    memory.keys += size_with_heap(&entry.key);
+                    memory.values += size_with_heap(&entry.value);
+                    n = x.next.load(Ordering::Acquire, &guard);
+                }
+            }
+            table = t.next.load(Ordering::Acquire, &guard);
+        }
+        memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn matches_hashmap() {
+        let map = ConcurrentHashMap::with_stripes(4);
+        let mut expected = HashMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..50000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % 5000;
+            match x & 3 {
+                0 => assert_eq!(map.remove(&key), expected.remove(&key).is_some()),
+                1 => assert_eq!(
+                    map.update(&key, |v| v + 1),
+                    expected.get_mut(&key).map(|v| *v += 1).is_some()
+                ),
+                _ => assert_eq!(map.insert(key, x), expected.insert(key, x).is_none()),
+            }
+            assert_eq!(map.len(), expected.len());
+        }
+        for key in 0..5000 {
+            assert_eq!(map.get(&key), expected.get(&key).copied());
+        }
+        assert!(map.resizes() > 0);
+        assert!(map.capacity() * 3 >= map.len() * 2);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_reads() {
+        /// Value of `key` once the thread writing it is done with it.
+        fn expected(key: u64) -> Option<String> {
+            let i = key / 8;
+            match (i % 3, i % 2) {
+                (0, _) => None,
+                (_, 0) => Some(format!("{key}!")),
+                _ => Some(key.to_string()),
+            }
+        }
+
+        let map = Arc::new(ConcurrentHashMap::with_stripes(8));
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..5000 {
+                        let key = i * 8 + t;
+                        assert!(map.insert(key, key.to_string()));
+                        if i % 2 == 0 {
+                            assert!(map.update(&key, |v| format!("{v}!")));
+                        }
+                        if i % 3 == 0 {
+                            assert!(map.remove(&key));
+                        }
+                        // Earlier keys of this thread stay visible while the
+                        // others grow the table.
+                        let earlier = i / 2 * 8 + t;
+                        assert_eq!(map.get(&earlier), expected(earlier));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let mut len = 0;
+        for key in 0..40000u64 {
+            len += expected(key).is_some() as usize;
+            assert_eq!(map.get(&key), expected(key));
+        }
+        assert_eq!(map.len(), len);
+        assert!(map.resizes() > 0);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod model {
+    use super::*;
+    use crate::sync::model::{self, Tracked};
+    use crate::sync::thread;
+    use loom::sync::Arc;
+
+    /// Map of one entry, which the next insert makes grow.
+    fn one_entry() -> ConcurrentHashMap<u64, u64> {
+        let map = ConcurrentHashMap::with_stripes(2);
+        map.insert(0, 0);
+        assert_eq!(map.capacity(), MIN_BUCKETS);
+        map
+    }
+
+    #[test]
+    fn get_during_growth() {
+        model::check(|| {
+            let map = Arc::new(one_entry());
+            let writer = {
+                let map = map.clone();
+                thread::spawn(move || assert!(map.insert(1, 1)))
+            };
+            assert_eq!(map.get(&0), Some(0));
+            assert!(matches!(map.get(&1), None | Some(1)));
+            writer.join().unwrap();
+            assert_eq!(map.get(&1), Some(1));
+            assert_eq!(map.resizes(), 1);
+            assert_eq!(map.capacity(), 2 * MIN_BUCKETS);
+        });
+    }
+
+    #[test]
+    fn remove_during_growth() {
+        model::check(|| {
+            let map = Arc::new(one_entry());
+            let writer = {
+                let map = map.clone();
+                thread::spawn(move || assert!(map.insert(1, 1)))
+            };
+            assert!(map.remove(&0));
+            assert!(!map.contains_key(&0));
+            writer.join().unwrap();
+            assert_eq!(map.get(&0), None);
+            assert_eq!(map.get(&1), Some(1));
+            assert_eq!(map.len(), 1);
+        });
+    }
+
+    #[test]
+    fn updates_are_not_lost() {
+        model::check(|| {
+            let map = Arc::new(one_entry());
+            let writer = {
+                let map = map.clone();
+                thread::spawn(move || assert!(map.update(&0, |v| v + 1)))
+            };
+            assert!(map.update(&0, |v| v + 1));
+            writer.join().unwrap();
+            assert_eq!(map.get(&0), Some(2));
+        });
+    }
+
+    #[test]
+    fn reclamation() {
+        model::check(|| {
+            let map = Arc::new(ConcurrentHashMap::with_stripes(2));
+            map.insert(0, Tracked::new(0));
+            let writer = {
+                let map = map.clone();
+                thread::spawn(move || {
+                    assert!(map.insert(1, Tracked::new(1)));
+                    assert!(map.remove(&0));
+                })
+            };
+            assert!(matches!(map.get(&0).map(|v| v.value), None | Some(0)));
+            assert!(map.insert(2, Tracked::new(2)));
+            writer.join().unwrap();
+            assert!(!map.insert(2, Tracked::new(20)));
+            assert_eq!(map.get(&2).map(|v| v.value), Some(20));
+            assert_eq!(map.len(), 2);
+        });
+    }
+}
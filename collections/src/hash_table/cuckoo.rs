@@ -0,0 +1,464 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use super::{check_load_factor, make_hash, slots_memory_usage, Bucket, ProbeStats};
+use crate::memory::{spare, HeapSize, Memory, MemoryUsage};
+
+const MIN_TABLE: usize = 8;
+const DEFAULT_LOAD_FACTOR: f64 = 0.45;
+/// Evictions an insert tries before putting the entry in the stash.
+const MAX_EVICTIONS: usize = 64;
+const STASH_SIZE: usize = 4;
+/// Doublings of the tables that may fail to place every entry before a
+/// rebuild gives up and lets the stash grow past `STASH_SIZE`, which takes
+/// keys sharing a full hash.
+const MAX_REBUILDS: usize = 8;
+
+enum Location {
+    Table(usize, usize),
+    Stash(usize),
+}
+
+/// Hash map with cuckoo hashing: each key has one slot in each of two
+/// tables, taken from different bits of its hash, and lives in one of
+/// them. An insert into two full slots evicts the entry of one, which moves
+/// to its slot in the other table, and so on. A chain that does not end
+/// within `MAX_EVICTIONS` leaves its last entry in a small stash, which
+/// spares a rehash for the rare cycles; the tables double once the stash
+/// is full. Entries that no doubling places, like more than two keys
+/// sharing a full hash, stay in the stash, which then grows as needed.
+///
+/// With two tables inserts start to fail at about half of the slots
+/// filled, so maximum load factors above 0.5 mostly trade a full stash for
+/// earlier growth.
+pub struct CuckooMap<K, V, S = RandomState> {
+    /// Two tables of the same power-of-two length.
+    tables: [Vec<Option<Bucket<K, V>>>; 2],
+    stash: Vec<Bucket<K, V>>,
+    /// Entries the stash takes before an insert rebuilds the tables:
+    /// `STASH_SIZE`, or after a rebuild that gave up twice the entries it
+    /// left there if more, so that such rebuilds stay rare.
+    stash_limit: usize,
+    len: usize,
+    max_load_factor: f64,
+    hasher: S,
+    displacements: u64,
+    resizes: u64,
+}
+
+impl<K: Hash + Eq, V> Default for CuckooMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V> CuckooMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> CuckooMap<K, V, S> {
+    /// Map with room for `capacity` entries at the default maximum load
+    /// factor of 0.45.
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        let mut map = CuckooMap {
+            tables: [Vec::new(), Vec::new()],
+            stash: Vec::new(),
+            stash_limit: STASH_SIZE,
+            len: 0,
+            max_load_factor: DEFAULT_LOAD_FACTOR,
+            hasher,
+            displacements: 0,
+            resizes: 0,
+        };
+        if capacity > 0 {
+            map.rebuild(map.table_for(capacity), None);
+        }
+        map
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots of both tables.
+    pub fn capacity(&self) -> usize {
+        self.tables[0].len() * 2
+    }
+
+    pub fn max_load_factor(&self) -> f64 {
+        self.max_load_factor
+    }
+
+    /// Sets the fraction of slots that may be filled before the tables
+    /// double, growing them now if they are fuller than that.
+    pub fn set_max_load_factor(&mut self, load_factor: f64) {
+        check_load_factor(load_factor);
+        self.max_load_factor = load_factor;
+        if self.len > 0 && self.table_for(self.len) > self.tables[0].len() {
+            self.rebuild(self.table_for(self.len), None);
+        }
+    }
+
+    /// Entries in the stash.
+    pub fn stash_len(&self) -> usize {
+        self.stash.len()
+    }
+
+    pub fn clear(&mut self) {
+        for table in &mut self.tables {
+            table.iter_mut().for_each(|x| *x = None);
+        }
+        self.stash.clear();
+        self.len = 0;
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(key)? {
+            Location::Table(t, i) => self.tables[t][i].as_ref().map(|b| &b.value),
+            Location::Stash(i) => Some(&self.stash[i].value),
+        }
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(key)? {
+            Location::Table(t, i) => self.tables[t][i].as_mut().map(|b| &mut b.value),
+            Location::Stash(i) => Some(&mut self.stash[i].value),
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(x) = self.get_mut(&key) {
+            return Some(std::mem::replace(x, value));
+        }
+        if self.table_for(self.len + 1) > self.tables[0].len() {
+            self.rebuild(self.tables[0].len() * 2, None);
+        }
+        let hash = make_hash(&self.hasher, &key);
+        if let Err(bucket) = self.add(Bucket { hash, key, value }) {
+            self.rebuild(self.tables[0].len() * 2, Some(bucket));
+        }
+        self.len += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bucket = match self.find(key)? {
+            Location::Table(t, i) => self.tables[t][i].take().unwrap(),
+            Location::Stash(i) => self.stash.swap_remove(i),
+        };
+        self.len -= 1;
+        // The freed slot may be one a stashed entry can move to.
+        for bucket in std::mem::take(&mut self.stash) {
+            match (0..2).find(|t| self.tables[*t][self.index(bucket.hash, *t)].is_none()) {
+                Some(t) => {
+                    let i = self.index(bucket.hash, t);
+                    self.tables[t][i] = Some(bucket);
+                }
+                None => self.stash.push(bucket),
+            }
+        }
+        Some(bucket.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.tables
+            .iter()
+            .flatten()
+            .flatten()
+            .chain(&self.stash)
+            .map(|b| (&b.key, &b.value))
+    }
+
+    /// Probe lengths count 1 for the first table, 2 for the second, and 2
+    /// plus the position for the stash.
+    pub fn stats(&self) -> ProbeStats {
+        let mut stats = ProbeStats {
+            len: self.len,
+            capacity: self.capacity(),
+            displacements: self.displacements,
+            resizes: self.resizes,
+            ..ProbeStats::default()
+        };
+        for (t, table) in self.tables.iter().enumerate() {
+            for _ in table.iter().flatten() {
+                stats.add_probe(t + 1);
+            }
+        }
+        for i in 0..self.stash.len() {
+            stats.add_probe(i + 3);
+        }
+        stats
+    }
+
+    /// Power-of-two length of each table holding `len` entries within the
+    /// maximum load factor.
+    fn table_for(&self, len: usize) -> usize {
+        let slots = (len as f64 / self.max_load_factor).ceil() as usize;
+        std::cmp::max(slots.div_ceil(2).next_power_of_two(), MIN_TABLE)
+    }
+
+    /// Slot of `hash` in table `t`, from the low bits for the first table
+    /// and the high bits for the second.
+    fn index(&self, hash: u64, t: usize) -> usize {
+        let bits = if t == 0 { hash } else { hash.rotate_left(32) };
+        bits as usize & (self.tables[t].len() - 1)
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<Location>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let hash = make_hash(&self.hasher, key);
+        let is_key = |b: &Bucket<K, V>| b.hash == hash && b.key.borrow() == key;
+        for t in 0..2 {
+            let i = self.index(hash, t);
+            if self.tables[t][i].as_ref().is_some_and(is_key) {
+                return Some(Location::Table(t, i));
+            }
+        }
+        self.stash.iter().position(is_key).map(Location::Stash)
+    }
+
+    /// Puts a new entry in the tables or the stash. Fails with the entry
+    /// left over from a long eviction chain when the stash is full.
+    fn add(&mut self, mut bucket: Bucket<K, V>) -> Result<(), Bucket<K, V>> {
+        for t in 0..2 {
+            let i = self.index(bucket.hash, t);
+            if self.tables[t][i].is_none() {
+                self.tables[t][i] = Some(bucket);
+                return Ok(());
+            }
+        }
+        let mut t = 0;
+        for _ in 0..MAX_EVICTIONS {
+            let i = self.index(bucket.hash, t);
+            match &mut self.tables[t][i] {
+                slot @ None => {
+                    *slot = Some(bucket);
+                    return Ok(());
+                }
+                Some(x) => std::mem::swap(x, &mut bucket),
+            }
+            // The evicted entry moves to its slot in the other table.
+            self.displacements += 1;
+            t = 1 - t;
+        }
+        if self.stash.len() < self.stash_limit {
+            self.stash.push(bucket);
+            Ok(())
+        } else {
+            Err(bucket)
+        }
+    }
+
+    /// Moves every entry, and `extra`, into tables of at least `len`
+    /// slots, doubling them until every entry finds a place. After
+    /// `MAX_REBUILDS` failed doublings the tables go back to the size the
+    /// load factor asks for and the stash keeps what does not fit.
+    fn rebuild(&mut self, len: usize, extra: Option<Bucket<K, V>>) {
+        let len = std::cmp::max(len, MIN_TABLE);
+        let mut pending: Vec<Bucket<K, V>> = extra.into_iter().collect();
+        self.stash_limit = STASH_SIZE;
+        for attempt in 0..=MAX_REBUILDS {
+            let give_up = attempt == MAX_REBUILDS;
+            let old = std::mem::take(&mut self.tables);
+            pending.extend(old.into_iter().flatten().flatten());
+            pending.append(&mut self.stash);
+            let table_len = if give_up {
+                self.table_for(pending.len())
+            } else {
+                len << attempt
+            };
+            self.tables = [0, 1].map(|_| (0..table_len).map(|_| None).collect());
+            self.resizes += 1;
+            let displacements = self.displacements;
+            while let Some(bucket) = pending.pop() {
+                if let Err(bucket) = self.add(bucket) {
+                    if give_up {
+                        self.stash.push(bucket);
+                    } else {
+                        pending.push(bucket);
+                        break;
+                    }
+                }
+            }
+            self.displacements = displacements;
+            if pending.is_empty() {
+                if give_up {
+                    self.stash_limit = std::cmp::max(STASH_SIZE, 2 * self.stash.len());
+                }
+                return;
+            }
+        }
+    }
+}
+
+impl<K: HeapSize, V: HeapSize, S> MemoryUsage for CuckooMap<K, V, S> {
+    fn memory_usage(&self) -> Memory {
+        let mut memory = slots_memory_usage(&self.tables[0]) + slots_memory_usage(&self.tables[1]);
+        memory.overhead += spare(&self.stash);
+        for bucket in &self.stash {
+            memory += bucket.memory_usage(std::mem::size_of::<Bucket<K, V>>());
+        }
+        memory
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for CuckooMap<K, V, S> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for CuckooMap<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut map = CuckooMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Hash + Eq + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug for CuckooMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_table::tests::{random_ops, FewHashes};
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = CuckooMap::new();
+        assert_eq!(map.insert("a".to_string(), 1), None);
+        assert_eq!(map.insert("b".to_string(), 2), None);
+        assert_eq!(map.insert("a".to_string(), 3), Some(1));
+        assert_eq!(map.get("a"), Some(&3));
+        *map.get_mut("b").unwrap() += 1;
+        assert_eq!(map.remove("b"), Some(3));
+        assert_eq!(map.remove("b"), None);
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key("a"));
+    }
+
+    #[test]
+    fn matches_hashmap() {
+        random_ops(CuckooMap::new(), 5000);
+        random_ops(
+            CuckooMap::with_capacity_and_hasher(0, FewHashes(4096)),
+            2000,
+        );
+    }
+
+    #[test]
+    fn stash() {
+        // Keys with one hash share their two slots, so a third goes to the
+        // stash and moves back once one of the others is removed.
+        let mut map = CuckooMap::with_capacity_and_hasher(0, FewHashes(1));
+        for i in 0..3 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.stash_len(), 1);
+        assert_eq!(map.stats().max_probe_length(), 3);
+        assert!((0..3).all(|i| map.get(&i) == Some(&i)));
+        map.remove(&0);
+        assert_eq!(map.stash_len(), 0);
+        assert_eq!(map.stats().probe_lengths, [1, 1]);
+    }
+
+    #[test]
+    fn stash_grows_for_colliding_hashes() {
+        // Every key shares one hash, so only two of them fit in the tables
+        // whatever their size.
+        let mut map = CuckooMap::with_capacity_and_hasher(0, FewHashes(1));
+        for i in 0..200 {
+            assert_eq!(map.insert(i, i), None);
+        }
+        assert_eq!(map.len(), 200);
+        assert_eq!(map.stash_len(), 198);
+        assert!((0..200).all(|i| map.get(&i) == Some(&i)));
+        // Giving up returns to the size the load factor asks for.
+        assert_eq!(map.capacity(), 512);
+        // The stash limit doubles with each rebuild that gives up, so that
+        // their count grows with the log of the colliding keys.
+        let rebuilds = map.stats().resizes / (MAX_REBUILDS as u64 + 1);
+        assert!(rebuilds <= 16, "{} rebuilds", rebuilds);
+        assert!(map.stash_len() <= map.stash_limit);
+
+        for i in 0..150 {
+            assert_eq!(map.remove(&i), Some(i));
+        }
+        assert_eq!(map.stash_len(), 48);
+        assert!((150..200).all(|i| map.get(&i) == Some(&i)));
+        assert_eq!(map.insert(0, 1), None);
+        assert_eq!(map.insert(0, 2), Some(1));
+        assert_eq!(map.len(), 51);
+
+        // The fewest colliding keys a rebuild gives up on, and removing
+        // them, leave the stash at least its usual size.
+        let mut map = CuckooMap::with_capacity_and_hasher(0, FewHashes(1));
+        for i in 0..STASH_SIZE as u64 + 3 {
+            assert_eq!(map.insert(i, i), None);
+        }
+        assert_eq!(map.stash_len(), STASH_SIZE + 1);
+        assert_eq!(map.stash_limit, 2 * (STASH_SIZE + 1));
+        for i in 0..STASH_SIZE as u64 + 2 {
+            assert_eq!(map.remove(&i), Some(i));
+        }
+        assert_eq!(map.stash_len(), 0);
+        assert!(map.stash_limit >= STASH_SIZE);
+    }
+
+    #[test]
+    fn load_factor() {
+        let mut map: CuckooMap<u64, u64> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.capacity(), 4096);
+        let stats = map.stats();
+        assert_eq!(stats.len, 1000);
+        assert!(stats.mean_probe_length() < 2.0);
+
+        map.set_max_load_factor(0.2);
+        assert_eq!(map.capacity(), 8192);
+        assert!((0..1000).all(|i| map.get(&i) == Some(&i)));
+    }
+}
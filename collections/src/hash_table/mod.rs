@@ -0,0 +1,211 @@
+//! Open-addressing hash maps.
+//!
+//! `RobinHoodMap` probes linearly from the home slot of a key and keeps
+//! entries ordered by their distance from home, so lookups stop early and
+//! removals shift the run after the entry back instead of leaving
+//! tombstones. `CuckooMap` gives every key one slot in each of two tables,
+//! so a lookup reads at most two slots plus a small stash of entries that
+//! found no place.
+
+use std::hash::{BuildHasher, Hash};
+use std::mem::size_of;
+
+use crate::memory::{size_with_heap, HeapSize, Memory};
+
+mod cuckoo;
+mod robin_hood;
+
+pub use cuckoo::CuckooMap;
+pub use robin_hood::RobinHoodMap;
+
+/// Occupancy and probe lengths of a map.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeStats {
+    pub len: usize,
+    pub capacity: usize,
+    /// Number of entries by the number of slots a lookup of the entry
+    /// reads, starting at length 1.
+    pub probe_lengths: Vec<usize>,
+    /// Entries moved to make room for an insert: swaps for Robin Hood,
+    /// evictions for cuckoo hashing.
+    pub displacements: u64,
+    /// Times the table was grown and rehashed.
+    pub resizes: u64,
+}
+
+impl ProbeStats {
+    pub fn load_factor(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.len as f64 / self.capacity as f64
+        }
+    }
+
+    pub fn mean_probe_length(&self) -> f64 {
+        let total: usize = self
+            .probe_lengths
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (i + 1) * n)
+            .sum();
+        if self.len == 0 {
+            0.0
+        } else {
+            total as f64 / self.len as f64
+        }
+    }
+
+    pub fn max_probe_length(&self) -> usize {
+        self.probe_lengths
+            .iter()
+            .rposition(|n| *n > 0)
+            .map_or(0, |i| i + 1)
+    }
+
+    fn add_probe(&mut self, length: usize) {
+        if self.probe_lengths.len() < length {
+            self.probe_lengths.resize(length, 0);
+        }
+        self.probe_lengths[length - 1] += 1;
+    }
+}
+
+/// Entry with the hash of its key, so that probes compare hashes first and
+/// resizes do not rehash keys.
+struct Bucket<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+}
+
+impl<K: HeapSize, V: HeapSize> Bucket<K, V> {
+    /// Memory of the bucket in a slot of `slot` bytes.
+    fn memory_usage(&self, slot: usize) -> Memory {
+        Memory {
+            nodes: slot - size_of::<K>() - size_of::<V>(),
+            keys: size_with_heap(&self.key),
+            values: size_with_heap(&self.value),
+            overhead: 0,
+        }
+    }
+}
+
+/// Memory of a table of `slots`, the empty ones counting as overhead.
+fn slots_memory_usage<K: HeapSize, V: HeapSize>(slots: &Vec<Option<Bucket<K, V>>>) -> Memory {
+    let slot = size_of::<Option<Bucket<K, V>>>();
+    let mut memory = Memory {
+        overhead: (slots.capacity() - slots.len()) * slot,
+        ..Memory::default()
+    };
+    for x in slots {
+        match x {
+            Some(bucket) => memory += bucket.memory_usage(slot),
+            None => memory.overhead += slot,
+        }
+    }
+    memory
+}
+
+fn make_hash<S: BuildHasher, Q: Hash + ?Sized>(hasher: &S, key: &Q) -> u64 {
+    hasher.hash_one(key)
+}
+
+fn check_load_factor(load_factor: f64) {
+    assert!(
+        load_factor > 0.0 && load_factor < 1.0,
+        "load factor must be in (0, 1)"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::hash::Hasher;
+
+    /// Hashes onto `0` to `n - 1` spread over 64 bits, to force collisions.
+    #[derive(Clone)]
+    pub struct FewHashes(pub u64);
+
+    pub struct FewHashesHasher(u64, u64);
+
+    impl BuildHasher for FewHashes {
+        type Hasher = FewHashesHasher;
+
+        fn build_hasher(&self) -> FewHashesHasher {
+            FewHashesHasher(0, self.0)
+        }
+    }
+
+    impl Hasher for FewHashesHasher {
+        fn write(&mut self, bytes: &[u8]) {
+            for b in bytes {
+                self.0 = self.0.wrapping_mul(31).wrapping_add(*b as u64);
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            (self.0 % self.1).wrapping_mul(0x9e3779b97f4a7c15)
+        }
+    }
+
+    pub trait TestMap {
+        fn insert(&mut self, key: u64, value: u64) -> Option<u64>;
+        fn remove(&mut self, key: u64) -> Option<u64>;
+        fn get(&self, key: u64) -> Option<u64>;
+        fn entries(&self) -> Vec<(u64, u64)>;
+        fn stats(&self) -> ProbeStats;
+    }
+
+    macro_rules! test_map {
+        ($map:ident) => {
+            impl<S: BuildHasher> TestMap for $map<u64, u64, S> {
+                fn insert(&mut self, key: u64, value: u64) -> Option<u64> {
+                    $map::insert(self, key, value)
+                }
+                fn remove(&mut self, key: u64) -> Option<u64> {
+                    $map::remove(self, &key)
+                }
+                fn get(&self, key: u64) -> Option<u64> {
+                    $map::get(self, &key).copied()
+                }
+                fn entries(&self) -> Vec<(u64, u64)> {
+                    self.iter().map(|(k, v)| (*k, *v)).collect()
+                }
+                fn stats(&self) -> ProbeStats {
+                    $map::stats(self)
+                }
+            }
+        };
+    }
+
+    test_map!(RobinHoodMap);
+    test_map!(CuckooMap);
+
+    /// Runs random inserts, removes and lookups of keys below `keys`
+    /// against `map` and a `HashMap`.
+    pub fn random_ops(mut map: impl TestMap, keys: u64) {
+        let mut expected = HashMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..20000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % keys;
+            match x >> 62 {
+                0 => assert_eq!(map.remove(key), expected.remove(&key)),
+                1 => assert_eq!(map.get(key), expected.get(&key).copied()),
+                _ => assert_eq!(map.insert(key, x), expected.insert(key, x)),
+            }
+        }
+        let mut entries = map.entries();
+        entries.sort();
+        let mut expected: Vec<(u64, u64)> = expected.into_iter().collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+        let stats = map.stats();
+        assert_eq!(stats.len, expected.len());
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), stats.len);
+    }
+}
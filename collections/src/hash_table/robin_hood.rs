@@ -0,0 +1,325 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use super::{check_load_factor, make_hash, slots_memory_usage, Bucket, ProbeStats};
+use crate::memory::{HeapSize, Memory, MemoryUsage};
+
+const MIN_CAPACITY: usize = 8;
+const DEFAULT_LOAD_FACTOR: f64 = 0.875;
+
+/// Hash map with linear probing and Robin Hood ordering: an insert takes
+/// the slot of any entry closer to its home than the new entry is, and
+/// carries that entry on. Entries of a probe run are thus ordered by their
+/// distance from home, so a lookup stops at the first entry closer to home
+/// than it has come. Removal shifts the rest of the run back by one slot.
+pub struct RobinHoodMap<K, V, S = RandomState> {
+    /// Power-of-two number of slots.
+    slots: Vec<Option<Bucket<K, V>>>,
+    len: usize,
+    max_load_factor: f64,
+    hasher: S,
+    displacements: u64,
+    resizes: u64,
+}
+
+impl<K: Hash + Eq, V> Default for RobinHoodMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V> RobinHoodMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> RobinHoodMap<K, V, S> {
+    /// Map with room for `capacity` entries at the default maximum load
+    /// factor of 0.875.
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        let mut map = RobinHoodMap {
+            slots: Vec::new(),
+            len: 0,
+            max_load_factor: DEFAULT_LOAD_FACTOR,
+            hasher,
+            displacements: 0,
+            resizes: 0,
+        };
+        if capacity > 0 {
+            map.resize(map.slots_for(capacity));
+        }
+        map
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn max_load_factor(&self) -> f64 {
+        self.max_load_factor
+    }
+
+    /// Sets the fraction of slots that may be filled before the table
+    /// doubles, growing it now if it is fuller than that.
+    pub fn set_max_load_factor(&mut self, load_factor: f64) {
+        check_load_factor(load_factor);
+        self.max_load_factor = load_factor;
+        if self.len > 0 && self.slots_for(self.len) > self.slots.len() {
+            self.resize(self.slots_for(self.len));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|x| *x = None);
+        self.len = 0;
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find(key)?;
+        self.slots[i].as_ref().map(|b| &b.value)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.find(key)?;
+        self.slots[i].as_mut().map(|b| &mut b.value)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(i) = self.find(&key) {
+            let bucket = self.slots[i].as_mut().unwrap();
+            return Some(std::mem::replace(&mut bucket.value, value));
+        }
+        if self.slots_for(self.len + 1) > self.slots.len() {
+            self.resize(std::cmp::max(self.slots.len() * 2, MIN_CAPACITY));
+        }
+        let hash = make_hash(&self.hasher, &key);
+        self.displacements += self.place(Bucket { hash, key, value });
+        self.len += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut i = self.find(key)?;
+        let bucket = self.slots[i].take().unwrap();
+        self.len -= 1;
+        // Backward shift: move the entries after it one slot closer to
+        // home, up to an empty slot or an entry already at home.
+        let mask = self.slots.len() - 1;
+        loop {
+            let j = (i + 1) & mask;
+            match &self.slots[j] {
+                Some(b) if self.distance(j, b.hash) > 0 => {
+                    self.slots[i] = self.slots[j].take();
+                    i = j;
+                }
+                _ => break,
+            }
+        }
+        Some(bucket.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().flatten().map(|b| (&b.key, &b.value))
+    }
+
+    pub fn stats(&self) -> ProbeStats {
+        let mut stats = ProbeStats {
+            len: self.len,
+            capacity: self.slots.len(),
+            displacements: self.displacements,
+            resizes: self.resizes,
+            ..ProbeStats::default()
+        };
+        for (i, b) in self.slots.iter().enumerate() {
+            if let Some(b) = b {
+                stats.add_probe(self.distance(i, b.hash) + 1);
+            }
+        }
+        stats
+    }
+
+    /// Power-of-two number of slots holding `len` entries within the
+    /// maximum load factor.
+    fn slots_for(&self, len: usize) -> usize {
+        let slots = (len as f64 / self.max_load_factor).ceil() as usize;
+        // Keep one slot empty so that probes terminate.
+        std::cmp::max(slots.max(len + 1).next_power_of_two(), MIN_CAPACITY)
+    }
+
+    fn home(&self, hash: u64) -> usize {
+        hash as usize & (self.slots.len() - 1)
+    }
+
+    fn distance(&self, i: usize, hash: u64) -> usize {
+        i.wrapping_sub(self.home(hash)) & (self.slots.len() - 1)
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let hash = make_hash(&self.hasher, key);
+        let mask = self.slots.len() - 1;
+        let mut i = self.home(hash);
+        for d in 0.. {
+            let b = self.slots[i].as_ref()?;
+            if self.distance(i, b.hash) < d {
+                return None;
+            }
+            if b.hash == hash && b.key.borrow() == key {
+                return Some(i);
+            }
+            i = (i + 1) & mask;
+        }
+        unreachable!()
+    }
+
+    /// Puts a new entry in the table, returning the number of entries it
+    /// displaced.
+    fn place(&mut self, mut bucket: Bucket<K, V>) -> u64 {
+        let mask = self.slots.len() - 1;
+        let mut i = self.home(bucket.hash);
+        let mut d = 0;
+        let mut displaced = 0;
+        loop {
+            let slot_distance = match &self.slots[i] {
+                None => {
+                    self.slots[i] = Some(bucket);
+                    return displaced;
+                }
+                Some(b) => self.distance(i, b.hash),
+            };
+            if slot_distance < d {
+                std::mem::swap(self.slots[i].as_mut().unwrap(), &mut bucket);
+                d = slot_distance;
+                displaced += 1;
+            }
+            i = (i + 1) & mask;
+            d += 1;
+        }
+    }
+
+    fn resize(&mut self, slots: usize) {
+        let old = std::mem::replace(&mut self.slots, (0..slots).map(|_| None).collect());
+        for bucket in old.into_iter().flatten() {
+            self.place(bucket);
+        }
+        self.resizes += 1;
+    }
+}
+
+impl<K: HeapSize, V: HeapSize, S> MemoryUsage for RobinHoodMap<K, V, S> {
+    fn memory_usage(&self) -> Memory {
+        slots_memory_usage(&self.slots)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for RobinHoodMap<K, V, S> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for RobinHoodMap<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut map = RobinHoodMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Hash + Eq + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug
+    for RobinHoodMap<K, V, S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_table::tests::{random_ops, FewHashes};
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = RobinHoodMap::new();
+        assert_eq!(map.insert("a".to_string(), 1), None);
+        assert_eq!(map.insert("b".to_string(), 2), None);
+        assert_eq!(map.insert("a".to_string(), 3), Some(1));
+        assert_eq!(map.get("a"), Some(&3));
+        *map.get_mut("b").unwrap() += 1;
+        assert_eq!(map.remove("b"), Some(3));
+        assert_eq!(map.remove("b"), None);
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key("a"));
+    }
+
+    #[test]
+    fn matches_hashmap() {
+        random_ops(RobinHoodMap::new(), 5000);
+        // Few distinct hashes make long runs to shift on removal.
+        random_ops(
+            RobinHoodMap::with_capacity_and_hasher(0, FewHashes(16)),
+            500,
+        );
+    }
+
+    #[test]
+    fn load_factor() {
+        let mut map: RobinHoodMap<u64, u64> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.capacity(), 2048);
+        let stats = map.stats();
+        assert_eq!(stats.len, 1000);
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), 1000);
+        assert!(stats.mean_probe_length() >= 1.0);
+        assert!(stats.max_probe_length() >= stats.mean_probe_length() as usize);
+
+        map.set_max_load_factor(0.25);
+        assert_eq!(map.capacity(), 4096);
+        assert!(map.stats().load_factor() <= 0.25);
+        assert!((0..1000).all(|i| map.get(&i) == Some(&i)));
+    }
+}
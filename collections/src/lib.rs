@@ -1 +1,28 @@
-mod skip_list;
+pub mod arena;
+pub mod art;
+pub mod blink_tree;
+pub mod bloom;
+pub mod bplus_tree;
+pub mod concurrent_hash_map;
+pub mod hash_table;
+pub mod lsm;
+pub mod memory;
+pub mod ordered_map;
+pub mod persistent_map;
+pub mod skip_list;
+mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use arena::{Arena, Heap, NodeAlloc};
+pub use art::Art;
+pub use blink_tree::BLinkTree;
+pub use bloom::{BlockedBloomFilter, BloomFilter, Filter};
+pub use bplus_tree::BPlusTree;
+pub use concurrent_hash_map::ConcurrentHashMap;
+pub use hash_table::{CuckooMap, RobinHoodMap};
+pub use lsm::Lsm;
+pub use memory::{HeapSize, Memory, MemoryUsage};
+pub use ordered_map::OrderedMap;
+pub use persistent_map::{PersistentMap, VersionCell};
+pub use skip_list::SkipList;
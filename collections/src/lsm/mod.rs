@@ -0,0 +1,932 @@
+//! Log-structured merge tree over byte strings.
+//!
+//! Writes go to a skip-list memtable. A full memtable becomes immutable and
+//! a background thread flushes it to a sorted table in level 0, whose
+//! tables may overlap. Levels 1 and up hold non-overlapping tables and grow
+//! by `level_multiplier`; when a level exceeds its size, one of its tables
+//! is merged into the overlapping tables of the next level (leveled
+//! compaction). Deletes write tombstones that are dropped once they reach
+//! the last populated level.
+//!
+//! The memtable is not logged, so the tree does not survive a restart;
+//! `open` starts from an empty directory. I/O errors after `open` panic.
+
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Condvar, Mutex, RwLock,
+};
+use std::thread::JoinHandle;
+
+use crate::memory::{Memory, MemoryUsage};
+use crate::skip_list::SkipList;
+
+mod sstable;
+use sstable::{Table, TableBuilder, TableIter};
+
+/// Key and value, `None` for a tombstone.
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Approximate per-entry overhead of the memtable in bytes.
+const ENTRY_OVERHEAD: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Size in bytes at which the memtable is frozen and flushed.
+    pub memtable_size: usize,
+    /// Frozen memtables waiting for a flush before writes stall.
+    pub max_immutable: usize,
+    /// Target size of a data block in bytes.
+    pub block_size: usize,
+    pub bloom_bits_per_key: usize,
+    /// Size in bytes at which compaction output is split into a new table.
+    pub table_size: u64,
+    /// Number of level 0 tables that triggers a compaction into level 1.
+    pub l0_compaction_trigger: usize,
+    /// Number of level 0 tables at which writes stall.
+    pub l0_stop_writes: usize,
+    /// Maximum size in bytes of level 1.
+    pub level_base_size: u64,
+    /// Size ratio between consecutive levels.
+    pub level_multiplier: u64,
+    pub max_levels: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            memtable_size: 4 << 20,
+            max_immutable: 2,
+            block_size: 4096,
+            bloom_bits_per_key: 10,
+            table_size: 2 << 20,
+            l0_compaction_trigger: 4,
+            l0_stop_writes: 12,
+            level_base_size: 10 << 20,
+            level_multiplier: 10,
+            max_levels: 7,
+        }
+    }
+}
+
+/// Counters since the tree was opened. Write amplification is
+/// `(flush_bytes + compaction_bytes_written) / user_bytes`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Bytes of keys and values passed to `put` and `delete`.
+    pub user_bytes: u64,
+    pub flushes: u64,
+    pub flush_bytes: u64,
+    pub compactions: u64,
+    pub compaction_bytes_read: u64,
+    pub compaction_bytes_written: u64,
+    /// Writes that waited for the background thread.
+    pub stalls: u64,
+    /// Table lookups skipped because the filter ruled the key out.
+    pub bloom_skips: u64,
+    /// Table lookups that read a block.
+    pub table_reads: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    user_bytes: AtomicU64,
+    flushes: AtomicU64,
+    flush_bytes: AtomicU64,
+    compactions: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    stalls: AtomicU64,
+    bloom_skips: AtomicU64,
+    table_reads: AtomicU64,
+}
+
+#[derive(Default)]
+struct Memtable {
+    map: SkipList<Vec<u8>, Option<Vec<u8>>>,
+    size: usize,
+}
+
+type MemtableRef = Arc<RwLock<Memtable>>;
+
+/// Immutable set of frozen memtables and tables. Readers work on the
+/// version current when they start; the background thread installs a new
+/// one after each flush or compaction.
+#[derive(Clone)]
+struct Version {
+    /// Newest first.
+    imm: Vec<MemtableRef>,
+    /// Level 0 newest first, other levels ordered by key.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+struct State {
+    mem: MemtableRef,
+    version: Arc<Version>,
+}
+
+enum Job {
+    Flush(MemtableRef),
+    Compact {
+        level: usize,
+        upper: Vec<Arc<Table>>,
+        lower: Vec<Arc<Table>>,
+    },
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    state: RwLock<State>,
+    /// Set on shutdown. Waiters on `changed` hold this lock while checking
+    /// the state they wait for.
+    shutdown: Mutex<bool>,
+    changed: Condvar,
+    next_id: AtomicU64,
+    /// Largest key compacted out of each level, to rotate through its
+    /// tables.
+    compact_pointer: Mutex<Vec<Vec<u8>>>,
+    counters: Counters,
+}
+
+pub struct Lsm {
+    inner: Arc<Inner>,
+    compactor: Option<JoinHandle<()>>,
+}
+
+impl Lsm {
+    /// Opens an empty tree in `dir`, removing tables left by an earlier
+    /// run, and starts its background thread.
+    pub fn open(dir: impl AsRef<Path>, options: Options) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|x| x == "sst") {
+                fs::remove_file(path)?;
+            }
+        }
+        let max_levels = std::cmp::max(options.max_levels, 2);
+        let inner = Arc::new(Inner {
+            dir,
+            state: RwLock::new(State {
+                mem: MemtableRef::default(),
+                version: Arc::new(Version {
+                    imm: Vec::new(),
+                    levels: vec![Vec::new(); max_levels],
+                }),
+            }),
+            options: Options {
+                max_levels,
+                ..options
+            },
+            shutdown: Mutex::new(false),
+            changed: Condvar::new(),
+            next_id: AtomicU64::new(1),
+            compact_pointer: Mutex::new(vec![Vec::new(); max_levels]),
+            counters: Counters::default(),
+        });
+        let compactor = {
+            let inner = inner.clone();
+            std::thread::Builder::new()
+                .name("lsm-compactor".to_string())
+                .spawn(move || inner.run())?
+        };
+        Ok(Lsm {
+            inner,
+            compactor: Some(compactor),
+        })
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.inner.write(key, Some(value));
+    }
+
+    pub fn delete(&self, key: Vec<u8>) {
+        self.inner.write(key, None);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.get(key)
+    }
+
+    /// Iterates over the live entries with keys in `range`. The iterator
+    /// reads the tables of the version current when it was created and the
+    /// memtables as they are when it reaches them, so it is not a snapshot.
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> Range {
+        let start = range.start_bound().map(|x| x.to_vec());
+        let end = range.end_bound().map(|x| x.to_vec());
+        let (mem, version) = self.inner.current();
+        let from = start.as_ref().map(|x| x.as_slice());
+
+        let mut sources: Vec<Box<dyn Iterator<Item = Entry> + Send>> = Vec::new();
+        for mem in std::iter::once(&mem).chain(&version.imm) {
+            sources.push(Box::new(MemtableIter {
+                mem: mem.clone(),
+                next: start.clone(),
+            }));
+        }
+        for table in &version.levels[0] {
+            sources.push(Box::new(TableIter::new(table.clone(), from)));
+        }
+        for level in &version.levels[1..] {
+            let i = match from {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    level.partition_point(|x| x.last_key.as_slice() < key)
+                }
+                Bound::Unbounded => 0,
+            };
+            let tables: Vec<Arc<Table>> = level[i..].to_vec();
+            let start = start.clone();
+            sources.push(Box::new(tables.into_iter().enumerate().flat_map(
+                move |(j, table)| {
+                    let from = if j == 0 {
+                        start.as_ref().map(|x| x.as_slice())
+                    } else {
+                        Bound::Unbounded
+                    };
+                    TableIter::new(table, from)
+                },
+            )));
+        }
+        Range {
+            merge: MergeIter::new(sources),
+            end,
+        }
+    }
+
+    /// Freezes the memtable and waits until every frozen memtable is
+    /// flushed to a table.
+    pub fn flush(&self) {
+        self.inner.rotate(None);
+        let mut shutdown = self.inner.shutdown.lock().unwrap();
+        while !self.inner.current().1.imm.is_empty() {
+            shutdown = self.inner.changed.wait(shutdown).unwrap();
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let c = &self.inner.counters;
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        Stats {
+            user_bytes: get(&c.user_bytes),
+            flushes: get(&c.flushes),
+            flush_bytes: get(&c.flush_bytes),
+            compactions: get(&c.compactions),
+            compaction_bytes_read: get(&c.compaction_bytes_read),
+            compaction_bytes_written: get(&c.compaction_bytes_written),
+            stalls: get(&c.stalls),
+            bloom_skips: get(&c.bloom_skips),
+            table_reads: get(&c.table_reads),
+        }
+    }
+
+    /// Number of tables and bytes in each level.
+    pub fn levels(&self) -> Vec<(usize, u64)> {
+        let (_, version) = self.inner.current();
+        version
+            .levels
+            .iter()
+            .map(|x| (x.len(), x.iter().map(|t| t.size).sum()))
+            .collect()
+    }
+}
+
+/// The memtables, and the indexes and filters of the tables, whose entries
+/// are on disk.
+impl MemoryUsage for Lsm {
+    fn memory_usage(&self) -> Memory {
+        let (mem, version) = self.inner.current();
+        let mut memory = Memory::default();
+        for mem in std::iter::once(&mem).chain(&version.imm) {
+            memory += mem.read().unwrap().map.memory_usage();
+        }
+        for table in version.levels.iter().flatten() {
+            memory += table.memory_usage();
+        }
+        memory
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        *self.inner.shutdown.lock().unwrap() = true;
+        self.inner.changed.notify_all();
+        if let Some(compactor) = self.compactor.take() {
+            let _ = compactor.join();
+        }
+    }
+}
+
+fn merge_into(old: &mut Vec<Arc<Table>>, removed: &[Arc<Table>], added: Vec<Arc<Table>>) {
+    old.retain(|x| !removed.iter().any(|y| y.id == x.id));
+    old.extend(added);
+}
+
+impl Inner {
+    fn current(&self) -> (MemtableRef, Arc<Version>) {
+        let state = self.state.read().unwrap();
+        (state.mem.clone(), state.version.clone())
+    }
+
+    fn write(&self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let size = key.len() + value.as_ref().map_or(0, |x| x.len());
+        self.counters
+            .user_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+        let full = {
+            // The state lock keeps the memtable from being frozen while
+            // the entry goes in.
+            let state = self.state.read().unwrap();
+            let mut mem = state.mem.write().unwrap();
+            mem.map.insert(key, value);
+            mem.size += size + ENTRY_OVERHEAD;
+            (mem.size >= self.options.memtable_size).then(|| state.mem.clone())
+        };
+        if let Some(mem) = full {
+            self.rotate(Some(mem));
+        }
+    }
+
+    /// Freezes the memtable, which must still be `mem` if given, once there
+    /// is room for another frozen memtable and level 0 is not full.
+    fn rotate(&self, mem: Option<MemtableRef>) {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        loop {
+            let (_, version) = self.current();
+            if version.imm.len() < self.options.max_immutable
+                && version.levels[0].len() < self.options.l0_stop_writes
+            {
+                break;
+            }
+            self.counters.stalls.fetch_add(1, Ordering::Relaxed);
+            shutdown = self.changed.wait(shutdown).unwrap();
+        }
+        drop(shutdown);
+
+        let mut state = self.state.write().unwrap();
+        if mem.is_some_and(|x| !Arc::ptr_eq(&x, &state.mem)) {
+            return;
+        }
+        if state.mem.read().unwrap().map.is_empty() {
+            return;
+        }
+        let frozen = std::mem::take(&mut state.mem);
+        let mut version = Version::clone(&state.version);
+        version.imm.insert(0, frozen);
+        state.version = Arc::new(version);
+        drop(state);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        let _shutdown = self.shutdown.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let (mem, version) = self.current();
+        for mem in std::iter::once(&mem).chain(&version.imm) {
+            if let Some(value) = mem.read().unwrap().map.get(key) {
+                return value.clone();
+            }
+        }
+        let level0 = version.levels[0].iter();
+        let deeper = version.levels[1..].iter().filter_map(|level| {
+            let i = level.partition_point(|x| x.last_key.as_slice() < key);
+            level.get(i)
+        });
+        for table in level0.chain(deeper) {
+            if !table.may_contain(key) {
+                if table.first_key.as_slice() <= key && key <= table.last_key.as_slice() {
+                    self.counters.bloom_skips.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            self.counters.table_reads.fetch_add(1, Ordering::Relaxed);
+            let ret = table
+                .get(key)
+                .unwrap_or_else(|e| panic!("cannot read {}: {}", table.path.display(), e));
+            if let Some(value) = ret {
+                return value;
+            }
+        }
+        None
+    }
+
+    fn table_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:06}.sst", id))
+    }
+
+    fn new_table(&self) -> TableBuilder {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        TableBuilder::new(
+            id,
+            self.table_path(id),
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        )
+        .unwrap()
+    }
+
+    /// Maximum size in bytes of `level`, 1 or more.
+    fn level_size(&self, level: usize) -> u64 {
+        let mut size = self.options.level_base_size;
+        for _ in 1..level {
+            size = size.saturating_mul(self.options.level_multiplier);
+        }
+        size
+    }
+
+    fn pick_job(&self) -> Option<Job> {
+        let (_, version) = self.current();
+        if let Some(mem) = version.imm.last() {
+            return Some(Job::Flush(mem.clone()));
+        }
+        // Compact the level furthest over its limit: level 0 by its number
+        // of tables, which all overlap, and the others by size.
+        let levels = &version.levels;
+        let mut best = (1.0, None);
+        for (level, tables) in levels[..levels.len() - 1].iter().enumerate() {
+            let score = if level == 0 {
+                tables.len() as f64 / self.options.l0_compaction_trigger as f64
+            } else {
+                let size: u64 = tables.iter().map(|x| x.size).sum();
+                size as f64 / self.level_size(level) as f64
+            };
+            if score >= best.0 {
+                best = (score, Some(level));
+            }
+        }
+        let level = best.1?;
+        let upper = if level == 0 {
+            levels[0].clone()
+        } else {
+            let pointer = &self.compact_pointer.lock().unwrap()[level];
+            let table = levels[level]
+                .iter()
+                .find(|x| x.first_key > *pointer)
+                .unwrap_or(&levels[level][0]);
+            vec![table.clone()]
+        };
+        let first = upper.iter().map(|x| &x.first_key).min().unwrap();
+        let last = upper.iter().map(|x| &x.last_key).max().unwrap();
+        let lower = levels[level + 1]
+            .iter()
+            .filter(|x| x.overlaps(first, last))
+            .cloned()
+            .collect();
+        Some(Job::Compact {
+            level,
+            upper,
+            lower,
+        })
+    }
+
+    fn run(&self) {
+        loop {
+            let job = {
+                let mut shutdown = self.shutdown.lock().unwrap();
+                loop {
+                    if *shutdown {
+                        return;
+                    }
+                    if let Some(job) = self.pick_job() {
+                        break job;
+                    }
+                    shutdown = self.changed.wait(shutdown).unwrap();
+                }
+            };
+            match job {
+                Job::Flush(mem) => self.flush(mem),
+                Job::Compact {
+                    level,
+                    upper,
+                    lower,
+                } => self.compact(level, upper, lower),
+            }
+            self.notify();
+        }
+    }
+
+    fn flush(&self, mem: MemtableRef) {
+        let mut builder = self.new_table();
+        for (key, value) in mem.read().unwrap().map.iter() {
+            builder.add(key, value.as_deref()).unwrap();
+        }
+        let table = Arc::new(builder.finish().unwrap());
+        self.counters.flushes.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .flush_bytes
+            .fetch_add(table.size, Ordering::Relaxed);
+
+        let mut state = self.state.write().unwrap();
+        let mut version = Version::clone(&state.version);
+        version.imm.retain(|x| !Arc::ptr_eq(x, &mem));
+        version.levels[0].insert(0, table);
+        state.version = Arc::new(version);
+    }
+
+    fn compact(&self, level: usize, upper: Vec<Arc<Table>>, lower: Vec<Arc<Table>>) {
+        // Tombstones can go once nothing older below the output level may
+        // hold the key.
+        let bottom = self.current().1.levels[level + 2..]
+            .iter()
+            .all(|x| x.is_empty());
+        let sources: Vec<Box<dyn Iterator<Item = Entry> + Send>> = upper
+            .iter()
+            .chain(&lower)
+            .map(|x| {
+                Box::new(TableIter::new(x.clone(), Bound::Unbounded))
+                    as Box<dyn Iterator<Item = Entry> + Send>
+            })
+            .collect();
+
+        let mut outputs = Vec::new();
+        let mut builder = self.new_table();
+        for (key, value) in MergeIter::new(sources) {
+            if value.is_none() && bottom {
+                continue;
+            }
+            if builder.size() >= self.options.table_size {
+                let next = self.new_table();
+                outputs.push(Arc::new(
+                    std::mem::replace(&mut builder, next).finish().unwrap(),
+                ));
+            }
+            builder.add(&key, value.as_deref()).unwrap();
+        }
+        if builder.is_empty() {
+            let path = self.table_path(builder.finish().unwrap().id);
+            fs::remove_file(path).unwrap();
+        } else {
+            outputs.push(Arc::new(builder.finish().unwrap()));
+        }
+
+        let read: u64 = upper.iter().chain(&lower).map(|x| x.size).sum();
+        let written: u64 = outputs.iter().map(|x| x.size).sum();
+        self.counters.compactions.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .compaction_bytes_read
+            .fetch_add(read, Ordering::Relaxed);
+        self.counters
+            .compaction_bytes_written
+            .fetch_add(written, Ordering::Relaxed);
+        if level > 0 {
+            self.compact_pointer.lock().unwrap()[level] = upper[0].last_key.clone();
+        }
+
+        {
+            let mut state = self.state.write().unwrap();
+            let mut version = Version::clone(&state.version);
+            merge_into(&mut version.levels[level], &upper, Vec::new());
+            merge_into(&mut version.levels[level + 1], &lower, outputs);
+            version.levels[level + 1].sort_by(|x, y| x.first_key.cmp(&y.first_key));
+            state.version = Arc::new(version);
+        }
+        // Readers still holding the old version keep the files open.
+        for table in upper.iter().chain(&lower) {
+            fs::remove_file(&table.path).unwrap();
+        }
+    }
+}
+
+/// Entries of a memtable from a start bound, looked up one at a time so
+/// that the memtable is only locked while stepping.
+struct MemtableIter {
+    mem: MemtableRef,
+    next: Bound<Vec<u8>>,
+}
+
+impl Iterator for MemtableIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let mem = self.mem.read().unwrap();
+        let from = self.next.as_ref().map(|x| x.as_slice());
+        let (key, value) = mem.map.range::<[u8], _>((from, Bound::Unbounded)).next()?;
+        self.next = Bound::Excluded(key.clone());
+        Some((key.clone(), value.clone()))
+    }
+}
+
+/// Merges sources ordered newest first, yielding each key once with the
+/// entry of the newest source holding it.
+struct MergeIter {
+    sources: Vec<Box<dyn Iterator<Item = Entry> + Send>>,
+    heads: Vec<Option<Entry>>,
+}
+
+impl MergeIter {
+    fn new(mut sources: Vec<Box<dyn Iterator<Item = Entry> + Send>>) -> Self {
+        let heads = sources.iter_mut().map(|x| x.next()).collect();
+        MergeIter { sources, heads }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                if min.is_none_or(|j| *key < self.heads[j].as_ref().unwrap().0) {
+                    min = Some(i);
+                }
+            }
+        }
+        let min = min?;
+        let entry = std::mem::replace(&mut self.heads[min], self.sources[min].next()).unwrap();
+        for i in min + 1..self.heads.len() {
+            if self.heads[i].as_ref().is_some_and(|(k, _)| *k == entry.0) {
+                self.heads[i] = self.sources[i].next();
+            }
+        }
+        Some(entry)
+    }
+}
+
+/// Live entries of a key range, see `Lsm::range`.
+pub struct Range {
+    merge: MergeIter,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for Range {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = self.merge.next()?;
+            let past_end = match &self.end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return None;
+            }
+            if let Some(value) = value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::testing::TestDir;
+
+    fn small_options() -> Options {
+        Options {
+            memtable_size: 4096,
+            block_size: 256,
+            table_size: 4096,
+            l0_compaction_trigger: 2,
+            level_base_size: 16384,
+            level_multiplier: 4,
+            ..Options::default()
+        }
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("key{:08}", i).into_bytes()
+    }
+
+    /// Waits until the background thread has no flush or compaction left.
+    fn settle(lsm: &Lsm) {
+        let inner = &lsm.inner;
+        let mut shutdown = inner.shutdown.lock().unwrap();
+        while inner.pick_job().is_some() {
+            shutdown = inner.changed.wait(shutdown).unwrap();
+        }
+    }
+
+    /// Entries of the tables of each level, tombstones included.
+    fn level_entries(lsm: &Lsm) -> Vec<Vec<Entry>> {
+        let (_, version) = lsm.inner.current();
+        version
+            .levels
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .flat_map(|table| TableIter::new(table.clone(), Bound::Unbounded))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn full_range(lsm: &Lsm) -> Range {
+        lsm.range::<(Bound<&[u8]>, Bound<&[u8]>)>((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        let dir = TestDir::new("lsm-btreemap");
+        let lsm = Lsm::open(dir.path(), small_options()).unwrap();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for i in 0..20000u64 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let k = key((x >> 33) % 2000);
+            if x & 7 == 0 {
+                lsm.delete(k.clone());
+                map.remove(&k);
+            } else {
+                let v = i.to_le_bytes().to_vec();
+                lsm.put(k.clone(), v.clone());
+                map.insert(k, v);
+            }
+            if i % 5000 == 4999 {
+                lsm.flush();
+            }
+        }
+        for i in 0..2000 {
+            assert_eq!(lsm.get(&key(i)), map.get(&key(i)).cloned());
+        }
+        assert!(full_range(&lsm).eq(map.clone().into_iter()));
+        let (from, to) = (key(500), key(900));
+        assert!(lsm
+            .range::<(Bound<&[u8]>, Bound<&[u8]>)>((Bound::Excluded(&from), Bound::Included(&to)))
+            .eq(map
+                .range((Bound::Excluded(from.clone()), Bound::Included(to.clone())))
+                .map(|(k, v)| (k.clone(), v.clone()))));
+
+        let stats = lsm.stats();
+        assert!(stats.flushes > 0);
+        assert!(stats.compactions > 0);
+    }
+
+    #[test]
+    fn reads_run_during_flushes_and_compactions() {
+        const KEYS: u64 = 500;
+        const ROUNDS: u64 = 40;
+        let dir = TestDir::new("lsm-concurrent");
+        let lsm = Lsm::open(dir.path(), small_options()).unwrap();
+        let value = |round: u64| round.to_be_bytes().to_vec();
+        for i in 0..KEYS {
+            lsm.put(key(i), value(0));
+        }
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for round in 1..ROUNDS {
+                    for i in 0..KEYS {
+                        lsm.put(key(i), value(round));
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            for _ in 0..2 {
+                s.spawn(|| {
+                    // Keys are only overwritten with later rounds, so every
+                    // key is always found and never goes back to an older
+                    // round.
+                    let mut seen = vec![0; KEYS as usize];
+                    while !done.load(Ordering::Relaxed) {
+                        for i in 0..KEYS {
+                            let v = lsm.get(&key(i)).expect("key lost");
+                            let round = u64::from_be_bytes(v.try_into().unwrap());
+                            assert!(round >= seen[i as usize]);
+                            seen[i as usize] = round;
+                        }
+                        let keys: Vec<Vec<u8>> = full_range(&lsm).map(|(k, _)| k).collect();
+                        assert_eq!(keys, (0..KEYS).map(key).collect::<Vec<_>>());
+                    }
+                });
+            }
+        });
+        let stats = lsm.stats();
+        assert!(stats.flushes > 0);
+        assert!(stats.compactions > 0);
+        for i in 0..KEYS {
+            assert_eq!(lsm.get(&key(i)), Some(value(ROUNDS - 1)));
+        }
+    }
+
+    #[test]
+    fn tombstones_are_dropped_at_the_bottom_level() {
+        let dir = TestDir::new("lsm-bottom");
+        let options = Options {
+            l0_compaction_trigger: 1,
+            ..Options::default()
+        };
+        let lsm = Lsm::open(dir.path(), options).unwrap();
+        for i in 0..100 {
+            lsm.put(key(i), vec![1]);
+        }
+        lsm.flush();
+        settle(&lsm);
+        for i in 0..50 {
+            lsm.delete(key(i));
+        }
+        lsm.flush();
+        settle(&lsm);
+
+        // Level 1 is the last populated level, so merging the tombstones
+        // into it drops them along with the values they delete.
+        let levels = level_entries(&lsm);
+        assert!(levels[0].is_empty());
+        assert_eq!(levels[1].len(), 50);
+        assert!(levels[1].iter().all(|(_, v)| v.is_some()));
+        assert_eq!(full_range(&lsm).count(), 50);
+        assert_eq!(lsm.get(&key(0)), None);
+    }
+
+    #[test]
+    fn tombstones_hide_values_in_deeper_levels() {
+        let dir = TestDir::new("lsm-deeper");
+        let options = Options {
+            l0_compaction_trigger: 1,
+            level_base_size: 4096,
+            ..Options::default()
+        };
+        let lsm = Lsm::open(dir.path(), options).unwrap();
+        for i in 0..100 {
+            lsm.put(key(i), vec![1; 100]);
+        }
+        lsm.flush();
+        settle(&lsm);
+        // The values overflow level 1 and move on to level 2.
+        assert_eq!(level_entries(&lsm)[2].len(), 100);
+
+        for i in 0..50 {
+            lsm.delete(key(i));
+        }
+        lsm.flush();
+        settle(&lsm);
+
+        // The tombstones fit in level 1 and stay there, above the values
+        // they delete.
+        let levels = level_entries(&lsm);
+        assert_eq!(levels[1].len(), 50);
+        assert!(levels[1].iter().all(|(_, v)| v.is_none()));
+        assert_eq!(levels[2].len(), 100);
+        for i in 0..100 {
+            assert_eq!(lsm.get(&key(i)).is_some(), i >= 50, "{}", i);
+        }
+        assert!(full_range(&lsm).map(|(k, _)| k).eq((50..100).map(key)));
+    }
+
+    #[test]
+    fn filter_skips_are_counted() {
+        let dir = TestDir::new("lsm-bloom");
+        let lsm = Lsm::open(dir.path(), Options::default()).unwrap();
+        for i in (0..2000).step_by(2) {
+            lsm.put(key(i), vec![1]);
+        }
+        lsm.flush();
+
+        for i in (0..2000).step_by(2) {
+            assert!(lsm.get(&key(i)).is_some());
+        }
+        let stats = lsm.stats();
+        assert_eq!((stats.table_reads, stats.bloom_skips), (1000, 0));
+
+        // Every missing key within the table is either ruled out by the
+        // filter or read as a false positive.
+        for i in (1..1998).step_by(2) {
+            assert_eq!(lsm.get(&key(i)), None);
+        }
+        let stats = lsm.stats();
+        assert_eq!(stats.table_reads - 1000 + stats.bloom_skips, 999);
+        assert!(stats.bloom_skips > 950, "{:?}", stats);
+
+        // Keys outside of the table are not counted as skips.
+        lsm.get(&key(5000));
+        assert_eq!(lsm.stats().bloom_skips, stats.bloom_skips);
+    }
+
+    #[test]
+    fn writes_stall_behind_the_background_thread() {
+        let dir = TestDir::new("lsm-stall");
+        let options = Options {
+            memtable_size: 1024,
+            max_immutable: 1,
+            l0_compaction_trigger: 1,
+            l0_stop_writes: 1,
+            ..Options::default()
+        };
+        let lsm = Lsm::open(dir.path(), options).unwrap();
+        // Every flush syncs a file, which takes longer than filling the
+        // next memtable.
+        for i in 0..2000 {
+            lsm.put(key(i), vec![1; 32]);
+            let (_, version) = lsm.inner.current();
+            assert!(version.imm.len() <= 1);
+        }
+        let stats = lsm.stats();
+        assert!(stats.stalls > 0, "{:?}", stats);
+        assert_eq!(full_range(&lsm).count(), 2000);
+    }
+}
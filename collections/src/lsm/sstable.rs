@@ -0,0 +1,470 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::bloom::{self, BloomFilter, Filter};
+use crate::lsm::Entry;
+use crate::memory::{Memory, MemoryUsage};
+
+// Layout of a table file:
+//
+//   data blocks   entries [klen: u32][key][tag: u8][vlen: u32][value],
+//                 tag 1 marking a tombstone without vlen and value
+//   index         per block [klen: u32][last key][offset: u64][len: u32]
+//   bloom filter  `BloomFilter::to_bytes` of every key
+//   footer        [index offset: u64][index len: u64][bloom offset: u64]
+//                 [bloom len: u64][entries: u64][MAGIC: u64]
+//
+// All integers are little endian.
+
+const MAGIC: u64 = 0x4c534d5353544142;
+const FOOTER_LEN: usize = 48;
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn get_u32(data: &[u8], pos: &mut usize) -> io::Result<u32> {
+    let x = data
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| invalid("truncated table"))?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(x.try_into().unwrap()))
+}
+
+fn get_u64(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let x = data
+        .get(*pos..*pos + 8)
+        .ok_or_else(|| invalid("truncated table"))?;
+    *pos += 8;
+    Ok(u64::from_le_bytes(x.try_into().unwrap()))
+}
+
+fn get_bytes(data: &[u8], pos: &mut usize) -> io::Result<Vec<u8>> {
+    let len = get_u32(data, pos)? as usize;
+    let x = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid("truncated table"))?;
+    *pos += len;
+    Ok(x.to_vec())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// Writes a table from entries added in strictly increasing key order.
+pub struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    out: BufWriter<File>,
+    block_size: usize,
+    bits_per_key: usize,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    offset: u64,
+}
+
+impl TableBuilder {
+    pub fn new(id: u64, path: PathBuf, block_size: usize, bits_per_key: usize) -> io::Result<Self> {
+        Ok(TableBuilder {
+            id,
+            out: BufWriter::new(File::create(&path)?),
+            path,
+            block_size,
+            bits_per_key,
+            block: Vec::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+            first_key: None,
+            last_key: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    pub fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> io::Result<()> {
+        debug_assert!(self.first_key.is_none() || key > self.last_key.as_slice());
+        put_bytes(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(TAG_PUT);
+                put_bytes(&mut self.block, value);
+            }
+            None => self.block.push(TAG_DELETE),
+        }
+        self.hashes.push(bloom::hash(key));
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.out.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Bytes written so far, to split the output of a compaction.
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_key.is_none()
+    }
+
+    /// Writes the index, the filter and the footer, syncs the file and
+    /// opens it for reading.
+    pub fn finish(mut self) -> io::Result<Table> {
+        self.finish_block()?;
+        let mut buf = Vec::new();
+        for handle in &self.index {
+            put_bytes(&mut buf, &handle.last_key);
+            buf.extend_from_slice(&handle.offset.to_le_bytes());
+            buf.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let index_offset = self.offset;
+        let index_len = buf.len() as u64;
+
+        let mut filter = BloomFilter::new(self.hashes.len(), self.bits_per_key);
+        for h in &self.hashes {
+            filter.insert_hash(*h);
+        }
+        let bloom = filter.to_bytes();
+        buf.extend_from_slice(&bloom);
+
+        for x in [
+            index_offset,
+            index_len,
+            index_offset + index_len,
+            bloom.len() as u64,
+            self.hashes.len() as u64,
+            MAGIC,
+        ] {
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+        self.out.write_all(&buf)?;
+        self.out.into_inner()?.sync_all()?;
+        Table::open(self.id, self.path)
+    }
+}
+
+/// Immutable sorted table on disk. The block index and the filter are kept
+/// in memory; blocks are read on demand.
+pub struct Table {
+    pub id: u64,
+    pub path: PathBuf,
+    file: File,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    /// Size of the file in bytes.
+    pub size: u64,
+}
+
+impl Table {
+    pub fn open(id: u64, path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(invalid("table too short"));
+        }
+        let mut footer = [0u8; FOOTER_LEN];
+        file.read_exact_at(&mut footer, size - FOOTER_LEN as u64)?;
+        let mut pos = 0;
+        let mut fields = [0u64; 6];
+        for x in &mut fields {
+            *x = get_u64(&footer, &mut pos)?;
+        }
+        let [index_offset, index_len, bloom_offset, bloom_len, _entries, magic] = fields;
+        if magic != MAGIC {
+            return Err(invalid("bad table magic"));
+        }
+
+        let mut buf = vec![0u8; index_len as usize];
+        file.read_exact_at(&mut buf, index_offset)?;
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            index.push(BlockHandle {
+                last_key: get_bytes(&buf, &mut pos)?,
+                offset: get_u64(&buf, &mut pos)?,
+                len: get_u32(&buf, &mut pos)?,
+            });
+        }
+
+        let mut buf = vec![0u8; bloom_len as usize];
+        file.read_exact_at(&mut buf, bloom_offset)?;
+        let filter = BloomFilter::from_bytes(&buf).ok_or_else(|| invalid("bad table filter"))?;
+
+        let mut table = Table {
+            id,
+            path,
+            file,
+            index,
+            filter,
+            first_key: Vec::new(),
+            last_key: Vec::new(),
+            size,
+        };
+        if let Some(handle) = table.index.last() {
+            table.last_key = handle.last_key.clone();
+            table.first_key = table.read_block(0)?.swap_remove(0).0;
+        }
+        Ok(table)
+    }
+
+    fn read_block(&self, i: usize) -> io::Result<Vec<Entry>> {
+        let handle = &self.index[i];
+        let mut data = vec![0u8; handle.len as usize];
+        self.file.read_exact_at(&mut data, handle.offset)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let key = get_bytes(&data, &mut pos)?;
+            let tag = *data.get(pos).ok_or_else(|| invalid("truncated table"))?;
+            pos += 1;
+            let value = match tag {
+                TAG_PUT => Some(get_bytes(&data, &mut pos)?),
+                TAG_DELETE => None,
+                _ => return Err(invalid("bad entry tag")),
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    /// Index of the first block that may hold keys at or after `key`.
+    fn block_index(&self, key: &[u8]) -> usize {
+        self.index.partition_point(|x| x.last_key.as_slice() < key)
+    }
+
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key.as_slice() <= last && first <= self.last_key.as_slice()
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.first_key.as_slice() <= key
+            && key <= self.last_key.as_slice()
+            && self.filter.may_contain(key)
+    }
+
+    /// Looks `key` up: `None` if the table has no entry for it, `Some(None)`
+    /// if the entry is a tombstone.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Option<Vec<u8>>>> {
+        let i = self.block_index(key);
+        if i >= self.index.len() {
+            return Ok(None);
+        }
+        let entries = self.read_block(i)?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|j| entries[j].1.clone()))
+    }
+}
+
+/// Only the index and filter, which the table keeps in memory; its entries
+/// stay on disk.
+impl MemoryUsage for Table {
+    fn memory_usage(&self) -> Memory {
+        let index: usize = self.index.iter().map(|x| x.last_key.capacity()).sum();
+        Memory {
+            overhead: self.index.capacity() * std::mem::size_of::<BlockHandle>()
+                + index
+                + self.first_key.capacity()
+                + self.last_key.capacity()
+                + self.filter.memory_usage().total(),
+            ..Memory::default()
+        }
+    }
+}
+
+/// Entries of a table in key order from a start bound. I/O errors panic.
+pub struct TableIter {
+    table: Arc<Table>,
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    pub fn new(table: Arc<Table>, start: Bound<&[u8]>) -> Self {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => table.block_index(key),
+            Bound::Unbounded => 0,
+        };
+        let mut iter = TableIter {
+            table,
+            block,
+            entries: Vec::new().into_iter(),
+        };
+        if iter.load() {
+            let entries: Vec<Entry> = iter
+                .entries
+                .by_ref()
+                .skip_while(|(k, _)| match start {
+                    Bound::Included(key) => k.as_slice() < key,
+                    Bound::Excluded(key) => k.as_slice() <= key,
+                    Bound::Unbounded => false,
+                })
+                .collect();
+            iter.entries = entries.into_iter();
+        }
+        iter
+    }
+
+    /// Decodes the current block, returning false past the last one.
+    fn load(&mut self) -> bool {
+        if self.block >= self.table.index.len() {
+            return false;
+        }
+        let entries = self
+            .table
+            .read_block(self.block)
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", self.table.path.display(), e));
+        self.entries = entries.into_iter();
+        true
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            if let Some(x) = self.entries.next() {
+                return Some(x);
+            }
+            self.block += 1;
+            if !self.load() {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testing::TestDir;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    /// Every third entry is a tombstone.
+    fn entries() -> Vec<Entry> {
+        (0..300)
+            .map(|i| (key(i), (i % 3 != 0).then(|| vec![i as u8; i % 7])))
+            .collect()
+    }
+
+    fn build(path: PathBuf, entries: &[Entry]) -> Table {
+        let mut builder = TableBuilder::new(1, path, 256, 10).unwrap();
+        for (key, value) in entries {
+            builder.add(key, value.as_deref()).unwrap();
+        }
+        builder.finish().unwrap()
+    }
+
+    fn scan(table: &Arc<Table>, start: Bound<&[u8]>) -> Vec<Entry> {
+        TableIter::new(table.clone(), start).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TestDir::new("sstable-round-trip");
+        let path = dir.path().join("1.sst");
+        let entries = entries();
+        let built = build(path.clone(), &entries);
+        assert!(built.index.len() > 1);
+
+        let table = Arc::new(Table::open(1, path).unwrap());
+        assert_eq!(table.size, built.size);
+        assert_eq!(table.first_key, key(0));
+        assert_eq!(table.last_key, key(299));
+        assert_eq!(scan(&table, Bound::Unbounded), entries);
+        for (key, value) in &entries {
+            assert!(table.may_contain(key));
+            assert_eq!(table.get(key).unwrap(), Some(value.clone()));
+        }
+        assert_eq!(table.get(b"key0000x").unwrap(), None);
+        assert_eq!(table.get(b"zzz").unwrap(), None);
+        assert!(!table.may_contain(b"zzz"));
+    }
+
+    #[test]
+    fn start_bounds_on_block_boundaries() {
+        let dir = TestDir::new("sstable-bounds");
+        let entries = entries();
+        let table = Arc::new(build(dir.path().join("1.sst"), &entries));
+        let boundaries: Vec<Vec<u8>> = table.index.iter().map(|x| x.last_key.clone()).collect();
+        assert!(boundaries.len() > 1);
+        for last in &boundaries {
+            let i = entries.iter().position(|(k, _)| k == last).unwrap();
+            assert_eq!(scan(&table, Bound::Included(last)), entries[i..]);
+            // The scan starts in the next block.
+            assert_eq!(scan(&table, Bound::Excluded(last)), entries[i + 1..]);
+        }
+        assert!(scan(&table, Bound::Excluded(&key(299))).is_empty());
+    }
+
+    #[test]
+    fn bad_footers_are_rejected() {
+        let dir = TestDir::new("sstable-footer");
+        let path = dir.path().join("1.sst");
+        build(path.clone(), &entries());
+        let data = fs::read(&path).unwrap();
+        let open = |data: &[u8]| {
+            fs::write(&path, data).unwrap();
+            Table::open(1, path.clone()).err().unwrap()
+        };
+
+        let mut bad_magic = data.clone();
+        *bad_magic.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&bad_magic).to_string(), "bad table magic");
+
+        assert_eq!(open(&data[..FOOTER_LEN - 1]).to_string(), "table too short");
+        // A truncated table no longer ends with the magic number.
+        assert_eq!(open(&data[..data.len() - 1]).to_string(), "bad table magic");
+
+        // An index beyond the end of the file.
+        let mut bad_index = data.clone();
+        let footer = data.len() - FOOTER_LEN;
+        bad_index[footer..footer + 8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        assert_eq!(open(&bad_index).kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
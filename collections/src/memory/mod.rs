@@ -0,0 +1,314 @@
+//! Memory accounting for the structures of this crate.
+//!
+//! `MemoryUsage` splits the bytes a structure holds into its nodes, its
+//! keys, its values and the rest. Keys and values count their own size
+//! and, through `HeapSize`, what they own on the heap. Sizes come from
+//! lengths and capacities and leave out the bookkeeping of the allocator.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::ops::{Add, AddAssign};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Bytes held by a structure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Memory {
+    /// Nodes, slots and links, without the keys and values stored in them.
+    pub nodes: usize,
+    /// Keys of the entries. Copies of keys kept to route searches, like
+    /// the separators of inner nodes, count as overhead.
+    pub keys: usize,
+    pub values: usize,
+    /// Spare capacity, free slots, separators, locks and filters.
+    pub overhead: usize,
+}
+
+impl Memory {
+    pub fn total(&self) -> usize {
+        self.nodes + self.keys + self.values + self.overhead
+    }
+}
+
+impl Add for Memory {
+    type Output = Memory;
+
+    fn add(mut self, other: Memory) -> Memory {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for Memory {
+    fn add_assign(&mut self, other: Memory) {
+        self.nodes += other.nodes;
+        self.keys += other.keys;
+        self.values += other.values;
+        self.overhead += other.overhead;
+    }
+}
+
+pub trait MemoryUsage {
+    /// Memory held now. Structures shared with other threads may change
+    /// while it is counted, which makes the result approximate.
+    fn memory_usage(&self) -> Memory;
+}
+
+/// Bytes a key or value owns outside of itself.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+/// Size of `x` with what it owns on the heap.
+pub fn size_with_heap<T: HeapSize>(x: &T) -> usize {
+    size_of::<T>() + x.heap_size()
+}
+
+/// `size_with_heap` summed over `items`.
+pub(crate) fn sum_with_heap<'a, T: HeapSize + 'a>(items: impl IntoIterator<Item = &'a T>) -> usize {
+    items.into_iter().map(size_with_heap).sum()
+}
+
+/// Bytes of the unused capacity of `v`.
+pub(crate) fn spare<T>(v: &Vec<T>) -> usize {
+    (v.capacity() - v.len()) * size_of::<T>()
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(impl HeapSize for $t {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+no_heap!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    bool,
+    char,
+    ()
+);
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl HeapSize for str {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl<T: HeapSize> HeapSize for [T] {
+    fn heap_size(&self) -> usize {
+        self.iter().map(HeapSize::heap_size).sum()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.as_slice().heap_size()
+    }
+}
+
+impl<T: HeapSize + ?Sized> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        std::mem::size_of_val::<T>(self) + (**self).heap_size()
+    }
+}
+
+/// Counted in full by every owner, so values shared between entries are
+/// counted more than once.
+impl<T: HeapSize + ?Sized> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        // Strong and weak counts.
+        2 * size_of::<usize>() + std::mem::size_of_val::<T>(self) + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+/// Locks `self` to read it.
+impl<T: HeapSize> HeapSize for RwLock<T> {
+    fn heap_size(&self) -> usize {
+        self.read().unwrap_or_else(|e| e.into_inner()).heap_size()
+    }
+}
+
+/// Locks `self` to read it.
+impl<T: HeapSize> HeapSize for Mutex<T> {
+    fn heap_size(&self) -> usize {
+        self.lock().unwrap_or_else(|e| e.into_inner()).heap_size()
+    }
+}
+
+/// Entries of a `BTreeMap` node.
+const BTREE_CAPACITY: usize = 11;
+
+/// Estimated, as std does not expose its nodes: assumes nodes 2/3 full,
+/// as random inserts leave them, with the layout of std's leaves (parent
+/// link, index and length before the keys and values) and inner nodes
+/// (the same and 12 children).
+impl<K: HeapSize, V: HeapSize> MemoryUsage for BTreeMap<K, V> {
+    fn memory_usage(&self) -> Memory {
+        if self.is_empty() {
+            return Memory::default();
+        }
+        let header = size_of::<usize>() + 2 * size_of::<u16>();
+        let slots = size_of::<K>() + size_of::<V>();
+        let per_node = BTREE_CAPACITY * 2 / 3;
+        let leaves = self.len().div_ceil(per_node);
+        let inner = (leaves - 1).div_ceil(per_node);
+        let nodes = leaves + inner;
+        Memory {
+            nodes: nodes * header + inner * (BTREE_CAPACITY + 1) * size_of::<usize>(),
+            keys: sum_with_heap(self.keys()),
+            values: sum_with_heap(self.values()),
+            overhead: (nodes * BTREE_CAPACITY - self.len()) * slots,
+        }
+    }
+}
+
+/// From the capacity, with std's layout of one control byte per bucket
+/// plus a group of 16 and 7/8 of the buckets usable.
+impl<K: HeapSize, V: HeapSize, S> MemoryUsage for HashMap<K, V, S> {
+    fn memory_usage(&self) -> Memory {
+        let buckets = match self.capacity() {
+            0 => return Memory::default(),
+            n if n < 8 => (n + 1).next_power_of_two(),
+            n => (n * 8 / 7).next_power_of_two(),
+        };
+        Memory {
+            nodes: buckets + 16,
+            keys: sum_with_heap(self.keys()),
+            values: sum_with_heap(self.values()),
+            overhead: (buckets - self.len()) * (size_of::<K>() + size_of::<V>()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Art, BLinkTree, BPlusTree, ConcurrentHashMap, CuckooMap, PersistentMap};
+    use crate::{RobinHoodMap, SkipList};
+
+    #[test]
+    fn heap_size() {
+        assert_eq!(size_with_heap(&7u64), 8);
+        assert_eq!(String::with_capacity(10).heap_size(), 10);
+        let v: Vec<Option<Vec<u8>>> = vec![Some(vec![0; 4]), None];
+        assert_eq!(v.heap_size(), 2 * size_of::<Option<Vec<u8>>>() + 4);
+        let s: Arc<str> = Arc::from("abc");
+        assert_eq!(s.heap_size(), 2 * size_of::<usize>() + 3);
+    }
+
+    /// Entries of 8-byte keys and values owning 10 bytes each, counted the
+    /// same by every map.
+    fn check(memory: Memory, len: usize) {
+        assert_eq!(memory.keys, len * 8, "{memory:?}");
+        assert_eq!(
+            memory.values,
+            len * (size_of::<Vec<u8>>() + 10),
+            "{memory:?}"
+        );
+        assert!(memory.nodes > 0, "{memory:?}");
+    }
+
+    fn value(i: u64) -> Vec<u8> {
+        vec![i as u8; 10]
+    }
+
+    #[test]
+    fn maps() {
+        let n = 5000;
+        let entries = || (0..n).map(|i| (i * 7919 % n, value(i)));
+
+        check(
+            entries().collect::<BTreeMap<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<HashMap<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<SkipList<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<BPlusTree<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<PersistentMap<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<RobinHoodMap<_, _>>().memory_usage(),
+            n as usize,
+        );
+        check(
+            entries().collect::<CuckooMap<_, _>>().memory_usage(),
+            n as usize,
+        );
+
+        let blink = BLinkTree::new();
+        let chm = ConcurrentHashMap::new();
+        for (k, v) in entries() {
+            blink.insert(k, v.clone());
+            chm.insert(k, v);
+        }
+        check(blink.memory_usage(), n as usize);
+        check(chm.memory_usage(), n as usize);
+
+        let mut art = Art::new();
+        for (k, v) in entries() {
+            art.insert(&k.to_be_bytes(), v);
+        }
+        let memory = art.memory_usage();
+        // Boxed byte strings of 8 bytes.
+        assert_eq!(memory.keys, n as usize * (size_of::<Box<[u8]>>() + 8));
+        assert_eq!(memory.values, n as usize * (size_of::<Vec<u8>>() + 10));
+    }
+
+    #[test]
+    fn removes_free_memory() {
+        let mut list: SkipList<u64, Vec<u8>> = (0..1000).map(|i| (i, value(i))).collect();
+        let full = list.memory_usage();
+        for i in 0..900 {
+            list.remove(&i);
+        }
+        let memory = list.memory_usage();
+        check(memory, 100);
+        assert!(memory.nodes < full.nodes);
+        // Freed nodes wait in the arena of the list for reuse.
+        assert!(memory.overhead > full.overhead);
+    }
+}
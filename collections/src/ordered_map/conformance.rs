@@ -0,0 +1,194 @@
+//! Checks of an `OrderedMap` against `BTreeMap`, for the tests of each map.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::ops::Bound;
+
+use super::OrderedMap;
+
+/// Runs every check on maps made by `new`, with keys made from numbers by
+/// `key`, which must give different keys for different numbers. Panics on
+/// the first difference from `BTreeMap`.
+pub fn check<M, Q, F>(new: impl Fn() -> M, key: F)
+where
+    M: OrderedMap<Q, Value = u64>,
+    M::Key: Ord + Clone + Debug,
+    Q: Ord + Debug + ?Sized,
+    F: Fn(u64) -> M::Key,
+{
+    empty(&new(), &key);
+    sequential(new(), &key);
+    differential(new(), &key, 1, 20000, 500);
+    differential(new(), &key, 2, 20000, 20000);
+}
+
+/// A new map has no entries.
+pub fn empty<M, Q, F>(map: &M, key: &F)
+where
+    M: OrderedMap<Q, Value = u64>,
+    Q: Ord + Debug + ?Sized,
+    F: Fn(u64) -> M::Key,
+{
+    let k = key(0);
+    assert_eq!(map.len(), 0);
+    assert!(map.is_empty());
+    assert_eq!(map.get(k.borrow()), None);
+    assert!(!map.contains_key(k.borrow()));
+    assert_eq!(map.first(), None);
+    assert_eq!(map.last(), None);
+    assert_eq!(map.range(Bound::Unbounded, Bound::Unbounded).count(), 0);
+}
+
+/// Inserts keys in order and in reverse, then removes every other one,
+/// which makes ordered structures split and merge along one edge.
+pub fn sequential<M, Q, F>(mut map: M, key: &F)
+where
+    M: OrderedMap<Q, Value = u64>,
+    M::Key: Ord + Clone + Debug,
+    Q: Ord + Debug + ?Sized,
+    F: Fn(u64) -> M::Key,
+{
+    let mut model = BTreeMap::new();
+    let mut keys: Vec<M::Key> = (0..2000).map(key).collect();
+    keys.sort();
+    for (i, k) in keys.iter().enumerate().take(1000) {
+        assert_eq!(map.insert(k.clone(), i as u64), None);
+        model.insert(k.clone(), i as u64);
+    }
+    for (i, k) in keys.iter().enumerate().skip(1000).rev() {
+        assert_eq!(map.insert(k.clone(), i as u64), None);
+        model.insert(k.clone(), i as u64);
+    }
+    assert_same(&map, &model);
+    for k in keys.iter().step_by(2) {
+        assert_eq!(map.remove(k.borrow()), model.remove(k.borrow()));
+    }
+    assert_same(&map, &model);
+    for k in keys.iter().rev() {
+        assert_eq!(map.remove(k.borrow()), model.remove(k.borrow()));
+    }
+    assert_same(&map, &model);
+}
+
+/// Runs `ops` random operations on keys made from numbers below `keys`,
+/// comparing every result with `BTreeMap`.
+pub fn differential<M, Q, F>(mut map: M, key: &F, seed: u64, ops: usize, keys: u64)
+where
+    M: OrderedMap<Q, Value = u64>,
+    M::Key: Ord + Clone + Debug,
+    Q: Ord + Debug + ?Sized,
+    F: Fn(u64) -> M::Key,
+{
+    let mut model: BTreeMap<M::Key, u64> = BTreeMap::new();
+    let mut x = seed;
+    let mut next = || {
+        x = x
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        x >> 16
+    };
+    for op in 0..ops {
+        let r = next();
+        let k = key(r % keys);
+        match (r >> 32) % 8 {
+            0 | 1 => assert_eq!(
+                map.remove(k.borrow()),
+                model.remove(k.borrow()),
+                "remove {k:?}"
+            ),
+            2 => assert_eq!(map.get(k.borrow()), model.get(k.borrow()), "get {k:?}"),
+            3 => assert_eq!(
+                map.contains_key(k.borrow()),
+                model.contains_key(k.borrow()),
+                "contains_key {k:?}"
+            ),
+            4 => {
+                let expected = model.get_mut(k.borrow()).map(|v| {
+                    *v += 1;
+                    *v
+                });
+                let actual = map.get_mut(k.borrow()).map(|v| {
+                    *v += 1;
+                    *v
+                });
+                assert_eq!(actual, expected, "get_mut {k:?}");
+            }
+            _ => assert_eq!(
+                map.insert(k.clone(), r),
+                model.insert(k.clone(), r),
+                "insert {k:?}"
+            ),
+        }
+        if op % 64 == 0 {
+            assert_eq!(map.len(), model.len());
+            assert_eq!(map.first(), first(&model));
+            assert_eq!(map.last(), last(&model));
+            let a = key(next() % keys);
+            let b = key(next() % keys);
+            let bound = |k: &M::Key, kind: u64| match kind % 3 {
+                0 => Bound::Included(k.clone()),
+                1 => Bound::Excluded(k.clone()),
+                _ => Bound::Unbounded,
+            };
+            let (a, b) = if a <= b { (a, b) } else { (b, a) };
+            let start = bound(&a, next());
+            let end = bound(&b, next());
+            assert_range(&map, &model, start, end);
+            // A start after the end gives nothing.
+            if a < b {
+                assert_eq!(
+                    map.range(Bound::Included(b.borrow()), Bound::Included(a.borrow()))
+                        .count(),
+                    0
+                );
+            }
+        }
+    }
+    assert_same(&map, &model);
+}
+
+fn first<K: Ord + Borrow<Q>, Q: ?Sized>(model: &BTreeMap<K, u64>) -> Option<(&Q, &u64)> {
+    model.first_key_value().map(|(k, v)| (k.borrow(), v))
+}
+
+fn last<K: Ord + Borrow<Q>, Q: ?Sized>(model: &BTreeMap<K, u64>) -> Option<(&Q, &u64)> {
+    model.last_key_value().map(|(k, v)| (k.borrow(), v))
+}
+
+fn assert_range<M, Q>(
+    map: &M,
+    model: &BTreeMap<M::Key, u64>,
+    start: Bound<M::Key>,
+    end: Bound<M::Key>,
+) where
+    M: OrderedMap<Q, Value = u64>,
+    M::Key: Ord + Debug,
+    Q: Ord + Debug + ?Sized,
+{
+    let start = start.as_ref().map(|k| k.borrow());
+    let end = end.as_ref().map(|k| k.borrow());
+    // `BTreeMap::range` panics on a start equal to an excluded end.
+    let expected: Vec<(&Q, &u64)> = match (start, end) {
+        (Bound::Excluded(s), Bound::Excluded(e)) if s == e => Vec::new(),
+        _ => model
+            .range::<Q, _>((start, end))
+            .map(|(k, v)| (k.borrow(), v))
+            .collect(),
+    };
+    let actual: Vec<(&Q, &u64)> = map.range(start, end).collect();
+    assert_eq!(actual, expected, "range {start:?}..{end:?}");
+}
+
+fn assert_same<M, Q>(map: &M, model: &BTreeMap<M::Key, u64>)
+where
+    M: OrderedMap<Q, Value = u64>,
+    M::Key: Ord + Debug,
+    Q: Ord + Debug + ?Sized,
+{
+    assert_eq!(map.len(), model.len());
+    assert_eq!(map.is_empty(), model.is_empty());
+    assert_eq!(map.first(), first(model));
+    assert_eq!(map.last(), last(model));
+    assert_range(map, model, Bound::Unbounded, Bound::Unbounded);
+}
@@ -0,0 +1,254 @@
+//! Ordered-map behavior shared by the single-threaded maps of this crate.
+//!
+//! `OrderedMap<Q>` is implemented by a map for each borrowed form `Q` of its
+//! keys it can be searched by, the way `BTreeMap<String, V>` can be
+//! searched by `&str`. New maps get checked against `BTreeMap` with
+//! `conformance::check`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::{Art, BPlusTree, PersistentMap, SkipList};
+
+pub mod conformance;
+
+/// Entries in key order, as returned by `OrderedMap::range`.
+pub type Entries<'a, Q, V> = Box<dyn Iterator<Item = (&'a Q, &'a V)> + 'a>;
+
+pub trait OrderedMap<Q: Ord + ?Sized> {
+    /// Owned key, taken by `insert`.
+    type Key: Borrow<Q>;
+    type Value;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &Q) -> Option<&Self::Value>;
+
+    fn get_mut(&mut self, key: &Q) -> Option<&mut Self::Value>;
+
+    fn contains_key(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    fn insert(&mut self, key: Self::Key, value: Self::Value) -> Option<Self::Value>;
+
+    fn remove(&mut self, key: &Q) -> Option<Self::Value>;
+
+    fn first(&self) -> Option<(&Q, &Self::Value)>;
+
+    fn last(&self) -> Option<(&Q, &Self::Value)>;
+
+    /// Entries with keys between `start` and `end`. Unlike
+    /// `BTreeMap::range`, a start after the end gives no entries rather than
+    /// a panic.
+    fn range<'a>(&'a self, start: Bound<&'a Q>, end: Bound<&'a Q>) -> Entries<'a, Q, Self::Value>;
+}
+
+/// Whether `start` is after `end`, leaving no keys between them.
+fn is_empty_range<Q: Ord + ?Sized>(start: Bound<&Q>, end: Bound<&Q>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+/// Implements `OrderedMap<Q>` for a map of keys `K: Borrow<Q>` with the
+/// inherent methods of `BTreeMap`, given the names of the methods returning
+/// the first and last entries and any further bounds on `K` and `V`.
+macro_rules! ordered_map {
+    ($map:ident, $first:ident, $last:ident $(, $param:ident: $bound:path)*) => {
+        impl<K, V, Q> OrderedMap<Q> for $map<K, V>
+        where
+            K: Ord + Borrow<Q>,
+            Q: Ord + ?Sized,
+            $($param: $bound,)*
+        {
+            type Key = K;
+            type Value = V;
+
+            fn len(&self) -> usize {
+                $map::len(self)
+            }
+
+            fn get(&self, key: &Q) -> Option<&V> {
+                $map::get(self, key)
+            }
+
+            fn get_mut(&mut self, key: &Q) -> Option<&mut V> {
+                $map::get_mut(self, key)
+            }
+
+            fn insert(&mut self, key: K, value: V) -> Option<V> {
+                $map::insert(self, key, value)
+            }
+
+            fn remove(&mut self, key: &Q) -> Option<V> {
+                $map::remove(self, key)
+            }
+
+            fn first(&self) -> Option<(&Q, &V)> {
+                $map::$first(self).map(|(k, v)| (k.borrow(), v))
+            }
+
+            fn last(&self) -> Option<(&Q, &V)> {
+                $map::$last(self).map(|(k, v)| (k.borrow(), v))
+            }
+
+            fn range<'a>(&'a self, start: Bound<&'a Q>, end: Bound<&'a Q>) -> Entries<'a, Q, V> {
+                if is_empty_range(start, end) {
+                    return Box::new(std::iter::empty());
+                }
+                Box::new($map::range(self, (start, end)).map(|(k, v)| (k.borrow(), v)))
+            }
+        }
+    };
+}
+
+ordered_map!(BTreeMap, first_key_value, last_key_value);
+ordered_map!(SkipList, first, last);
+ordered_map!(BPlusTree, first, last, K: Clone);
+ordered_map!(PersistentMap, first, last, K: Clone, V: Clone);
+
+impl<V> OrderedMap<[u8]> for Art<V> {
+    type Key = Vec<u8>;
+    type Value = V;
+
+    fn len(&self) -> usize {
+        Art::len(self)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&V> {
+        Art::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        Art::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        Art::insert(self, &key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<V> {
+        Art::remove(self, key)
+    }
+
+    fn first(&self) -> Option<(&[u8], &V)> {
+        Art::first(self)
+    }
+
+    fn last(&self) -> Option<(&[u8], &V)> {
+        Art::last(self)
+    }
+
+    fn range<'a>(&'a self, start: Bound<&'a [u8]>, end: Bound<&'a [u8]>) -> Entries<'a, [u8], V> {
+        if is_empty_range(start, end) {
+            return Box::new(std::iter::empty());
+        }
+        let iter = match start {
+            Bound::Included(start) | Bound::Excluded(start) => self.range_from(start),
+            Bound::Unbounded => self.iter(),
+        };
+        Box::new(
+            iter.skip_while(move |(k, _)| start == Bound::Excluded(*k))
+                .take_while(move |(k, _)| match end {
+                    Bound::Included(end) => *k <= end,
+                    Bound::Excluded(end) => *k < end,
+                    Bound::Unbounded => true,
+                }),
+        )
+    }
+}
+
+/// Keys inserted as strings, so that an `Art` stands in for the other maps
+/// of string keys. Reading a key that is not UTF-8, which only inserts of
+/// bytes can make, panics.
+impl<V> OrderedMap<str> for Art<V> {
+    type Key = String;
+    type Value = V;
+
+    fn len(&self) -> usize {
+        Art::len(self)
+    }
+
+    fn get(&self, key: &str) -> Option<&V> {
+        Art::get(self, key.as_bytes())
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        Art::get_mut(self, key.as_bytes())
+    }
+
+    fn insert(&mut self, key: String, value: V) -> Option<V> {
+        Art::insert(self, key.as_bytes(), value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        Art::remove(self, key.as_bytes())
+    }
+
+    fn first(&self) -> Option<(&str, &V)> {
+        Art::first(self).map(utf8)
+    }
+
+    fn last(&self) -> Option<(&str, &V)> {
+        Art::last(self).map(utf8)
+    }
+
+    fn range<'a>(&'a self, start: Bound<&'a str>, end: Bound<&'a str>) -> Entries<'a, str, V> {
+        let start = start.map(str::as_bytes);
+        let end = end.map(str::as_bytes);
+        Box::new(OrderedMap::<[u8]>::range(self, start, end).map(utf8))
+    }
+}
+
+fn utf8<'a, V>((key, value): (&'a [u8], &'a V)) -> (&'a str, &'a V) {
+    (std::str::from_utf8(key).expect("key is not UTF-8"), value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn btreemap() {
+        conformance::check(BTreeMap::new, |i| i);
+        conformance::check::<BTreeMap<String, u64>, str, _>(BTreeMap::new, |i| i.to_string());
+    }
+
+    #[test]
+    fn skip_list() {
+        conformance::check(SkipList::new, |i| i);
+    }
+
+    #[test]
+    fn bplus_tree() {
+        for fanout in [4, 5, 64] {
+            conformance::check(|| BPlusTree::with_fanout(fanout), |i| i);
+        }
+    }
+
+    #[test]
+    fn persistent_map() {
+        conformance::check(PersistentMap::new, |i| i);
+        conformance::check::<PersistentMap<String, u64>, str, _>(PersistentMap::new, |i| {
+            format!("user{i}")
+        });
+    }
+
+    #[test]
+    fn art() {
+        // Hex keys of different lengths are often prefixes of each other.
+        conformance::check::<Art<u64>, [u8], _>(Art::new, |i| format!("{i:x}").into_bytes());
+        conformance::check::<Art<u64>, str, _>(Art::new, |i| format!("user{i}"));
+    }
+}
@@ -0,0 +1,867 @@
+//! Persistent ordered map: versions share the nodes they have in common.
+//!
+//! `PersistentMap` is a B+tree whose nodes are reference counted. Cloning a
+//! map copies only the root pointer; changing a map copies the nodes on the
+//! path to the changed entry that other versions still hold and keeps the
+//! rest shared. `VersionCell` publishes the latest version to readers that
+//! load it without locking.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+
+use crate::memory::{spare, sum_with_heap, HeapSize, Memory, MemoryUsage};
+use crate::sync::atomic::{AtomicU64, Ordering};
+
+/// Maximum entries of a leaf and children of an internal node. Every change
+/// copies up to one node per level, so this is lower than for `BPlusTree`.
+const FANOUT: usize = 16;
+const MIN_SIZE: usize = FANOUT / 2;
+
+#[derive(Clone)]
+enum Kind<K, V> {
+    /// Child `i` holds the keys before `keys[i]` and at or after
+    /// `keys[i - 1]`.
+    Internal(Vec<Arc<Node<K, V>>>),
+    Leaf(Vec<V>),
+}
+
+#[derive(Clone)]
+struct Node<K, V> {
+    keys: Vec<K>,
+    kind: Kind<K, V>,
+}
+
+type Children<K, V> = [Arc<Node<K, V>>];
+
+/// Separator key and right half of a node that split.
+type Split<K, V> = (K, Arc<Node<K, V>>);
+
+impl<K, V> Node<K, V> {
+    fn leaf() -> Self {
+        Node {
+            keys: Vec::new(),
+            kind: Kind::Leaf(Vec::new()),
+        }
+    }
+
+    /// Entries of a leaf or children of an internal node.
+    fn size(&self) -> usize {
+        match &self.kind {
+            Kind::Internal(children) => children.len(),
+            Kind::Leaf(values) => values.len(),
+        }
+    }
+}
+
+/// Index of the child of an internal node with `keys` that covers `key`.
+fn child_index<K, Q>(keys: &[K], key: &Q) -> usize
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    keys.partition_point(|k| k.borrow() <= key)
+}
+
+/// Ordered map on a B+tree of shared nodes. `clone` is `O(1)`, and changes
+/// to one clone never show in another: `insert`, `remove` and `get_mut`
+/// copy the nodes on their path that are shared before changing them, which
+/// takes `K: Clone` and `V: Clone`. `update` and `without` return the
+/// changed map as a new version and leave `self` as it was.
+///
+/// Nodes hold at most 16 entries or children and, except for the root, at
+/// least 8.
+pub struct PersistentMap<K, V> {
+    root: Option<Arc<Node<K, V>>>,
+    len: usize,
+}
+
+impl<K, V> Clone for PersistentMap<K, V> {
+    fn clone(&self) -> Self {
+        PersistentMap {
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K, V> Default for PersistentMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> PersistentMap<K, V> {
+    pub fn new() -> Self {
+        PersistentMap { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Whether both maps are the same version, sharing all their nodes.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        let mut height = 0;
+        let mut node = self.root.as_deref();
+        while let Some(n) = node {
+            height += 1;
+            node = match &n.kind {
+                Kind::Internal(children) => Some(&children[0]),
+                Kind::Leaf(_) => None,
+            };
+        }
+        height
+    }
+}
+
+impl<K: Ord, V> PersistentMap<K, V> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.as_deref()?;
+        loop {
+            match &node.kind {
+                Kind::Internal(children) => node = &children[child_index(&node.keys, key)],
+                Kind::Leaf(values) => {
+                    let i = node.keys.binary_search_by(|k| k.borrow().cmp(key)).ok()?;
+                    return Some(&values[i]);
+                }
+            }
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        loop {
+            match &node.kind {
+                Kind::Internal(children) => node = &children[0],
+                Kind::Leaf(values) => return Some((node.keys.first()?, values.first()?)),
+            }
+        }
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        loop {
+            match &node.kind {
+                Kind::Internal(children) => node = children.last().unwrap(),
+                Kind::Leaf(values) => return Some((node.keys.last()?, values.last()?)),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::seek::<K>(self.root.as_deref(), Bound::Unbounded)
+    }
+
+    /// Iterates over the entries with keys in `range`, in key order.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V, Q, R>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Range {
+            iter: Iter::seek(self.root.as_deref(), range.start_bound()),
+            range,
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        // Look first so that a missing key copies nothing.
+        self.get(key)?;
+        let mut node = Arc::make_mut(self.root.as_mut().unwrap());
+        loop {
+            let Node { keys, kind } = node;
+            match kind {
+                Kind::Internal(children) => {
+                    node = Arc::make_mut(&mut children[child_index(keys, key)]);
+                }
+                Kind::Leaf(values) => {
+                    let i = keys.binary_search_by(|k| k.borrow().cmp(key)).ok()?;
+                    return Some(&mut values[i]);
+                }
+            }
+        }
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let root = self.root.get_or_insert_with(|| Arc::new(Node::leaf()));
+        let (old, split) = insert(Arc::make_mut(root), key, value);
+        if let Some((separator, right)) = split {
+            let left = self.root.take().unwrap();
+            self.root = Some(Arc::new(Node {
+                keys: vec![separator],
+                kind: Kind::Internal(vec![left, right]),
+            }));
+        }
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        // Look first so that a missing key copies nothing.
+        self.get(key)?;
+        let root = self.root.as_mut().unwrap();
+        let value = remove(Arc::make_mut(root), key);
+        self.len -= 1;
+        let only_child = match &root.kind {
+            Kind::Internal(children) if children.len() == 1 => Some(children[0].clone()),
+            _ => None,
+        };
+        if let Some(child) = only_child {
+            *root = child;
+        }
+        if self.len == 0 {
+            self.root = None;
+        }
+        Some(value)
+    }
+
+    /// New version with `value` under `key`.
+    #[must_use]
+    pub fn update(&self, key: K, value: V) -> Self {
+        let mut map = self.clone();
+        map.insert(key, value);
+        map
+    }
+
+    /// New version without `key`.
+    #[must_use]
+    pub fn without<Q>(&self, key: &Q) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut map = self.clone();
+        map.remove(key);
+        map
+    }
+}
+
+/// Inserts into the subtree of `node`, returning the replaced value and the
+/// split of `node` if it grew too large.
+fn insert<K: Ord + Clone, V: Clone>(
+    node: &mut Node<K, V>,
+    key: K,
+    value: V,
+) -> (Option<V>, Option<Split<K, V>>) {
+    let Node { keys, kind } = node;
+    match kind {
+        Kind::Leaf(values) => match keys.binary_search(&key) {
+            Ok(i) => return (Some(std::mem::replace(&mut values[i], value)), None),
+            Err(i) => {
+                keys.insert(i, key);
+                values.insert(i, value);
+            }
+        },
+        Kind::Internal(children) => {
+            let i = child_index(keys, &key);
+            match insert(Arc::make_mut(&mut children[i]), key, value) {
+                (old, None) => return (old, None),
+                (_, Some((separator, right))) => {
+                    keys.insert(i, separator);
+                    children.insert(i + 1, right);
+                }
+            }
+        }
+    }
+    if node.size() <= FANOUT {
+        return (None, None);
+    }
+    (None, Some(split(node)))
+}
+
+/// Moves the upper half of `node` to a new node, returning the separator
+/// between the halves and the new node.
+fn split<K: Clone, V>(node: &mut Node<K, V>) -> Split<K, V> {
+    let mid = node.size() / 2;
+    let (separator, right) = match &mut node.kind {
+        Kind::Leaf(values) => {
+            let keys = node.keys.split_off(mid);
+            let right = Node {
+                keys,
+                kind: Kind::Leaf(values.split_off(mid)),
+            };
+            (right.keys[0].clone(), right)
+        }
+        Kind::Internal(children) => {
+            let mut keys = node.keys.split_off(mid - 1);
+            let separator = keys.remove(0);
+            let right = Node {
+                keys,
+                kind: Kind::Internal(children.split_off(mid)),
+            };
+            (separator, right)
+        }
+    };
+    (separator, Arc::new(right))
+}
+
+/// Removes `key`, which must be in the subtree of `node`.
+fn remove<K, V, Q>(node: &mut Node<K, V>, key: &Q) -> V
+where
+    K: Ord + Clone + Borrow<Q>,
+    V: Clone,
+    Q: Ord + ?Sized,
+{
+    let Node { keys, kind } = node;
+    match kind {
+        Kind::Leaf(values) => {
+            let i = keys.binary_search_by(|k| k.borrow().cmp(key)).unwrap();
+            keys.remove(i);
+            values.remove(i)
+        }
+        Kind::Internal(children) => {
+            let i = child_index(keys, key);
+            let value = remove(Arc::make_mut(&mut children[i]), key);
+            if children[i].size() < MIN_SIZE {
+                rebalance(keys, children, i);
+            }
+            value
+        }
+    }
+}
+
+/// Refills child `i` of an internal node, which has fallen below the
+/// minimum size, by merging it with a sibling or taking one entry or child
+/// from it.
+fn rebalance<K: Clone, V: Clone>(keys: &mut Vec<K>, children: &mut Vec<Arc<Node<K, V>>>, i: usize) {
+    let i = if i + 1 < children.len() { i } else { i - 1 };
+    let (left, right) = children.split_at_mut(i + 1);
+    let left = Arc::make_mut(&mut left[i]);
+    let right = Arc::make_mut(&mut right[0]);
+    if left.size() + right.size() <= FANOUT {
+        match (&mut left.kind, &mut right.kind) {
+            (Kind::Leaf(lv), Kind::Leaf(rv)) => lv.append(rv),
+            (Kind::Internal(lc), Kind::Internal(rc)) => {
+                left.keys.push(keys[i].clone());
+                lc.append(rc);
+            }
+            _ => unreachable!(),
+        }
+        left.keys.append(&mut right.keys);
+        keys.remove(i);
+        children.remove(i + 1);
+    } else if left.size() < right.size() {
+        match (&mut left.kind, &mut right.kind) {
+            (Kind::Leaf(lv), Kind::Leaf(rv)) => {
+                left.keys.push(right.keys.remove(0));
+                lv.push(rv.remove(0));
+                keys[i] = right.keys[0].clone();
+            }
+            (Kind::Internal(lc), Kind::Internal(rc)) => {
+                left.keys
+                    .push(std::mem::replace(&mut keys[i], right.keys.remove(0)));
+                lc.push(rc.remove(0));
+            }
+            _ => unreachable!(),
+        }
+    } else {
+        match (&mut left.kind, &mut right.kind) {
+            (Kind::Leaf(lv), Kind::Leaf(rv)) => {
+                right.keys.insert(0, left.keys.pop().unwrap());
+                rv.insert(0, lv.pop().unwrap());
+                keys[i] = right.keys[0].clone();
+            }
+            (Kind::Internal(lc), Kind::Internal(rc)) => {
+                let separator = std::mem::replace(&mut keys[i], left.keys.pop().unwrap());
+                right.keys.insert(0, separator);
+                rc.insert(0, lc.pop().unwrap());
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    /// Children of the internal nodes above the current leaf, with the
+    /// index of the next child to visit.
+    stack: Vec<(&'a Children<K, V>, usize)>,
+    entries: std::iter::Zip<std::slice::Iter<'a, K>, std::slice::Iter<'a, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    /// Iterator starting at the first key of the tree under `root` within
+    /// `start`.
+    fn seek<Q>(root: Option<&'a Node<K, V>>, start: Bound<&Q>) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            entries: [].iter().zip([].iter()),
+        };
+        let Some(mut node) = root else {
+            return iter;
+        };
+        loop {
+            match &node.kind {
+                Kind::Internal(children) => {
+                    let i = match start {
+                        Bound::Included(key) | Bound::Excluded(key) => child_index(&node.keys, key),
+                        Bound::Unbounded => 0,
+                    };
+                    iter.stack.push((children, i + 1));
+                    node = &children[i];
+                }
+                Kind::Leaf(values) => {
+                    let i = match start {
+                        Bound::Included(key) => node.keys.partition_point(|k| k.borrow() < key),
+                        Bound::Excluded(key) => node.keys.partition_point(|k| k.borrow() <= key),
+                        Bound::Unbounded => 0,
+                    };
+                    iter.entries = node.keys[i..].iter().zip(values[i..].iter());
+                    return iter;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(entry);
+            }
+            // Move to the leftmost leaf of the next subtree.
+            let mut node = loop {
+                let (children, i) = self.stack.last_mut()?;
+                let children: &'a Children<K, V> = children;
+                if *i < children.len() {
+                    *i += 1;
+                    break &children[*i - 1];
+                }
+                self.stack.pop();
+            };
+            loop {
+                match &node.kind {
+                    Kind::Internal(children) => {
+                        self.stack.push((children, 1));
+                        node = &children[0];
+                    }
+                    Kind::Leaf(values) => {
+                        self.entries = node.keys.iter().zip(values.iter());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Range<'a, K, V, Q: ?Sized, R> {
+    iter: Iter<'a, K, V>,
+    range: R,
+    _key: PhantomData<fn(&Q)>,
+}
+
+impl<'a, K, V, Q, R> Iterator for Range<'a, K, V, Q, R>
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.iter.next()?;
+        let past_end = match self.range.end_bound() {
+            Bound::Included(end) => k.borrow() > end,
+            Bound::Excluded(end) => k.borrow() >= end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.iter.stack.clear();
+            self.iter.entries = [].iter().zip([].iter());
+            return None;
+        }
+        Some((k, v))
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a PersistentMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> FromIterator<(K, V)> for PersistentMap<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut map = PersistentMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Extend<(K, V)> for PersistentMap<K, V> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for PersistentMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Latest version of a `PersistentMap` shared between threads. Readers load
+/// it without locking and keep the version they loaded however the map
+/// changes after. Writers change a clone and publish it with a
+/// compare-and-swap, starting over if another writer published first.
+pub struct VersionCell<K, V> {
+    current: Atomic<PersistentMap<K, V>>,
+    /// Changes redone because another writer published first.
+    retries: AtomicU64,
+}
+
+impl<K, V> VersionCell<K, V> {
+    pub fn new(map: PersistentMap<K, V>) -> Self {
+        VersionCell {
+            current: Atomic::new(map),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    /// Calls `f` on the latest version.
+    pub fn read<R>(&self, f: impl FnOnce(&PersistentMap<K, V>) -> R) -> R {
+        let guard = epoch::pin();
+        let map = self.current.load(Ordering::Acquire, &guard);
+        // Versions are only freed after every thread pinned when they were
+        // replaced has unpinned.
+        f(unsafe { map.deref() })
+    }
+
+    /// The latest version, which stays as it is while writers go on.
+    pub fn snapshot(&self) -> PersistentMap<K, V> {
+        self.read(PersistentMap::clone)
+    }
+
+    /// Publishes `map` as the latest version.
+    pub fn store(&self, map: PersistentMap<K, V>) {
+        let guard = epoch::pin();
+        let old = self.current.swap(Owned::new(map), Ordering::AcqRel, &guard);
+        unsafe { guard.defer_destroy(old) };
+    }
+
+    /// Calls `f` on a clone of the latest version and publishes the clone,
+    /// calling `f` again on a clone of the newer version if another writer
+    /// published one in between.
+    pub fn update<R>(&self, mut f: impl FnMut(&mut PersistentMap<K, V>) -> R) -> R {
+        let guard = epoch::pin();
+        loop {
+            let current = self.current.load(Ordering::Acquire, &guard);
+            let mut map = unsafe { current.deref() }.clone();
+            let ret = f(&mut map);
+            match self.current.compare_exchange(
+                current,
+                Owned::new(map),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                &guard,
+            ) {
+                Ok(_) => {
+                    unsafe { guard.defer_destroy(current) };
+                    return ret;
+                }
+                Err(_) => {
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+}
+
+impl<K, V> Default for VersionCell<K, V> {
+    fn default() -> Self {
+        Self::new(PersistentMap::new())
+    }
+}
+
+impl<K, V> Drop for VersionCell<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let guard = epoch::unprotected();
+            drop(self.current.load(Ordering::Relaxed, guard).into_owned());
+        }
+    }
+}
+
+/// Counts every node of this version, including the nodes it shares with
+/// other versions.
+impl<K: HeapSize, V: HeapSize> MemoryUsage for PersistentMap<K, V> {
+    fn memory_usage(&self) -> Memory {
+        fn walk<K: HeapSize, V: HeapSize>(node: &Node<K, V>, memory: &mut Memory) {
+            // With the reference counts of the `Arc`.
+            memory.nodes += 2 * size_of::<usize>() + size_of::<Node<K, V>>();
+            memory.overhead += spare(&node.keys);
+            match &node.kind {
+                Kind::Internal(children) => {
+                    memory.nodes += children.capacity() * size_of::<Arc<Node<K, V>>>();
+                    memory.overhead += sum_with_heap(&node.keys);
+                    for child in children {
+                        walk(child, memory);
+                    }
+                }
+                Kind::Leaf(values) => {
+                    memory.keys += sum_with_heap(&node.keys);
+                    memory.values += sum_with_heap(values);
+                    memory.overhead += spare(values);
+                }
+            }
+        }
+        let mut memory = Memory::default();
+        if let Some(root) = &self.root {
+            walk(root, &mut memory);
+        }
+        memory
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> MemoryUsage for VersionCell<K, V> {
+    fn memory_usage(&self) -> Memory {
+        self.read(|map| map.memory_usage())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = PersistentMap::new();
+        assert_eq!(map.insert(2, "b"), None);
+        assert_eq!(map.insert(1, "a"), None);
+        assert_eq!(map.insert(2, "B"), Some("b"));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&2), Some(&"B"));
+        assert_eq!(map.get(&3), None);
+        assert_eq!(map.first(), Some((&1, &"a")));
+        assert_eq!(map.last(), Some((&2, &"B")));
+        assert_eq!(map.remove(&1), Some("a"));
+        assert_eq!(map.remove(&1), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.remove(&2), Some("B"));
+        assert!(map.is_empty());
+        assert_eq!(map.height(), 0);
+    }
+
+    #[test]
+    fn versions_are_independent() {
+        let mut map: PersistentMap<u64, u64> = (0..10000).map(|i| (i, i)).collect();
+        let mut versions = vec![(map.clone(), map.iter().map(|(k, v)| (*k, *v)).collect())];
+        let mut x: u64 = 1;
+        for round in 0..20 {
+            for _ in 0..500 {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let key = (x >> 33) % 12000;
+                if x & 1 == 0 {
+                    map.remove(&key);
+                } else {
+                    map.insert(key, round);
+                }
+            }
+            let expected: BTreeMap<u64, u64> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            versions.push((map.clone(), expected));
+        }
+        for (version, expected) in &versions {
+            assert_eq!(version.len(), expected.len());
+            assert!(version.iter().eq(expected.iter()));
+        }
+    }
+
+    #[test]
+    fn update_shares_nodes() {
+        let old: PersistentMap<u64, u64> = (0..10000).map(|i| (i, i)).collect();
+        let new = old.update(5000, 0).without(&0);
+        assert_eq!(old.get(&5000), Some(&5000));
+        assert_eq!(new.get(&5000), Some(&0));
+        assert!(old.contains_key(&0) && !new.contains_key(&0));
+        assert!(!old.ptr_eq(&new));
+        // Only the leaves of the two changed keys were copied.
+        let leaves = |map: &PersistentMap<u64, u64>| {
+            let mut leaves = Vec::new();
+            let mut stack = vec![map.root.clone().unwrap()];
+            while let Some(node) = stack.pop() {
+                match &node.kind {
+                    Kind::Internal(children) => stack.extend(children.iter().cloned()),
+                    Kind::Leaf(_) => leaves.push(node.clone()),
+                }
+            }
+            leaves
+        };
+        let old_leaves = leaves(&old);
+        let copied = leaves(&new)
+            .iter()
+            .filter(|a| !old_leaves.iter().any(|b| Arc::ptr_eq(a, b)))
+            .count();
+        assert_eq!(copied, 2);
+    }
+
+    #[test]
+    fn range() {
+        let map: PersistentMap<u64, u64> = (0..1000).map(|i| (i * 2, i)).collect();
+        let keys: Vec<u64> = map.range(100..=110).map(|(k, _)| *k).collect();
+        assert_eq!(keys, [100, 102, 104, 106, 108, 110]);
+        let keys: Vec<u64> = map
+            .range((Bound::Excluded(1995), Bound::Unbounded))
+            .map(|(k, _)| *k)
+            .collect();
+        assert_eq!(keys, [1996, 1998]);
+        assert_eq!(map.range(2000..).count(), 0);
+    }
+
+    #[test]
+    fn version_cell() {
+        let cell = Arc::new(VersionCell::default());
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let cell = cell.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000u64 {
+                        cell.update(|map| map.insert(i * 4 + t, i));
+                    }
+                })
+            })
+            .collect();
+        // Every version a reader sees has each writer's keys without gaps.
+        for _ in 0..100 {
+            let snapshot = cell.snapshot();
+            for t in 0..4 {
+                let n = snapshot.range(0..).filter(|(k, _)| *k % 4 == t).count() as u64;
+                assert!((0..n).all(|i| snapshot.get(&(i * 4 + t)) == Some(&i)));
+            }
+        }
+        for w in writers {
+            w.join().unwrap();
+        }
+        assert_eq!(cell.read(|map| map.len()), 4000);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod model {
+    use super::*;
+    use crate::sync::model::{self, Tracked};
+    use crate::sync::thread;
+    use loom::sync::Arc as LoomArc;
+
+    #[test]
+    fn concurrent_updates() {
+        model::check(|| {
+            let cell = LoomArc::new(VersionCell::default());
+            let writer = {
+                let cell = cell.clone();
+                thread::spawn(move || cell.update(|map| map.insert(1, 1)))
+            };
+            assert_eq!(cell.update(|map| map.insert(2, 2)), None);
+            assert_eq!(writer.join().unwrap(), None);
+            let map = cell.snapshot();
+            assert_eq!(map.iter().collect::<Vec<_>>(), [(&1, &1), (&2, &2)]);
+        });
+    }
+
+    #[test]
+    fn readers_see_whole_versions() {
+        model::check(|| {
+            let cell = LoomArc::new(VersionCell::new([(0, 0)].into_iter().collect()));
+            let writer = {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    cell.update(|map| {
+                        map.insert(1, 1);
+                        map.remove(&0);
+                    })
+                })
+            };
+            let keys = cell.read(|map| map.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+            assert!(keys == [0] || keys == [1], "{keys:?}");
+            writer.join().unwrap();
+            assert_eq!(cell.read(|map| map.len()), 1);
+        });
+    }
+
+    #[test]
+    fn reclamation() {
+        model::check(|| {
+            let cell = LoomArc::new(VersionCell::new(
+                [(0, Tracked::new(0))].into_iter().collect(),
+            ));
+            let writer = {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    cell.update(|map| map.insert(1, Tracked::new(1)));
+                })
+            };
+            let old = cell.snapshot();
+            cell.update(|map| map.remove(&0));
+            assert_eq!(old.get(&0).map(|v| v.value), Some(0));
+            writer.join().unwrap();
+            cell.store(PersistentMap::new());
+        });
+    }
+}
@@ -1,15 +1,695 @@
-/*pub struct SkipList<K, V> {
+use std::alloc::Layout;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Bound, RangeBounds};
+use std::ptr::NonNull;
+
+use crate::arena::{Heap, NodeAlloc};
+use crate::memory::{spare, sum_with_heap, HeapSize, Memory, MemoryUsage};
+
+const MAX_LEVEL: usize = 32;
+const NIL: usize = usize::MAX;
+const HEAD: usize = 0;
+
+#[derive(Clone, Copy)]
+struct Link {
+    next: usize,
+    /// Distance in entries from this node to `next`, the rank of the head
+    /// being 0 and that of `NIL` one past the last entry.
+    span: usize,
+}
+
+struct Node<K, V> {
+    /// `None` for the head and for freed nodes.
+    entry: Option<(K, V)>,
+    /// `height` links, one per level, from the allocator of the list.
+    tower: NonNull<Link>,
+    height: usize,
+}
+
+/// Ordered map on a skip list. Nodes live in one arena and link to each
+/// other by index; removed nodes are recycled by later inserts. Each node
+/// gets a tower of height `h` with probability `2^-h`.
+///
+/// Links also record how many entries they skip, as Redis sorted sets do,
+/// so that `rank`, `select` and `remove_index_range` find entries by their
+/// index in key order in `O(log n)`.
+///
+/// The towers of links come from the allocator `A`, so that inserts can
+/// take them from an `Arena` rather than the global allocator.
+pub struct SkipList<K, V, A: NodeAlloc = Heap> {
+    nodes: Vec<Node<K, V>>,
+    alloc: A,
+    free: Vec<usize>,
+    /// Height of the tallest tower.
+    level: usize,
+    len: usize,
+    rng: u64,
+}
+
+// Towers are owned by their nodes like the boxes they stand for.
+unsafe impl<K: Send, V: Send, A: NodeAlloc> Send for SkipList<K, V, A> {}
+unsafe impl<K: Sync, V: Sync, A: NodeAlloc> Sync for SkipList<K, V, A> {}
+
+impl<K: Ord, V, A: NodeAlloc> Default for SkipList<K, V, A> {
+    fn default() -> Self {
+        Self::new_in(A::default())
+    }
+}
+
+impl<K: Ord, V> SkipList<K, V> {
+    pub fn new() -> Self {
+        Self::new_in(Heap)
+    }
+}
+
+impl<K, V, A: NodeAlloc> SkipList<K, V, A> {
+    fn links(&self, x: usize) -> &[Link] {
+        let node = &self.nodes[x];
+        unsafe { std::slice::from_raw_parts(node.tower.as_ptr(), node.height) }
+    }
+
+    fn links_mut(&mut self, x: usize) -> &mut [Link] {
+        let node = &mut self.nodes[x];
+        unsafe { std::slice::from_raw_parts_mut(node.tower.as_ptr(), node.height) }
+    }
+
+    fn tower(&self, height: usize, span: usize) -> NonNull<Link> {
+        let tower = self
+            .alloc
+            .alloc(Layout::array::<Link>(height).unwrap())
+            .cast();
+        for l in 0..height {
+            unsafe { tower.add(l).write(Link { next: NIL, span }) };
+        }
+        tower
+    }
+
+    /// Frees the tower of `node`, which must be one of `nodes`.
+    unsafe fn free_tower(&self, node: &Node<K, V>) {
+        if node.height > 0 {
+            let layout = Layout::array::<Link>(node.height).unwrap();
+            self.alloc.free(node.tower.cast(), layout);
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+}
+
+impl<K: Ord, V, A: NodeAlloc> SkipList<K, V, A> {
+    pub fn new_in(alloc: A) -> Self {
+        let mut list = SkipList {
+            nodes: Vec::new(),
+            alloc,
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            rng: 0x2545f4914f6cdd1d,
+        };
+        let tower = list.tower(MAX_LEVEL, 1);
+        list.nodes.push(Node {
+            entry: None,
+            tower,
+            height: MAX_LEVEL,
+        });
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new_in(A::default());
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        std::cmp::min(self.rng.trailing_ones() as usize + 1, MAX_LEVEL)
+    }
+
+    fn key(&self, i: usize) -> &K {
+        &self.nodes[i].entry.as_ref().unwrap().0
+    }
+
+    /// Last node on each level whose key is before `key` (or at most `key`
+    /// when `inclusive`), top-down search starting at the head.
+    fn predecessors<Q>(&self, key: &Q, inclusive: bool) -> [usize; MAX_LEVEL]
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.ranked_predecessors(key, inclusive).0
+    }
+
+    /// `predecessors` with the rank of each of them.
+    fn ranked_predecessors<Q>(
+        &self,
+        key: &Q,
+        inclusive: bool,
+    ) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL])
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut preds = [HEAD; MAX_LEVEL];
+        let mut ranks = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        let mut rank = 0;
+        for l in (0..self.level).rev() {
+            loop {
+                let next = self.links(x)[l].next;
+                if next == NIL {
+                    break;
+                }
+                match self.key(next).borrow().cmp(key) {
+                    Ordering::Less => {}
+                    Ordering::Equal if inclusive => {}
+                    _ => break,
+                }
+                rank += self.links(x)[l].span;
+                x = next;
+            }
+            preds[l] = x;
+            ranks[l] = rank;
+        }
+        (preds, ranks)
+    }
+
+    /// Last node on each level before the entry at `index`.
+    fn predecessors_at(&self, index: usize) -> [usize; MAX_LEVEL] {
+        let mut preds = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        let mut rank = 0;
+        for l in (0..self.level).rev() {
+            while self.links(x)[l].next != NIL && rank + self.links(x)[l].span <= index {
+                rank += self.links(x)[l].span;
+                x = self.links(x)[l].next;
+            }
+            preds[l] = x;
+        }
+        preds
+    }
+
+    fn find<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let x = self.links(self.predecessors(key, false)[0])[0].next;
+        (x != NIL && self.key(x).borrow() == key).then_some(x)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let x = self.find(key)?;
+        self.nodes[x].entry.as_ref().map(|(_, v)| v)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let x = self.find(key)?;
+        self.nodes[x].entry.as_mut().map(|(_, v)| v)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let (preds, ranks) = self.ranked_predecessors(&key, false);
+        let x = self.links(preds[0])[0].next;
+        if x != NIL && *self.key(x) == key {
+            let entry = self.nodes[x].entry.as_mut().unwrap();
+            return Some(std::mem::replace(&mut entry.1, value));
+        }
+
+        let height = self.random_level();
+        let node = Node {
+            entry: Some((key, value)),
+            tower: self.tower(height, 0),
+            height,
+        };
+        let x = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        // Levels above the current height link from the head, which is
+        // what `predecessors` left there, to the end.
+        for l in self.level..height {
+            self.links_mut(HEAD)[l].span = self.len + 1;
+        }
+        self.level = std::cmp::max(self.level, height);
+        for l in 0..self.level {
+            let pred = preds[l];
+            if l < height {
+                // Entries between the predecessor and the new node.
+                let before = ranks[0] - ranks[l];
+                let link = self.links(pred)[l];
+                self.links_mut(x)[l] = Link {
+                    next: link.next,
+                    span: link.span - before,
+                };
+                self.links_mut(pred)[l] = Link {
+                    next: x,
+                    span: before + 1,
+                };
+            } else {
+                self.links_mut(pred)[l].span += 1;
+            }
+        }
+        self.len += 1;
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let preds = self.predecessors(key, false);
+        let x = self.links(preds[0])[0].next;
+        if x == NIL || self.key(x).borrow() != key {
+            return None;
+        }
+        self.unlink(x, &preds).map(|(_, v)| v)
+    }
+
+    /// Removes the entries with indexes in `range`, returning how many
+    /// there were. Takes `O(log n)` to find the first of them and then
+    /// `O(1)` expected for each.
+    pub fn remove_index_range<R: RangeBounds<usize>>(&mut self, range: R) -> usize {
+        let start = match range.start_bound() {
+            Bound::Included(i) => *i,
+            Bound::Excluded(i) => i.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(i) => i.saturating_add(1),
+            Bound::Excluded(i) => *i,
+            Bound::Unbounded => self.len,
+        };
+        let end = std::cmp::min(end, self.len);
+        if start >= end {
+            return 0;
+        }
+        let preds = self.predecessors_at(start);
+        for _ in start..end {
+            let x = self.links(preds[0])[0].next;
+            self.unlink(x, &preds);
+        }
+        end - start
+    }
+
+    /// Removes node `x` given its predecessors on each level.
+    fn unlink(&mut self, x: usize, preds: &[usize; MAX_LEVEL]) -> Option<(K, V)> {
+        let height = self.nodes[x].height;
+        for (l, pred) in preds.iter().enumerate().take(self.level) {
+            if l < height {
+                let link = self.links(x)[l];
+                let pred = &mut self.links_mut(*pred)[l];
+                pred.next = link.next;
+                pred.span += link.span - 1;
+            } else {
+                self.links_mut(*pred)[l].span -= 1;
+            }
+        }
+        while self.level > 1 && self.links(HEAD)[self.level - 1].next == NIL {
+            self.level -= 1;
+        }
+        self.len -= 1;
+        let node = std::mem::replace(
+            &mut self.nodes[x],
+            Node {
+                entry: None,
+                tower: NonNull::dangling(),
+                height: 0,
+            },
+        );
+        unsafe { self.free_tower(&node) };
+        self.free.push(x);
+        node.entry
+    }
+
+    /// Index of `key` in key order, if it is in the map.
+    pub fn rank<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (preds, ranks) = self.ranked_predecessors(key, true);
+        let x = preds[0];
+        (x != HEAD && self.key(x).borrow() == key).then(|| ranks[0] - 1)
+    }
+
+    /// Entry at `index` in key order.
+    pub fn select(&self, index: usize) -> Option<(&K, &V)> {
+        if index >= self.len {
+            return None;
+        }
+        self.entry(self.links(self.predecessors_at(index)[0])[0].next)
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.entry(self.links(HEAD)[0].next)
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        let mut x = HEAD;
+        for l in (0..self.level).rev() {
+            while self.links(x)[l].next != NIL {
+                x = self.links(x)[l].next;
+            }
+        }
+        self.entry(x)
+    }
+
+    fn entry(&self, x: usize) -> Option<(&K, &V)> {
+        self.nodes.get(x)?.entry.as_ref().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V, A> {
+        Iter {
+            list: self,
+            next: self.links(HEAD)[0].next,
+        }
+    }
+
+    /// Iterates over the entries with keys in `range`, in key order.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V, Q, R, A>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let next = match range.start_bound() {
+            Bound::Included(key) => self.links(self.predecessors(key, false)[0])[0].next,
+            Bound::Excluded(key) => self.links(self.predecessors(key, true)[0])[0].next,
+            Bound::Unbounded => self.links(HEAD)[0].next,
+        };
+        Range {
+            iter: Iter { list: self, next },
+            range,
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K, V, A: NodeAlloc> Drop for SkipList<K, V, A> {
+    fn drop(&mut self) {
+        if !A::FREES_ON_DROP {
+            for node in &self.nodes {
+                unsafe { self.free_tower(node) };
+            }
+        }
+    }
+}
+
+pub struct Iter<'a, K, V, A: NodeAlloc = Heap> {
+    list: &'a SkipList<K, V, A>,
+    next: usize,
+}
+
+impl<'a, K, V, A: NodeAlloc> Iterator for Iter<'a, K, V, A> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NIL {
+            return None;
+        }
+        let x = self.next;
+        self.next = self.list.links(x)[0].next;
+        self.list.nodes[x].entry.as_ref().map(|(k, v)| (k, v))
+    }
+}
+
+pub struct Range<'a, K, V, Q: ?Sized, R, A: NodeAlloc = Heap> {
+    iter: Iter<'a, K, V, A>,
+    range: R,
+    _key: PhantomData<fn(&Q)>,
+}
+
+impl<'a, K, V, Q, R, A> Iterator for Range<'a, K, V, Q, R, A>
+where
+    A: NodeAlloc,
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.iter.next()?;
+        let past_end = match self.range.end_bound() {
+            Bound::Included(end) => k.borrow() > end,
+            Bound::Excluded(end) => k.borrow() >= end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.iter.next = NIL;
+            return None;
+        }
+        Some((k, v))
+    }
+}
+
+impl<'a, K: Ord, V, A: NodeAlloc> IntoIterator for &'a SkipList<K, V, A> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Ord, V, A: NodeAlloc> FromIterator<(K, V)> for SkipList<K, V, A> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut list = SkipList::default();
+        list.extend(iter);
+        list
+    }
+}
+
+impl<K: Ord, V, A: NodeAlloc> Extend<(K, V)> for SkipList<K, V, A> {
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug, A: NodeAlloc> fmt::Debug for SkipList<K, V, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize, A: NodeAlloc> MemoryUsage for SkipList<K, V, A> {
+    fn memory_usage(&self) -> Memory {
+        let node = size_of::<Node<K, V>>();
+        let links: usize = self.nodes.iter().map(|n| n.height).sum();
+        let entries = || self.nodes.iter().filter_map(|n| n.entry.as_ref());
+        Memory {
+            nodes: (self.len + 1) * node - self.len * (size_of::<K>() + size_of::<V>())
+                + links * size_of::<Link>(),
+            keys: sum_with_heap(entries().map(|(k, _)| k)),
+            values: sum_with_heap(entries().map(|(_, v)| v)),
+            overhead: self.free.len() * node
+                + spare(&self.nodes)
+                + self.free.capacity() * size_of::<usize>(),
+        }
+    }
 }
-*/
-/*
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arena::Arena;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut list = SkipList::new();
+        assert_eq!(list.insert(2, "b"), None);
+        assert_eq!(list.insert(1, "a"), None);
+        assert_eq!(list.insert(2, "B"), Some("b"));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get(&2), Some(&"B"));
+        assert_eq!(list.get(&3), None);
+        assert_eq!(list.first(), Some((&1, &"a")));
+        assert_eq!(list.last(), Some((&2, &"B")));
+        assert_eq!(list.remove(&1), Some("a"));
+        assert_eq!(list.remove(&1), None);
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        let mut list = SkipList::new();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..20000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % 1000;
+            if x & 3 == 0 {
+                assert_eq!(list.remove(&key), map.remove(&key));
+            } else {
+                assert_eq!(list.insert(key, x), map.insert(key, x));
+            }
+        }
+        assert_eq!(list.len(), map.len());
+        assert!(list.iter().eq(map.iter()));
+        assert!(list.range(100..=200).eq(map.range(100..=200)));
+        assert!(list
+            .range((Bound::Excluded(500), Bound::Unbounded))
+            .eq(map.range((Bound::Excluded(500), Bound::Unbounded))));
+    }
+
+    /// Checks the span of every link against the ranks of the nodes.
+    fn check_spans<K: Ord, V, A: NodeAlloc>(list: &SkipList<K, V, A>) {
+        let mut ranks = std::collections::HashMap::from([(HEAD, 0), (NIL, list.len + 1)]);
+        let mut x = list.links(HEAD)[0].next;
+        while x != NIL {
+            ranks.insert(x, ranks.len() - 1);
+            x = list.links(x)[0].next;
+        }
+        for l in 0..list.level {
+            let mut x = HEAD;
+            while x != NIL {
+                let next = list.links(x)[l].next;
+                assert_eq!(list.links(x)[l].span, ranks[&next] - ranks[&x]);
+                x = next;
+            }
+        }
+    }
+
+    #[test]
+    fn rank_select() {
+        let mut list = SkipList::new();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for i in 0..20000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % 2000;
+            match x & 255 {
+                0 => {
+                    let start = (x >> 8) as usize % (map.len() + 1);
+                    let end = start + (x >> 20) as usize % 50;
+                    let keys: Vec<u64> =
+                        map.keys().skip(start).take(end - start).copied().collect();
+                    for k in &keys {
+                        map.remove(k);
+                    }
+                    assert_eq!(list.remove_index_range(start..end), keys.len());
+                }
+                1..=63 => assert_eq!(list.remove(&key), map.remove(&key)),
+                _ => assert_eq!(list.insert(key, x), map.insert(key, x)),
+            }
+            if i % 1000 == 0 {
+                check_spans(&list);
+            }
+        }
+        check_spans(&list);
+        assert_eq!(list.len(), map.len());
+        for (i, (k, v)) in map.iter().enumerate() {
+            assert_eq!(list.select(i), Some((k, v)));
+            assert_eq!(list.rank(k), Some(i));
+        }
+        assert_eq!(list.select(map.len()), None);
+        assert_eq!(list.rank(&2000), None);
+    }
+
+    #[test]
+    fn remove_index_range() {
+        let mut list: SkipList<u64, u64> = (0..100).map(|i| (i, i)).collect();
+        assert_eq!(list.remove_index_range(10..20), 10);
+        assert_eq!(list.remove_index_range(..=4), 5);
+        assert_eq!(list.remove_index_range(80..), 5);
+        assert_eq!(list.remove_index_range(90..), 0);
+        assert_eq!(list.remove_index_range(70..), 10);
+        assert_eq!(list.len(), 70);
+        assert_eq!(list.select(0), Some((&5, &5)));
+        assert_eq!(list.select(5), Some((&20, &20)));
+        assert_eq!(list.rank(&84), Some(69));
+        assert_eq!(list.last(), Some((&84, &84)));
+        check_spans(&list);
+        assert_eq!(list.remove_index_range(..), 70);
+        assert!(list.is_empty());
+        check_spans(&list);
+    }
+
+    #[test]
+    fn arena() {
+        let mut list: SkipList<u64, String, Arena> = SkipList::default();
+        let mut map = BTreeMap::new();
+        let mut x: u64 = 1;
+        for _ in 0..20000 {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (x >> 33) % 1000;
+            if x & 3 == 0 {
+                assert_eq!(list.remove(&key), map.remove(&key));
+            } else {
+                assert_eq!(
+                    list.insert(key, x.to_string()),
+                    map.insert(key, x.to_string())
+                );
+            }
+        }
+        check_spans(&list);
+        assert!(list.iter().eq(map.iter()));
+        // One tower per entry and the head's.
+        assert_eq!(list.allocator().stats().live, list.len() + 1);
+        list.remove_index_range(..);
+        assert_eq!(list.allocator().stats().live, 1);
+    }
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn borrowed_keys() {
+        let list: SkipList<String, usize> = ["b", "a", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.to_string(), i))
+            .collect();
+        assert_eq!(list.get("c"), Some(&2));
+        let keys: Vec<&String> = list
+            .range::<str, _>((Bound::Included("b"), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["b", "c"]);
     }
 }
-*/
@@ -0,0 +1,28 @@
+//! Synchronization primitives of the concurrent structures.
+//!
+//! Built with `--cfg loom`, they come from loom, whose scheduler runs a test
+//! under the interleavings of the atomic operations and locks of its
+//! threads, and the epoch-based reclamation of crossbeam runs on loom as
+//! well. The model tests of each structure run with `cargo loom`, an alias
+//! defined in `.cargo/config.toml` for
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom --cfg crossbeam_loom" CARGO_TARGET_DIR=target/loom \
+//!     cargo test -p collections --release --lib model
+//! ```
+//!
+//! Under loom the structures use smaller nodes and tables, so that the few
+//! operations of a model reach splits and growth.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{atomic, Mutex, MutexGuard};
+#[cfg(loom)]
+pub(crate) use loom::{hint, thread};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::{atomic, Mutex, MutexGuard};
+#[cfg(not(loom))]
+pub(crate) use std::{hint, thread};
+
+#[cfg(all(test, loom))]
+pub(crate) mod model;
@@ -0,0 +1,30 @@
+//! Helpers of the model tests run under loom.
+
+/// Runs `f` under the interleavings of its threads with up to two
+/// preemptions, unless `LOOM_MAX_PREEMPTIONS` sets another bound. Every
+/// operation pins the epoch, which takes a few atomic operations of its
+/// own, so that a third preemption makes a model run for many minutes.
+pub(crate) fn check(f: impl Fn() + Send + Sync + 'static) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound.get_or_insert(2);
+    builder.check(f);
+}
+
+/// Value tracked by loom, which fails the model if any copy is still alive
+/// at the end of an execution, after the epoch collector has run the frees
+/// deferred to it. A structure that loses a value it removed or replaced
+/// without freeing it fails the model that way.
+#[derive(Clone, Debug)]
+pub(crate) struct Tracked {
+    pub(crate) value: u64,
+    _alive: loom::sync::Arc<()>,
+}
+
+impl Tracked {
+    pub(crate) fn new(value: u64) -> Self {
+        Tracked {
+            value,
+            _alive: loom::sync::Arc::new(()),
+        }
+    }
+}
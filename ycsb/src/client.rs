@@ -3,16 +3,19 @@ use std::sync::{
     Arc,
 };
 
+use anyhow::bail;
 use serde::Deserialize;
 use toml::Table;
 
-use crate::db::DB;
+use crate::db::{DBWrapper, DB};
+use crate::history::History;
+use crate::measurements::Measurements;
 use crate::workloads::Workload;
 
-pub struct Client<T: DB, U: Workload> {
+pub struct Client<T: DB + ?Sized, U: Workload> {
     props: ClientProperties,
 
-    db: T,
+    db: DBWrapper<T>,
     workload: Arc<U>,
 
     thread_index: u32,
@@ -21,18 +24,22 @@ pub struct Client<T: DB, U: Workload> {
     progress: Arc<AtomicU64>,
 }
 
-impl<T: DB, U: Workload> Client<T, U> {
+impl<T: DB + ?Sized, U: Workload> Client<T, U> {
     pub fn new(
         client_props: ClientProperties,
-        props: Table,
+        db: Box<T>,
         workload: Arc<U>,
         thread_index: u32,
         thread_count: u32,
         progress: Arc<AtomicU64>,
     ) -> Self {
+        let mut db = DBWrapper::new(db);
+        if client_props.history || client_props.scan_verify {
+            db.record_history(thread_index, client_props.scan_verify);
+        }
         Client {
-            props: client_props.clone(),
-            db: T::new(props),
+            props: client_props,
+            db,
             workload,
             thread_index,
             thread_count,
@@ -54,9 +61,12 @@ impl<T: DB, U: Workload> Client<T, U> {
             } else {
                 0
             });
-        for _ in 0..count {
-            self.workload.do_insert(&mut self.db);
-            self.progress.fetch_add(1, Ordering::Relaxed);
+        let mut i = 0;
+        while i < count {
+            let n = std::cmp::min(self.props.batch_size, count - i);
+            self.workload.do_batch_insert(&mut self.db, n as usize);
+            self.progress.fetch_add(n, Ordering::Relaxed);
+            i += n;
         }
     }
 
@@ -69,10 +79,28 @@ impl<T: DB, U: Workload> Client<T, U> {
             } else {
                 0
             });
-        for _ in 0..count {
-            self.progress.fetch_add(1, Ordering::Relaxed);
+        let mut i = 0;
+        while i < count {
+            let n = std::cmp::min(self.props.batch_size, count - i);
+            self.workload.do_batch_transaction(&mut self.db, n as usize);
+            self.progress.fetch_add(n, Ordering::Relaxed);
+            i += n;
         }
     }
+
+    /// Returns the measurements recorded since the previous call.
+    pub fn take_measurements(&mut self) -> Measurements {
+        self.db.take_measurements()
+    }
+
+    /// Operations of both phases, when `history` or `scanverify` is set.
+    pub fn take_history(&mut self) -> Option<History> {
+        self.db.take_history()
+    }
+
+    pub fn cleanup(&mut self) {
+        self.db.cleanup();
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -82,11 +110,32 @@ pub struct ClientProperties {
 
     #[serde(rename = "recordcount", default = "default_record_count")]
     pub record_count: u64,
+
+    #[serde(rename = "batchsize", default = "default_batch_size")]
+    pub batch_size: u64,
+
+    /// Records the operations of the clients and checks after the run that
+    /// they are linearizable. The history is kept in memory and the check
+    /// is exponential in the operations running at once on a key, so that
+    /// it is meant for short runs on a few records.
+    #[serde(default = "default_history")]
+    pub history: bool,
+
+    /// Records the operations and scans of the clients and checks after
+    /// the run that every scan returned its keys in order from its start
+    /// key, without a key deleted before it started and with every key
+    /// existing while it ran. The history is kept in memory.
+    #[serde(rename = "scanverify", default = "default_scan_verify")]
+    pub scan_verify: bool,
 }
 
 impl ClientProperties {
-    pub fn parse(props: Table) -> Result<Self, toml::de::Error> {
-        props.try_into()
+    pub fn parse(props: Table) -> Result<Self, anyhow::Error> {
+        let props: Self = props.try_into()?;
+        if props.batch_size < 1 {
+            bail!("batchsize must be at least 1");
+        }
+        Ok(props)
     }
 }
 
@@ -96,3 +145,33 @@ fn default_operation_count() -> u64 {
 fn default_record_count() -> u64 {
     0
 }
+fn default_batch_size() -> u64 {
+    1
+}
+fn default_history() -> bool {
+    false
+}
+fn default_scan_verify() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientProperties;
+
+    fn parse(text: &str) -> Result<ClientProperties, anyhow::Error> {
+        ClientProperties::parse(text.parse().unwrap())
+    }
+
+    #[test]
+    fn batch_size_defaults_to_one() {
+        assert_eq!(parse("").unwrap().batch_size, 1);
+        assert_eq!(parse("batchsize = 16").unwrap().batch_size, 16);
+    }
+
+    #[test]
+    fn zero_batch_size_is_rejected() {
+        let err = parse("batchsize = 0").unwrap_err();
+        assert!(err.to_string().contains("batchsize"));
+    }
+}
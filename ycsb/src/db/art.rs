@@ -0,0 +1,121 @@
+use std::sync::{Arc, RwLock};
+
+use collections::{Art, Memory, MemoryUsage};
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+type TreeType = Art<RowValueType>;
+
+pub struct ArtMap {
+    catalog: Arc<ColumnCatalog>,
+    tree: Arc<RwLock<TreeType>>,
+}
+
+pub struct ArtMapHandle {
+    columns: CatalogCache,
+    tree: Arc<RwLock<TreeType>>,
+}
+
+pub const PROPERTIES: &[Property] = &[];
+
+impl DBFactory for ArtMap {
+    type DB = ArtMapHandle;
+
+    fn new(_: &Table) -> Self {
+        ArtMap {
+            catalog: ColumnCatalog::new(),
+            tree: Arc::new(RwLock::new(Art::new())),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        ArtMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            tree: self.tree.clone(),
+        }
+    }
+
+    /// Reports the nodes of each size and the bytes of the tree without the
+    /// rows, to compare with the keys and nodes of the comparison-based maps.
+    fn report(&self, measurements: &mut Measurements) {
+        let stats = self.tree.read().unwrap().stats();
+        measurements.count("ART", "Node4", stats.node4 as u64);
+        measurements.count("ART", "Node16", stats.node16 as u64);
+        measurements.count("ART", "Node48", stats.node48 as u64);
+        measurements.count("ART", "Node256", stats.node256 as u64);
+        measurements.count("ART", "Leaves", stats.leaves as u64);
+        measurements.count("ART", "Index-Bytes", stats.bytes as u64);
+    }
+}
+
+impl MemoryUsage for ArtMap {
+    fn memory_usage(&self) -> Memory {
+        self.tree.read().unwrap().memory_usage()
+    }
+}
+
+impl DB for ArtMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.tree.write().unwrap().insert(key.as_bytes(), row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        let row = self.tree.read().unwrap().get(key.as_bytes()).cloned();
+        match row {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let rows: Vec<(String, RowValueType)> = self
+            .tree
+            .read()
+            .unwrap()
+            .range_from(start_key.as_bytes())
+            .take(record_count)
+            .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.clone()))
+            .collect();
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        match self.tree.write().unwrap().get_mut(key.as_bytes()) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        match self.tree.write().unwrap().remove(key.as_bytes()) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        }
+    }
+}
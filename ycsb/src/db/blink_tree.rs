@@ -0,0 +1,108 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use collections::{BLinkTree, Memory, MemoryUsage};
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::registry::Property;
+
+type TreeType = BLinkTree<String, RowValueType>;
+
+pub struct BLinkTreeMap {
+    catalog: Arc<ColumnCatalog>,
+    tree: Arc<TreeType>,
+}
+
+pub struct BLinkTreeMapHandle {
+    columns: CatalogCache,
+    tree: Arc<TreeType>,
+}
+
+pub const PROPERTIES: &[Property] = &[];
+
+impl DBFactory for BLinkTreeMap {
+    type DB = BLinkTreeMapHandle;
+
+    fn new(_: &Table) -> Self {
+        BLinkTreeMap {
+            catalog: ColumnCatalog::new(),
+            tree: Arc::new(BLinkTree::new()),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        BLinkTreeMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            tree: self.tree.clone(),
+        }
+    }
+}
+
+impl MemoryUsage for BLinkTreeMap {
+    fn memory_usage(&self) -> Memory {
+        self.tree.memory_usage()
+    }
+}
+
+impl DB for BLinkTreeMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.tree.insert(key, row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.tree.get(key) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        Ok(self
+            .tree
+            .range(Bound::Included(start_key), record_count)
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    /// Builds the new row under the lock of the leaf, so concurrent updates
+    /// of one record do not lose fields.
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        let updated = self.tree.update(key, |row| {
+            let mut row = row.clone();
+            catalog::apply(&mut row, values);
+            row
+        });
+        if updated {
+            Ok(())
+        } else {
+            Err(DBError::NotFound)
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        if self.tree.remove(key) {
+            Ok(())
+        } else {
+            Err(DBError::NotFound)
+        }
+    }
+}
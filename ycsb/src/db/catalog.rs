@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+use crate::db::ValueListType;
+
+pub type RowValueType = Vec<Option<Vec<u8>>>;
+pub type ColumnValueListType = Vec<(usize, Vec<u8>)>;
+
+/// Field name to dense column id dictionary shared by all handles of a
+/// row-oriented store. Columns are only ever appended, and every append bumps
+/// `epoch` so handles can tell whether their `CatalogCache` is stale without
+/// taking the lock.
+#[derive(Default)]
+pub struct ColumnCatalog {
+    epoch: AtomicU64,
+    columns: RwLock<Columns>,
+}
+
+#[derive(Default, Clone)]
+struct Columns {
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+}
+
+impl ColumnCatalog {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    fn get_or_insert(&self, name: &str) -> usize {
+        let mut x = self.columns.write().unwrap();
+        if let Some(id) = x.ids.get(name) {
+            return *id;
+        }
+        let id = x.names.len();
+        x.names.push(name.to_string());
+        x.ids.insert(name.to_string(), id);
+        self.epoch.fetch_add(1, Ordering::Release);
+        id
+    }
+}
+
+/// Per-handle copy of a `ColumnCatalog`, refreshed only when the catalog
+/// epoch has moved.
+pub struct CatalogCache {
+    catalog: Arc<ColumnCatalog>,
+    epoch: u64,
+    columns: Columns,
+}
+
+impl CatalogCache {
+    pub fn new(catalog: Arc<ColumnCatalog>) -> Self {
+        CatalogCache {
+            catalog,
+            epoch: 0,
+            columns: Columns::default(),
+        }
+    }
+
+    fn refresh(&mut self) -> bool {
+        let epoch = self.catalog.epoch();
+        if epoch == self.epoch {
+            return false;
+        }
+        let x = self.catalog.columns.read().unwrap();
+        for name in &x.names[self.columns.names.len()..] {
+            self.columns.ids.insert(name.clone(), x.ids[name]);
+            self.columns.names.push(name.clone());
+        }
+        // Read the epoch again under the lock: columns appended between the
+        // first load and taking the lock are already copied.
+        self.epoch = self.catalog.epoch();
+        true
+    }
+
+    pub fn column_id(&mut self, name: &str) -> Option<usize> {
+        if let Some(id) = self.columns.ids.get(name) {
+            return Some(*id);
+        }
+        if self.refresh() {
+            return self.columns.ids.get(name).copied();
+        }
+        None
+    }
+
+    pub fn column_id_or_insert(&mut self, name: &str) -> usize {
+        if let Some(id) = self.column_id(name) {
+            return id;
+        }
+        let id = self.catalog.get_or_insert(name);
+        self.refresh();
+        id
+    }
+
+    pub fn column_name(&mut self, id: usize) -> &str {
+        if id >= self.columns.names.len() {
+            self.refresh();
+        }
+        &self.columns.names[id]
+    }
+
+    /// Resolves `fields` to column ids. `None` selects every column; unknown
+    /// field names are dropped since no row can hold them.
+    pub fn projection(&mut self, fields: Option<&[String]>) -> Option<Vec<usize>> {
+        fields.map(|fields| {
+            fields
+                .iter()
+                .filter_map(|name| self.column_id(name))
+                .collect()
+        })
+    }
+
+    /// Replaces field names by column ids, registering unknown fields.
+    /// Backends call this before taking their own locks so the catalog lock
+    /// is never acquired while holding them.
+    pub fn resolve(&mut self, values: ValueListType) -> ColumnValueListType {
+        values
+            .into_iter()
+            .map(|(name, value)| (self.column_id_or_insert(&name), value))
+            .collect()
+    }
+
+    pub fn build_row(&mut self, values: ValueListType) -> RowValueType {
+        let mut row = RowValueType::new();
+        apply(&mut row, self.resolve(values));
+        row
+    }
+
+    pub fn row_values(
+        &mut self,
+        row: &RowValueType,
+        projection: Option<&[usize]>,
+    ) -> ValueListType {
+        let mut ret = ValueListType::new();
+        match projection {
+            Some(ids) => {
+                for id in ids {
+                    if let Some(Some(v)) = row.get(*id) {
+                        ret.push((self.column_name(*id).to_string(), v.clone()));
+                    }
+                }
+            }
+            None => {
+                for (id, v) in row.iter().enumerate() {
+                    if let Some(v) = v {
+                        ret.push((self.column_name(id).to_string(), v.clone()));
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+pub fn apply(row: &mut RowValueType, values: ColumnValueListType) {
+    for (id, value) in values {
+        if row.len() <= id {
+            row.resize(id + 1, None);
+        }
+        row[id] = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    fn values(names: &[&str]) -> ValueListType {
+        names
+            .iter()
+            .map(|n| (n.to_string(), n.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn projection() {
+        let mut cache = CatalogCache::new(ColumnCatalog::new());
+        let row = cache.build_row(values(&["a", "b", "c"]));
+        assert_eq!(cache.row_values(&row, None), values(&["a", "b", "c"]));
+
+        let fields = vec!["c".to_string(), "x".to_string(), "a".to_string()];
+        let projection = cache.projection(Some(&fields)).unwrap();
+        assert_eq!(
+            cache.row_values(&row, Some(&projection)),
+            values(&["c", "a"])
+        );
+    }
+
+    #[test]
+    fn stale_cache_sees_new_columns() {
+        let catalog = ColumnCatalog::new();
+        let mut c0 = CatalogCache::new(catalog.clone());
+        let mut c1 = CatalogCache::new(catalog.clone());
+        assert_eq!(c0.column_id_or_insert("a"), 0);
+        assert_eq!(c1.column_id("a"), Some(0));
+        assert_eq!(c1.column_id_or_insert("b"), 1);
+
+        let epoch = catalog.epoch();
+        assert_eq!(c0.column_id("b"), Some(1));
+        assert_eq!(c0.column_id_or_insert("b"), 1);
+        assert_eq!(catalog.epoch(), epoch);
+        assert_eq!(epoch, 2);
+        assert_eq!(c0.column_name(1), "b");
+    }
+
+    #[test]
+    fn concurrent_registration() {
+        const THREADS: usize = 8;
+        const COLUMNS: usize = 64;
+
+        let catalog = ColumnCatalog::new();
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let catalog = catalog.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut cache = CatalogCache::new(catalog);
+                    barrier.wait();
+                    // Every thread registers the same names starting at a
+                    // different offset so appends race with cache refreshes.
+                    let mut ret = vec![0; COLUMNS];
+                    for i in 0..COLUMNS {
+                        let j = (i * 5 + t * 3) % COLUMNS;
+                        ret[j] = cache.column_id_or_insert(&format!("field{}", j));
+                    }
+                    ret
+                })
+            })
+            .collect();
+        let results: Vec<Vec<usize>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(catalog.epoch(), COLUMNS as u64);
+        for r in &results[1..] {
+            assert_eq!(r, &results[0]);
+        }
+        let mut ids = results[0].clone();
+        ids.sort();
+        assert_eq!(ids, (0..COLUMNS).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use collections::{ConcurrentHashMap, Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+type MapType = ConcurrentHashMap<String, RowValueType>;
+
+pub struct ConcurrentHashMapDB {
+    catalog: Arc<ColumnCatalog>,
+    map: Arc<MapType>,
+}
+
+pub struct ConcurrentHashMapHandle {
+    columns: CatalogCache,
+    map: Arc<MapType>,
+}
+
+pub const PROPERTIES: &[Property] = &[Property {
+    name: "chmstripes",
+    default: "64",
+    description: "write locks of the map, rounded up to a power of two",
+}];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "chmstripes", default = "default_stripes")]
+    stripes: usize,
+}
+
+fn default_stripes() -> usize {
+    64
+}
+
+impl DBFactory for ConcurrentHashMapDB {
+    type DB = ConcurrentHashMapHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        ConcurrentHashMapDB {
+            catalog: ColumnCatalog::new(),
+            map: Arc::new(ConcurrentHashMap::with_stripes(std::cmp::max(
+                props.stripes,
+                1,
+            ))),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        ConcurrentHashMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            map: self.map.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        measurements.count("CHM", "Buckets", self.map.capacity() as u64);
+        measurements.count("CHM", "Resizes", self.map.resizes());
+    }
+}
+
+impl MemoryUsage for ConcurrentHashMapDB {
+    fn memory_usage(&self) -> Memory {
+        self.map.memory_usage()
+    }
+}
+
+impl DB for ConcurrentHashMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.map.insert(key, row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.map.get(key) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: usize,
+        _: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    /// Builds the new row under the stripe lock, so concurrent updates of
+    /// one record do not lose fields.
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        let updated = self.map.update(key, |row| {
+            let mut row = row.clone();
+            catalog::apply(&mut row, values);
+            row
+        });
+        if updated {
+            Ok(())
+        } else {
+            Err(DBError::NotFound)
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        if self.map.remove(key) {
+            Ok(())
+        } else {
+            Err(DBError::NotFound)
+        }
+    }
+}
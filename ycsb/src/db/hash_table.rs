@@ -0,0 +1,175 @@
+use std::sync::{Arc, RwLock};
+
+use collections::hash_table::ProbeStats;
+use collections::{CuckooMap, Memory, MemoryUsage, RobinHoodMap};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+pub type RobinHoodHashMap = HashTableMap<RobinHoodMap<String, RowValueType>>;
+pub type CuckooHashMap = HashTableMap<CuckooMap<String, RowValueType>>;
+
+/// Open-addressing map from `collections` behind the backend.
+pub trait HashTable: 'static + Default + Send + Sync + MemoryUsage {
+    /// Section of the report.
+    const NAME: &'static str;
+
+    fn set_max_load_factor(&mut self, load_factor: f64);
+    fn insert(&mut self, key: String, row: RowValueType);
+    fn get(&self, key: &str) -> Option<&RowValueType>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut RowValueType>;
+    fn remove(&mut self, key: &str) -> Option<RowValueType>;
+    fn stats(&self) -> ProbeStats;
+}
+
+macro_rules! hash_table {
+    ($map:ident, $name:expr) => {
+        impl HashTable for $map<String, RowValueType> {
+            const NAME: &'static str = $name;
+
+            fn set_max_load_factor(&mut self, load_factor: f64) {
+                $map::set_max_load_factor(self, load_factor)
+            }
+            fn insert(&mut self, key: String, row: RowValueType) {
+                $map::insert(self, key, row);
+            }
+            fn get(&self, key: &str) -> Option<&RowValueType> {
+                $map::get(self, key)
+            }
+            fn get_mut(&mut self, key: &str) -> Option<&mut RowValueType> {
+                $map::get_mut(self, key)
+            }
+            fn remove(&mut self, key: &str) -> Option<RowValueType> {
+                $map::remove(self, key)
+            }
+            fn stats(&self) -> ProbeStats {
+                $map::stats(self)
+            }
+        }
+    };
+}
+
+hash_table!(RobinHoodMap, "ROBINHOOD");
+hash_table!(CuckooMap, "CUCKOO");
+
+pub struct HashTableMap<M: HashTable> {
+    catalog: Arc<ColumnCatalog>,
+    map: Arc<RwLock<M>>,
+}
+
+pub struct HashTableMapHandle<M: HashTable> {
+    columns: CatalogCache,
+    map: Arc<RwLock<M>>,
+}
+
+pub const PROPERTIES: &[Property] = &[Property {
+    name: "hashloadfactor",
+    default: "0",
+    description: "fraction of slots filled before the table doubles, below 1; \
+                  0 keeps the default of the map (0.875 robin_hood, 0.45 cuckoo)",
+}];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "hashloadfactor", default)]
+    load_factor: f64,
+}
+
+impl<M: HashTable> DBFactory for HashTableMap<M> {
+    type DB = HashTableMapHandle<M>;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let mut map = M::default();
+        if props.load_factor != 0.0 {
+            if !(props.load_factor > 0.0 && props.load_factor < 1.0) {
+                panic!("invalid hashloadfactor");
+            }
+            map.set_max_load_factor(props.load_factor);
+        }
+        HashTableMap {
+            catalog: ColumnCatalog::new(),
+            map: Arc::new(RwLock::new(map)),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        HashTableMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            map: self.map.clone(),
+        }
+    }
+
+    /// Reports the number of records by probe length, so the mean and the
+    /// tail of lookups can be read off.
+    fn report(&self, measurements: &mut Measurements) {
+        let stats = self.map.read().unwrap().stats();
+        measurements.count(M::NAME, "Records", stats.len as u64);
+        measurements.count(M::NAME, "Capacity", stats.capacity as u64);
+        measurements.count(M::NAME, "Displacements", stats.displacements);
+        measurements.count(M::NAME, "Resizes", stats.resizes);
+        for (i, n) in stats.probe_lengths.iter().enumerate() {
+            measurements.count(M::NAME, &format!("Probe-Length-{:02}", i + 1), *n as u64);
+        }
+    }
+}
+
+impl<M: HashTable> MemoryUsage for HashTableMap<M> {
+    fn memory_usage(&self) -> Memory {
+        self.map.read().unwrap().memory_usage()
+    }
+}
+
+impl<M: HashTable> DB for HashTableMapHandle<M> {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.map.write().unwrap().insert(key, row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        let row = self.map.read().unwrap().get(key).cloned();
+        match row {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: usize,
+        _: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        match self.map.write().unwrap().get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        match self.map.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::db::ValueListType;
+
+/// Mutation stored in a log. Records are framed as
+/// `[crc32: u32][len: u32][payload: len bytes]` in little endian, the
+/// checksum covering the payload.
+pub enum Record {
+    /// Whole record.
+    Put(String, ValueListType),
+    /// Fields overwritten in an existing record.
+    Update(String, ValueListType),
+    Delete(String),
+}
+
+/// When appended records are forced to disk.
+pub enum Fsync {
+    Never,
+    Always,
+    /// After every given number of operations.
+    Interval(u64),
+}
+
+impl Fsync {
+    /// Parses the policy `property` set to `policy` (never, always,
+    /// interval).
+    pub fn new(property: &str, policy: &str, interval: u64) -> Self {
+        match policy {
+            "never" => Fsync::Never,
+            "always" => Fsync::Always,
+            "interval" => Fsync::Interval(std::cmp::max(interval, 1)),
+            _ => {
+                panic!("invalid {}", property);
+            }
+        }
+    }
+
+    /// Whether `unsynced` operations written since the last fsync call for
+    /// another one.
+    pub fn due(&self, unsynced: u64) -> bool {
+        match self {
+            Fsync::Never => false,
+            Fsync::Always => true,
+            Fsync::Interval(n) => unsynced >= *n,
+        }
+    }
+}
+
+const HEADER_LEN: usize = 8;
+/// Larger lengths can only come from a corrupted header.
+const MAX_RECORD_LEN: usize = 1 << 30;
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_UPDATE: u8 = 3;
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for b in data {
+        c = CRC32_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn get_bytes<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if data.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    if data.len() < 4 + len {
+        return None;
+    }
+    let ret = &data[4..4 + len];
+    *data = &data[4 + len..];
+    Some(ret)
+}
+
+fn get_string(data: &mut &[u8]) -> Option<String> {
+    String::from_utf8(get_bytes(data)?.to_vec()).ok()
+}
+
+/// Appends `values` unframed, for stores that keep records as byte strings.
+pub fn put_values(buf: &mut Vec<u8>, values: &ValueListType) {
+    buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    for (name, value) in values {
+        put_bytes(buf, name.as_bytes());
+        put_bytes(buf, value);
+    }
+}
+
+/// Appends a record whose payload `body` writes and returns its framed
+/// length.
+fn frame(buf: &mut Vec<u8>, op: u8, key: &str, body: impl FnOnce(&mut Vec<u8>)) -> usize {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; HEADER_LEN]);
+    buf.push(op);
+    put_bytes(buf, key.as_bytes());
+    body(buf);
+    let payload_len = buf.len() - start - HEADER_LEN;
+    let crc = crc32(&buf[start + HEADER_LEN..]);
+    buf[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+    buf[start + 4..start + 8].copy_from_slice(&(payload_len as u32).to_le_bytes());
+    buf.len() - start
+}
+
+/// Reads values written by `put_values`, advancing `data` past them.
+pub fn get_values(data: &mut &[u8]) -> Option<ValueListType> {
+    let n = u32::from_le_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+    *data = &data[4..];
+    let mut values = ValueListType::new();
+    for _ in 0..n {
+        let name = get_string(data)?;
+        let value = get_bytes(data)?.to_vec();
+        values.push((name, value));
+    }
+    Some(values)
+}
+
+impl Record {
+    /// Appends the framed record to `buf` and returns its length.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> usize {
+        match self {
+            Record::Put(key, values) => Self::encode_put(buf, key, values),
+            Record::Update(key, values) => Self::encode_update(buf, key, values),
+            Record::Delete(key) => Self::encode_delete(buf, key),
+        }
+    }
+
+    /// Encodes a `Put` without taking ownership of its contents.
+    pub fn encode_put(buf: &mut Vec<u8>, key: &str, values: &ValueListType) -> usize {
+        frame(buf, OP_PUT, key, |buf| put_values(buf, values))
+    }
+
+    pub fn encode_update(buf: &mut Vec<u8>, key: &str, values: &ValueListType) -> usize {
+        frame(buf, OP_UPDATE, key, |buf| put_values(buf, values))
+    }
+
+    pub fn encode_delete(buf: &mut Vec<u8>, key: &str) -> usize {
+        frame(buf, OP_DELETE, key, |_| {})
+    }
+
+    fn decode(mut data: &[u8]) -> Option<Record> {
+        let op = *data.first()?;
+        data = &data[1..];
+        let key = get_string(&mut data)?;
+        match op {
+            OP_PUT => Some(Record::Put(key, get_values(&mut data)?)),
+            OP_UPDATE => Some(Record::Update(key, get_values(&mut data)?)),
+            OP_DELETE => Some(Record::Delete(key)),
+            _ => None,
+        }
+    }
+
+    /// Reads the record of `len` bytes written at `offset` of `file`.
+    pub fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Record> {
+        let mut buf = vec![0u8; len];
+        file.read_exact_at(&mut buf, offset)?;
+        Self::check(&buf)
+            .and_then(Self::decode)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupted log record"))
+    }
+
+    fn check(frame: &[u8]) -> Option<&[u8]> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let crc = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(frame[4..8].try_into().unwrap()) as usize;
+        let payload = frame.get(HEADER_LEN..HEADER_LEN + len)?;
+        if crc32(payload) != crc {
+            return None;
+        }
+        Some(payload)
+    }
+}
+
+pub fn open_log(path: &Path) -> File {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e))
+}
+
+/// Makes renames in `dir` durable.
+pub fn sync_dir(dir: &Path) {
+    File::open(dir).and_then(|x| x.sync_all()).unwrap();
+}
+
+/// Reads the records of a log from the start, stopping at the end of the
+/// file or at the first torn or corrupted record. `valid_len` is the length
+/// of the readable prefix, where appending should resume.
+pub struct LogReader {
+    reader: BufReader<File>,
+    pub valid_len: u64,
+}
+
+impl LogReader {
+    pub fn new(file: File) -> Self {
+        LogReader {
+            reader: BufReader::new(file),
+            valid_len: 0,
+        }
+    }
+}
+
+impl Iterator for LogReader {
+    /// Offset, framed length and record.
+    type Item = (u64, usize, Record);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut frame = vec![0u8; HEADER_LEN];
+        self.reader.read_exact(&mut frame).ok()?;
+        let len = u32::from_le_bytes(frame[4..8].try_into().unwrap()) as usize;
+        if len > MAX_RECORD_LEN {
+            return None;
+        }
+        frame.resize(HEADER_LEN + len, 0);
+        self.reader.read_exact(&mut frame[HEADER_LEN..]).ok()?;
+        let record = Record::check(&frame).and_then(Record::decode)?;
+        let offset = self.valid_len;
+        self.valid_len += frame.len() as u64;
+        Some((offset, frame.len(), record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    use collections::testing::TestDir;
+
+    fn values(v: &str) -> ValueListType {
+        vec![("field0".to_string(), v.as_bytes().to_vec())]
+    }
+
+    fn keys(file: File) -> (Vec<String>, u64) {
+        let mut reader = LogReader::new(file);
+        let keys = reader
+            .by_ref()
+            .map(|(_, _, record)| match record {
+                Record::Put(key, _) | Record::Update(key, _) | Record::Delete(key) => key,
+            })
+            .collect();
+        (keys, reader.valid_len)
+    }
+
+    #[test]
+    fn fsync_policies() {
+        let never = Fsync::new("p", "never", 0);
+        assert!(!never.due(1_000_000));
+        let always = Fsync::new("p", "always", 0);
+        assert!(always.due(1));
+        let interval = Fsync::new("p", "interval", 3);
+        assert!(!interval.due(2));
+        assert!(interval.due(3));
+        // An interval of zero syncs every operation.
+        assert!(Fsync::new("p", "interval", 0).due(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid logfsync")]
+    fn unknown_fsync_policy() {
+        Fsync::new("logfsync", "sometimes", 0);
+    }
+
+    #[test]
+    fn reader_stops_at_corrupted_and_torn_records() {
+        let dir = TestDir::new("log-reader");
+        let path = dir.path().join("test.log");
+        let mut buf = Vec::new();
+        let a = Record::encode_put(&mut buf, "a", &values("1"));
+        let b = Record::encode_update(&mut buf, "b", &values("2"));
+        let c = Record::encode_delete(&mut buf, "c");
+        let lens = [a as u64, (a + b) as u64, (a + b + c) as u64];
+
+        fs::write(&path, &buf).unwrap();
+        let file = open_log(&path);
+        assert_eq!(
+            keys(file.try_clone().unwrap()),
+            (vec!["a".into(), "b".into(), "c".into()], lens[2])
+        );
+        match Record::read_at(&file, a as u64, b).unwrap() {
+            Record::Update(key, v) => assert_eq!((key, v), ("b".to_string(), values("2"))),
+            _ => panic!("expected an update"),
+        }
+
+        // A torn tail: the last record cut short.
+        fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        assert_eq!(
+            keys(open_log(&path)),
+            (vec!["a".into(), "b".into()], lens[1])
+        );
+        // A header cut short.
+        fs::write(&path, &buf[..lens[1] as usize + 3]).unwrap();
+        assert_eq!(
+            keys(open_log(&path)),
+            (vec!["a".into(), "b".into()], lens[1])
+        );
+
+        // A flipped payload byte of the second record hides it and
+        // everything after it.
+        let mut corrupted = buf.clone();
+        corrupted[a + HEADER_LEN + 2] ^= 0xff;
+        fs::write(&path, &corrupted).unwrap();
+        let file = open_log(&path);
+        assert_eq!(keys(file.try_clone().unwrap()), (vec!["a".into()], lens[0]));
+        assert!(Record::read_at(&file, a as u64, b).is_err());
+
+        // A length beyond any record is taken as corruption, not allocated.
+        let mut garbage = buf[..a].to_vec();
+        garbage.extend_from_slice(&[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        fs::write(&path, &garbage).unwrap();
+        open_log(&path).write_all(&[0; 16]).unwrap();
+        assert_eq!(keys(open_log(&path)), (vec!["a".into()], lens[0]));
+    }
+}
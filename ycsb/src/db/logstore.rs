@@ -0,0 +1,583 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+
+use collections::{HeapSize, Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::log::{self, Fsync, LogReader, Record};
+use crate::db::{project_values, update_values, DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+const LOG_FILE: &str = "logstore.log";
+const COMPACT_FILE: &str = "logstore.compact";
+
+/// Location of the newest record of a key in the log.
+#[derive(Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: usize,
+}
+
+impl HeapSize for Entry {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// Offsets of the live records together with the file they point into, so
+/// that compaction swaps both at once.
+struct Index {
+    entries: BTreeMap<String, Entry>,
+    file: File,
+}
+
+/// Append side of the log. Held while writing so that records land in the
+/// order their index entries are published.
+struct Log {
+    file: File,
+    len: u64,
+    /// Bytes of the records still referenced by the index.
+    live: u64,
+    /// Operations appended since the last fsync.
+    unsynced: u64,
+}
+
+/// Append-only log of every mutation with an in-memory index of the newest
+/// record of each key. Updates and deletes append a full row or a tombstone
+/// and leave the superseded records behind as garbage until compaction
+/// rewrites the live records into a new log.
+struct Store {
+    dir: PathBuf,
+    log: Mutex<Log>,
+    index: RwLock<Index>,
+    fsync: Fsync,
+    compact_ratio: f64,
+    compact_min_bytes: u64,
+    bytes_written: AtomicU64,
+    fsyncs: AtomicU64,
+    compactions: AtomicU64,
+    recovered: AtomicU64,
+}
+
+pub struct LogStore {
+    store: Arc<Store>,
+}
+
+pub struct LogStoreHandle {
+    store: Arc<Store>,
+    buf: Vec<u8>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "logstoredir",
+        default: "logstore",
+        description: "directory of the log, recovered on startup if it exists",
+    },
+    Property {
+        name: "logstorefsync",
+        default: "never",
+        description: "when to fsync the log (never, always, interval)",
+    },
+    Property {
+        name: "logstorefsyncinterval",
+        default: "1000",
+        description: "operations between fsyncs when logstorefsync is interval",
+    },
+    Property {
+        name: "logstorecompactratio",
+        default: "0.5",
+        description: "fraction of garbage in the log that triggers compaction",
+    },
+    Property {
+        name: "logstorecompactminbytes",
+        default: "67108864",
+        description: "log size below which compaction never runs",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "logstoredir", default = "default_dir")]
+    dir: String,
+
+    #[serde(rename = "logstorefsync", default = "default_fsync")]
+    fsync: String,
+
+    #[serde(rename = "logstorefsyncinterval", default = "default_fsync_interval")]
+    fsync_interval: u64,
+
+    #[serde(rename = "logstorecompactratio", default = "default_compact_ratio")]
+    compact_ratio: f64,
+
+    #[serde(
+        rename = "logstorecompactminbytes",
+        default = "default_compact_min_bytes"
+    )]
+    compact_min_bytes: u64,
+}
+
+fn default_dir() -> String {
+    "logstore".to_string()
+}
+fn default_fsync() -> String {
+    "never".to_string()
+}
+fn default_fsync_interval() -> u64 {
+    1000
+}
+fn default_compact_ratio() -> f64 {
+    0.5
+}
+fn default_compact_min_bytes() -> u64 {
+    64 << 20
+}
+
+/// Rebuilds the index from the log at `path`, replaying every record in
+/// order, and cuts off a torn or corrupted tail left by a crash. Returns
+/// the number of records replayed.
+fn recover(path: &Path) -> (Log, Index, u64) {
+    let file = log::open_log(path);
+    let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+    let mut live = 0;
+    let mut records = 0;
+    let mut reader = LogReader::new(file.try_clone().unwrap());
+    for (offset, len, record) in reader.by_ref() {
+        records += 1;
+        let old = match record {
+            Record::Put(key, _) => {
+                live += len as u64;
+                entries.insert(key, Entry { offset, len })
+            }
+            Record::Delete(key) => entries.remove(&key),
+            Record::Update(key, _) => panic!("unexpected partial update of {} in the log", key),
+        };
+        if let Some(old) = old {
+            live -= old.len as u64;
+        }
+    }
+    let len = reader.valid_len;
+    file.set_len(len).unwrap();
+    let log = Log {
+        file: file.try_clone().unwrap(),
+        len,
+        live,
+        unsynced: 0,
+    };
+    (log, Index { entries, file }, records)
+}
+
+impl Store {
+    fn read(&self, key: &str) -> Option<ValueListType> {
+        let index = self.index.read().unwrap();
+        let entry = *index.entries.get(key)?;
+        Some(Self::read_entry(&index.file, entry))
+    }
+
+    fn read_entry(file: &File, entry: Entry) -> ValueListType {
+        match Record::read_at(file, entry.offset, entry.len) {
+            Ok(Record::Put(_, values)) => values,
+            Ok(Record::Update(key, _) | Record::Delete(key)) => {
+                panic!("index of {} points at a partial record", key)
+            }
+            Err(e) => panic!("cannot read log record: {}", e),
+        }
+    }
+
+    /// Appends the framed `records` in `buf` and publishes them in the index.
+    /// `ops` is the number of operations they account for in the fsync
+    /// policy.
+    fn append(&self, log: &mut Log, buf: &[u8], records: Vec<(Record, usize)>, ops: u64) {
+        log.file.write_all(buf).unwrap();
+        self.bytes_written
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        {
+            let mut index = self.index.write().unwrap();
+            let mut offset = log.len;
+            for (record, len) in records {
+                let old = match record {
+                    Record::Put(key, _) => {
+                        log.live += len as u64;
+                        index.entries.insert(key, Entry { offset, len })
+                    }
+                    Record::Delete(key) => index.entries.remove(&key),
+                    Record::Update(..) => unreachable!("updates append whole records"),
+                };
+                if let Some(old) = old {
+                    log.live -= old.len as u64;
+                }
+                offset += len as u64;
+            }
+        }
+        log.len += buf.len() as u64;
+
+        log.unsynced += ops;
+        if self.fsync.due(log.unsynced) {
+            log.file.sync_data().unwrap();
+            log.unsynced = 0;
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let garbage = log.len - log.live;
+        if log.len >= self.compact_min_bytes
+            && garbage as f64 >= log.len as f64 * self.compact_ratio
+        {
+            self.compact(log);
+        }
+    }
+
+    /// Rewrites the live records in key order into a new log, then renames
+    /// it over the current one. Readers and writers are blocked meanwhile.
+    fn compact(&self, log: &mut Log) {
+        let mut index = self.index.write().unwrap();
+        let index = &mut *index;
+        let path = self.dir.join(COMPACT_FILE);
+        let mut out = BufWriter::new(File::create(&path).unwrap());
+        let mut offset = 0;
+        let mut buf = vec![];
+        for entry in index.entries.values_mut() {
+            buf.resize(entry.len, 0);
+            index.file.read_exact_at(&mut buf, entry.offset).unwrap();
+            out.write_all(&buf).unwrap();
+            entry.offset = offset;
+            offset += entry.len as u64;
+        }
+        out.into_inner().unwrap().sync_all().unwrap();
+        fs::rename(&path, self.dir.join(LOG_FILE)).unwrap();
+        log::sync_dir(&self.dir);
+
+        let file = log::open_log(&self.dir.join(LOG_FILE));
+        index.file = file.try_clone().unwrap();
+        log.file = file;
+        log.len = offset;
+        log.live = offset;
+        log.unsynced = 0;
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl DBFactory for LogStore {
+    type DB = LogStoreHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let fsync = Fsync::new("logstorefsync", &props.fsync, props.fsync_interval);
+        let dir = PathBuf::from(props.dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (log, index, records) = recover(&dir.join(LOG_FILE));
+        LogStore {
+            store: Arc::new(Store {
+                dir,
+                log: Mutex::new(log),
+                index: RwLock::new(index),
+                fsync,
+                compact_ratio: props.compact_ratio,
+                compact_min_bytes: props.compact_min_bytes,
+                bytes_written: AtomicU64::new(0),
+                fsyncs: AtomicU64::new(0),
+                compactions: AtomicU64::new(0),
+                recovered: AtomicU64::new(records),
+            }),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        LogStoreHandle {
+            store: self.store.clone(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reports the log activity of the phase. Records replayed during
+    /// recovery are reported with the load phase.
+    fn report(&self, measurements: &mut Measurements) {
+        let store = &self.store;
+        let counters = [
+            ("Recovered-Records", &store.recovered),
+            ("Bytes-Written", &store.bytes_written),
+            ("Fsyncs", &store.fsyncs),
+            ("Compactions", &store.compactions),
+        ];
+        for (name, counter) in counters {
+            measurements.count("LOGSTORE", name, counter.swap(0, Ordering::Relaxed));
+        }
+        let log = store.log.lock().unwrap();
+        measurements.count("LOGSTORE", "Log-Bytes", log.len);
+        measurements.count("LOGSTORE", "Live-Bytes", log.live);
+    }
+}
+
+/// Only the index: the records themselves are on disk.
+impl MemoryUsage for LogStore {
+    fn memory_usage(&self) -> Memory {
+        self.store.index.read().unwrap().entries.memory_usage()
+    }
+}
+
+impl LogStoreHandle {
+    fn write(&mut self, records: Vec<Record>, ops: u64) {
+        self.buf.clear();
+        let records = records
+            .into_iter()
+            .map(|record| {
+                let len = record.encode(&mut self.buf);
+                (record, len)
+            })
+            .collect();
+        let mut log = self.store.log.lock().unwrap();
+        self.store.append(&mut log, &self.buf, records, ops);
+    }
+}
+
+impl DB for LogStoreHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        self.write(vec![Record::Put(key, values)], 1);
+        Ok(())
+    }
+
+    fn batch_insert(
+        &mut self,
+        _: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let n = records.len() as u64;
+        let records = records
+            .into_iter()
+            .map(|(key, values)| Record::Put(key, values))
+            .collect();
+        self.write(records, n);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        match self.store.read(key) {
+            Some(values) => Ok(project_values(values, fields)),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let index = self.store.index.read().unwrap();
+        Ok(index
+            .entries
+            .range::<str, _>((Bound::Included(start_key), Bound::Unbounded))
+            .take(record_count)
+            .map(|(k, entry)| {
+                let values = Store::read_entry(&index.file, *entry);
+                (k.clone(), project_values(values, fields))
+            })
+            .collect())
+    }
+
+    /// Appends the whole row with `values` applied, so that every record is
+    /// self-contained. The append lock is held across the read to keep
+    /// concurrent updates of the same key from losing writes.
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let store = self.store.clone();
+        let mut log = store.log.lock().unwrap();
+        let mut row = match store.read(key) {
+            Some(row) => row,
+            None => return Err(DBError::NotFound),
+        };
+        update_values(&mut row, values);
+        let record = Record::Put(key.to_string(), row);
+        self.buf.clear();
+        let len = record.encode(&mut self.buf);
+        store.append(&mut log, &self.buf, vec![(record, len)], 1);
+        Ok(())
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        let store = self.store.clone();
+        let mut log = store.log.lock().unwrap();
+        if !store.index.read().unwrap().entries.contains_key(key) {
+            return Err(DBError::NotFound);
+        }
+        let record = Record::Delete(key.to_string());
+        self.buf.clear();
+        let len = record.encode(&mut self.buf);
+        store.append(&mut log, &self.buf, vec![(record, len)], 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    use collections::testing::TestDir;
+
+    fn open(dir: &Path, props: &str) -> LogStore {
+        let props = format!("logstoredir = {:?}\n{}", dir.to_str().unwrap(), props);
+        LogStore::new(&props.parse().unwrap())
+    }
+
+    fn value(v: &str) -> ValueListType {
+        vec![("field0".to_string(), v.as_bytes().to_vec())]
+    }
+
+    /// Every record in key order with its value.
+    fn dump(db: &LogStore) -> Vec<(String, String)> {
+        let rows = db.create().scan("t", "", 1 << 20, None).unwrap();
+        rows.into_iter()
+            .map(|(k, v)| (k, String::from_utf8(v[0].1.clone()).unwrap()))
+            .collect()
+    }
+
+    fn rows(rows: &[(&str, &str)]) -> Vec<(String, String)> {
+        rows.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn log_len(dir: &Path) -> u64 {
+        fs::metadata(dir.join(LOG_FILE)).unwrap().len()
+    }
+
+    #[test]
+    fn reopen_replays_writes_and_deletes() {
+        let tmp = TestDir::new("logstore-reopen");
+        let dir = tmp.path();
+        let db = open(dir, "");
+        let mut h = db.create();
+        h.insert("t", "a".into(), value("1")).unwrap();
+        h.batch_insert(
+            "t",
+            vec![("b".into(), value("1")), ("c".into(), value("1"))],
+        )
+        .unwrap();
+        h.update("t", "a", value("2")).unwrap();
+        h.delete("t", "b").unwrap();
+        assert_eq!(h.delete("t", "b"), Err(DBError::NotFound));
+        drop((h, db));
+
+        let db = open(dir, "");
+        assert_eq!(db.store.recovered.load(Ordering::Relaxed), 5);
+        assert_eq!(dump(&db), rows(&[("a", "2"), ("c", "1")]));
+        let mut h = db.create();
+        h.insert("t", "b".into(), value("3")).unwrap();
+        h.delete("t", "c").unwrap();
+        drop((h, db));
+
+        let db = open(dir, "");
+        assert_eq!(dump(&db), rows(&[("a", "2"), ("b", "3")]));
+    }
+
+    #[test]
+    fn recover_truncates_torn_and_corrupted_tails() {
+        let tmp = TestDir::new("logstore-recover");
+        let dir = tmp.path();
+        let db = open(dir, "");
+        let mut h = db.create();
+        for k in ["a", "b", "c"] {
+            h.insert("t", k.into(), value("1")).unwrap();
+        }
+        drop((h, db));
+        let full = log_len(dir);
+
+        // A crash in the middle of an append leaves a partial record.
+        let mut buf = Vec::new();
+        Record::encode_put(&mut buf, "d", &value("1"));
+        log::open_log(&dir.join(LOG_FILE))
+            .write_all(&buf[..buf.len() / 2])
+            .unwrap();
+        let db = open(dir, "");
+        assert_eq!(log_len(dir), full);
+        assert_eq!(dump(&db), rows(&[("a", "1"), ("b", "1"), ("c", "1")]));
+        // Appends resume at the end of the valid prefix.
+        db.create().insert("t", "d".into(), value("2")).unwrap();
+        drop(db);
+        let db = open(dir, "");
+        assert_eq!(db.store.recovered.load(Ordering::Relaxed), 4);
+        assert_eq!(dump(&db).len(), 4);
+        drop(db);
+
+        // A flipped bit in the record of "d" cuts it off as well.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, full + 12).unwrap();
+        file.write_all_at(&[byte[0] ^ 1], full + 12).unwrap();
+        drop(file);
+        let db = open(dir, "");
+        assert_eq!(log_len(dir), full);
+        assert_eq!(dump(&db), rows(&[("a", "1"), ("b", "1"), ("c", "1")]));
+    }
+
+    #[test]
+    fn compaction_keeps_exactly_the_live_records() {
+        let tmp = TestDir::new("logstore-compact");
+        let dir = tmp.path();
+        let props = "logstorecompactminbytes = 4096\nlogstorecompactratio = 0.5";
+        let db = open(dir, props);
+        let mut h = db.create();
+        let mut model = BTreeMap::new();
+        for i in 0..2000u64 {
+            let key = format!("user{}", i % 50);
+            if i % 7 == 3 {
+                let ret = h.delete("t", &key);
+                assert_eq!(ret.is_ok(), model.remove(&key).is_some());
+            } else {
+                h.insert("t", key.clone(), value(&i.to_string())).unwrap();
+                model.insert(key, i.to_string());
+            }
+        }
+        let compactions = db.store.compactions.load(Ordering::Relaxed);
+        assert!(compactions > 0);
+        assert!(!dir.join(COMPACT_FILE).exists());
+        let expected: Vec<(String, String)> = model.into_iter().collect();
+        assert_eq!(dump(&db), expected);
+        drop((h, db));
+
+        let db = open(dir, props);
+        assert_eq!(dump(&db), expected);
+        let log = db.store.log.lock().unwrap();
+        assert!(log.len - log.live < log.len / 2);
+        drop(log);
+
+        // Compacting everything away leaves only live records on disk.
+        let mut h = db.create();
+        for (key, _) in &expected {
+            h.update("t", key, value("x")).unwrap();
+        }
+        {
+            let mut log = db.store.log.lock().unwrap();
+            db.store.compact(&mut log);
+            assert_eq!(log.len, log.live);
+            assert_eq!(log_len(dir), log.len);
+        }
+        drop((h, db));
+        let db = open(dir, props);
+        assert_eq!(
+            db.store.recovered.load(Ordering::Relaxed),
+            expected.len() as u64
+        );
+        assert!(dump(&db).iter().all(|(_, v)| v == "x"));
+        assert_eq!(dump(&db).len(), expected.len());
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use collections::lsm::{self, Lsm, Stats};
+use collections::{Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::log;
+use crate::db::{project_values, update_values, DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+/// Locks serializing the read-modify-write of updates per key.
+const KEY_LOCKS: usize = 64;
+
+struct Store {
+    lsm: Lsm,
+    key_locks: Vec<Mutex<()>>,
+    /// Counters at the previous report.
+    reported: Mutex<Stats>,
+}
+
+pub struct LsmTree {
+    store: Arc<Store>,
+}
+
+pub struct LsmTreeHandle {
+    store: Arc<Store>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "lsmdir",
+        default: "lsm",
+        description: "directory of the tables, emptied on startup",
+    },
+    Property {
+        name: "lsmmemtablesize",
+        default: "4194304",
+        description: "bytes buffered in the memtable before a flush",
+    },
+    Property {
+        name: "lsmblocksize",
+        default: "4096",
+        description: "target size of a table data block",
+    },
+    Property {
+        name: "lsmbloombitsperkey",
+        default: "10",
+        description: "bloom filter bits per key of each table",
+    },
+    Property {
+        name: "lsmtablesize",
+        default: "2097152",
+        description: "size at which compaction output is split into a new table",
+    },
+    Property {
+        name: "lsml0trigger",
+        default: "4",
+        description: "level 0 tables that trigger a compaction into level 1",
+    },
+    Property {
+        name: "lsmlevelbasesize",
+        default: "10485760",
+        description: "maximum size of level 1",
+    },
+    Property {
+        name: "lsmlevelmultiplier",
+        default: "10",
+        description: "size ratio between consecutive levels",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "lsmdir", default = "default_dir")]
+    dir: String,
+
+    #[serde(rename = "lsmmemtablesize", default = "default_memtable_size")]
+    memtable_size: usize,
+
+    #[serde(rename = "lsmblocksize", default = "default_block_size")]
+    block_size: usize,
+
+    #[serde(rename = "lsmbloombitsperkey", default = "default_bloom_bits_per_key")]
+    bloom_bits_per_key: usize,
+
+    #[serde(rename = "lsmtablesize", default = "default_table_size")]
+    table_size: u64,
+
+    #[serde(rename = "lsml0trigger", default = "default_l0_trigger")]
+    l0_trigger: usize,
+
+    #[serde(rename = "lsmlevelbasesize", default = "default_level_base_size")]
+    level_base_size: u64,
+
+    #[serde(rename = "lsmlevelmultiplier", default = "default_level_multiplier")]
+    level_multiplier: u64,
+}
+
+fn default_dir() -> String {
+    "lsm".to_string()
+}
+fn default_memtable_size() -> usize {
+    4 << 20
+}
+fn default_block_size() -> usize {
+    4096
+}
+fn default_bloom_bits_per_key() -> usize {
+    10
+}
+fn default_table_size() -> u64 {
+    2 << 20
+}
+fn default_l0_trigger() -> usize {
+    4
+}
+fn default_level_base_size() -> u64 {
+    10 << 20
+}
+fn default_level_multiplier() -> u64 {
+    10
+}
+
+impl DBFactory for LsmTree {
+    type DB = LsmTreeHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let options = lsm::Options {
+            memtable_size: props.memtable_size,
+            block_size: props.block_size,
+            bloom_bits_per_key: props.bloom_bits_per_key,
+            table_size: props.table_size,
+            l0_compaction_trigger: std::cmp::max(props.l0_trigger, 1),
+            level_base_size: props.level_base_size,
+            level_multiplier: std::cmp::max(props.level_multiplier, 2),
+            ..lsm::Options::default()
+        };
+        let lsm = Lsm::open(&props.dir, options)
+            .unwrap_or_else(|e| panic!("cannot open {}: {}", props.dir, e));
+        LsmTree {
+            store: Arc::new(Store {
+                lsm,
+                key_locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+                reported: Mutex::new(Stats::default()),
+            }),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        LsmTreeHandle {
+            store: self.store.clone(),
+        }
+    }
+
+    /// Reports the tree activity of the phase and the current shape of the
+    /// levels. Write amplification is `(Flush-Bytes +
+    /// Compaction-Bytes-Written) / User-Bytes`.
+    fn report(&self, measurements: &mut Measurements) {
+        let stats = self.store.lsm.stats();
+        let last = std::mem::replace(&mut *self.store.reported.lock().unwrap(), stats.clone());
+        let counters = [
+            ("User-Bytes", stats.user_bytes - last.user_bytes),
+            ("Flushes", stats.flushes - last.flushes),
+            ("Flush-Bytes", stats.flush_bytes - last.flush_bytes),
+            ("Compactions", stats.compactions - last.compactions),
+            (
+                "Compaction-Bytes-Read",
+                stats.compaction_bytes_read - last.compaction_bytes_read,
+            ),
+            (
+                "Compaction-Bytes-Written",
+                stats.compaction_bytes_written - last.compaction_bytes_written,
+            ),
+            ("Stalls", stats.stalls - last.stalls),
+            ("Bloom-Skips", stats.bloom_skips - last.bloom_skips),
+            ("Table-Reads", stats.table_reads - last.table_reads),
+        ];
+        for (name, n) in counters {
+            measurements.count("LSM", name, n);
+        }
+        for (i, (tables, bytes)) in self.store.lsm.levels().into_iter().enumerate() {
+            measurements.count("LSM", &format!("Level{}-Tables", i), tables as u64);
+            measurements.count("LSM", &format!("Level{}-Bytes", i), bytes);
+        }
+    }
+}
+
+fn decode(value: &[u8], fields: Option<&[String]>) -> ValueListType {
+    let values = log::get_values(&mut &value[..]).expect("corrupted lsm value");
+    project_values(values, fields)
+}
+
+fn encode(values: &ValueListType) -> Vec<u8> {
+    let mut buf = Vec::new();
+    log::put_values(&mut buf, values);
+    buf
+}
+
+/// Memtables and the indexes and filters of the tables; the blocks of the
+/// tables are on disk.
+impl MemoryUsage for LsmTree {
+    fn memory_usage(&self) -> Memory {
+        let store = &self.store;
+        let mut memory = store.lsm.memory_usage();
+        memory.overhead += store.key_locks.len() * std::mem::size_of::<Mutex<()>>();
+        memory
+    }
+}
+
+impl LsmTreeHandle {
+    fn key_lock(&self, key: &str) -> &Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.store.key_locks[(hasher.finish() % KEY_LOCKS as u64) as usize]
+    }
+}
+
+impl DB for LsmTreeHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let value = encode(&values);
+        let _guard = self.key_lock(&key).lock().unwrap();
+        self.store.lsm.put(key.into_bytes(), value);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        match self.store.lsm.get(key.as_bytes()) {
+            Some(value) => Ok(decode(&value, fields)),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        Ok(self
+            .store
+            .lsm
+            .range::<(Bound<&[u8]>, Bound<&[u8]>)>((
+                Bound::Included(start_key.as_bytes()),
+                Bound::Unbounded,
+            ))
+            .take(record_count)
+            .map(|(k, v)| {
+                let key = String::from_utf8(k).expect("non-utf8 lsm key");
+                (key, decode(&v, fields))
+            })
+            .collect())
+    }
+
+    /// Merges `values` into the stored record under the lock of the key, as
+    /// the tree itself only replaces whole values.
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let store = &self.store;
+        let _guard = self.key_lock(key).lock().unwrap();
+        let mut row = match store.lsm.get(key.as_bytes()) {
+            Some(value) => decode(&value, None),
+            None => return Err(DBError::NotFound),
+        };
+        update_values(&mut row, values);
+        let value = encode(&row);
+        store.lsm.put(key.as_bytes().to_vec(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        let store = &self.store;
+        let _guard = self.key_lock(key).lock().unwrap();
+        if store.lsm.get(key.as_bytes()).is_none() {
+            return Err(DBError::NotFound);
+        }
+        store.lsm.delete(key.as_bytes().to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    use collections::testing::TestDir;
+
+    const THREADS: usize = 4;
+
+    fn open(dir: &TestDir) -> LsmTree {
+        let props: Table = format!(
+            "lsmdir = {:?}\nlsmmemtablesize = 4096",
+            dir.path().to_str().unwrap()
+        )
+        .parse()
+        .unwrap();
+        LsmTree::new(&props)
+    }
+
+    fn field(name: String, i: usize) -> (String, Vec<u8>) {
+        (name, i.to_le_bytes().to_vec())
+    }
+
+    #[test]
+    fn concurrent_updates_of_a_key_are_not_lost() {
+        let dir = TestDir::new("lsm-adapter-update");
+        let db = open(&dir);
+        db.create().insert("t", "k".into(), vec![]).unwrap();
+        let start = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (mut h, start) = (db.create(), &start);
+                s.spawn(move || {
+                    start.wait();
+                    for i in 0..100 {
+                        let values = vec![field(format!("f{}-{}", t, i), i)];
+                        h.update("t", "k", values).unwrap();
+                    }
+                });
+            }
+        });
+        // Each update adds a field to the record read under the lock of the
+        // key, so no update drops the field of another.
+        let mut row = db.create().read("t", "k", None).unwrap();
+        row.sort();
+        let mut expected: ValueListType = (0..THREADS)
+            .flat_map(|t| (0..100).map(move |i| field(format!("f{}-{}", t, i), i)))
+            .collect();
+        expected.sort();
+        assert_eq!(row, expected);
+    }
+
+    #[test]
+    fn deletes_race_with_updates_and_deletes() {
+        let dir = TestDir::new("lsm-adapter-delete");
+        let db = open(&dir);
+        let keys: Vec<String> = (0..2000).map(|i| format!("user{}", i)).collect();
+        let mut h = db.create();
+        for key in &keys {
+            h.insert("t", key.clone(), vec![field("a".into(), 0)])
+                .unwrap();
+        }
+        let start = Barrier::new(THREADS);
+        let deleted: Vec<usize> = thread::scope(|s| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let (mut h, keys, start) = (db.create(), &keys, &start);
+                    s.spawn(move || {
+                        start.wait();
+                        let mut deleted = 0;
+                        for key in keys {
+                            if t % 2 == 0 {
+                                deleted += h.delete("t", key).is_ok() as usize;
+                            } else {
+                                let _ = h.update("t", key, vec![field("b".into(), t)]);
+                            }
+                        }
+                        deleted
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|x| x.join().unwrap()).collect()
+        });
+        // Every key is deleted exactly once, and an update never brings a
+        // deleted key back.
+        assert_eq!(deleted.iter().sum::<usize>(), keys.len());
+        for key in &keys {
+            assert_eq!(h.read("t", key, None), Err(DBError::NotFound));
+        }
+        assert!(h.scan("t", "", 10, None).unwrap().is_empty());
+    }
+}
@@ -1,12 +1,361 @@
+use std::sync::Arc;
+
+use collections::MemoryUsage;
 use toml::Table;
 
+use crate::measurements::Measurements;
+use crate::registry::{Property, Registration};
+
+mod art;
+mod blink_tree;
+mod bplus_tree;
+mod catalog;
+mod concurrent_hashmap;
+mod hash_table;
+mod log;
+mod logstore;
+mod lsm;
+mod mvcc;
+mod ordered_map;
+mod persistent_map;
+mod range_btree;
+mod read_filter;
+mod shard;
+mod sharded_hashmap;
+mod skip_list;
 mod std_btree;
+mod wal;
+mod wrapper;
+pub use art::ArtMap;
+pub use blink_tree::BLinkTreeMap;
+pub use bplus_tree::BPlusTreeMap;
+pub use concurrent_hashmap::ConcurrentHashMapDB;
+pub use hash_table::{CuckooHashMap, RobinHoodHashMap};
+pub use logstore::LogStore;
+pub use lsm::LsmTree;
+pub use mvcc::Mvcc;
+pub use ordered_map::{
+    OrderedArt, OrderedBPlusTree, OrderedBTreeMap, OrderedPersistentMap, OrderedSkipList,
+};
+pub use persistent_map::PersistentMapDB;
+pub use range_btree::RangeBTreeMap;
+pub use sharded_hashmap::ShardedHashMap;
 pub use std_btree::{StdBTreeMapMutex, StdBTreeMapRwLock};
+pub use wrapper::DBWrapper;
+
+pub type DBRegistration = Registration<fn(&Table) -> Arc<dyn DynDBFactory>>;
+
+/// Properties applying to every registered database.
+pub const COMMON_PROPERTIES: &[Property] = read_filter::PROPERTIES;
+
+pub const DATABASES: &[DBRegistration] = &[
+    Registration {
+        name: "std_btreemap_mutex",
+        description:
+            "std::collections::BTreeMap behind a single Mutex, optionally write-ahead logged",
+        properties: std_btree::PROPERTIES,
+        init: new_factory::<StdBTreeMapMutex>,
+    },
+    Registration {
+        name: "std_btreemap_rwlock",
+        description:
+            "std::collections::BTreeMap behind a single RwLock, optionally write-ahead logged",
+        properties: std_btree::PROPERTIES,
+        init: new_factory::<StdBTreeMapRwLock>,
+    },
+    Registration {
+        name: "bplus_tree",
+        description: "B+tree with configurable fanout and linked leaves behind a single RwLock",
+        properties: bplus_tree::PROPERTIES,
+        init: new_factory::<BPlusTreeMap>,
+    },
+    Registration {
+        name: "skip_list",
+        description: "skip list with span counts for rank and select behind a single RwLock",
+        properties: skip_list::PROPERTIES,
+        init: skip_list::open,
+    },
+    Registration {
+        name: "blink_tree",
+        description: "concurrent B-link tree with optimistic lock coupling, readers never lock",
+        properties: blink_tree::PROPERTIES,
+        init: new_factory::<BLinkTreeMap>,
+    },
+    Registration {
+        name: "art",
+        description: "adaptive radix tree on the key bytes behind a single RwLock",
+        properties: art::PROPERTIES,
+        init: new_factory::<ArtMap>,
+    },
+    Registration {
+        name: "ordered_map_btreemap",
+        description: "std BTreeMap through the OrderedMap trait behind a single RwLock",
+        properties: ordered_map::PROPERTIES,
+        init: new_factory::<OrderedBTreeMap>,
+    },
+    Registration {
+        name: "ordered_map_skip_list",
+        description: "SkipList through the OrderedMap trait behind a single RwLock",
+        properties: ordered_map::PROPERTIES,
+        init: new_factory::<OrderedSkipList>,
+    },
+    Registration {
+        name: "ordered_map_bplus_tree",
+        description: "BPlusTree through the OrderedMap trait behind a single RwLock",
+        properties: ordered_map::PROPERTIES,
+        init: new_factory::<OrderedBPlusTree>,
+    },
+    Registration {
+        name: "ordered_map_art",
+        description: "Art through the OrderedMap trait behind a single RwLock",
+        properties: ordered_map::PROPERTIES,
+        init: new_factory::<OrderedArt>,
+    },
+    Registration {
+        name: "ordered_map_persistent_map",
+        description:
+            "PersistentMap through the OrderedMap trait behind a single RwLock, readers lock too",
+        properties: ordered_map::PROPERTIES,
+        init: new_factory::<OrderedPersistentMap>,
+    },
+    Registration {
+        name: "persistent_map",
+        description: "persistent B+tree published by atomic swap, readers never lock",
+        properties: persistent_map::PROPERTIES,
+        init: new_factory::<PersistentMapDB>,
+    },
+    Registration {
+        name: "mvcc",
+        description: "snapshot-isolation multi-version BTreeMap, first committer wins",
+        properties: mvcc::PROPERTIES,
+        init: new_factory::<Mvcc>,
+    },
+    Registration {
+        name: "sharded_hashmap",
+        description: "std::collections::HashMap partitioned over locked shards, no scans",
+        properties: sharded_hashmap::PROPERTIES,
+        init: new_factory::<ShardedHashMap>,
+    },
+    Registration {
+        name: "concurrent_hashmap",
+        description: "hash map with lock-free reads, striped write locks and incremental growth",
+        properties: concurrent_hashmap::PROPERTIES,
+        init: new_factory::<ConcurrentHashMapDB>,
+    },
+    Registration {
+        name: "robin_hood",
+        description: "Robin Hood hash map with backward-shift deletion behind a single RwLock",
+        properties: hash_table::PROPERTIES,
+        init: new_factory::<RobinHoodHashMap>,
+    },
+    Registration {
+        name: "cuckoo",
+        description: "two-table cuckoo hash map with a stash behind a single RwLock",
+        properties: hash_table::PROPERTIES,
+        init: new_factory::<CuckooHashMap>,
+    },
+    Registration {
+        name: "range_btreemap",
+        description: "std::collections::BTreeMap range-partitioned over locked shards",
+        properties: range_btree::PROPERTIES,
+        init: new_factory::<RangeBTreeMap>,
+    },
+    Registration {
+        name: "logstore",
+        description: "append-only checksummed log on disk with an in-memory BTreeMap index",
+        properties: logstore::PROPERTIES,
+        init: new_factory::<LogStore>,
+    },
+    Registration {
+        name: "lsm",
+        description: "log-structured merge tree with leveled compaction, not durable",
+        properties: lsm::PROPERTIES,
+        init: new_factory::<LsmTree>,
+    },
+];
 
 pub type ValueListType = Vec<(String, Vec<u8>)>;
 
-pub trait DB {
-    fn new(props: Table) -> Self;
+/// Overwrites the fields of `row` given in `values`, appending new ones.
+/// The counterpart of `catalog::apply` for backends keeping field names in
+/// their rows.
+pub fn update_values(row: &mut ValueListType, values: ValueListType) {
+    for (name, value) in values {
+        match row.iter_mut().find(|(x, _)| *x == name) {
+            Some(field) => field.1 = value,
+            None => row.push((name, value)),
+        }
+    }
+}
+
+/// Keeps the fields of `values` listed in `fields`, all when `None`.
+pub fn project_values(values: ValueListType, fields: Option<&[String]>) -> ValueListType {
+    match fields {
+        Some(fields) => values
+            .into_iter()
+            .filter(|(name, _)| fields.contains(name))
+            .collect(),
+        None => values,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DBError {
+    NotFound,
+    /// The running transaction conflicts with a concurrent one and has to be
+    /// aborted; it may succeed when retried.
+    Conflict,
+    NotImplemented,
+}
+
+impl DBError {
+    /// Name used in the `Return=` lines of the report.
+    pub fn status(&self) -> &'static str {
+        match self {
+            DBError::NotFound => "NOT_FOUND",
+            DBError::Conflict => "CONFLICT",
+            DBError::NotImplemented => "NOT_IMPLEMENTED",
+        }
+    }
+}
+
+/// Owns the state of one database instance. A factory is created once per
+/// benchmark run and hands a handle to every client; the store is released
+/// when the factory and all of its handles are dropped. Its `MemoryUsage`
+/// is that of the records it holds in memory.
+pub trait DBFactory: 'static + std::marker::Send + std::marker::Sync + MemoryUsage {
+    type DB: DB;
+
+    fn new(props: &Table) -> Self;
+    fn create(&self) -> Self::DB;
+
+    /// Adds backend statistics gathered since the previous call to the
+    /// report of the current phase.
+    fn report(&self, _measurements: &mut Measurements) {}
+}
+
+/// Object-safe view of a `DBFactory` used by the registry.
+pub trait DynDBFactory: 'static + std::marker::Send + std::marker::Sync + MemoryUsage {
+    fn create(&self) -> Box<dyn DB>;
+    fn report(&self, measurements: &mut Measurements);
+}
+
+impl<T: DBFactory> DynDBFactory for T {
+    fn create(&self) -> Box<dyn DB> {
+        Box::new(DBFactory::create(self))
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        DBFactory::report(self, measurements);
+    }
+}
+
+fn new_factory<T: DBFactory>(props: &Table) -> Arc<dyn DynDBFactory> {
+    Arc::new(T::new(props))
+}
+
+/// Creates the factory of `db` with the wrappers selected by the
+/// `COMMON_PROPERTIES`.
+pub fn open(db: &DBRegistration, props: &Table) -> Arc<dyn DynDBFactory> {
+    read_filter::wrap(props, (db.init)(props))
+}
+
+pub trait DB: 'static + std::marker::Send {
+    fn insert(&mut self, table: &str, key: String, values: ValueListType) -> Result<(), DBError>;
+
+    /// Inserts all `records`, stopping at the first failure. Backends that
+    /// can amortize locking or I/O over a batch should override this.
+    fn batch_insert(
+        &mut self,
+        table: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        for (key, values) in records {
+            self.insert(table, key, values)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `key`, returning only `fields` when given and every field
+    /// otherwise.
+    fn read(
+        &mut self,
+        table: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError>;
+
+    /// Reads every key of `keys`, returning one result per key in order.
+    fn multi_read(
+        &mut self,
+        table: &str,
+        keys: &[String],
+        fields: Option<&[String]>,
+    ) -> Vec<Result<ValueListType, DBError>> {
+        keys.iter()
+            .map(|key| self.read(table, key, fields))
+            .collect()
+    }
+
+    /// Reads up to `record_count` records in key order starting at
+    /// `start_key`.
+    fn scan(
+        &mut self,
+        table: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError>;
+
+    /// Overwrites the given fields of an existing record.
+    fn update(&mut self, table: &str, key: &str, values: ValueListType) -> Result<(), DBError>;
+
+    /// Removes `key`, failing with `DBError::NotFound` if it does not exist.
+    fn delete(&mut self, _table: &str, _key: &str) -> Result<(), DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    /// Index of `key` among the keys of `table` in order, for backends
+    /// keeping counts in their index like sorted sets.
+    fn rank(&mut self, _table: &str, _key: &str) -> Result<usize, DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    /// Reads the record at `index` in key order.
+    fn select(
+        &mut self,
+        _table: &str,
+        _index: usize,
+        _fields: Option<&[String]>,
+    ) -> Result<(String, ValueListType), DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    /// Removes up to `count` records starting at `index` in key order,
+    /// returning how many there were.
+    fn remove_index_range(
+        &mut self,
+        _table: &str,
+        _index: usize,
+        _count: usize,
+    ) -> Result<usize, DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    /// Begins a transaction covering the following operations of this
+    /// handle up to `commit` or `abort`. Backends without transactions run
+    /// every operation on its own and keep the default no-op hooks.
+    fn start(&mut self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<(), DBError> {
+        Ok(())
+    }
 
-    fn insert(&mut self, table: &str, key: String, values: ValueListType) -> Result<(), ()>;
+    fn cleanup(&mut self) {}
 }
@@ -0,0 +1,620 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+
+use collections::{HeapSize, Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::registry::Property;
+
+struct Version {
+    commit_ts: u64,
+    /// `None` is the tombstone left by a delete.
+    row: Option<Arc<RowValueType>>,
+}
+
+impl HeapSize for Version {
+    fn heap_size(&self) -> usize {
+        self.row.heap_size()
+    }
+}
+
+/// Versions of one key, oldest first.
+type ChainType = Arc<RwLock<Vec<Version>>>;
+
+/// Rows written by a transaction, with `None` for deleted keys.
+type WriteSet = BTreeMap<String, Option<Arc<RowValueType>>>;
+
+/// Snapshot-isolation store. A transaction reads the newest versions committed
+/// at or before its read timestamp and buffers its writes until commit, where
+/// it fails with `DBError::Conflict` if any written key got a version newer
+/// than its read timestamp (first committer wins).
+struct Store {
+    index: RwLock<BTreeMap<String, ChainType>>,
+    /// Timestamp of the last commit. Only advanced under `commit_lock` and
+    /// after the versions of the commit are installed.
+    clock: AtomicU64,
+    /// Serializes validation and installation of commits. Holds the number
+    /// of commits since the last full garbage collection pass.
+    commit_lock: Mutex<u64>,
+    /// Read timestamps of the running transactions with their counts.
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    gc_interval: u64,
+}
+
+pub struct Mvcc {
+    catalog: Arc<ColumnCatalog>,
+    store: Arc<Store>,
+}
+
+pub struct MvccHandle {
+    columns: CatalogCache,
+    store: Arc<Store>,
+    txn: Option<Transaction>,
+}
+
+struct Transaction {
+    read_ts: u64,
+    writes: WriteSet,
+}
+
+pub const PROPERTIES: &[Property] = &[Property {
+    name: "mvccgcinterval",
+    default: "1024",
+    description: "commits between full garbage collection passes over all keys",
+}];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "mvccgcinterval", default = "default_gc_interval")]
+    gc_interval: u64,
+}
+
+fn default_gc_interval() -> u64 {
+    1024
+}
+
+fn visible(chain: &[Version], ts: u64) -> Option<Arc<RowValueType>> {
+    chain
+        .iter()
+        .rev()
+        .find(|v| v.commit_ts <= ts)
+        .and_then(|v| v.row.clone())
+}
+
+/// Drops the versions no snapshot at or after `watermark` can see: all but
+/// the newest one committed at or before it, and that one too if it is a
+/// tombstone, since finding no version reads the same as finding it.
+fn prune(chain: &mut Vec<Version>, watermark: u64) {
+    if let Some(i) = chain.iter().rposition(|v| v.commit_ts <= watermark) {
+        let end = if chain[i].row.is_none() { i + 1 } else { i };
+        chain.drain(..end);
+    }
+}
+
+impl Store {
+    fn begin(&self) -> u64 {
+        let mut x = self.snapshots.lock().unwrap();
+        let ts = self.clock.load(Ordering::Acquire);
+        *x.entry(ts).or_insert(0) += 1;
+        ts
+    }
+
+    fn end(&self, ts: u64) {
+        let mut x = self.snapshots.lock().unwrap();
+        let n = x.get_mut(&ts).unwrap();
+        *n -= 1;
+        if *n == 0 {
+            x.remove(&ts);
+        }
+    }
+
+    /// Oldest timestamp any running or future transaction may read at.
+    fn watermark(&self) -> u64 {
+        let x = self.snapshots.lock().unwrap();
+        match x.keys().next() {
+            Some(ts) => *ts,
+            None => self.clock.load(Ordering::Acquire),
+        }
+    }
+
+    /// Reads `key` at `ts`; `None` reads the newest committed version.
+    fn get(&self, key: &str, ts: Option<u64>) -> Option<Arc<RowValueType>> {
+        let chain = self.index.read().unwrap().get(key).cloned()?;
+        let chain = chain.read().unwrap();
+        match ts {
+            Some(ts) => visible(&chain, ts),
+            None => chain.last().and_then(|v| v.row.clone()),
+        }
+    }
+
+    fn commit(&self, read_ts: u64, writes: WriteSet) -> Result<(), DBError> {
+        let mut commits = self.commit_lock.lock().unwrap();
+        let mut missing = Vec::new();
+        {
+            let index = self.index.read().unwrap();
+            for key in writes.keys() {
+                match index.get(key) {
+                    Some(chain) => {
+                        let chain = chain.read().unwrap();
+                        if chain.last().is_some_and(|v| v.commit_ts > read_ts) {
+                            return Err(DBError::Conflict);
+                        }
+                    }
+                    None => missing.push(key),
+                }
+            }
+        }
+        if !missing.is_empty() {
+            let mut index = self.index.write().unwrap();
+            for key in missing {
+                index.entry(key.clone()).or_default();
+            }
+        }
+
+        let commit_ts = self.clock.load(Ordering::Relaxed) + 1;
+        let watermark = self.watermark();
+        {
+            let index = self.index.read().unwrap();
+            for (key, row) in writes {
+                let mut chain = index[&key].write().unwrap();
+                chain.push(Version { commit_ts, row });
+                prune(&mut chain, watermark);
+            }
+        }
+        self.clock.store(commit_ts, Ordering::Release);
+
+        *commits += 1;
+        if *commits >= self.gc_interval {
+            *commits = 0;
+            self.collect(watermark);
+        }
+        Ok(())
+    }
+
+    /// Prunes the chains of every key, including keys not written recently,
+    /// and drops the keys left without versions. Called under `commit_lock`,
+    /// so no commit is installing versions meanwhile.
+    fn collect(&self, watermark: u64) {
+        let mut index = self.index.write().unwrap();
+        index.retain(|_, chain| {
+            let mut chain = chain.write().unwrap();
+            prune(&mut chain, watermark);
+            !chain.is_empty()
+        });
+    }
+
+    /// Runs `f` as its own transaction, retrying when it conflicts.
+    fn autocommit<F>(&self, mut f: F) -> Result<(), DBError>
+    where
+        F: FnMut(u64) -> Result<WriteSet, DBError>,
+    {
+        loop {
+            let ts = self.begin();
+            let ret = f(ts).and_then(|writes| self.commit(ts, writes));
+            self.end(ts);
+            match ret {
+                Err(DBError::Conflict) => continue,
+                ret => return ret,
+            }
+        }
+    }
+}
+
+impl DBFactory for Mvcc {
+    type DB = MvccHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        Mvcc {
+            catalog: ColumnCatalog::new(),
+            store: Arc::new(Store {
+                index: RwLock::new(BTreeMap::new()),
+                clock: AtomicU64::new(0),
+                commit_lock: Mutex::new(0),
+                snapshots: Mutex::new(BTreeMap::new()),
+                gc_interval: std::cmp::max(props.gc_interval, 1),
+            }),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        MvccHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            store: self.store.clone(),
+            txn: None,
+        }
+    }
+}
+
+/// Every version still kept counts as a value, so that garbage waiting for
+/// collection shows up.
+impl MemoryUsage for Mvcc {
+    fn memory_usage(&self) -> Memory {
+        self.store.index.read().unwrap().memory_usage()
+    }
+}
+
+impl MvccHandle {
+    /// Row of `key` as seen by the running transaction, including its own
+    /// writes, or the newest committed row outside of a transaction.
+    fn get(&self, key: &str) -> Option<Arc<RowValueType>> {
+        match &self.txn {
+            Some(txn) => match txn.writes.get(key) {
+                Some(row) => row.clone(),
+                None => self.store.get(key, Some(txn.read_ts)),
+            },
+            None => self.store.get(key, None),
+        }
+    }
+
+    fn end_transaction(&mut self) -> Option<Transaction> {
+        let txn = self.txn.take()?;
+        self.store.end(txn.read_ts);
+        Some(txn)
+    }
+}
+
+impl Drop for MvccHandle {
+    fn drop(&mut self) {
+        self.end_transaction();
+    }
+}
+
+impl DB for MvccHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = Arc::new(self.columns.build_row(values));
+        match &mut self.txn {
+            Some(txn) => {
+                txn.writes.insert(key, Some(row));
+                Ok(())
+            }
+            None => self
+                .store
+                .autocommit(|_| Ok(BTreeMap::from([(key.clone(), Some(row.clone()))]))),
+        }
+    }
+
+    fn batch_insert(
+        &mut self,
+        _: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let rows: WriteSet = records
+            .into_iter()
+            .map(|(key, values)| (key, Some(Arc::new(self.columns.build_row(values)))))
+            .collect();
+        match &mut self.txn {
+            Some(txn) => {
+                txn.writes.extend(rows);
+                Ok(())
+            }
+            None => self.store.autocommit(|_| Ok(rows.clone())),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.get(key) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let empty = BTreeMap::new();
+        let (read_ts, writes, snapshot) = match &self.txn {
+            Some(txn) => (txn.read_ts, &txn.writes, false),
+            None => (self.store.begin(), &empty, true),
+        };
+        let range = (Bound::Included(start_key), Bound::Unbounded);
+        let mut rows: Vec<(String, Arc<RowValueType>)> = Vec::with_capacity(record_count);
+        {
+            let index = self.store.index.read().unwrap();
+            let mut committed = index.range::<str, _>(range).peekable();
+            let mut own = writes.range::<str, _>(range).peekable();
+            while rows.len() < record_count {
+                // Merge the committed keys with the keys written by this
+                // transaction, which shadow committed versions.
+                let take_own = match (committed.peek(), own.peek()) {
+                    (None, None) => break,
+                    (Some(_), None) => false,
+                    (None, Some(_)) => true,
+                    (Some((k0, _)), Some((k1, _))) => k1 <= k0,
+                };
+                if take_own {
+                    let (k, row) = own.next().unwrap();
+                    if committed.peek().is_some_and(|(k0, _)| *k0 == k) {
+                        committed.next();
+                    }
+                    if let Some(row) = row {
+                        rows.push((k.clone(), row.clone()));
+                    }
+                } else {
+                    let (k, chain) = committed.next().unwrap();
+                    if let Some(row) = visible(&chain.read().unwrap(), read_ts) {
+                        rows.push((k.clone(), row));
+                    }
+                }
+            }
+        }
+        if snapshot {
+            self.store.end(read_ts);
+        }
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        if let Some(txn) = &mut self.txn {
+            let base = match txn.writes.get(key) {
+                Some(row) => row.clone(),
+                None => self.store.get(key, Some(txn.read_ts)),
+            };
+            let mut row = match base {
+                Some(row) => RowValueType::clone(&row),
+                None => return Err(DBError::NotFound),
+            };
+            catalog::apply(&mut row, values);
+            txn.writes.insert(key.to_string(), Some(Arc::new(row)));
+            return Ok(());
+        }
+        let store = &self.store;
+        store.autocommit(|ts| {
+            let mut row = match store.get(key, Some(ts)) {
+                Some(row) => RowValueType::clone(&row),
+                None => return Err(DBError::NotFound),
+            };
+            catalog::apply(&mut row, values.clone());
+            Ok(BTreeMap::from([(key.to_string(), Some(Arc::new(row)))]))
+        })
+    }
+
+    /// Writes a tombstone version, so that snapshots older than the delete
+    /// keep reading the record.
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        if let Some(txn) = &mut self.txn {
+            let exists = match txn.writes.get(key) {
+                Some(row) => row.is_some(),
+                None => self.store.get(key, Some(txn.read_ts)).is_some(),
+            };
+            if !exists {
+                return Err(DBError::NotFound);
+            }
+            txn.writes.insert(key.to_string(), None);
+            return Ok(());
+        }
+        let store = &self.store;
+        store.autocommit(|ts| match store.get(key, Some(ts)) {
+            Some(_) => Ok(BTreeMap::from([(key.to_string(), None)])),
+            None => Err(DBError::NotFound),
+        })
+    }
+
+    fn start(&mut self) -> Result<(), DBError> {
+        self.end_transaction();
+        self.txn = Some(Transaction {
+            read_ts: self.store.begin(),
+            writes: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), DBError> {
+        match self.end_transaction() {
+            Some(txn) if !txn.writes.is_empty() => self.store.commit(txn.read_ts, txn.writes),
+            _ => Ok(()),
+        }
+    }
+
+    fn abort(&mut self) -> Result<(), DBError> {
+        self.end_transaction();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(gc_interval: u64) -> Mvcc {
+        let props: Table = format!("mvccgcinterval = {}", gc_interval).parse().unwrap();
+        Mvcc::new(&props)
+    }
+
+    fn value(v: &str) -> ValueListType {
+        vec![("f".to_string(), v.as_bytes().to_vec())]
+    }
+
+    fn get(db: &mut MvccHandle, key: &str) -> Option<String> {
+        match db.read("t", key, None) {
+            Ok(values) => Some(String::from_utf8(values[0].1.clone()).unwrap()),
+            Err(DBError::NotFound) => None,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    fn scan(db: &mut MvccHandle) -> Vec<String> {
+        let rows = db.scan("t", "", 100, None).unwrap();
+        rows.into_iter().map(|(k, _)| k).collect()
+    }
+
+    fn versions(db: &Mvcc, key: &str) -> Option<usize> {
+        let index = db.store.index.read().unwrap();
+        index.get(key).map(|chain| chain.read().unwrap().len())
+    }
+
+    #[test]
+    fn first_committer_wins() {
+        let db = open(1024);
+        let (mut h0, mut h1) = (db.create(), db.create());
+        h0.insert("t", "k".to_string(), value("0")).unwrap();
+
+        h0.start().unwrap();
+        h1.start().unwrap();
+        h0.update("t", "k", value("a")).unwrap();
+        h1.update("t", "k", value("b")).unwrap();
+        assert_eq!(h0.commit(), Ok(()));
+        assert_eq!(h1.commit(), Err(DBError::Conflict));
+        assert_eq!(get(&mut h1, "k").as_deref(), Some("a"));
+
+        // A delete conflicts like any other write.
+        h0.start().unwrap();
+        h1.start().unwrap();
+        h0.delete("t", "k").unwrap();
+        h1.update("t", "k", value("c")).unwrap();
+        assert_eq!(h0.commit(), Ok(()));
+        assert_eq!(h1.commit(), Err(DBError::Conflict));
+        assert_eq!(get(&mut h1, "k"), None);
+    }
+
+    #[test]
+    fn reader_keeps_its_snapshot() {
+        let db = open(1024);
+        let (mut reader, mut writer) = (db.create(), db.create());
+        for k in ["a", "b", "c"] {
+            writer.insert("t", k.to_string(), value("0")).unwrap();
+        }
+
+        reader.start().unwrap();
+        writer.update("t", "a", value("1")).unwrap();
+        writer.delete("t", "b").unwrap();
+        writer.insert("t", "d".to_string(), value("1")).unwrap();
+
+        assert_eq!(get(&mut reader, "a").as_deref(), Some("0"));
+        assert_eq!(get(&mut reader, "b").as_deref(), Some("0"));
+        assert_eq!(get(&mut reader, "d"), None);
+        assert_eq!(scan(&mut reader), ["a", "b", "c"]);
+        assert_eq!(reader.commit(), Ok(()));
+
+        assert_eq!(get(&mut reader, "a").as_deref(), Some("1"));
+        assert_eq!(get(&mut reader, "b"), None);
+        assert_eq!(scan(&mut reader), ["a", "c", "d"]);
+    }
+
+    #[test]
+    fn transaction_sees_its_own_writes() {
+        let db = open(1024);
+        let mut h = db.create();
+        h.insert("t", "a".to_string(), value("0")).unwrap();
+        h.insert("t", "b".to_string(), value("0")).unwrap();
+
+        h.start().unwrap();
+        h.delete("t", "a").unwrap();
+        assert_eq!(h.delete("t", "a"), Err(DBError::NotFound));
+        assert_eq!(h.update("t", "a", value("1")), Err(DBError::NotFound));
+        h.insert("t", "c".to_string(), value("1")).unwrap();
+        assert_eq!(scan(&mut h), ["b", "c"]);
+        h.abort().unwrap();
+        assert_eq!(scan(&mut h), ["a", "b"]);
+
+        assert_eq!(h.delete("t", "x"), Err(DBError::NotFound));
+        h.delete("t", "a").unwrap();
+        h.insert("t", "a".to_string(), value("2")).unwrap();
+        assert_eq!(get(&mut h, "a").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn autocommit_retries_on_conflict() {
+        let db = open(1024);
+        let store = &db.store;
+        let row = |v: &str| Some(Arc::new(vec![Some(v.as_bytes().to_vec())]));
+        let mut attempts = 0;
+        store
+            .autocommit(|_| {
+                attempts += 1;
+                if attempts == 1 {
+                    // Another client commits the key after our snapshot.
+                    store
+                        .autocommit(|_| Ok(BTreeMap::from([("k".to_string(), row("other"))])))
+                        .unwrap();
+                }
+                Ok(BTreeMap::from([("k".to_string(), row("ours"))]))
+            })
+            .unwrap();
+        assert_eq!(attempts, 2);
+        assert_eq!(store.get("k", None), row("ours"));
+        assert!(store.snapshots.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn gc_keeps_versions_visible_to_snapshots() {
+        // Collect on every commit.
+        let db = open(1);
+        let (mut reader, mut writer) = (db.create(), db.create());
+        writer.insert("t", "a".to_string(), value("0")).unwrap();
+        writer.insert("t", "b".to_string(), value("0")).unwrap();
+
+        reader.start().unwrap();
+        for i in 1..10 {
+            writer.update("t", "a", value(&i.to_string())).unwrap();
+        }
+        writer.delete("t", "b").unwrap();
+        assert_eq!(get(&mut reader, "a").as_deref(), Some("0"));
+        assert_eq!(get(&mut reader, "b").as_deref(), Some("0"));
+        assert_eq!(versions(&db, "a"), Some(10));
+        assert_eq!(versions(&db, "b"), Some(2));
+        reader.commit().unwrap();
+
+        // The next commit collects everything older than the newest
+        // versions, and the deleted key altogether.
+        writer.insert("t", "c".to_string(), value("0")).unwrap();
+        assert_eq!(versions(&db, "a"), Some(1));
+        assert_eq!(versions(&db, "b"), None);
+        assert_eq!(get(&mut reader, "a").as_deref(), Some("9"));
+        assert_eq!(get(&mut reader, "b"), None);
+    }
+
+    #[test]
+    fn prune_keeps_newest_version_at_watermark() {
+        let chain = |ts: &[(u64, bool)]| -> Vec<Version> {
+            ts.iter()
+                .map(|&(commit_ts, live)| Version {
+                    commit_ts,
+                    row: live.then(|| Arc::new(vec![])),
+                })
+                .collect()
+        };
+        let commit_ts = |chain: &[Version]| chain.iter().map(|v| v.commit_ts).collect::<Vec<_>>();
+
+        let mut c = chain(&[(1, true), (3, true), (5, true)]);
+        prune(&mut c, 0);
+        assert_eq!(commit_ts(&c), [1, 3, 5]);
+        prune(&mut c, 4);
+        assert_eq!(commit_ts(&c), [3, 5]);
+        prune(&mut c, 9);
+        assert_eq!(commit_ts(&c), [5]);
+
+        // A tombstone visible at the watermark is dropped with everything
+        // before it, but not while a snapshot may see the row before it.
+        let mut c = chain(&[(1, true), (3, false), (5, true)]);
+        prune(&mut c, 2);
+        assert_eq!(commit_ts(&c), [1, 3, 5]);
+        prune(&mut c, 3);
+        assert_eq!(commit_ts(&c), [5]);
+        for ts in 3..9 {
+            assert_eq!(visible(&c, ts).is_some(), ts >= 5);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use collections::{Art, BPlusTree, Memory, MemoryUsage, OrderedMap, PersistentMap, SkipList};
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+/// Any `OrderedMap` of the collections crate behind a single RwLock, so
+/// that the maps are compared through the same code. Each map is
+/// registered as its own `ordered_map_*` database.
+pub struct OrderedMapDB<M> {
+    catalog: Arc<ColumnCatalog>,
+    map: Arc<RwLock<M>>,
+}
+
+pub struct OrderedMapHandle<M> {
+    columns: CatalogCache,
+    map: Arc<RwLock<M>>,
+}
+
+pub type OrderedBTreeMap = OrderedMapDB<BTreeMap<String, RowValueType>>;
+pub type OrderedSkipList = OrderedMapDB<SkipList<String, RowValueType>>;
+pub type OrderedBPlusTree = OrderedMapDB<BPlusTree<String, RowValueType>>;
+pub type OrderedArt = OrderedMapDB<Art<RowValueType>>;
+pub type OrderedPersistentMap = OrderedMapDB<PersistentMap<String, RowValueType>>;
+
+pub const PROPERTIES: &[Property] = &[];
+
+impl<M: MemoryUsage> MemoryUsage for OrderedMapDB<M> {
+    fn memory_usage(&self) -> Memory {
+        self.map.read().unwrap().memory_usage()
+    }
+}
+
+impl<M> DBFactory for OrderedMapDB<M>
+where
+    M: OrderedMap<str, Key = String, Value = RowValueType>
+        + MemoryUsage
+        + Default
+        + Send
+        + Sync
+        + 'static,
+{
+    type DB = OrderedMapHandle<M>;
+
+    fn new(_: &Table) -> Self {
+        OrderedMapDB {
+            catalog: ColumnCatalog::new(),
+            map: Arc::new(RwLock::new(M::default())),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        OrderedMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            map: self.map.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        let len = self.map.read().unwrap().len();
+        measurements.count("ORDEREDMAP", "Records", len as u64);
+    }
+}
+
+impl<M> DB for OrderedMapHandle<M>
+where
+    M: OrderedMap<str, Key = String, Value = RowValueType> + Send + Sync + 'static,
+{
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.map.write().unwrap().insert(key, row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        let row = self.map.read().unwrap().get(key).cloned();
+        match row {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let rows: Vec<(String, RowValueType)> = self
+            .map
+            .read()
+            .unwrap()
+            .range(Bound::Included(start_key), Bound::Unbounded)
+            .take(record_count)
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        match self.map.write().unwrap().get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        match self.map.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        }
+    }
+}
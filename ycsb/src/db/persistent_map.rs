@@ -0,0 +1,138 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use collections::VersionCell;
+use collections::{Memory, MemoryUsage};
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+/// Keys and rows are reference counted so that copying a node on a write
+/// path copies pointers only.
+type CellType = VersionCell<Arc<str>, Arc<RowValueType>>;
+
+pub struct PersistentMapDB {
+    catalog: Arc<ColumnCatalog>,
+    cell: Arc<CellType>,
+}
+
+pub struct PersistentMapHandle {
+    columns: CatalogCache,
+    cell: Arc<CellType>,
+}
+
+pub const PROPERTIES: &[Property] = &[];
+
+impl DBFactory for PersistentMapDB {
+    type DB = PersistentMapHandle;
+
+    fn new(_: &Table) -> Self {
+        PersistentMapDB {
+            catalog: ColumnCatalog::new(),
+            cell: Arc::new(VersionCell::default()),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        PersistentMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            cell: self.cell.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        let height = self.cell.read(|map| map.height());
+        measurements.count("PERSISTENT", "Height", height as u64);
+        measurements.count("PERSISTENT", "Write-Retries", self.cell.retries());
+    }
+}
+
+impl MemoryUsage for PersistentMapDB {
+    fn memory_usage(&self) -> Memory {
+        self.cell.memory_usage()
+    }
+}
+
+impl DB for PersistentMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let key: Arc<str> = key.into();
+        let row = Arc::new(self.columns.build_row(values));
+        self.cell.update(|map| map.insert(key.clone(), row.clone()));
+        Ok(())
+    }
+
+    /// Publishes the whole batch as one version.
+    fn batch_insert(
+        &mut self,
+        _: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let rows: Vec<(Arc<str>, Arc<RowValueType>)> = records
+            .into_iter()
+            .map(|(key, values)| (key.into(), Arc::new(self.columns.build_row(values))))
+            .collect();
+        self.cell.update(|map| map.extend(rows.iter().cloned()));
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.cell.read(|map| map.get(key).cloned()) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    /// Scans a snapshot, so that writers go on while the rows are copied.
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let snapshot = self.cell.snapshot();
+        Ok(snapshot
+            .range::<str, _>((Bound::Included(start_key), Bound::Unbounded))
+            .take(record_count)
+            .map(|(k, row)| {
+                (
+                    k.to_string(),
+                    self.columns.row_values(row, projection.as_deref()),
+                )
+            })
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        let found = self.cell.update(|map| match map.get_mut(key) {
+            Some(row) => {
+                catalog::apply(Arc::make_mut(row), values.clone());
+                true
+            }
+            None => false,
+        });
+        if found {
+            Ok(())
+        } else {
+            Err(DBError::NotFound)
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        match self.cell.update(|map| map.remove(key)) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use collections::{HeapSize, Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::shard::{self, Shard};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+use crate::workloads::build_key_name;
+
+type MapType = BTreeMap<String, RowValueType>;
+
+/// Number of keys sampled per partition to place the boundaries.
+const SAMPLES_PER_PARTITION: u64 = 1024;
+
+/// Key space split into `partitions` contiguous ranges, each its own locked
+/// `BTreeMap`. Partition `i` holds the keys in `[lower[i], lower[i + 1])`.
+struct Partitions {
+    lower: Vec<String>,
+    shards: Vec<Shard<MapType>>,
+}
+
+impl Partitions {
+    fn index(&self, key: &str) -> usize {
+        self.lower.partition_point(|x| x.as_str() <= key) - 1
+    }
+}
+
+pub struct RangeBTreeMap {
+    catalog: Arc<ColumnCatalog>,
+    partitions: Arc<Partitions>,
+}
+
+pub struct RangeBTreeMapHandle {
+    columns: CatalogCache,
+    partitions: Arc<Partitions>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "partitions",
+        default: "16",
+        description: "number of key ranges, placed by sampling the keys of recordcount records",
+    },
+    Property {
+        name: "partitionlock",
+        default: "rwlock",
+        description: "lock guarding each key range (mutex, rwlock)",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(default = "default_partitions")]
+    partitions: u32,
+
+    #[serde(rename = "partitionlock", default = "default_partition_lock")]
+    partition_lock: String,
+
+    #[serde(rename = "recordcount", default = "default_record_count")]
+    record_count: u64,
+
+    #[serde(rename = "insertstart", default = "default_insert_start")]
+    insert_start: u64,
+
+    #[serde(rename = "zeropadding", default = "default_zero_padding")]
+    zero_padding: u32,
+
+    #[serde(rename = "insertorder", default = "default_insert_order")]
+    insert_order: String,
+}
+
+fn default_partitions() -> u32 {
+    16
+}
+fn default_partition_lock() -> String {
+    "rwlock".to_string()
+}
+fn default_record_count() -> u64 {
+    0
+}
+fn default_insert_start() -> u64 {
+    0
+}
+fn default_zero_padding() -> u32 {
+    1
+}
+fn default_insert_order() -> String {
+    "hashed".to_string()
+}
+
+/// Lower bounds of the partitions, chosen as quantiles of a sample of the
+/// keys `CoreWorkload` generates so that each range receives about the same
+/// number of records whatever the padding and insert order.
+fn boundaries(props: &Properties) -> Vec<String> {
+    let partitions = std::cmp::max(props.partitions, 1) as u64;
+    let samples = std::cmp::min(props.record_count, partitions * SAMPLES_PER_PARTITION);
+    let ordered_inserts = props.insert_order != "hashed";
+    let mut keys: Vec<String> = (0..samples)
+        .map(|i| {
+            let n = props.insert_start + i * props.record_count / samples;
+            build_key_name(n, props.zero_padding, ordered_inserts)
+        })
+        .collect();
+    keys.sort();
+
+    let mut lower = vec![String::new()];
+    for i in 1..partitions {
+        if let Some(key) = keys.get((i * samples / partitions) as usize) {
+            if lower.last().unwrap() < key {
+                lower.push(key.clone());
+            }
+        }
+    }
+    lower
+}
+
+impl DBFactory for RangeBTreeMap {
+    type DB = RangeBTreeMapHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let lower = boundaries(&props);
+        let shards = lower
+            .iter()
+            .map(|_| Shard::new(&props.partition_lock))
+            .collect();
+        RangeBTreeMap {
+            catalog: ColumnCatalog::new(),
+            partitions: Arc::new(Partitions { lower, shards }),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        RangeBTreeMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            partitions: self.partitions.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        shard::report("PARTITIONS", &self.partitions.shards, measurements);
+    }
+}
+
+impl MemoryUsage for RangeBTreeMap {
+    fn memory_usage(&self) -> Memory {
+        let partitions = &self.partitions;
+        let mut memory = partitions
+            .shards
+            .iter()
+            .map(MemoryUsage::memory_usage)
+            .fold(Memory::default(), |a, b| a + b);
+        memory.overhead += partitions.lower.heap_size();
+        memory
+    }
+}
+
+impl RangeBTreeMapHandle {
+    fn shard(&self, key: &str) -> &Shard<MapType> {
+        &self.partitions.shards[self.partitions.index(key)]
+    }
+}
+
+impl DB for RangeBTreeMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.shard(&key).write(|x| x.insert(key, row));
+        Ok(())
+    }
+
+    fn batch_insert(
+        &mut self,
+        _: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let mut partitions: Vec<Vec<(String, RowValueType)>> =
+            self.partitions.shards.iter().map(|_| Vec::new()).collect();
+        for (key, values) in records {
+            let row = self.columns.build_row(values);
+            partitions[self.partitions.index(&key)].push((key, row));
+        }
+        for (shard, rows) in self.partitions.shards.iter().zip(partitions) {
+            if !rows.is_empty() {
+                shard.write(|x| x.extend(rows));
+            }
+        }
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.shard(key).read(|x| x.get(key).cloned()) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    /// Walks the partition holding `start_key` and its successors, locking
+    /// one partition at a time. The result is ordered but, unlike a scan of
+    /// the global map, not a snapshot across partitions.
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let mut rows: Vec<(String, RowValueType)> = Vec::with_capacity(record_count);
+        let first = self.partitions.index(start_key);
+        for shard in &self.partitions.shards[first..] {
+            if rows.len() >= record_count {
+                break;
+            }
+            shard.read(|x| {
+                let n = record_count - rows.len();
+                rows.extend(
+                    x.range::<str, _>((Bound::Included(start_key), Bound::Unbounded))
+                        .take(n)
+                        .map(|(k, v)| (k.clone(), v.clone())),
+                );
+            });
+        }
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        self.shard(key).write(|x| match x.get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        })
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        self.shard(key).write(|x| match x.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props(text: &str) -> Table {
+        text.parse().unwrap()
+    }
+
+    fn hashed_keys(n: u64) -> Vec<String> {
+        (0..n).map(|i| build_key_name(i, 1, false)).collect()
+    }
+
+    #[test]
+    fn boundaries_are_sorted_and_distinct() {
+        for insert_order in ["hashed", "ordered"] {
+            for (records, partitions) in [(0, 4), (3, 16), (100, 4), (100_000, 16)] {
+                let props: Properties = props(&format!(
+                    "recordcount = {}\npartitions = {}\ninsertorder = \"{}\"",
+                    records, partitions, insert_order
+                ))
+                .try_into()
+                .unwrap();
+                let lower = boundaries(&props);
+                assert_eq!(lower[0], "");
+                assert!(lower.windows(2).all(|x| x[0] < x[1]), "{:?}", lower);
+                assert!(lower.len() <= std::cmp::min(partitions, records + 1) as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_outside_the_sampled_range_have_a_partition() {
+        let db = RangeBTreeMap::new(&props("recordcount = 1000\ninsertorder = \"ordered\""));
+        let partitions = &db.partitions;
+        assert_eq!(partitions.lower.len(), 16);
+        assert_eq!(partitions.index(""), 0);
+        assert_eq!(partitions.index("a"), 0);
+        assert_eq!(partitions.index("user0"), 0);
+        assert_eq!(partitions.index("user999999"), 15);
+        assert_eq!(partitions.index("zzz"), 15);
+        for (i, lower) in partitions.lower.iter().enumerate() {
+            assert_eq!(partitions.index(lower), i);
+        }
+
+        let mut h = db.create();
+        for key in ["", "a", "user999999", "zzz"] {
+            h.insert("t", key.to_string(), vec![]).unwrap();
+            assert_eq!(h.read("t", key, None), Ok(vec![]));
+        }
+    }
+
+    #[test]
+    fn scan_crosses_partitions_in_key_order() {
+        let record_count = 1000;
+        let db = RangeBTreeMap::new(&props(&format!("recordcount = {}", record_count)));
+        let mut h = db.create();
+        let mut expected = hashed_keys(record_count);
+        h.batch_insert("t", expected.iter().map(|k| (k.clone(), vec![])).collect())
+            .unwrap();
+        expected.sort();
+        let occupied = db
+            .partitions
+            .shards
+            .iter()
+            .filter(|x| x.read(|x| !x.is_empty()))
+            .count();
+        assert_eq!(occupied, db.partitions.shards.len());
+
+        let scan = |h: &mut RangeBTreeMapHandle, start: &str, n: usize| -> Vec<String> {
+            let rows = h.scan("t", start, n, None).unwrap();
+            rows.into_iter().map(|(k, _)| k).collect()
+        };
+        assert_eq!(scan(&mut h, "", record_count as usize), expected);
+        assert_eq!(scan(&mut h, "", 2 * record_count as usize), expected);
+        assert_eq!(scan(&mut h, &expected[500], 300), expected[500..800]);
+
+        for key in &expected[..500] {
+            h.delete("t", key).unwrap();
+        }
+        assert_eq!(h.delete("t", &expected[0]), Err(DBError::NotFound));
+        assert_eq!(scan(&mut h, "", 100), expected[500..600]);
+    }
+}
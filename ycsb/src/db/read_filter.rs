@@ -0,0 +1,371 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use collections::bloom::{self, BlockedBloomFilter, BloomFilter, Filter};
+use collections::{Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::{DBError, DynDBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+/// Filter shared by the handles of one factory. It is seeded with the keys
+/// the backend holds when it is wrapped, such as those a persistent backend
+/// recovered, and keys are added before they are inserted, so a negative
+/// answer is never wrong.
+struct Shared {
+    filter: RwLock<Box<dyn Filter + Send + Sync>>,
+    /// Reads answered by the filter without reaching the backend.
+    skips: AtomicU64,
+    /// Reads passed on to the backend.
+    passes: AtomicU64,
+    /// Passed reads the backend did not find.
+    false_positives: AtomicU64,
+}
+
+/// Puts a membership filter in front of the reads of any backend to measure
+/// what answering misses without a lookup saves.
+struct ReadFilter {
+    inner: Arc<dyn DynDBFactory>,
+    shared: Arc<Shared>,
+}
+
+struct ReadFilterHandle {
+    db: Box<dyn DB>,
+    shared: Arc<Shared>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "readfilter",
+        default: "none",
+        description: "filter in front of reads of any db: none, bloom or blocked",
+    },
+    Property {
+        name: "readfilterbitsperkey",
+        default: "10",
+        description: "bits per key of the read filter",
+    },
+    Property {
+        name: "readfilterhashes",
+        default: "0",
+        description: "probes per key of the read filter, 0 for the optimum",
+    },
+    Property {
+        name: "readfilterkeys",
+        default: "recordcount",
+        description: "keys the read filter is sized for",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "readfilter", default = "default_read_filter")]
+    read_filter: String,
+
+    #[serde(rename = "readfilterbitsperkey", default = "default_bits_per_key")]
+    bits_per_key: usize,
+
+    #[serde(rename = "readfilterhashes", default = "default_hashes")]
+    hashes: u32,
+
+    #[serde(rename = "readfilterkeys")]
+    keys: Option<usize>,
+
+    #[serde(rename = "recordcount", default = "default_record_count")]
+    record_count: usize,
+
+    #[serde(default = "default_table")]
+    table: String,
+}
+
+fn default_read_filter() -> String {
+    "none".to_string()
+}
+fn default_bits_per_key() -> usize {
+    10
+}
+fn default_hashes() -> u32 {
+    0
+}
+fn default_record_count() -> usize {
+    0
+}
+fn default_table() -> String {
+    "usertable".to_string()
+}
+
+/// Keys read per scan while seeding the filter.
+const SEED_BATCH: usize = 1024;
+
+/// Wraps `inner` in a read filter if the `readfilter` property asks for one.
+pub fn wrap(props: &Table, inner: Arc<dyn DynDBFactory>) -> Arc<dyn DynDBFactory> {
+    let props: Properties = props.clone().try_into().unwrap();
+    let keys = props.keys.unwrap_or(props.record_count);
+    let mut filter: Box<dyn Filter + Send + Sync> = match (&*props.read_filter, props.hashes) {
+        ("none", _) => return inner,
+        ("bloom", 0) => Box::new(BloomFilter::new(keys, props.bits_per_key)),
+        ("bloom", n) => Box::new(BloomFilter::with_hashes(keys, props.bits_per_key, n)),
+        ("blocked", 0) => Box::new(BlockedBloomFilter::new(keys, props.bits_per_key)),
+        ("blocked", n) => Box::new(BlockedBloomFilter::with_hashes(keys, props.bits_per_key, n)),
+        _ => panic!("invalid readfilter"),
+    };
+    seed(&*inner, &props.table, &mut *filter);
+    Arc::new(ReadFilter {
+        inner,
+        shared: Arc::new(Shared {
+            filter: RwLock::new(filter),
+            skips: AtomicU64::new(0),
+            passes: AtomicU64::new(0),
+            false_positives: AtomicU64::new(0),
+        }),
+    })
+}
+
+/// Adds the keys already in `inner` to `filter`, scanning them in key
+/// order. Backends that cannot scan are in-memory hash tables, which start
+/// empty.
+fn seed(inner: &dyn DynDBFactory, table: &str, filter: &mut (dyn Filter + Send + Sync)) {
+    let mut db = inner.create();
+    let mut start = String::new();
+    loop {
+        let rows = match db.scan(table, &start, SEED_BATCH, Some(&[])) {
+            Ok(rows) => rows,
+            Err(DBError::NotImplemented) => return,
+            Err(e) => panic!("cannot seed the read filter: {:?}", e),
+        };
+        for (key, _) in &rows {
+            filter.insert_hash(bloom::hash(key.as_bytes()));
+        }
+        match rows.last() {
+            // The smallest key after the last one returned.
+            Some((key, _)) if rows.len() == SEED_BATCH => start = format!("{}\0", key),
+            _ => return,
+        }
+    }
+}
+
+impl MemoryUsage for ReadFilter {
+    fn memory_usage(&self) -> Memory {
+        self.inner.memory_usage() + self.shared.filter.read().unwrap().memory_usage()
+    }
+}
+
+impl DynDBFactory for ReadFilter {
+    fn create(&self) -> Box<dyn DB> {
+        Box::new(ReadFilterHandle {
+            db: self.inner.create(),
+            shared: self.shared.clone(),
+        })
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        self.inner.report(measurements);
+        let shared = &self.shared;
+        measurements.count("FILTER", "Skips", shared.skips.swap(0, Ordering::Relaxed));
+        measurements.count("FILTER", "Passes", shared.passes.swap(0, Ordering::Relaxed));
+        measurements.count(
+            "FILTER",
+            "False-Positives",
+            shared.false_positives.swap(0, Ordering::Relaxed),
+        );
+    }
+}
+
+impl ReadFilterHandle {
+    fn add(&self, hashes: impl IntoIterator<Item = u64>) {
+        let mut filter = self.shared.filter.write().unwrap();
+        for h in hashes {
+            filter.insert_hash(h);
+        }
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        let h = bloom::hash(key.as_bytes());
+        let ret = self.shared.filter.read().unwrap().may_contain_hash(h);
+        let counter = if ret {
+            &self.shared.passes
+        } else {
+            &self.shared.skips
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        ret
+    }
+
+    fn count_miss<T>(&self, ret: &Result<T, DBError>) {
+        if matches!(ret, Err(DBError::NotFound)) {
+            self.shared.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl DB for ReadFilterHandle {
+    fn insert(&mut self, table: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        self.add([bloom::hash(key.as_bytes())]);
+        self.db.insert(table, key, values)
+    }
+
+    fn batch_insert(
+        &mut self,
+        table: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        self.add(records.iter().map(|(key, _)| bloom::hash(key.as_bytes())));
+        self.db.batch_insert(table, records)
+    }
+
+    fn read(
+        &mut self,
+        table: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        if !self.may_contain(key) {
+            return Err(DBError::NotFound);
+        }
+        let ret = self.db.read(table, key, fields);
+        self.count_miss(&ret);
+        ret
+    }
+
+    /// Reads the keys that pass the filter in one call to the backend.
+    fn multi_read(
+        &mut self,
+        table: &str,
+        keys: &[String],
+        fields: Option<&[String]>,
+    ) -> Vec<Result<ValueListType, DBError>> {
+        let passed: Vec<bool> = keys.iter().map(|key| self.may_contain(key)).collect();
+        let lookups: Vec<String> = keys
+            .iter()
+            .zip(&passed)
+            .filter(|(_, passed)| **passed)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut found = self.db.multi_read(table, &lookups, fields).into_iter();
+        passed
+            .into_iter()
+            .map(|passed| {
+                if !passed {
+                    return Err(DBError::NotFound);
+                }
+                let ret = found.next().unwrap();
+                self.count_miss(&ret);
+                ret
+            })
+            .collect()
+    }
+
+    fn scan(
+        &mut self,
+        table: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        self.db.scan(table, start_key, record_count, fields)
+    }
+
+    fn update(&mut self, table: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        self.db.update(table, key, values)
+    }
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), DBError> {
+        self.db.delete(table, key)
+    }
+
+    fn rank(&mut self, table: &str, key: &str) -> Result<usize, DBError> {
+        self.db.rank(table, key)
+    }
+
+    fn select(
+        &mut self,
+        table: &str,
+        index: usize,
+        fields: Option<&[String]>,
+    ) -> Result<(String, ValueListType), DBError> {
+        self.db.select(table, index, fields)
+    }
+
+    /// Leaves the removed keys in the filter, which only costs false
+    /// positives.
+    fn remove_index_range(
+        &mut self,
+        table: &str,
+        index: usize,
+        count: usize,
+    ) -> Result<usize, DBError> {
+        self.db.remove_index_range(table, index, count)
+    }
+
+    fn start(&mut self) -> Result<(), DBError> {
+        self.db.start()
+    }
+
+    fn commit(&mut self) -> Result<(), DBError> {
+        self.db.commit()
+    }
+
+    fn abort(&mut self) -> Result<(), DBError> {
+        self.db.abort()
+    }
+
+    fn cleanup(&mut self) {
+        self.db.cleanup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collections::testing::TestDir;
+
+    use crate::db::logstore::LogStore;
+    use crate::db::std_btree::StdBTreeMapMutex;
+    use crate::db::DBFactory;
+
+    const KEYS: usize = 3000;
+
+    fn key(i: usize) -> String {
+        format!("user{}", i)
+    }
+
+    /// Writes `KEYS` records through an unfiltered backend, then reopens it
+    /// behind a read filter and reads them back.
+    fn recovered_keys_are_found<F: DBFactory>(name: &str, dir_property: &str) {
+        let dir = TestDir::new(&format!("read-filter-{}", name));
+        let props: Table = format!(
+            "{} = {:?}\nreadfilter = \"bloom\"\nrecordcount = {}",
+            dir_property,
+            dir.path().to_str().unwrap(),
+            KEYS
+        )
+        .parse()
+        .unwrap();
+
+        let db = F::new(&props);
+        let mut h = db.create();
+        for i in 0..KEYS {
+            h.insert("usertable", key(i), vec![]).unwrap();
+        }
+        drop((h, db));
+
+        let db = wrap(&props, Arc::new(F::new(&props)));
+        let mut h = db.create();
+        for i in 0..KEYS {
+            assert_eq!(h.read("usertable", &key(i), None), Ok(vec![]), "{}", key(i));
+        }
+        assert_eq!(h.read("usertable", "missing", None), Err(DBError::NotFound));
+    }
+
+    #[test]
+    fn logstore_recovered_keys_are_found() {
+        recovered_keys_are_found::<LogStore>("logstore", "logstoredir");
+    }
+
+    #[test]
+    fn wal_recovered_keys_are_found() {
+        recovered_keys_are_found::<StdBTreeMapMutex>("wal", "waldir");
+    }
+}
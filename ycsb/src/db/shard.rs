@@ -0,0 +1,156 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    LockResult, Mutex, RwLock, TryLockError, TryLockResult,
+};
+
+use collections::{Memory, MemoryUsage};
+
+use crate::measurements::Measurements;
+
+enum ShardLock<M> {
+    Mutex(Mutex<M>),
+    RwLock(RwLock<M>),
+}
+
+/// One locked partition of a partitioned backend. Every acquisition is
+/// counted in `ops`, and in `contended` when the lock was not immediately
+/// available.
+pub struct Shard<M> {
+    lock: ShardLock<M>,
+    ops: AtomicU64,
+    contended: AtomicU64,
+}
+
+impl<M: Default> Shard<M> {
+    /// Creates an empty shard guarded by `lock`, which is "mutex" or
+    /// "rwlock".
+    pub fn new(lock: &str) -> Self {
+        Shard {
+            lock: match lock {
+                "mutex" => ShardLock::Mutex(Mutex::new(M::default())),
+                "rwlock" => ShardLock::RwLock(RwLock::new(M::default())),
+                _ => {
+                    panic!("invalid shard lock: {}", lock);
+                }
+            },
+            ops: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<M> Shard<M> {
+    pub fn read<R>(&self, f: impl FnOnce(&M) -> R) -> R {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        match &self.lock {
+            ShardLock::Mutex(x) => {
+                let guard = self.acquire(x.try_lock(), || x.lock());
+                f(&guard)
+            }
+            ShardLock::RwLock(x) => {
+                let guard = self.acquire(x.try_read(), || x.read());
+                f(&guard)
+            }
+        }
+    }
+
+    pub fn write<R>(&self, f: impl FnOnce(&mut M) -> R) -> R {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        match &self.lock {
+            ShardLock::Mutex(x) => {
+                let mut guard = self.acquire(x.try_lock(), || x.lock());
+                f(&mut guard)
+            }
+            ShardLock::RwLock(x) => {
+                let mut guard = self.acquire(x.try_write(), || x.write());
+                f(&mut guard)
+            }
+        }
+    }
+
+    fn acquire<G>(&self, guard: TryLockResult<G>, lock: impl FnOnce() -> LockResult<G>) -> G {
+        match guard {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                lock().unwrap()
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+}
+
+/// Locks the shard without counting the acquisition, so that measuring
+/// memory leaves the operation counts alone.
+impl<M: MemoryUsage> MemoryUsage for Shard<M> {
+    fn memory_usage(&self) -> Memory {
+        match &self.lock {
+            ShardLock::Mutex(x) => x.lock().unwrap().memory_usage(),
+            ShardLock::RwLock(x) => x.read().unwrap().memory_usage(),
+        }
+    }
+}
+
+/// Reports and resets the counters of `shards` under `op`.
+pub fn report<M>(op: &str, shards: &[Shard<M>], measurements: &mut Measurements) {
+    for (i, shard) in shards.iter().enumerate() {
+        let ops = shard.ops.swap(0, Ordering::Relaxed);
+        let contended = shard.contended.swap(0, Ordering::Relaxed);
+        measurements.count(op, &format!("Shard{:03}-Operations", i), ops);
+        measurements.count(op, &format!("Shard{:03}-Contended", i), contended);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    fn contended_while_held(lock: &str) -> (u64, u64) {
+        let shard = Arc::new(Shard::<u64>::new(lock));
+        let (held, hold) = (mpsc::channel(), mpsc::channel::<()>());
+        let owner = {
+            let shard = shard.clone();
+            thread::spawn(move || {
+                shard.write(|x| {
+                    held.0.send(()).unwrap();
+                    hold.1.recv().unwrap();
+                    *x += 1;
+                })
+            })
+        };
+        held.1.recv().unwrap();
+        let reader = {
+            let shard = shard.clone();
+            thread::spawn(move || shard.read(|x| *x))
+        };
+        // The reader counts the contention before it blocks on the lock.
+        while shard.contended.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+        hold.0.send(()).unwrap();
+        owner.join().unwrap();
+        assert_eq!(reader.join().unwrap(), 1);
+        (
+            shard.ops.load(Ordering::Relaxed),
+            shard.contended.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn counts_contended_acquisitions() {
+        assert_eq!(contended_while_held("mutex"), (2, 1));
+        assert_eq!(contended_while_held("rwlock"), (2, 1));
+    }
+
+    #[test]
+    fn uncontended_acquisitions() {
+        let shard = Shard::<u64>::new("rwlock");
+        shard.write(|x| *x = 1);
+        // Shared acquisitions do not contend with each other.
+        shard.read(|_| shard.read(|x| assert_eq!(*x, 1)));
+        assert_eq!(shard.ops.load(Ordering::Relaxed), 3);
+        assert_eq!(shard.contended.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use collections::{Memory, MemoryUsage};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::shard::{self, Shard};
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+type MapType = HashMap<String, RowValueType>;
+
+pub struct ShardedHashMap {
+    catalog: Arc<ColumnCatalog>,
+    shards: Arc<Vec<Shard<MapType>>>,
+}
+
+pub struct ShardedHashMapHandle {
+    columns: CatalogCache,
+    shards: Arc<Vec<Shard<MapType>>>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "shards",
+        default: "16",
+        description: "number of partitions of the key space",
+    },
+    Property {
+        name: "shardlock",
+        default: "mutex",
+        description: "lock guarding each partition (mutex, rwlock)",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(default = "default_shards")]
+    shards: u32,
+
+    #[serde(rename = "shardlock", default = "default_shard_lock")]
+    shard_lock: String,
+}
+
+fn default_shards() -> u32 {
+    16
+}
+fn default_shard_lock() -> String {
+    "mutex".to_string()
+}
+
+impl DBFactory for ShardedHashMap {
+    type DB = ShardedHashMapHandle;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let shards = (0..std::cmp::max(props.shards, 1))
+            .map(|_| Shard::new(&props.shard_lock))
+            .collect();
+        ShardedHashMap {
+            catalog: ColumnCatalog::new(),
+            shards: Arc::new(shards),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        ShardedHashMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            shards: self.shards.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        shard::report("SHARDS", &self.shards, measurements);
+    }
+}
+
+impl MemoryUsage for ShardedHashMap {
+    fn memory_usage(&self) -> Memory {
+        self.shards
+            .iter()
+            .map(MemoryUsage::memory_usage)
+            .fold(Memory::default(), |a, b| a + b)
+    }
+}
+
+impl ShardedHashMapHandle {
+    fn shard_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &Shard<MapType> {
+        &self.shards[self.shard_index(key)]
+    }
+}
+
+impl DB for ShardedHashMapHandle {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.shard(&key).write(|x| x.insert(key, row));
+        Ok(())
+    }
+
+    fn batch_insert(
+        &mut self,
+        _: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let mut partitions: Vec<Vec<(String, RowValueType)>> =
+            (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for (key, values) in records {
+            let row = self.columns.build_row(values);
+            partitions[self.shard_index(&key)].push((key, row));
+        }
+        for (shard, rows) in self.shards.iter().zip(partitions) {
+            if !rows.is_empty() {
+                shard.write(|x| x.extend(rows));
+            }
+        }
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        match self.shard(key).read(|x| x.get(key).cloned()) {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: usize,
+        _: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        Err(DBError::NotImplemented)
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        self.shard(key).write(|x| match x.get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        })
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        self.shard(key).write(|x| match x.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurements::tests::counter;
+
+    fn open(shards: u32) -> ShardedHashMap {
+        let props: Table = format!("shards = {}", shards).parse().unwrap();
+        ShardedHashMap::new(&props)
+    }
+
+    /// Reports and resets the operation counters of the shards.
+    fn ops(db: &ShardedHashMap) -> Vec<u64> {
+        let mut m = Measurements::new();
+        db.report(&mut m);
+        (0..db.shards.len())
+            .map(|i| counter(&m, "SHARDS", &format!("Shard{:03}-Operations", i)))
+            .collect()
+    }
+
+    #[test]
+    fn keys_live_in_their_shard() {
+        let db = open(8);
+        let mut h = db.create();
+        let keys: Vec<String> = (0..200).map(|i| format!("user{}", i)).collect();
+        for key in &keys[..100] {
+            h.insert("t", key.clone(), vec![]).unwrap();
+        }
+        h.batch_insert(
+            "t",
+            keys[100..].iter().map(|k| (k.clone(), vec![])).collect(),
+        )
+        .unwrap();
+
+        for key in &keys {
+            let owner = h.shard_index(key);
+            for (i, shard) in db.shards.iter().enumerate() {
+                assert_eq!(shard.read(|x| x.contains_key(key)), i == owner, "{}", key);
+            }
+        }
+        let sizes: Vec<usize> = db.shards.iter().map(|x| x.read(|x| x.len())).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), keys.len());
+        assert!(sizes.iter().all(|&n| n > 0), "{:?}", sizes);
+
+        for key in &keys[..50] {
+            h.delete("t", key).unwrap();
+            assert_eq!(h.delete("t", key), Err(DBError::NotFound));
+            assert_eq!(h.read("t", key, None), Err(DBError::NotFound));
+        }
+        for key in &keys[50..] {
+            assert_eq!(h.read("t", key, None), Ok(vec![]));
+        }
+    }
+
+    #[test]
+    fn operations_are_counted_on_their_shard() {
+        let db = open(4);
+        let mut h = db.create();
+        let key = "user1";
+        let owner = h.shard_index(key);
+        ops(&db);
+        h.insert("t", key.to_string(), vec![]).unwrap();
+        h.read("t", key, None).unwrap();
+        h.update("t", key, vec![]).unwrap();
+        h.delete("t", key).unwrap();
+        let counts = ops(&db);
+        for (i, n) in counts.into_iter().enumerate() {
+            assert_eq!(n, if i == owner { 4 } else { 0 });
+        }
+
+        // Reporting resets the counters.
+        assert_eq!(ops(&db), [0; 4]);
+    }
+}
@@ -0,0 +1,192 @@
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use collections::{Arena, Heap, Memory, MemoryUsage, NodeAlloc, SkipList};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::{DBError, DBFactory, DynDBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
+
+type ListType<A> = SkipList<String, RowValueType, A>;
+
+pub struct SkipListMap<A: NodeAlloc = Heap> {
+    catalog: Arc<ColumnCatalog>,
+    list: Arc<RwLock<ListType<A>>>,
+}
+
+pub struct SkipListMapHandle<A: NodeAlloc> {
+    columns: CatalogCache,
+    list: Arc<RwLock<ListType<A>>>,
+}
+
+pub const PROPERTIES: &[Property] = &[Property {
+    name: "skiplistallocator",
+    default: "heap",
+    description: "allocator of the towers: heap for the global allocator or arena for a slab arena",
+}];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "skiplistallocator", default = "default_allocator")]
+    allocator: String,
+}
+
+fn default_allocator() -> String {
+    "heap".to_string()
+}
+
+/// Creates the factory of the list with the allocator named by the
+/// `skiplistallocator` property.
+pub fn open(props: &Table) -> Arc<dyn DynDBFactory> {
+    let parsed: Properties = props.clone().try_into().unwrap();
+    match &*parsed.allocator {
+        "heap" => Arc::new(SkipListMap::<Heap>::new(props)),
+        "arena" => Arc::new(SkipListMap::<Arena>::new(props)),
+        _ => panic!("invalid skiplistallocator"),
+    }
+}
+
+/// Allocator statistics for the report.
+trait ReportAlloc {
+    fn report(&self, measurements: &mut Measurements);
+}
+
+impl ReportAlloc for Heap {
+    fn report(&self, _: &mut Measurements) {}
+}
+
+impl ReportAlloc for Arena {
+    fn report(&self, measurements: &mut Measurements) {
+        let stats = self.stats();
+        measurements.count("SKIPLIST", "Arena-Chunks", stats.chunks as u64);
+        measurements.count("SKIPLIST", "Arena-Bytes", stats.reserved_bytes as u64);
+    }
+}
+
+impl<A: NodeAlloc + ReportAlloc + 'static> DBFactory for SkipListMap<A> {
+    type DB = SkipListMapHandle<A>;
+
+    fn new(_: &Table) -> Self {
+        SkipListMap {
+            catalog: ColumnCatalog::new(),
+            list: Arc::new(RwLock::new(SkipList::default())),
+        }
+    }
+
+    fn create(&self) -> Self::DB {
+        SkipListMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            list: self.list.clone(),
+        }
+    }
+
+    fn report(&self, measurements: &mut Measurements) {
+        let list = self.list.read().unwrap();
+        measurements.count("SKIPLIST", "Records", list.len() as u64);
+        list.allocator().report(measurements);
+    }
+}
+
+impl<A: NodeAlloc> MemoryUsage for SkipListMap<A> {
+    fn memory_usage(&self) -> Memory {
+        self.list.read().unwrap().memory_usage()
+    }
+}
+
+impl<A: NodeAlloc + 'static> DB for SkipListMapHandle<A> {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let row = self.columns.build_row(values);
+        self.list.write().unwrap().insert(key, row);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        let row = self.list.read().unwrap().get(key).cloned();
+        match row {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let rows: Vec<(String, RowValueType)> = self
+            .list
+            .read()
+            .unwrap()
+            .range::<str, _>((Bound::Included(start_key), Bound::Unbounded))
+            .take(record_count)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let values = self.columns.resolve(values);
+        match self.list.write().unwrap().get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        match self.list.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn rank(&mut self, _: &str, key: &str) -> Result<usize, DBError> {
+        self.list.read().unwrap().rank(key).ok_or(DBError::NotFound)
+    }
+
+    fn select(
+        &mut self,
+        _: &str,
+        index: usize,
+        fields: Option<&[String]>,
+    ) -> Result<(String, ValueListType), DBError> {
+        let projection = self.columns.projection(fields);
+        let record = self
+            .list
+            .read()
+            .unwrap()
+            .select(index)
+            .map(|(k, v)| (k.clone(), v.clone()));
+        match record {
+            Some((key, row)) => Ok((key, self.columns.row_values(&row, projection.as_deref()))),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn remove_index_range(
+        &mut self,
+        _: &str,
+        index: usize,
+        count: usize,
+    ) -> Result<usize, DBError> {
+        let end = index.saturating_add(count);
+        Ok(self.list.write().unwrap().remove_index_range(index..end))
+    }
+}
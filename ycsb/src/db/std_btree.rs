@@ -1,140 +1,346 @@
-use std::collections::{BTreeMap, HashMap};
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex, RwLock,
-};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
 
-use once_cell::sync::Lazy;
+use collections::{Memory, MemoryUsage};
+use serde::Deserialize;
 use toml::Table;
 
-use crate::db::DB;
+use crate::db::catalog::{self, CatalogCache, ColumnCatalog, RowValueType};
+use crate::db::log::{Fsync, Record};
+use crate::db::wal::Wal;
+use crate::db::{DBError, DBFactory, ValueListType, DB};
+use crate::measurements::Measurements;
+use crate::registry::Property;
 
-type RowValueType = Vec<Option<Vec<u8>>>;
-type ColumnMappingType = (HashMap<String, usize>, Vec<String>);
+type MapType = BTreeMap<String, RowValueType>;
 
-static COLUMN_MAPPING_LEN: Lazy<Arc<AtomicUsize>> = Lazy::new(|| Arc::new(AtomicUsize::new(0)));
-static COLUMN_MAPPING: Lazy<Arc<RwLock<ColumnMappingType>>> =
-    Lazy::new(|| Arc::new(RwLock::new((HashMap::new(), Vec::new()))));
+pub type StdBTreeMapMutex = StdBTreeMap<Mutex<MapType>>;
+pub type StdBTreeMapRwLock = StdBTreeMap<RwLock<MapType>>;
 
-static MUTEX_INSTANCE: Lazy<Arc<Mutex<BTreeMap<String, RowValueType>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(BTreeMap::new())));
+/// Lock protecting the whole map. Reads take the shared side where the lock
+/// has one.
+pub trait MapLock: 'static + Default + std::marker::Send + std::marker::Sync {
+    fn read<R>(&self, f: impl FnOnce(&MapType) -> R) -> R;
+    fn write<R>(&self, f: impl FnOnce(&mut MapType) -> R) -> R;
+}
 
-static RWLOCK_INSTANCE: Lazy<Arc<RwLock<BTreeMap<String, RowValueType>>>> =
-    Lazy::new(|| Arc::new(RwLock::new(BTreeMap::new())));
+impl MapLock for Mutex<MapType> {
+    fn read<R>(&self, f: impl FnOnce(&MapType) -> R) -> R {
+        f(&self.lock().unwrap())
+    }
+    fn write<R>(&self, f: impl FnOnce(&mut MapType) -> R) -> R {
+        f(&mut self.lock().unwrap())
+    }
+}
 
-pub struct StdBTreeMapMutex {
-    columns_cache: ColumnMappingType,
-    n_columns: Arc<AtomicUsize>,
-    columns: Arc<RwLock<ColumnMappingType>>,
-    db: Arc<Mutex<BTreeMap<String, RowValueType>>>,
+impl MapLock for RwLock<MapType> {
+    fn read<R>(&self, f: impl FnOnce(&MapType) -> R) -> R {
+        f(&self.read().unwrap())
+    }
+    fn write<R>(&self, f: impl FnOnce(&mut MapType) -> R) -> R {
+        f(&mut self.write().unwrap())
+    }
 }
 
-pub struct StdBTreeMapRwLock {}
-
-fn get_key_indices(
-    columns: &Arc<RwLock<ColumnMappingType>>,
-    cache: &mut ColumnMappingType,
-    values: &Vec<(String, Vec<u8>)>,
-) -> (Vec<isize>, usize) {
-    let mut key_to_idx: Vec<isize> = Vec::with_capacity(values.len());
-    let mut max_idx = 0usize;
-    {
-        let x = columns.read().unwrap();
-        for (k, v) in values {
-            match x.0.get(k) {
-                Some(i) => {
-                    key_to_idx.push(*i as isize);
-                    max_idx = std::cmp::max(max_idx, *i);
-                }
-                _ => {
-                    key_to_idx.push(-1);
-                }
-            }
-        }
+/// In-memory map, made durable by a write-ahead log with periodic snapshots
+/// when `waldir` is set.
+pub struct StdBTreeMap<L: MapLock> {
+    catalog: Arc<ColumnCatalog>,
+    db: Arc<L>,
+    wal: Option<Arc<Wal>>,
+}
+
+pub struct StdBTreeMapHandle<L: MapLock> {
+    columns: CatalogCache,
+    db: Arc<L>,
+    wal: Option<Arc<Wal>>,
+    /// Log records of the running operation.
+    buf: Vec<u8>,
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "waldir",
+        default: "",
+        description: "directory of the write-ahead log and snapshots, recovered on startup; \
+                      empty keeps the map in memory only",
+    },
+    Property {
+        name: "walfsync",
+        default: "never",
+        description: "when to fsync the write-ahead log (never, always, interval)",
+    },
+    Property {
+        name: "walfsyncinterval",
+        default: "1000",
+        description: "operations between fsyncs when walfsync is interval",
+    },
+    Property {
+        name: "snapshotinterval",
+        default: "1000000",
+        description: "logged operations between snapshots of the whole map, 0 for none",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "waldir", default)]
+    wal_dir: String,
+
+    #[serde(rename = "walfsync", default = "default_wal_fsync")]
+    wal_fsync: String,
+
+    #[serde(rename = "walfsyncinterval", default = "default_wal_fsync_interval")]
+    wal_fsync_interval: u64,
+
+    #[serde(rename = "snapshotinterval", default = "default_snapshot_interval")]
+    snapshot_interval: u64,
+}
+
+fn default_wal_fsync() -> String {
+    "never".to_string()
+}
+fn default_wal_fsync_interval() -> u64 {
+    1000
+}
+fn default_snapshot_interval() -> u64 {
+    1000000
+}
+
+impl<L: MapLock> DBFactory for StdBTreeMap<L> {
+    type DB = StdBTreeMapHandle<L>;
+
+    fn new(props: &Table) -> Self {
+        let props: Properties = props.clone().try_into().unwrap();
+        let catalog = ColumnCatalog::new();
+        let db = Arc::new(L::default());
+        let wal = if props.wal_dir.is_empty() {
+            None
+        } else {
+            let fsync = Fsync::new("walfsync", &props.wal_fsync, props.wal_fsync_interval);
+            let mut columns = CatalogCache::new(catalog.clone());
+            let wal = db.write(|x| {
+                Wal::open(&props.wal_dir, fsync, props.snapshot_interval, |record| {
+                    replay(x, &mut columns, record)
+                })
+            });
+            Some(Arc::new(wal))
+        };
+        StdBTreeMap { catalog, db, wal }
     }
-    {
-        let mut x = columns.write().unwrap();
-        for i in 0..values.len() {
-            if key_to_idx[i] >= 0 {
-                continue;
-            }
-            let new_idx = x.1.len();
-            x.1.push(values[i].0.clone());
-            x.0.insert(values[i].0.clone(), new_idx);
-            key_to_idx[i] = new_idx as isize;
-            max_idx = new_idx;
+
+    fn create(&self) -> Self::DB {
+        StdBTreeMapHandle {
+            columns: CatalogCache::new(self.catalog.clone()),
+            db: self.db.clone(),
+            wal: self.wal.clone(),
+            buf: Vec::new(),
         }
     }
-    return (key_to_idx, max_idx + 1);
-}
 
-fn convert_to_row(
-    n_columns: &Arc<AtomicUsize>,
-    columns: &Arc<RwLock<ColumnMappingType>>,
-    cache: &mut ColumnMappingType,
-    values: &mut Vec<(String, Vec<u8>)>,
-) -> RowValueType {
-    let mut row = RowValueType::with_capacity(std::cmp::max(cache.1.len(), values.len()));
-    row.resize(row.capacity(), None);
-
-    if cache.1.len() != n_columns.load(Ordering::Relaxed) {
-        let x = columns.read().unwrap();
-        for i in cache.1.len()..x.0.len() {
-            cache.1.push(x.1[i].clone());
-            cache.0.insert(x.1[i].clone(), x.0[&x.1[i]]);
+    fn report(&self, measurements: &mut Measurements) {
+        if let Some(wal) = &self.wal {
+            wal.report(measurements);
         }
     }
+}
 
-    while !values.is_empty() {
-        if let Some(idx) = cache.0.get(&values[0].0) {
-            if row.len() <= *idx {
-                row.resize(idx + 1, None);
-            }
-            row[*idx] = Some(values.swap_remove(0).1);
-        } else {
-            let mut x = columns.write().unwrap();
-            let new_idx = x.1.len();
-            x.1.push(values[0].0.clone());
-            x.0.insert(values[0].0.clone(), new_idx);
-            cache.1.push(values[0].0.clone());
-            cache.0.insert(values[0].0.clone(), new_idx);
-            n_columns.fetch_add(0, Ordering::Relaxed);
-            if row.len() <= new_idx {
-                row.resize(new_idx + 1, None);
+fn replay(map: &mut MapType, columns: &mut CatalogCache, record: Record) {
+    match record {
+        Record::Put(key, values) => {
+            map.insert(key, columns.build_row(values));
+        }
+        Record::Update(key, values) => {
+            if let Some(row) = map.get_mut(&key) {
+                catalog::apply(row, columns.resolve(values));
             }
-            row[new_idx] = Some(values.swap_remove(0).1);
         }
+        Record::Delete(key) => {
+            map.remove(&key);
+        }
+    }
+}
+
+impl<L: MapLock> MemoryUsage for StdBTreeMap<L> {
+    fn memory_usage(&self) -> Memory {
+        self.db.read(|map| map.memory_usage())
+    }
+}
+
+impl<L: MapLock> StdBTreeMapHandle<L> {
+    /// Whether mutations are logged. The caller then encodes their records
+    /// into `buf` before calling `write`.
+    fn logging(&mut self) -> bool {
+        self.buf.clear();
+        self.wal.is_some()
     }
 
-    return row;
+    /// Runs `f` on the map under its write lock, appending the records in
+    /// `buf` to the log first and writing a snapshot afterwards when one is
+    /// due.
+    fn write<R>(&mut self, ops: u64, f: impl FnOnce(&mut MapType) -> R) -> R {
+        let Some(wal) = &self.wal else {
+            return self.db.write(f);
+        };
+        let columns = &mut self.columns;
+        let buf = &self.buf;
+        self.db.write(|x| {
+            let due = wal.append(buf, ops);
+            let ret = f(x);
+            if due {
+                wal.snapshot(
+                    x.iter()
+                        .map(|(k, row)| Record::Put(k.clone(), columns.row_values(row, None))),
+                );
+            }
+            ret
+        })
+    }
 }
 
-impl DB for StdBTreeMapMutex {
-    fn new(props: Table) -> Self {
-        StdBTreeMapMutex {
-            columns_cache: (HashMap::new(), Vec::new()),
-            n_columns: COLUMN_MAPPING_LEN.clone(),
-            columns: COLUMN_MAPPING.clone(),
-            db: MUTEX_INSTANCE.clone(),
+impl<L: MapLock> DB for StdBTreeMapHandle<L> {
+    fn insert(&mut self, _: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        if self.logging() {
+            Record::encode_put(&mut self.buf, &key, &values);
         }
+        let row = self.columns.build_row(values);
+        self.write(1, |x| x.insert(key, row));
+        Ok(())
     }
 
-    fn insert(
+    fn batch_insert(
         &mut self,
         _: &str,
-        key: String,
-        mut values: Vec<(String, Vec<u8>)>,
-    ) -> Result<(), ()> {
-        let row = convert_to_row(
-            &self.n_columns,
-            &self.columns,
-            &mut self.columns_cache,
-            &mut values,
-        );
-        {
-            let mut x = self.db.lock().unwrap();
-            x.insert(key, row);
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        if self.logging() {
+            for (key, values) in &records {
+                Record::encode_put(&mut self.buf, key, values);
+            }
         }
+        let n = records.len() as u64;
+        let rows: Vec<(String, RowValueType)> = records
+            .into_iter()
+            .map(|(key, values)| (key, self.columns.build_row(values)))
+            .collect();
+        self.write(n, |x| x.extend(rows));
         Ok(())
     }
+
+    fn read(
+        &mut self,
+        _: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let projection = self.columns.projection(fields);
+        let row = self.db.read(|x| x.get(key).cloned());
+        match row {
+            Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+            None => Err(DBError::NotFound),
+        }
+    }
+
+    fn multi_read(
+        &mut self,
+        _: &str,
+        keys: &[String],
+        fields: Option<&[String]>,
+    ) -> Vec<Result<ValueListType, DBError>> {
+        let projection = self.columns.projection(fields);
+        let rows: Vec<Option<RowValueType>> = self
+            .db
+            .read(|x| keys.iter().map(|key| x.get(key).cloned()).collect());
+        rows.into_iter()
+            .map(|row| match row {
+                Some(row) => Ok(self.columns.row_values(&row, projection.as_deref())),
+                None => Err(DBError::NotFound),
+            })
+            .collect()
+    }
+
+    fn scan(
+        &mut self,
+        _: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let projection = self.columns.projection(fields);
+        let rows: Vec<(String, RowValueType)> = self.db.read(|x| {
+            x.range::<str, _>((Bound::Included(start_key), Bound::Unbounded))
+                .take(record_count)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        });
+        Ok(rows
+            .into_iter()
+            .map(|(k, row)| (k, self.columns.row_values(&row, projection.as_deref())))
+            .collect())
+    }
+
+    /// Logs the overwritten fields only; the record is a no-op on replay
+    /// when the key did not exist.
+    fn update(&mut self, _: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        if self.logging() {
+            Record::encode_update(&mut self.buf, key, &values);
+        }
+        let values = self.columns.resolve(values);
+        self.write(1, |x| match x.get_mut(key) {
+            Some(row) => {
+                catalog::apply(row, values);
+                Ok(())
+            }
+            None => Err(DBError::NotFound),
+        })
+    }
+
+    fn delete(&mut self, _: &str, key: &str) -> Result<(), DBError> {
+        if self.logging() {
+            Record::encode_delete(&mut self.buf, key);
+        }
+        self.write(1, |x| match x.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotFound),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collections::testing::TestDir;
+
+    fn field(name: &str, i: u64) -> (String, Vec<u8>) {
+        (name.to_string(), i.to_le_bytes().to_vec())
+    }
+
+    /// Recovery itself is tested in `wal`; this only checks that `waldir`
+    /// and `snapshotinterval` reach it.
+    #[test]
+    fn waldir_logs_and_recovers_the_map() {
+        let dir = TestDir::new("std-btree-waldir");
+        let props: Table = format!(
+            "waldir = {:?}\nsnapshotinterval = 4",
+            dir.path().to_str().unwrap()
+        )
+        .parse()
+        .unwrap();
+        let db = StdBTreeMapRwLock::new(&props);
+        let mut h = db.create();
+        for i in 0..6 {
+            h.insert("t", format!("k{}", i), vec![field("a", i)])
+                .unwrap();
+        }
+        h.update("t", "k1", vec![field("b", 1)]).unwrap();
+        h.delete("t", "k2").unwrap();
+        let expected = h.scan("t", "", 100, None).unwrap();
+        assert_eq!(expected.len(), 5);
+        drop((h, db));
+        assert!(dir.path().join("wal.log").exists());
+
+        let db = StdBTreeMapRwLock::new(&props);
+        assert_eq!(db.create().scan("t", "", 100, None).unwrap(), expected);
+    }
 }
@@ -0,0 +1,302 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use crate::db::log::{self, Fsync, LogReader, Record};
+use crate::measurements::Measurements;
+
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+
+struct WalState {
+    file: File,
+    /// Operations appended since the last fsync.
+    unsynced: u64,
+    /// Operations appended since the last snapshot.
+    logged: u64,
+}
+
+/// Write-ahead log of an in-memory store with periodic snapshots. The owner
+/// appends the records of each mutation before applying it, under the lock
+/// that orders its mutations, and writes a snapshot of its whole content
+/// when `append` says one is due. A snapshot replaces the log, which is
+/// emptied once the snapshot is durable.
+pub struct Wal {
+    dir: PathBuf,
+    state: Mutex<WalState>,
+    fsync: Fsync,
+    snapshot_interval: u64,
+    bytes_written: AtomicU64,
+    fsyncs: AtomicU64,
+    snapshots: AtomicU64,
+    snapshot_bytes: AtomicU64,
+    recovered: AtomicU64,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, passing the records of the last snapshot and
+    /// then those of the log tail to `replay` in order. A torn tail left by
+    /// a crash is cut off. `snapshot_interval` is the number of operations
+    /// between snapshots, 0 disabling them.
+    pub fn open(
+        dir: &str,
+        fsync: Fsync,
+        snapshot_interval: u64,
+        mut replay: impl FnMut(Record),
+    ) -> Self {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut recovered = 0;
+        if let Ok(file) = File::open(dir.join(SNAPSHOT_FILE)) {
+            for (_, _, record) in LogReader::new(file) {
+                recovered += 1;
+                replay(record);
+            }
+        }
+        let file = log::open_log(&dir.join(WAL_FILE));
+        let mut reader = LogReader::new(file.try_clone().unwrap());
+        let mut logged = 0;
+        for (_, _, record) in reader.by_ref() {
+            logged += 1;
+            replay(record);
+        }
+        file.set_len(reader.valid_len).unwrap();
+        Wal {
+            dir,
+            state: Mutex::new(WalState {
+                file,
+                unsynced: 0,
+                logged,
+            }),
+            fsync,
+            snapshot_interval,
+            bytes_written: AtomicU64::new(0),
+            fsyncs: AtomicU64::new(0),
+            snapshots: AtomicU64::new(0),
+            snapshot_bytes: AtomicU64::new(0),
+            recovered: AtomicU64::new(recovered + logged),
+        }
+    }
+
+    /// Appends the framed records in `buf`, accounting for `ops` operations,
+    /// and returns whether a snapshot is due.
+    pub fn append(&self, buf: &[u8], ops: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.file.write_all(buf).unwrap();
+        self.bytes_written
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        state.unsynced += ops;
+        state.logged += ops;
+        if self.fsync.due(state.unsynced) {
+            state.file.sync_data().unwrap();
+            state.unsynced = 0;
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        }
+        self.snapshot_interval > 0 && state.logged >= self.snapshot_interval
+    }
+
+    /// Writes `records`, the whole content of the store, as the new snapshot
+    /// and empties the log. The caller keeps the store from changing until
+    /// this returns.
+    pub fn snapshot(&self, records: impl Iterator<Item = Record>) {
+        let mut state = self.state.lock().unwrap();
+        let path = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut out = BufWriter::new(File::create(&path).unwrap());
+        let mut buf = Vec::new();
+        let mut bytes = 0;
+        for record in records {
+            buf.clear();
+            bytes += record.encode(&mut buf) as u64;
+            out.write_all(&buf).unwrap();
+        }
+        out.into_inner().unwrap().sync_all().unwrap();
+        fs::rename(&path, self.dir.join(SNAPSHOT_FILE)).unwrap();
+        log::sync_dir(&self.dir);
+
+        state.file.set_len(0).unwrap();
+        state.file.sync_all().unwrap();
+        state.unsynced = 0;
+        state.logged = 0;
+        self.snapshots.fetch_add(1, Ordering::Relaxed);
+        self.snapshot_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Reports and resets the counters under `WAL`. Records replayed during
+    /// recovery are reported with the load phase.
+    pub fn report(&self, measurements: &mut Measurements) {
+        let counters = [
+            ("Recovered-Records", &self.recovered),
+            ("Bytes-Written", &self.bytes_written),
+            ("Fsyncs", &self.fsyncs),
+            ("Snapshots", &self.snapshots),
+            ("Snapshot-Bytes", &self.snapshot_bytes),
+        ];
+        for (name, counter) in counters {
+            measurements.count("WAL", name, counter.swap(0, Ordering::Relaxed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use collections::testing::TestDir;
+
+    use crate::db::{update_values, ValueListType};
+
+    type Map = BTreeMap<String, ValueListType>;
+
+    fn apply(map: &mut Map, record: Record) {
+        match record {
+            Record::Put(key, values) => {
+                map.insert(key, values);
+            }
+            Record::Update(key, values) => {
+                if let Some(row) = map.get_mut(&key) {
+                    update_values(row, values);
+                }
+            }
+            Record::Delete(key) => {
+                map.remove(&key);
+            }
+        }
+    }
+
+    fn open(dir: &Path, snapshot_interval: u64) -> (Wal, Map) {
+        let mut map = Map::new();
+        let wal = Wal::open(
+            dir.to_str().unwrap(),
+            Fsync::Never,
+            snapshot_interval,
+            |record| apply(&mut map, record),
+        );
+        (wal, map)
+    }
+
+    /// The `i`th operation of a mix of puts, partial updates and deletes
+    /// over a few keys, including updates and deletes of missing keys.
+    fn op(i: u64) -> Record {
+        let key = format!("k{}", i * 7 % 5);
+        let field = |name: &str| (name.to_string(), i.to_le_bytes().to_vec());
+        match i % 4 {
+            0 | 1 => Record::Put(key, vec![field("a"), field("b")]),
+            2 => Record::Update(key, vec![field("b"), field("c")]),
+            _ => Record::Delete(key),
+        }
+    }
+
+    /// Logs and applies `op(i)` for `i` in `ops`, snapshotting when due.
+    fn run(wal: &Wal, map: &mut Map, ops: std::ops::Range<u64>) {
+        let mut buf = Vec::new();
+        for i in ops {
+            let record = op(i);
+            buf.clear();
+            record.encode(&mut buf);
+            let due = wal.append(&buf, 1);
+            apply(map, record);
+            if due {
+                snapshot(wal, map);
+            }
+        }
+    }
+
+    fn snapshot(wal: &Wal, map: &Map) {
+        wal.snapshot(map.iter().map(|(k, v)| Record::Put(k.clone(), v.clone())));
+    }
+
+    fn file_len(dir: &Path, name: &str) -> u64 {
+        fs::metadata(dir.join(name)).unwrap().len()
+    }
+
+    #[test]
+    fn snapshot_truncates_the_log_and_replays() {
+        let tmp = TestDir::new("wal-snapshot");
+        let dir = tmp.path();
+        let (wal, mut map) = open(dir, 8);
+        run(&wal, &mut map, 0..8);
+        assert_eq!(wal.snapshots.load(Ordering::Relaxed), 1);
+        assert_eq!(file_len(dir, WAL_FILE), 0);
+        assert!(!dir.join(SNAPSHOT_TMP_FILE).exists());
+        run(&wal, &mut map, 8..13);
+        assert!(file_len(dir, WAL_FILE) > 0);
+        drop(wal);
+
+        let (wal, recovered) = open(dir, 8);
+        assert_eq!(recovered, map);
+        // The snapshot holds one record per key, the log the 5 operations
+        // since.
+        let snapshot_records = LogReader::new(File::open(dir.join(SNAPSHOT_FILE)).unwrap()).count();
+        assert_eq!(
+            wal.recovered.load(Ordering::Relaxed),
+            snapshot_records as u64 + 5
+        );
+        // The count of logged operations survives the reopen.
+        let mut map = recovered;
+        run(&wal, &mut map, 13..16);
+        assert_eq!(wal.snapshots.load(Ordering::Relaxed), 1);
+        assert_eq!(file_len(dir, WAL_FILE), 0);
+        drop(wal);
+        assert_eq!(open(dir, 8).1, map);
+    }
+
+    #[test]
+    fn replay_is_idempotent_after_a_crash_during_snapshot() {
+        for n in 1..24 {
+            let tmp = TestDir::new("wal-crash");
+            let dir = tmp.path();
+            let (wal, mut map) = open(dir, 0);
+            run(&wal, &mut map, 0..n);
+            let log = fs::read(dir.join(WAL_FILE)).unwrap();
+            snapshot(&wal, &map);
+            drop(wal);
+
+            // The snapshot was renamed into place but the log was not
+            // truncated, so its records are replayed over the snapshot
+            // that already contains them.
+            fs::write(dir.join(WAL_FILE), &log).unwrap();
+            // A snapshot being written when the crash happened is ignored.
+            fs::write(dir.join(SNAPSHOT_TMP_FILE), &log[..log.len() / 2]).unwrap();
+            let (wal, recovered) = open(dir, 0);
+            assert_eq!(recovered, map, "after {} operations", n);
+
+            // The next snapshot replaces both.
+            snapshot(&wal, &recovered);
+            drop(wal);
+            assert_eq!(file_len(dir, WAL_FILE), 0);
+            assert_eq!(open(dir, 0).1, map);
+        }
+    }
+
+    #[test]
+    fn torn_last_record_is_cut_off() {
+        let tmp = TestDir::new("wal-torn");
+        let dir = tmp.path();
+        let (wal, mut map) = open(dir, 0);
+        run(&wal, &mut map, 0..10);
+        drop(wal);
+        let len = file_len(dir, WAL_FILE);
+
+        let mut buf = Vec::new();
+        op(10).encode(&mut buf);
+        log::open_log(&dir.join(WAL_FILE))
+            .write_all(&buf[..buf.len() - 3])
+            .unwrap();
+        let (wal, mut recovered) = open(dir, 0);
+        assert_eq!(recovered, map);
+        assert_eq!(file_len(dir, WAL_FILE), len);
+
+        // Appending resumes after the last whole record.
+        run(&wal, &mut recovered, 10..12);
+        run(&wal, &mut map, 10..12);
+        drop(wal);
+        assert_eq!(open(dir, 0).1, map);
+    }
+}
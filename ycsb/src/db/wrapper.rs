@@ -0,0 +1,263 @@
+use std::time::{Duration, Instant};
+
+use crate::db::{DBError, ValueListType, DB};
+use crate::history::{self, History, Input, Output};
+use crate::measurements::Measurements;
+
+/// Forwards every operation to the wrapped backend and records its latency
+/// and return status. Batched operations are recorded once under
+/// `BATCH-<OP>` and once per item under `<OP>` with the batch latency split
+/// evenly between the items. With a history, the single-key operations
+/// and optionally the scans are recorded in it as well, batches as one
+/// operation per item spanning the whole batch.
+pub struct DBWrapper<T: DB + ?Sized> {
+    db: Box<T>,
+    measurements: Measurements,
+    history: Option<History>,
+}
+
+impl<T: DB + ?Sized> DBWrapper<T> {
+    pub fn new(db: Box<T>) -> Self {
+        DBWrapper {
+            db,
+            measurements: Measurements::new(),
+            history: None,
+        }
+    }
+
+    /// Records the operations from now on in a history of `client`, with
+    /// the keys returned by scans when `scans` is set.
+    pub fn record_history(&mut self, client: u32, scans: bool) {
+        self.history = Some(History::new(client, scans));
+    }
+
+    pub fn take_history(&mut self) -> Option<History> {
+        self.history.take()
+    }
+
+    /// Measurements of this client, for workloads recording their own
+    /// metrics such as transaction latency.
+    pub fn measurements(&mut self) -> &mut Measurements {
+        &mut self.measurements
+    }
+
+    pub fn take_measurements(&mut self) -> Measurements {
+        std::mem::take(&mut self.measurements)
+    }
+
+    fn measure<R>(&mut self, op: &str, latency: Duration, ret: &Result<R, DBError>) {
+        self.measurements.measure(op, latency);
+        let status = match ret {
+            Ok(_) => "Return=OK".to_string(),
+            Err(e) => format!("Return={}", e.status()),
+        };
+        self.measurements.count(op, &status, 1);
+    }
+
+    /// Records an operation invoked at `invoke` and returning now, unless
+    /// it failed without effect.
+    fn record(&mut self, key: &str, input: Input, output: Option<Output>, invoke: Instant) {
+        let response = Instant::now();
+        if let (Some(history), Some(output)) = (&mut self.history, output) {
+            history.record(key, input, output, invoke, response);
+        }
+    }
+
+    /// Counts an operation the history cannot check: index range removals,
+    /// and transactions, whose operations take effect at commit.
+    fn untracked<R>(&mut self, ret: &Result<R, DBError>) {
+        if let (Some(history), Ok(_)) = (&mut self.history, ret) {
+            history.untracked();
+        }
+    }
+}
+
+impl<T: DB + ?Sized> DB for DBWrapper<T> {
+    fn insert(&mut self, table: &str, key: String, values: ValueListType) -> Result<(), DBError> {
+        let input = self
+            .history
+            .as_ref()
+            .map(|_| (key.clone(), Input::Insert(history::fields(&values))));
+        let start = Instant::now();
+        let ret = self.db.insert(table, key, values);
+        if let Some((key, input)) = input {
+            self.record(&key, input, Output::of(&ret, |_| Output::Ok), start);
+        }
+        self.measure("INSERT", start.elapsed(), &ret);
+        ret
+    }
+
+    fn batch_insert(
+        &mut self,
+        table: &str,
+        records: Vec<(String, ValueListType)>,
+    ) -> Result<(), DBError> {
+        let n = records.len();
+        let inputs: Option<Vec<(String, Input)>> = self.history.as_ref().map(|_| {
+            records
+                .iter()
+                .map(|(key, values)| (key.clone(), Input::Insert(history::fields(values))))
+                .collect()
+        });
+        let start = Instant::now();
+        let ret = self.db.batch_insert(table, records);
+        match (inputs, &ret) {
+            (Some(inputs), Ok(())) => {
+                for (key, input) in inputs {
+                    self.record(&key, input, Some(Output::Ok), start);
+                }
+            }
+            // Which of the records a failed batch inserted is not known.
+            (Some(_), Err(_)) => self.history.as_mut().unwrap().untracked(),
+            (None, _) => {}
+        }
+        let latency = start.elapsed();
+        self.measure("BATCH-INSERT", latency, &ret);
+        for _ in 0..n {
+            self.measure("INSERT", latency / n as u32, &ret);
+        }
+        ret
+    }
+
+    fn read(
+        &mut self,
+        table: &str,
+        key: &str,
+        fields: Option<&[String]>,
+    ) -> Result<ValueListType, DBError> {
+        let start = Instant::now();
+        let ret = self.db.read(table, key, fields);
+        if self.history.is_some() {
+            let input = Input::Read(fields.map(<[String]>::to_vec));
+            let output = Output::of(&ret, |values| Output::Read(history::fields(values)));
+            self.record(key, input, output, start);
+        }
+        self.measure("READ", start.elapsed(), &ret);
+        ret
+    }
+
+    fn multi_read(
+        &mut self,
+        table: &str,
+        keys: &[String],
+        fields: Option<&[String]>,
+    ) -> Vec<Result<ValueListType, DBError>> {
+        let start = Instant::now();
+        let ret = self.db.multi_read(table, keys, fields);
+        if self.history.is_some() {
+            for (key, x) in keys.iter().zip(&ret) {
+                let input = Input::Read(fields.map(<[String]>::to_vec));
+                let output = Output::of(x, |values| Output::Read(history::fields(values)));
+                self.record(key, input, output, start);
+            }
+        }
+        let latency = start.elapsed();
+        self.measurements.measure("BATCH-READ", latency);
+        for x in &ret {
+            self.measure("READ", latency / ret.len() as u32, x);
+        }
+        ret
+    }
+
+    fn scan(
+        &mut self,
+        table: &str,
+        start_key: &str,
+        record_count: usize,
+        fields: Option<&[String]>,
+    ) -> Result<Vec<(String, ValueListType)>, DBError> {
+        let start = Instant::now();
+        let ret = self.db.scan(table, start_key, record_count, fields);
+        if let (Some(history), Ok(records)) = (&mut self.history, &ret) {
+            if history.records_scans() {
+                let response = Instant::now();
+                let keys = records.iter().map(|(key, _)| key.clone()).collect();
+                history.record_scan(start_key, record_count, keys, start, response);
+            }
+        }
+        self.measure("SCAN", start.elapsed(), &ret);
+        ret
+    }
+
+    fn update(&mut self, table: &str, key: &str, values: ValueListType) -> Result<(), DBError> {
+        let input = self
+            .history
+            .as_ref()
+            .map(|_| Input::Update(history::fields(&values)));
+        let start = Instant::now();
+        let ret = self.db.update(table, key, values);
+        if let Some(input) = input {
+            self.record(key, input, Output::of(&ret, |_| Output::Ok), start);
+        }
+        self.measure("UPDATE", start.elapsed(), &ret);
+        ret
+    }
+
+    fn delete(&mut self, table: &str, key: &str) -> Result<(), DBError> {
+        let start = Instant::now();
+        let ret = self.db.delete(table, key);
+        if self.history.is_some() {
+            self.record(key, Input::Delete, Output::of(&ret, |_| Output::Ok), start);
+        }
+        self.measure("DELETE", start.elapsed(), &ret);
+        ret
+    }
+
+    fn rank(&mut self, table: &str, key: &str) -> Result<usize, DBError> {
+        let start = Instant::now();
+        let ret = self.db.rank(table, key);
+        self.measure("RANK", start.elapsed(), &ret);
+        ret
+    }
+
+    fn select(
+        &mut self,
+        table: &str,
+        index: usize,
+        fields: Option<&[String]>,
+    ) -> Result<(String, ValueListType), DBError> {
+        let start = Instant::now();
+        let ret = self.db.select(table, index, fields);
+        self.measure("SELECT", start.elapsed(), &ret);
+        ret
+    }
+
+    fn remove_index_range(
+        &mut self,
+        table: &str,
+        index: usize,
+        count: usize,
+    ) -> Result<usize, DBError> {
+        let start = Instant::now();
+        let ret = self.db.remove_index_range(table, index, count);
+        self.untracked(&ret);
+        self.measure("REMOVE-RANGE", start.elapsed(), &ret);
+        ret
+    }
+
+    fn start(&mut self) -> Result<(), DBError> {
+        let start = Instant::now();
+        let ret = self.db.start();
+        self.untracked(&ret);
+        self.measure("START", start.elapsed(), &ret);
+        ret
+    }
+
+    fn commit(&mut self) -> Result<(), DBError> {
+        let start = Instant::now();
+        let ret = self.db.commit();
+        self.measure("COMMIT", start.elapsed(), &ret);
+        ret
+    }
+
+    fn abort(&mut self) -> Result<(), DBError> {
+        let start = Instant::now();
+        let ret = self.db.abort();
+        self.measure("ABORT", start.elapsed(), &ret);
+        ret
+    }
+
+    fn cleanup(&mut self) {
+        self.db.cleanup();
+    }
+}
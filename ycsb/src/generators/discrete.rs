@@ -0,0 +1,39 @@
+use super::Generator;
+
+use rand::{thread_rng, Rng};
+
+/// Picks one of the registered values with probability proportional to its
+/// weight.
+pub struct Discrete<T> {
+    values: Vec<(f64, T)>,
+    sum: f64,
+}
+
+impl<T: 'static + Clone + Send + Sync> Discrete<T> {
+    pub fn new() -> Self {
+        Discrete {
+            values: Vec::new(),
+            sum: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, weight: f64, value: T) {
+        if weight > 0.0 {
+            self.values.push((weight, value));
+            self.sum += weight;
+        }
+    }
+}
+
+impl<T: 'static + Clone + Send + Sync> Generator<T> for Discrete<T> {
+    fn next(&self) -> T {
+        let mut val = thread_rng().gen::<f64>() * self.sum;
+        for (weight, value) in &self.values {
+            if val < *weight {
+                return value.clone();
+            }
+            val -= weight;
+        }
+        self.values.last().unwrap().1.clone()
+    }
+}
@@ -1,9 +1,13 @@
 mod constant;
 mod counter;
+mod discrete;
+mod uniform;
 mod zipfian;
 
 pub use constant::Constant;
 pub use counter::Counter;
+pub use discrete::Discrete;
+pub use uniform::Uniform;
 pub use zipfian::Zipfian;
 
 pub trait Generator<T>: 'static + std::marker::Send + std::marker::Sync {
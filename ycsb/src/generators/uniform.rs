@@ -0,0 +1,21 @@
+use super::{Generator, NumberGenerator};
+
+use rand::{thread_rng, Rng};
+
+pub struct Uniform {
+    min: u64,
+    max: u64,
+}
+
+impl Uniform {
+    pub fn new(min: u64, max: u64) -> Self {
+        Uniform { min, max }
+    }
+}
+
+impl NumberGenerator for Uniform {}
+impl Generator<u64> for Uniform {
+    fn next(&self) -> u64 {
+        thread_rng().gen_range(self.min..=self.max)
+    }
+}
@@ -18,9 +18,6 @@ pub struct Zipfian {
 const ZIPFIAN_CONSTANT: f64 = 0.99;
 
 impl Zipfian {
-    pub fn new_from_count(items: u64) -> Zipfian {
-        Self::new_from_range(0, items - 1)
-    }
     pub fn new_from_range(min: u64, max: u64) -> Zipfian {
         Self::new(
             min,
@@ -30,18 +27,17 @@ impl Zipfian {
         )
     }
     pub fn new(min: u64, max: u64, constant: f64, zetan: f64) -> Zipfian {
-        let zetan = Self::zetastatic(0, max - min + 1, constant, 0.0);
         let items = max - min + 1;
         let theta = constant;
         let zeta2theta = Self::zeta(2, theta);
         Zipfian {
-            items: items,
+            items,
             base: min,
             // constant: constant,
-            theta: theta,
+            theta,
             // zeta2theta: zeta2theta,
             alpha: 1.0 / (1.0 - theta),
-            zetan: zetan,
+            zetan,
             // count_for_zeta: items,
             eta: (1.0 - (2.0 / items as f64)).powf(1.0 - theta) / (1.0 - zeta2theta / zetan),
             // last_value: 0,
@@ -57,7 +53,7 @@ impl Zipfian {
         for i in st..n {
             sum += 1.0 / ((i + 1) as f64).powf(theta);
         }
-        return sum;
+        sum
     }
 }
 
@@ -78,6 +74,6 @@ impl Generator<u64> for Zipfian {
         let ret = self.base
             + ((self.items as f64) * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
         // self.last_value = ret;
-        return ret;
+        ret
     }
 }
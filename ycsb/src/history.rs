@@ -0,0 +1,666 @@
+//! Histories of the operations of the clients, and a checker of their
+//! linearizability with respect to a sequential key-value store.
+//!
+//! Every operation is recorded with the instants it was invoked and
+//! returned at, and is linearizable if it can be placed at one instant of
+//! that interval such that the sequence of all of them is a run of the
+//! sequential store. Linearizability is local, so that the operations of
+//! every key are checked on their own. Within a key the history is cut at
+//! the instants no operation is running, and each segment is searched for
+//! the orders that run, starting from the states the previous segment can
+//! end in, as Wing and Gong do with the memoization of Lowe: a set of
+//! linearized operations reached in a state already seen is not explored
+//! again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::hash::Hasher;
+use std::time::Instant;
+
+use crate::db::{DBError, ValueListType};
+
+/// Fields of a record sorted by name, with their values hashed.
+pub type Fields = Vec<(String, u64)>;
+
+/// Record of a key in the sequential store, `None` when it does not exist.
+pub type State = Option<Fields>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Replaces the record.
+    Insert(Fields),
+    /// Overwrites the given fields of an existing record.
+    Update(Fields),
+    Delete,
+    /// Reads the given fields, or every field.
+    Read(Option<Vec<String>>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    Ok,
+    Read(Fields),
+    NotFound,
+}
+
+impl Output {
+    /// Output of an operation that returned `ret`, `None` for failures
+    /// other than `NotFound`, which leave the store as it is and are not
+    /// recorded.
+    pub fn of<R>(ret: &Result<R, DBError>, f: impl FnOnce(&R) -> Output) -> Option<Output> {
+        match ret {
+            Ok(x) => Some(f(x)),
+            Err(DBError::NotFound) => Some(Output::NotFound),
+            Err(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub client: u32,
+    pub key: String,
+    pub input: Input,
+    pub output: Output,
+    pub invoke: Instant,
+    pub response: Instant,
+}
+
+impl Operation {
+    /// Whether the operation changed the record.
+    fn writes(&self) -> bool {
+        match self.input {
+            Input::Insert(_) => true,
+            Input::Update(_) | Input::Delete => self.output == Output::Ok,
+            Input::Read(_) => false,
+        }
+    }
+
+    /// Whether the output of the operation depends on the record.
+    fn observes(&self) -> bool {
+        !matches!(self.input, Input::Insert(_))
+    }
+}
+
+/// Scan that returned `keys`, in the order it returned them.
+#[derive(Clone, Debug)]
+pub struct Scan {
+    pub client: u32,
+    pub start_key: String,
+    pub count: usize,
+    pub keys: Vec<String>,
+    pub invoke: Instant,
+    pub response: Instant,
+}
+
+/// Operations recorded by one client, or merged from all of them, with
+/// the scans when they are recorded too. Operations that are not
+/// single-key reads and writes, such as index range removals and
+/// transactions, are only counted as untracked: a history with any of
+/// them is not checked.
+pub struct History {
+    client: u32,
+    operations: Vec<Operation>,
+    scans: Option<Vec<Scan>>,
+    untracked: u64,
+}
+
+impl History {
+    pub fn new(client: u32, scans: bool) -> Self {
+        History {
+            client,
+            operations: Vec::new(),
+            scans: scans.then(Vec::new),
+            untracked: 0,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        key: &str,
+        input: Input,
+        output: Output,
+        invoke: Instant,
+        response: Instant,
+    ) {
+        self.operations.push(Operation {
+            client: self.client,
+            key: key.to_string(),
+            input,
+            output,
+            invoke,
+            response,
+        });
+    }
+
+    pub fn record_scan(
+        &mut self,
+        start_key: &str,
+        count: usize,
+        keys: Vec<String>,
+        invoke: Instant,
+        response: Instant,
+    ) {
+        if let Some(scans) = &mut self.scans {
+            scans.push(Scan {
+                client: self.client,
+                start_key: start_key.to_string(),
+                count,
+                keys,
+                invoke,
+                response,
+            });
+        }
+    }
+
+    /// Whether scans are recorded, so that `record_scan` is worth calling.
+    pub fn records_scans(&self) -> bool {
+        self.scans.is_some()
+    }
+
+    pub fn untracked(&mut self) {
+        self.untracked += 1;
+    }
+
+    pub fn merge(&mut self, other: History) {
+        self.operations.extend(other.operations);
+        if let (Some(scans), Some(other)) = (&mut self.scans, other.scans) {
+            scans.extend(other);
+        }
+        self.untracked += other.untracked;
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn scans(&self) -> &[Scan] {
+        self.scans.as_deref().unwrap_or_default()
+    }
+
+    pub fn untracked_operations(&self) -> u64 {
+        self.untracked
+    }
+}
+
+/// Fields of `values`, the last value of a field given twice winning as it
+/// does in the backends.
+pub fn fields(values: &ValueListType) -> Fields {
+    let fields: BTreeMap<&str, u64> = values
+        .iter()
+        .map(|(name, value)| {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(value);
+            (name.as_str(), hasher.finish())
+        })
+        .collect();
+    fields
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+/// Operations of one key that are not linearizable from any of the
+/// `initial` states. Leaving out any of them that can be left out without
+/// making a linearizable history look otherwise makes them linearizable.
+pub struct Violation<'a> {
+    pub key: &'a str,
+    pub initial: Vec<State>,
+    pub operations: Vec<&'a Operation>,
+}
+
+pub struct Report<'a> {
+    pub keys: usize,
+    pub violations: Vec<Violation<'a>>,
+}
+
+/// Checks the operations of every key of `history`, each key starting
+/// without a record.
+pub fn check(history: &History) -> Report<'_> {
+    let mut keys: BTreeMap<&str, Vec<&Operation>> = BTreeMap::new();
+    for op in &history.operations {
+        keys.entry(&op.key).or_default().push(op);
+    }
+    let mut violations = Vec::new();
+    for (key, ops) in &keys {
+        if let Some((initial, ops)) = first_violation(vec![None], ops.clone()) {
+            let (initial, operations) = minimize(initial, ops);
+            violations.push(Violation {
+                key,
+                initial,
+                operations,
+            });
+        }
+    }
+    Report {
+        keys: keys.len(),
+        violations,
+    }
+}
+
+/// Finds the first segment of `ops` that is not linearizable from the
+/// states the ones before it end in, returning those states with it.
+/// Segments are separated by instants when none of `ops` is running, so
+/// that every operation of a segment runs after all of the previous one in
+/// any order.
+fn first_violation(
+    mut states: Vec<State>,
+    mut ops: Vec<&Operation>,
+) -> Option<(Vec<State>, Vec<&Operation>)> {
+    ops.sort_by_key(|op| op.invoke);
+    let mut start = 0;
+    while start < ops.len() {
+        let mut end = start + 1;
+        let mut running_until = ops[start].response;
+        while end < ops.len() && ops[end].invoke <= running_until {
+            running_until = std::cmp::max(running_until, ops[end].response);
+            end += 1;
+        }
+        let segment = &ops[start..end];
+        let next = final_states(&states, segment);
+        if next.is_empty() {
+            return Some((states, segment.to_vec()));
+        }
+        states = next;
+        start = end;
+    }
+    None
+}
+
+/// Leaves out the operations of a violation that are not needed for it,
+/// last first. Only operations leaving out of which keeps any linearizable
+/// history linearizable are tried: those that did not change the record,
+/// and writes invoked after every operation looking at the record returned,
+/// which come last in any order.
+fn minimize(mut initial: Vec<State>, mut ops: Vec<&Operation>) -> (Vec<State>, Vec<&Operation>) {
+    let mut i = ops.len();
+    while i > 0 {
+        i -= 1;
+        let op = ops[i];
+        let removable = !op.writes()
+            || ops
+                .iter()
+                .all(|x| std::ptr::eq(*x, op) || !x.observes() || x.response < op.invoke);
+        if !removable {
+            continue;
+        }
+        let mut rest = ops.clone();
+        rest.remove(i);
+        if let Some((states, segment)) = first_violation(initial.clone(), rest) {
+            initial = states;
+            ops = segment;
+            i = ops.len();
+        }
+    }
+    (initial, ops)
+}
+
+/// States the sequential store can end in after running `ops` in an order
+/// consistent with their real-time order, starting from any of `initial`.
+/// Empty when `ops` are not linearizable.
+fn final_states(initial: &[State], ops: &[&Operation]) -> Vec<State> {
+    let n = ops.len();
+    // Invocations sort before returns at the same instant, so that the two
+    // operations are taken as concurrent.
+    let mut events: Vec<(Instant, bool, usize)> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| [(op.invoke, false, i), (op.response, true, i)])
+        .collect();
+    events.sort();
+    let mut ret = vec![0; n];
+    for (e, &(_, is_return, i)) in events.iter().enumerate() {
+        if is_return {
+            ret[i] = e;
+        }
+    }
+    // Events not linearized yet, in a list between `head` and `tail`.
+    let head = 2 * n;
+    let tail = head + 1;
+    let order: Vec<usize> = [head].into_iter().chain(0..2 * n).chain([tail]).collect();
+    let mut list = List {
+        next: vec![tail; tail + 1],
+        prev: vec![head; tail + 1],
+    };
+    for pair in order.windows(2) {
+        list.next[pair[0]] = pair[1];
+        list.prev[pair[1]] = pair[0];
+    }
+
+    let mut finals = Vec::new();
+    let mut linearized = vec![0u64; n.div_ceil(64)];
+    let mut seen: HashSet<(Vec<u64>, State)> = HashSet::new();
+    for state in initial {
+        let mut state = state.clone();
+        let mut stack: Vec<(usize, State)> = Vec::new();
+        let mut entry = list.next[head];
+        loop {
+            if list.next[head] == tail {
+                finals.push(state.clone());
+            } else if !events[entry].1 {
+                let i = events[entry].2;
+                if let Some(next) = step(&state, ops[i]) {
+                    linearized[i / 64] |= 1 << (i % 64);
+                    if seen.insert((linearized.clone(), next.clone())) {
+                        list.unlink(entry);
+                        list.unlink(ret[i]);
+                        stack.push((entry, std::mem::replace(&mut state, next)));
+                        entry = list.next[head];
+                        continue;
+                    }
+                    linearized[i / 64] &= !(1 << (i % 64));
+                }
+                entry = list.next[entry];
+                continue;
+            }
+            // Every operation is linearized, or one returned before any
+            // order of the running ones allows it: try the next order.
+            let Some((call, previous)) = stack.pop() else {
+                break;
+            };
+            let i = events[call].2;
+            list.relink(ret[i]);
+            list.relink(call);
+            linearized[i / 64] &= !(1 << (i % 64));
+            state = previous;
+            entry = list.next[call];
+        }
+    }
+    finals.sort();
+    finals.dedup();
+    finals
+}
+
+/// Doubly linked list of events. An unlinked event keeps its links, so that
+/// events unlinked last are relinked first in place.
+struct List {
+    next: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl List {
+    fn unlink(&mut self, e: usize) {
+        let (prev, next) = (self.prev[e], self.next[e]);
+        self.next[prev] = next;
+        self.prev[next] = prev;
+    }
+
+    fn relink(&mut self, e: usize) {
+        let (prev, next) = (self.prev[e], self.next[e]);
+        self.next[prev] = e;
+        self.prev[next] = e;
+    }
+}
+
+/// Runs `op` on the sequential store in `state`, returning the next state
+/// if `op` returns there what it returned.
+fn step(state: &State, op: &Operation) -> Option<State> {
+    match (&op.input, &op.output, state) {
+        (Input::Insert(fields), Output::Ok, _) => Some(Some(fields.clone())),
+        (Input::Update(fields), Output::Ok, Some(record)) => {
+            let mut record: BTreeMap<&str, u64> =
+                record.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+            record.extend(fields.iter().map(|(k, v)| (k.as_str(), *v)));
+            Some(Some(
+                record
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            ))
+        }
+        (Input::Delete, Output::Ok, Some(_)) => Some(None),
+        (Input::Update(_) | Input::Delete | Input::Read(_), Output::NotFound, None) => Some(None),
+        (Input::Read(names), Output::Read(values), Some(record)) => {
+            let read = record
+                .iter()
+                .filter(|(k, _)| names.as_ref().is_none_or(|names| names.contains(k)));
+            if read.eq(values.iter()) {
+                Some(state.clone())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+struct DisplayFields<'a>(&'a Fields);
+
+impl fmt::Display for DisplayFields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}=#{:08x}", name, value >> 32)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Display for Violation<'_> {
+    /// Lists the operations with their intervals in microseconds since the
+    /// first invocation, values shown by the start of their hash.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "key {} is not linearizable from", self.key)?;
+        for state in &self.initial {
+            match state {
+                Some(record) => writeln!(f, "    {}", DisplayFields(record))?,
+                None => writeln!(f, "    no record")?,
+            }
+        }
+        let first = self.operations.iter().map(|op| op.invoke).min();
+        for op in &self.operations {
+            let us = |t: Instant| (t - first.unwrap()).as_micros();
+            write!(
+                f,
+                "  [{:>8}, {:>8}] client {}: ",
+                us(op.invoke),
+                us(op.response),
+                op.client
+            )?;
+            match &op.input {
+                Input::Insert(fields) => write!(f, "insert {}", DisplayFields(fields))?,
+                Input::Update(fields) => write!(f, "update {}", DisplayFields(fields))?,
+                Input::Delete => write!(f, "delete")?,
+                Input::Read(None) => write!(f, "read")?,
+                Input::Read(Some(names)) => write!(f, "read {}", names.join(", "))?,
+            }
+            match &op.output {
+                Output::Ok => writeln!(f, " -> OK")?,
+                Output::Read(fields) => writeln!(f, " -> {}", DisplayFields(fields))?,
+                Output::NotFound => writeln!(f, " -> NOT_FOUND")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Builds histories from operations given with their interval in
+    /// microseconds.
+    struct Builder {
+        start: Instant,
+        history: History,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder {
+                start: Instant::now(),
+                history: History::new(0, false),
+            }
+        }
+
+        fn op(&mut self, client: u32, key: &str, us: (u64, u64), input: Input, output: Output) {
+            let at = |us| self.start + Duration::from_micros(us);
+            self.history.client = client;
+            self.history.record(key, input, output, at(us.0), at(us.1));
+        }
+    }
+
+    fn values(x: &[(&str, &str)]) -> Fields {
+        let values: ValueListType = x
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+            .collect();
+        fields(&values)
+    }
+
+    fn insert(x: &[(&str, &str)]) -> Input {
+        Input::Insert(values(x))
+    }
+
+    fn update(x: &[(&str, &str)]) -> Input {
+        Input::Update(values(x))
+    }
+
+    fn read(x: &[(&str, &str)]) -> Output {
+        Output::Read(values(x))
+    }
+
+    #[test]
+    fn sequential() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 1), Input::Read(None), Output::NotFound);
+        h.op(
+            0,
+            "a",
+            (2, 3),
+            insert(&[("f0", "x"), ("f1", "y")]),
+            Output::Ok,
+        );
+        h.op(0, "a", (4, 5), update(&[("f1", "z")]), Output::Ok);
+        h.op(
+            0,
+            "a",
+            (6, 7),
+            Input::Read(None),
+            read(&[("f0", "x"), ("f1", "z")]),
+        );
+        let f1 = Some(vec!["f1".to_string()]);
+        h.op(0, "a", (8, 9), Input::Read(f1), read(&[("f1", "z")]));
+        h.op(0, "a", (10, 11), Input::Delete, Output::Ok);
+        h.op(0, "a", (12, 13), update(&[("f1", "w")]), Output::NotFound);
+        h.op(0, "a", (14, 15), Input::Delete, Output::NotFound);
+        let report = check(&h.history);
+        assert_eq!(report.keys, 1);
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn concurrent_writes_in_any_order() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 10), insert(&[("f0", "x")]), Output::Ok);
+        h.op(1, "a", (1, 11), insert(&[("f0", "y")]), Output::Ok);
+        h.op(2, "a", (2, 3), Input::Read(None), read(&[("f0", "y")]));
+        h.op(2, "a", (4, 5), Input::Read(None), read(&[("f0", "x")]));
+        h.op(2, "a", (12, 13), Input::Read(None), read(&[("f0", "x")]));
+        assert!(check(&h.history).violations.is_empty());
+    }
+
+    #[test]
+    fn stale_read() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 1), insert(&[("f0", "x")]), Output::Ok);
+        h.op(0, "a", (2, 3), update(&[("f0", "y")]), Output::Ok);
+        h.op(1, "a", (4, 5), Input::Read(None), read(&[("f0", "x")]));
+        let report = check(&h.history);
+        assert_eq!(report.violations.len(), 1);
+        let v = &report.violations[0];
+        assert_eq!(v.key, "a");
+        assert_eq!(v.initial, vec![Some(values(&[("f0", "y")]))]);
+        assert_eq!(v.operations.len(), 1);
+        assert_eq!(v.operations[0].client, 1);
+    }
+
+    #[test]
+    fn reads_going_back_in_time() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 10), insert(&[("f0", "x")]), Output::Ok);
+        h.op(1, "a", (1, 11), insert(&[("f0", "y")]), Output::Ok);
+        h.op(2, "a", (2, 3), Input::Read(None), read(&[("f0", "x")]));
+        h.op(2, "a", (4, 5), Input::Read(None), read(&[("f0", "y")]));
+        h.op(3, "a", (6, 7), Input::Read(None), read(&[("f0", "x")]));
+        h.op(2, "a", (8, 9), Input::Read(None), read(&[("f0", "y")]));
+        let report = check(&h.history);
+        assert_eq!(report.violations.len(), 1);
+        // The last read is not needed.
+        assert_eq!(report.violations[0].operations.len(), 5);
+    }
+
+    #[test]
+    fn lost_update() {
+        let mut h = Builder::new();
+        h.op(
+            0,
+            "a",
+            (0, 1),
+            insert(&[("f0", "x"), ("f1", "x")]),
+            Output::Ok,
+        );
+        h.op(0, "a", (2, 10), update(&[("f0", "y")]), Output::Ok);
+        h.op(1, "a", (3, 11), update(&[("f1", "y")]), Output::Ok);
+        h.op(
+            2,
+            "a",
+            (4, 5),
+            Input::Read(None),
+            read(&[("f0", "x"), ("f1", "x")]),
+        );
+        h.op(
+            2,
+            "a",
+            (12, 13),
+            Input::Read(None),
+            read(&[("f0", "x"), ("f1", "y")]),
+        );
+        let report = check(&h.history);
+        assert_eq!(report.violations.len(), 1);
+        // Without the read of the record as inserted, the updates end before
+        // the last read in the only state both orders of them end in.
+        let v = &report.violations[0];
+        assert_eq!(v.initial, vec![Some(values(&[("f0", "y"), ("f1", "y")]))]);
+        assert_eq!(v.operations.len(), 1);
+        assert_eq!(v.operations[0].output, read(&[("f0", "x"), ("f1", "y")]));
+    }
+
+    #[test]
+    fn resurrected_record() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 1), insert(&[("f0", "x")]), Output::Ok);
+        h.op(0, "a", (2, 3), Input::Delete, Output::Ok);
+        h.op(1, "a", (4, 5), update(&[("f0", "y")]), Output::Ok);
+        let report = check(&h.history);
+        assert_eq!(report.violations.len(), 1);
+        assert_eq!(report.violations[0].initial, vec![None]);
+    }
+
+    #[test]
+    fn keys_are_checked_on_their_own() {
+        let mut h = Builder::new();
+        h.op(0, "a", (0, 10), insert(&[("f0", "x")]), Output::Ok);
+        h.op(1, "b", (1, 2), Input::Read(None), Output::NotFound);
+        h.op(1, "b", (3, 4), insert(&[("f0", "y")]), Output::Ok);
+        h.op(1, "a", (5, 6), Input::Read(None), Output::NotFound);
+        h.op(0, "b", (11, 12), Input::Read(None), read(&[("f0", "x")]));
+        let report = check(&h.history);
+        assert_eq!(report.keys, 2);
+        assert_eq!(report.violations.len(), 1);
+        assert_eq!(report.violations[0].key, "b");
+    }
+
+    #[test]
+    fn failures_without_effect_are_not_recorded() {
+        let ret: Result<(), DBError> = Err(DBError::NotImplemented);
+        assert_eq!(Output::of(&ret, |_| Output::Ok), None);
+        let ret: Result<(), DBError> = Err(DBError::NotFound);
+        assert_eq!(Output::of(&ret, |_| Output::Ok), Some(Output::NotFound));
+    }
+}
@@ -1,38 +1,53 @@
 mod client;
 mod db;
 mod generators;
+mod history;
+mod measurements;
+mod registry;
+mod scan_verify;
 mod workloads;
 
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Barrier, Mutex,
+};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{Duration, Instant};
-use std::{
-    borrow::BorrowMut,
-    ops::{Add, AddAssign},
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Barrier, Mutex,
-    },
-};
+use std::time::Instant;
 
 use client::{Client, ClientProperties};
-use db::{StdBTreeMapMutex, StdBTreeMapRwLock, DB};
+use db::{DBRegistration, DynDBFactory};
+use history::History;
+use measurements::Measurements;
 use workloads::Workload;
 
 use serde::Deserialize;
 use toml::Table;
 
 fn main() -> Result<(), anyhow::Error> {
+    for arg in std::env::args().skip(1) {
+        match &*arg {
+            "--list-dbs" => {
+                registry::print(db::DATABASES);
+                println!("all databases");
+                registry::print_properties(db::COMMON_PROPERTIES);
+                return Ok(());
+            }
+            "--list-workloads" => {
+                registry::print(workloads::WORKLOADS);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+
     let args = parse_args();
     let core_props: CoreProperties = args.clone().try_into()?;
     let client_props = ClientProperties::parse(args.clone())?;
 
-    let state = match &*core_props.workload {
-        "core" => init_clients::<workloads::CoreWorkload>(&core_props, &client_props, &args),
-        _ => {
-            panic!("invalid workload");
-        }
-    };
+    let workload = registry::find(workloads::WORKLOADS, "workload", &core_props.workload)?;
+    let db = registry::find(db::DATABASES, "db", &core_props.db)?;
+    let state = (workload.init)(&core_props, &client_props, &args, db)?;
     let show_progress = |count: u64| {
         let mut prev_percentage = 0u64;
         loop {
@@ -72,10 +87,26 @@ fn main() -> Result<(), anyhow::Error> {
         insert_time,
         (client_props.record_count as f64) / insert_time
     );
+    state.print_measurements(Some(client_props.record_count));
+    state.barrier.wait();
 
     println!("START");
     show_progress(client_props.operation_count);
     state.barrier.wait();
+    let benchmark_end_time: Instant = state
+        .clients
+        .iter()
+        .map(|x| *x.benchmark_end_time.lock().unwrap())
+        .max()
+        .unwrap();
+    let benchmark_time = (benchmark_end_time - end_time).as_secs_f64();
+    println!(
+        "{:.2} s ({:.2} ops)",
+        benchmark_time,
+        (client_props.operation_count as f64) / benchmark_time
+    );
+    state.print_measurements(None);
+    state.check_history(&client_props);
 
     for client_handle in state.clients {
         client_handle.join_handle.join().unwrap();
@@ -84,15 +115,136 @@ fn main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-struct State {
+pub struct State {
     barrier: Arc<Barrier>,
     clients: Vec<ClientHandle>,
+    factory: Arc<dyn DynDBFactory>,
+}
+
+impl State {
+    /// Prints the report of the phase that just ended, with the memory
+    /// held by the database when `records` were loaded.
+    fn print_measurements(&self, records: Option<u64>) {
+        let mut measurements = Measurements::new();
+        for x in &self.clients {
+            measurements.merge(&x.measurements.lock().unwrap());
+        }
+        self.factory.report(&mut measurements);
+        if let Some(records) = records {
+            self.report_memory(records, &mut measurements);
+        }
+        measurements.print();
+    }
+
+    /// Checks the history recorded by the clients: the linearizability of
+    /// its operations with `history`, and its scans with `scanverify`.
+    fn check_history(&self, props: &ClientProperties) {
+        let mut histories = self
+            .clients
+            .iter()
+            .filter_map(|x| x.history.lock().unwrap().take());
+        let Some(mut history) = histories.next() else {
+            return;
+        };
+        for x in histories {
+            history.merge(x);
+        }
+        let mut measurements = Measurements::new();
+        if props.history {
+            check_linearizability(&history, &mut measurements);
+        }
+        if props.scan_verify {
+            verify_scans(&history, &mut measurements);
+        }
+        measurements.print();
+    }
+
+    fn report_memory(&self, records: u64, measurements: &mut Measurements) {
+        let memory = self.factory.memory_usage();
+        let per_record = |bytes: u64| bytes / records.max(1);
+        measurements.count("MEMORY", "Nodes-Bytes", memory.nodes as u64);
+        measurements.count("MEMORY", "Keys-Bytes", memory.keys as u64);
+        measurements.count("MEMORY", "Values-Bytes", memory.values as u64);
+        measurements.count("MEMORY", "Overhead-Bytes", memory.overhead as u64);
+        measurements.count("MEMORY", "Total-Bytes", memory.total() as u64);
+        measurements.count(
+            "MEMORY",
+            "Bytes-Per-Record",
+            per_record(memory.total() as u64),
+        );
+        // The whole process, including the workload and the measurements.
+        if let Some(rss) = rss_bytes() {
+            measurements.count("MEMORY", "RSS-Bytes", rss);
+            measurements.count("MEMORY", "RSS-Bytes-Per-Record", per_record(rss));
+        }
+    }
+}
+
+/// Prints the operations of every key of `history` that is not
+/// linearizable.
+fn check_linearizability(history: &History, measurements: &mut Measurements) {
+    let operations = history.operations().len() as u64;
+    measurements.count("LINEARIZABILITY", "Operations", operations);
+    let untracked = history.untracked_operations();
+    if untracked > 0 {
+        println!("history not checked: it has transactions or index range removals");
+        measurements.count("LINEARIZABILITY", "Untracked-Operations", untracked);
+        return;
+    }
+    let start = Instant::now();
+    let report = history::check(history);
+    println!("history checked in {:.2} s", start.elapsed().as_secs_f64());
+    for violation in &report.violations {
+        print!("{}", violation);
+    }
+    measurements.count("LINEARIZABILITY", "Keys", report.keys as u64);
+    let violations = report.violations.len() as u64;
+    measurements.count("LINEARIZABILITY", "Violations", violations);
+}
+
+/// Prints the first scans of `history` that failed verification, and
+/// counts the problems of all of them.
+fn verify_scans(history: &History, measurements: &mut Measurements) {
+    const PRINTED: usize = 10;
+    let report = scan_verify::check(history);
+    if !report.checked_keys {
+        println!(
+            "scans only checked for order: the history has transactions or index range removals"
+        );
+        let untracked = history.untracked_operations();
+        measurements.count("SCAN-VERIFY", "Untracked-Operations", untracked);
+    }
+    for failure in report.failures.iter().take(PRINTED) {
+        print!("{}", failure);
+    }
+    if report.failures.len() > PRINTED {
+        println!("... and {} more", report.failures.len() - PRINTED);
+    }
+    measurements.count("SCAN-VERIFY", "Scans", report.scans as u64);
+    let failed = report.failures.len() as u64;
+    measurements.count("SCAN-VERIFY", "Failed-Scans", failed);
+    for name in scan_verify::Problem::NAMES {
+        measurements.count("SCAN-VERIFY", name, 0);
+    }
+    for problem in report.failures.iter().flat_map(|x| &x.problems) {
+        measurements.count("SCAN-VERIFY", problem.name(), 1);
+    }
+}
+
+/// Resident set size of this process, where /proc reports it.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
 }
 
 struct ClientHandle {
     progress: Arc<AtomicU64>,
+    measurements: Arc<Mutex<Measurements>>,
     insert_end_time: Arc<Mutex<Instant>>,
     benchmark_end_time: Arc<Mutex<Instant>>,
+    history: Arc<Mutex<Option<History>>>,
     join_handle: JoinHandle<()>,
 }
 
@@ -100,25 +252,22 @@ fn init_clients<U: Workload>(
     core_props: &CoreProperties,
     client_props: &ClientProperties,
     props: &Table,
-) -> State {
-    let workload = U::new(&core_props, &client_props, &props);
-    match &*core_props.db {
-        "std_btreemap_mutex" => init_clients_internal::<StdBTreeMapMutex, U>(
-            &core_props,
-            &client_props,
-            &props,
-            workload,
-        ),
-        _ => {
-            panic!("invalid db");
-        }
-    }
+    db: &DBRegistration,
+) -> Result<State, anyhow::Error> {
+    let workload = U::new(core_props, client_props, props)?;
+    let factory = db::open(db, props);
+    Ok(init_clients_internal::<U>(
+        core_props,
+        client_props,
+        factory,
+        workload,
+    ))
 }
 
-fn init_clients_internal<T: DB, U: Workload>(
+fn init_clients_internal<U: Workload>(
     core_props: &CoreProperties,
     client_props: &ClientProperties,
-    props: &Table,
+    factory: Arc<dyn DynDBFactory>,
     workload: U,
 ) -> State {
     let mut clients = Vec::with_capacity(core_props.thread_count as usize);
@@ -128,19 +277,23 @@ fn init_clients_internal<T: DB, U: Workload>(
         let thread_index = i;
         let thread_count = core_props.thread_count;
         let client_props = client_props.clone();
-        let props = props.clone();
+        let factory = factory.clone();
         let workload = workload.clone();
         let barrier = barrier.clone();
         let progress = Arc::new(AtomicU64::new(0));
         let progress_client = progress.clone();
+        let measurements = Arc::new(Mutex::new(Measurements::new()));
+        let measurements_client = measurements.clone();
         let insert_end_time = Arc::new(Mutex::new(Instant::now()));
         let benchmark_end_time = Arc::new(Mutex::new(Instant::now()));
         let insert_end_time_client = insert_end_time.clone();
         let benchmark_end_time_client = benchmark_end_time.clone();
+        let history = Arc::new(Mutex::new(None));
+        let history_client = history.clone();
         let join_handle = thread::spawn(move || {
-            let mut client = Client::<T, U>::new(
+            let mut client = Client::<dyn db::DB, U>::new(
                 client_props,
-                props,
+                factory.create(),
                 workload,
                 thread_index,
                 thread_count,
@@ -156,24 +309,36 @@ fn init_clients_internal<T: DB, U: Workload>(
                 let mut x = insert_end_time_client.lock().unwrap();
                 *x = Instant::now();
             }
+            *measurements_client.lock().unwrap() = client.take_measurements();
             barrier.wait();
+            // Wait for the load report before starting the run phase.
             progress_client.store(0, Ordering::Release);
+            barrier.wait();
 
             client.benchmark();
             {
                 let mut x = benchmark_end_time_client.lock().unwrap();
                 *x = Instant::now();
             }
+            *measurements_client.lock().unwrap() = client.take_measurements();
+            *history_client.lock().unwrap() = client.take_history();
+            client.cleanup();
             barrier.wait();
         });
         clients.push(ClientHandle {
             progress,
+            measurements,
             insert_end_time,
             benchmark_end_time,
+            history,
             join_handle,
         });
     }
-    State { barrier, clients }
+    State {
+        barrier,
+        clients,
+        factory,
+    }
 }
 
 fn parse_args() -> Table {
@@ -183,8 +348,7 @@ fn parse_args() -> Table {
     let mut overwrites: Vec<(String, String)> = Vec::new();
     let mut ret = Table::new();
 
-    while !args.is_empty() {
-        let k = args.pop().unwrap();
+    while let Some(k) = args.pop() {
         match &*k {
             "-P" => {
                 let path = args.pop().unwrap();
@@ -215,7 +379,7 @@ fn parse_args() -> Table {
         });
     }
 
-    return ret;
+    ret
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const FINE_BUCKETS: usize = 1000;
+const COARSE_BUCKETS: usize = 1000;
+
+/// Latency histogram with 1 us buckets below 1 ms and 1 ms buckets up to 1 s.
+/// Slower operations are only accounted in `max` and the average.
+#[derive(Clone)]
+struct Histogram {
+    count: u64,
+    sum_us: u64,
+    min_us: u64,
+    max_us: u64,
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            count: 0,
+            sum_us: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+            buckets: vec![0; FINE_BUCKETS + COARSE_BUCKETS + 1],
+        }
+    }
+
+    fn bucket(us: u64) -> usize {
+        let us = us as usize;
+        if us < FINE_BUCKETS {
+            us
+        } else {
+            FINE_BUCKETS + std::cmp::min(us / 1000 - 1, COARSE_BUCKETS)
+        }
+    }
+
+    fn bucket_upper_us(idx: usize) -> u64 {
+        if idx < FINE_BUCKETS {
+            idx as u64
+        } else {
+            ((idx - FINE_BUCKETS + 2) * 1000) as u64
+        }
+    }
+
+    fn add(&mut self, us: u64) {
+        self.count += 1;
+        self.sum_us += us;
+        self.min_us = std::cmp::min(self.min_us, us);
+        self.max_us = std::cmp::max(self.max_us, us);
+        self.buckets[Self::bucket(us)] += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        self.count += other.count;
+        self.sum_us += other.sum_us;
+        self.min_us = std::cmp::min(self.min_us, other.min_us);
+        self.max_us = std::cmp::max(self.max_us, other.max_us);
+        for (x, y) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *x += *y;
+        }
+    }
+
+    fn percentile(&self, p: f64) -> u64 {
+        let target = ((self.count as f64) * p).ceil() as u64;
+        let mut sum = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            sum += n;
+            if sum >= target {
+                return std::cmp::min(Self::bucket_upper_us(i), self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+/// Per-client latency and counter statistics. Each client owns one instance so
+/// recording never contends; the main thread merges them for the report.
+#[derive(Clone, Default)]
+pub struct Measurements {
+    histograms: BTreeMap<String, Histogram>,
+    counters: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Measurements {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn measure(&mut self, op: &str, latency: Duration) {
+        if !self.histograms.contains_key(op) {
+            self.histograms.insert(op.to_string(), Histogram::new());
+        }
+        self.histograms
+            .get_mut(op)
+            .unwrap()
+            .add(latency.as_micros() as u64);
+    }
+
+    /// Adds `n` to the counter `name` reported under `op`, e.g. the
+    /// `Return=OK` line of an operation.
+    pub fn count(&mut self, op: &str, name: &str, n: u64) {
+        if !self.counters.contains_key(op) {
+            self.counters.insert(op.to_string(), BTreeMap::new());
+        }
+        *self
+            .counters
+            .get_mut(op)
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert(0) += n;
+    }
+
+    pub fn merge(&mut self, other: &Measurements) {
+        for (op, h) in &other.histograms {
+            self.histograms
+                .entry(op.clone())
+                .or_insert_with(Histogram::new)
+                .merge(h);
+        }
+        for (op, counters) in &other.counters {
+            for (name, n) in counters {
+                self.count(op, name, *n);
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        let mut ops: Vec<&String> = self.histograms.keys().collect();
+        ops.extend(
+            self.counters
+                .keys()
+                .filter(|x| !self.histograms.contains_key(*x)),
+        );
+        ops.sort();
+        for op in ops {
+            if let Some(h) = self.histograms.get(op) {
+                println!("[{}], Operations, {}", op, h.count);
+                println!(
+                    "[{}], AverageLatency(us), {:.2}",
+                    op,
+                    (h.sum_us as f64) / (h.count as f64)
+                );
+                println!("[{}], MinLatency(us), {}", op, h.min_us);
+                println!("[{}], MaxLatency(us), {}", op, h.max_us);
+                println!(
+                    "[{}], 95thPercentileLatency(us), {}",
+                    op,
+                    h.percentile(0.95)
+                );
+                println!(
+                    "[{}], 99thPercentileLatency(us), {}",
+                    op,
+                    h.percentile(0.99)
+                );
+            }
+            if let Some(counters) = self.counters.get(op) {
+                for (name, n) in counters {
+                    println!("[{}], {}, {}", op, name, n);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::Measurements;
+
+    /// Value of the counter `name` reported under `op`, 0 if it was never
+    /// counted.
+    pub fn counter(m: &Measurements, op: &str, name: &str) -> u64 {
+        m.counters
+            .get(op)
+            .and_then(|counters| counters.get(name))
+            .copied()
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::anyhow;
+
+/// A property read by a registered backend or workload.
+pub struct Property {
+    pub name: &'static str,
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+/// Entry of the `db::DATABASES` or `workloads::WORKLOADS` tables. `init` is
+/// the type-erased constructor of the registered implementation.
+pub struct Registration<F> {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub properties: &'static [Property],
+    pub init: F,
+}
+
+pub fn find<'a, F>(
+    registry: &'a [Registration<F>],
+    kind: &str,
+    name: &str,
+) -> Result<&'a Registration<F>, anyhow::Error> {
+    registry.iter().find(|x| x.name == name).ok_or_else(|| {
+        let names: Vec<&str> = registry.iter().map(|x| x.name).collect();
+        anyhow!(
+            "unknown {} \"{}\" (available: {})",
+            kind,
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+pub fn print<F>(registry: &[Registration<F>]) {
+    for x in registry {
+        println!("{}", x.name);
+        println!("    {}", x.description);
+        print_properties(x.properties);
+    }
+}
+
+pub fn print_properties(properties: &[Property]) {
+    for p in properties {
+        println!(
+            "    {} (default: {})\n        {}",
+            p.name, p.default, p.description
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Verification of the scans of a history.
+//!
+//! Every scan has to return its keys strictly ordered from its start key.
+//! The single-key operations of the history also tell when a key is known
+//! to exist, or known not to, whatever order they took effect in: a scan
+//! must not return a key known not to exist while it ran, and must return
+//! every key of the range it covers that is known to exist while it ran.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::time::Instant;
+
+use crate::history::{History, Input, Output, Scan};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The key was returned after a greater one.
+    OutOfOrder(String),
+    Duplicate(String),
+    BeforeStart(String),
+    /// The key was deleted before the scan started and not inserted again
+    /// before it returned.
+    Deleted(String),
+    /// The key existed from before the scan started until it returned, in
+    /// the range the scan covered, and was not returned.
+    Missing(String),
+}
+
+impl Problem {
+    /// Name of the counter of the problem in the `SCAN-VERIFY` report.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Problem::OutOfOrder(_) => "Out-Of-Order-Keys",
+            Problem::Duplicate(_) => "Duplicate-Keys",
+            Problem::BeforeStart(_) => "Before-Start-Keys",
+            Problem::Deleted(_) => "Deleted-Keys",
+            Problem::Missing(_) => "Missing-Keys",
+        }
+    }
+
+    pub const NAMES: &'static [&'static str] = &[
+        "Out-Of-Order-Keys",
+        "Duplicate-Keys",
+        "Before-Start-Keys",
+        "Deleted-Keys",
+        "Missing-Keys",
+    ];
+}
+
+pub struct Failure<'a> {
+    pub scan: &'a Scan,
+    pub problems: Vec<Problem>,
+}
+
+pub struct Report<'a> {
+    pub scans: usize,
+    /// Whether the keys returned were checked against the other
+    /// operations, which a history with untracked operations does not
+    /// allow.
+    pub checked_keys: bool,
+    pub failures: Vec<Failure<'a>>,
+}
+
+/// Checks every scan of `history`.
+pub fn check(history: &History) -> Report<'_> {
+    let checked_keys = history.untracked_operations() == 0;
+    let mut timelines: BTreeMap<&str, Timeline> = BTreeMap::new();
+    if checked_keys {
+        for op in history.operations() {
+            let timeline = timelines.entry(&op.key).or_default();
+            let interval = (op.invoke, op.response);
+            match (&op.input, &op.output) {
+                (Input::Insert(_), Output::Ok) => {
+                    timeline.present.push(interval);
+                    timeline.inserts.push(interval);
+                }
+                (Input::Delete, Output::Ok) => {
+                    timeline.absent.push(interval);
+                    timeline.deletes.push(interval);
+                }
+                (_, Output::NotFound) => timeline.absent.push(interval),
+                _ => timeline.present.push(interval),
+            }
+        }
+    }
+
+    let mut failures = Vec::new();
+    for scan in history.scans() {
+        let mut problems = Vec::new();
+        let mut returned = HashSet::new();
+        for (i, key) in scan.keys.iter().enumerate() {
+            if !returned.insert(key.as_str()) {
+                problems.push(Problem::Duplicate(key.clone()));
+                continue;
+            }
+            if i > 0 && scan.keys[i - 1] > *key {
+                problems.push(Problem::OutOfOrder(key.clone()));
+            }
+            if *key < scan.start_key {
+                problems.push(Problem::BeforeStart(key.clone()));
+            }
+            let timeline = timelines.get(key.as_str());
+            if timeline.is_some_and(|x| x.absent(scan.invoke, scan.response)) {
+                problems.push(Problem::Deleted(key.clone()));
+            }
+        }
+        if checked_keys {
+            // A scan returning fewer keys than asked covers every key
+            // after its start.
+            let end = match scan.keys.last() {
+                Some(last) if scan.keys.len() >= scan.count => Bound::Included(last.as_str()),
+                _ => Bound::Unbounded,
+            };
+            let range = (Bound::Included(scan.start_key.as_str()), end);
+            for (key, timeline) in timelines.range::<&str, _>(range) {
+                if !returned.contains(key) && timeline.exists(scan.invoke, scan.response) {
+                    problems.push(Problem::Missing(key.to_string()));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            failures.push(Failure { scan, problems });
+        }
+    }
+    Report {
+        scans: history.scans().len(),
+        checked_keys,
+        failures,
+    }
+}
+
+/// Intervals of the operations on one key that tell whether it exists.
+#[derive(Default)]
+struct Timeline {
+    /// Operations that found the key, or inserted it.
+    present: Vec<(Instant, Instant)>,
+    /// Operations that did not find the key, or deleted it.
+    absent: Vec<(Instant, Instant)>,
+    inserts: Vec<(Instant, Instant)>,
+    deletes: Vec<(Instant, Instant)>,
+}
+
+impl Timeline {
+    /// Whether the key exists from `from` to `to` in any order of the
+    /// operations: an operation that returned before `from` found it, and
+    /// no delete can take effect between that one and `to`.
+    fn exists(&self, from: Instant, to: Instant) -> bool {
+        known_since(&self.present, from)
+            .is_some_and(|since| !self.deletes.iter().any(|x| x.1 > since && x.0 < to))
+    }
+
+    /// Whether the key does not exist from `from` to `to` in any order of
+    /// the operations.
+    fn absent(&self, from: Instant, to: Instant) -> bool {
+        known_since(&self.absent, from)
+            .is_some_and(|since| !self.inserts.iter().any(|x| x.1 > since && x.0 < to))
+    }
+}
+
+/// Latest invocation of the operations of `intervals` that returned before
+/// `from`: what they found holds from there at the latest.
+fn known_since(intervals: &[(Instant, Instant)], from: Instant) -> Option<Instant> {
+    intervals.iter().filter(|x| x.1 < from).map(|x| x.0).max()
+}
+
+impl fmt::Display for Failure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "scan of client {} from {} for {} keys returned {}:",
+            self.scan.client,
+            self.scan.start_key,
+            self.scan.count,
+            self.scan.keys.len()
+        )?;
+        const PRINTED: usize = 10;
+        for problem in self.problems.iter().take(PRINTED) {
+            match problem {
+                Problem::OutOfOrder(key) => writeln!(f, "    {} after a greater key", key)?,
+                Problem::Duplicate(key) => writeln!(f, "    {} more than once", key)?,
+                Problem::BeforeStart(key) => writeln!(f, "    {} before the start", key)?,
+                Problem::Deleted(key) => writeln!(f, "    {} deleted before", key)?,
+                Problem::Missing(key) => writeln!(f, "    not {}, existing throughout", key)?,
+            }
+        }
+        if self.problems.len() > PRINTED {
+            writeln!(f, "    ... and {} more", self.problems.len() - PRINTED)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct Builder {
+        start: Instant,
+        history: History,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder {
+                start: Instant::now(),
+                history: History::new(0, true),
+            }
+        }
+
+        fn at(&self, us: u64) -> Instant {
+            self.start + Duration::from_micros(us)
+        }
+
+        fn op(&mut self, key: &str, us: (u64, u64), input: Input, output: Output) {
+            let (invoke, response) = (self.at(us.0), self.at(us.1));
+            self.history.record(key, input, output, invoke, response);
+        }
+
+        fn insert(&mut self, key: &str, us: (u64, u64)) {
+            self.op(key, us, Input::Insert(Vec::new()), Output::Ok);
+        }
+
+        fn delete(&mut self, key: &str, us: (u64, u64)) {
+            self.op(key, us, Input::Delete, Output::Ok);
+        }
+
+        fn scan(&mut self, start_key: &str, count: usize, keys: &[&str], us: (u64, u64)) {
+            let keys = keys.iter().map(|x| x.to_string()).collect();
+            let (invoke, response) = (self.at(us.0), self.at(us.1));
+            self.history
+                .record_scan(start_key, count, keys, invoke, response);
+        }
+
+        fn problems(&self) -> Vec<Vec<Problem>> {
+            let report = check(&self.history);
+            report.failures.into_iter().map(|x| x.problems).collect()
+        }
+    }
+
+    #[test]
+    fn ordered_scans() {
+        let mut h = Builder::new();
+        h.insert("a", (0, 1));
+        h.insert("b", (0, 1));
+        h.insert("c", (0, 1));
+        h.scan("a", 3, &["a", "b", "c"], (2, 3));
+        h.scan("b", 1, &["b"], (2, 3));
+        h.scan("b", 5, &["b", "c"], (2, 3));
+        h.scan("d", 5, &[], (2, 3));
+        let report = check(&h.history);
+        assert_eq!(report.scans, 4);
+        assert!(report.checked_keys);
+        assert!(report.failures.is_empty());
+    }
+
+    #[test]
+    fn order_and_duplicates() {
+        let mut h = Builder::new();
+        h.insert("a", (0, 1));
+        h.insert("b", (0, 1));
+        h.insert("c", (0, 1));
+        h.scan("b", 10, &["a", "c", "b", "c"], (2, 3));
+        assert_eq!(
+            h.problems(),
+            vec![vec![
+                Problem::BeforeStart("a".to_string()),
+                Problem::OutOfOrder("b".to_string()),
+                Problem::Duplicate("c".to_string()),
+            ]]
+        );
+    }
+
+    #[test]
+    fn deleted_keys() {
+        let mut h = Builder::new();
+        h.insert("a", (0, 1));
+        h.insert("b", (0, 1));
+        h.delete("a", (2, 3));
+        h.delete("b", (2, 3));
+        // Inserted again while the scan runs.
+        h.insert("b", (3, 6));
+        h.scan("a", 10, &["a", "b"], (4, 5));
+        assert_eq!(h.problems(), vec![vec![Problem::Deleted("a".to_string())]]);
+    }
+
+    #[test]
+    fn missing_keys() {
+        let mut h = Builder::new();
+        h.insert("a", (0, 1));
+        h.insert("b", (0, 1));
+        h.insert("c", (0, 1));
+        h.insert("d", (0, 1));
+        // Deleted while the scan runs.
+        h.delete("c", (3, 6));
+        h.scan("a", 2, &["a", "c"], (4, 5));
+        h.scan("a", 3, &["a", "b"], (4, 5));
+        h.scan("b", 3, &["b", "d"], (4, 5));
+        assert_eq!(
+            h.problems(),
+            vec![
+                vec![Problem::Missing("b".to_string())],
+                vec![Problem::Missing("d".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn untracked_operations_leave_keys_unchecked() {
+        let mut h = Builder::new();
+        h.insert("a", (0, 1));
+        h.insert("b", (0, 1));
+        h.delete("a", (2, 3));
+        h.history.untracked();
+        h.scan("a", 2, &["a"], (4, 5));
+        h.scan("b", 2, &["b", "b"], (4, 5));
+        let report = check(&h.history);
+        assert!(!report.checked_keys);
+        assert_eq!(
+            h.problems(),
+            vec![vec![Problem::Duplicate("b".to_string())]]
+        );
+    }
+}
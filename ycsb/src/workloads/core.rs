@@ -1,18 +1,18 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hasher;
-use std::sync::Arc;
 
-use rand::{thread_rng, Rng, RngCore};
+use anyhow::{anyhow, bail};
+use rand::{thread_rng, RngCore};
 use serde::Deserialize;
 use toml::Table;
 
-use crate::db::{ValueListType, DB};
+use crate::db::{DBWrapper, ValueListType, DB};
 use crate::generators::{self, Generator, NumberGenerator};
+use crate::registry::Property;
 use crate::workloads::Workload;
 use crate::{client::ClientProperties, CoreProperties};
 
 pub struct CoreWorkload {
-    client_props: ClientProperties,
     props: Properties,
 
     key_sequence: generators::Counter,
@@ -20,10 +20,29 @@ pub struct CoreWorkload {
 
     field_length_generator: Box<dyn NumberGenerator>,
     field_names: Vec<String>,
+
+    operation_chooser: generators::Discrete<Operation>,
+    key_chooser: Box<dyn NumberGenerator>,
+    field_chooser: Box<dyn NumberGenerator>,
+    scan_length_chooser: Box<dyn NumberGenerator>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+    Delete,
 }
 
 impl Workload for CoreWorkload {
-    fn new(core_props: &CoreProperties, client_props: &ClientProperties, props: &Table) -> Self {
+    fn new(
+        _: &CoreProperties,
+        client_props: &ClientProperties,
+        props: &Table,
+    ) -> Result<Self, anyhow::Error> {
         let props: Properties = props.clone().try_into().unwrap();
         let key_sequence = generators::Counter::new(props.insert_start);
         let ordered_inserts = props.insert_order != "hashed";
@@ -33,71 +52,406 @@ impl Workload for CoreWorkload {
             n.push_str(&i.to_string());
             field_names.push(n);
         }
-        let field_length_generator = {
+        let field_length_generator: Box<dyn NumberGenerator> = {
+            let min = props.field_length_min as u64;
+            let max = props.field_length as u64;
+            if props.field_length_distribution != "constant" && min > max {
+                bail!(
+                    "minfieldlength ({}) is larger than fieldlength ({})",
+                    min,
+                    max
+                );
+            }
             match &*props.field_length_distribution {
-                "constant" => Box::new(generators::Constant::new(props.field_length as u64)),
+                "constant" => Box::new(generators::Constant::new(max)),
+                "uniform" => Box::new(generators::Uniform::new(min, max)),
+                "zipfian" => Box::new(generators::Zipfian::new_from_range(min, max)),
+                x => bail!(
+                    "invalid fieldlengthdistribution \"{}\" (available: constant, uniform, zipfian)",
+                    x
+                ),
+            }
+        };
+        let mut operation_chooser = generators::Discrete::new();
+        operation_chooser.add(props.read_proportion, Operation::Read);
+        operation_chooser.add(props.update_proportion, Operation::Update);
+        operation_chooser.add(props.insert_proportion, Operation::Insert);
+        operation_chooser.add(props.scan_proportion, Operation::Scan);
+        operation_chooser.add(
+            props.read_modify_write_proportion,
+            Operation::ReadModifyWrite,
+        );
+        operation_chooser.add(props.delete_proportion, Operation::Delete);
+        let key_chooser: Box<dyn NumberGenerator> = {
+            let (min, max) = key_range(
+                props.insert_start,
+                props.insert_count,
+                client_props.record_count,
+            )?;
+            match &*props.request_distribution {
+                "uniform" => Box::new(generators::Uniform::new(min, max)),
+                "zipfian" => Box::new(generators::Zipfian::new_from_range(min, max)),
                 _ => {
-                    panic!("invalid fieldlengthdistribution");
+                    panic!("invalid requestdistribution");
+                }
+            }
+        };
+        let field_chooser = Box::new(generators::Uniform::new(0, props.field_count as u64 - 1));
+        let scan_length_chooser: Box<dyn NumberGenerator> = {
+            let min = props.min_scan_length as u64;
+            let max = props.max_scan_length as u64;
+            match &*props.scan_length_distribution {
+                "uniform" => Box::new(generators::Uniform::new(min, max)),
+                "zipfian" => Box::new(generators::Zipfian::new_from_range(min, max)),
+                _ => {
+                    panic!("invalid scanlengthdistribution");
                 }
             }
         };
         let mut ret = CoreWorkload {
-            client_props: client_props.clone(),
             props,
             key_sequence,
             ordered_inserts,
             field_length_generator,
             field_names,
+            operation_chooser,
+            key_chooser,
+            field_chooser,
+            scan_length_chooser,
         };
         ret.init_internal();
-        return ret;
+        Ok(ret)
     }
 
-    fn init(&self, thread_idx: u32, thread_count: u32) {}
+    fn init(&self, _thread_idx: u32, _thread_count: u32) {}
 
-    fn do_insert<T: DB>(&self, db: &mut T) {
-        let key = self.build_key(self.key_sequence.next());
-        let values = self.build_values(&key);
+    fn do_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        let (key, values) = self.build_record();
         let _ = db.insert(&self.props.table, key, values);
     }
+
+    fn do_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        match self.operation_chooser.next() {
+            Operation::Read => self.do_transaction_read(db),
+            Operation::Update => self.do_transaction_update(db),
+            Operation::Insert => self.do_insert(db),
+            Operation::Scan => self.do_transaction_scan(db),
+            Operation::ReadModifyWrite => self.do_transaction_read_modify_write(db),
+            Operation::Delete => self.do_transaction_delete(db),
+        }
+    }
+
+    fn do_batch_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        if count == 1 {
+            return self.do_insert(db);
+        }
+        let records = (0..count).map(|_| self.build_record()).collect();
+        let _ = db.batch_insert(&self.props.table, records);
+    }
+
+    /// Reads and inserts of the batch are grouped into one `multi_read` and
+    /// one `batch_insert`; the other operations run one by one.
+    fn do_batch_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        if count == 1 {
+            return self.do_transaction(db);
+        }
+        let mut read_keys = Vec::new();
+        let mut records = Vec::new();
+        for _ in 0..count {
+            match self.operation_chooser.next() {
+                Operation::Read => read_keys.push(self.next_key()),
+                Operation::Update => self.do_transaction_update(db),
+                Operation::Insert => records.push(self.build_record()),
+                Operation::Scan => self.do_transaction_scan(db),
+                Operation::ReadModifyWrite => self.do_transaction_read_modify_write(db),
+                Operation::Delete => self.do_transaction_delete(db),
+            }
+        }
+        let fields = self.read_fields();
+        match read_keys.len() {
+            0 => {}
+            1 => {
+                let _ = db.read(&self.props.table, &read_keys[0], fields.as_deref());
+            }
+            _ => {
+                let _ = db.multi_read(&self.props.table, &read_keys, fields.as_deref());
+            }
+        }
+        match records.len() {
+            0 => {}
+            1 => {
+                let (key, values) = records.pop().unwrap();
+                let _ = db.insert(&self.props.table, key, values);
+            }
+            _ => {
+                let _ = db.batch_insert(&self.props.table, records);
+            }
+        }
+    }
+}
+
+/// Inclusive range of key numbers chosen by operations: `insertcount` keys
+/// starting at `insertstart`, where `insertcount` defaults to the records
+/// left after `insertstart`.
+fn key_range(
+    insert_start: u64,
+    insert_count: Option<u64>,
+    record_count: u64,
+) -> Result<(u64, u64), anyhow::Error> {
+    let insert_count = match insert_count {
+        Some(count) => count,
+        None => record_count.checked_sub(insert_start).ok_or_else(|| {
+            anyhow!(
+                "insertstart ({}) is larger than recordcount ({})",
+                insert_start,
+                record_count
+            )
+        })?,
+    };
+    if insert_count == 0 {
+        bail!(
+            "no keys to operate on: insertcount is 0 (insertstart = {}, recordcount = {})",
+            insert_start,
+            record_count
+        );
+    }
+    let max = insert_start.checked_add(insert_count - 1).ok_or_else(|| {
+        anyhow!(
+            "insertstart ({}) + insertcount ({}) overflows the key space",
+            insert_start,
+            insert_count
+        )
+    })?;
+    Ok((insert_start, max))
 }
 
 impl CoreWorkload {
     fn init_internal(&mut self) {}
 
-    fn build_key(&self, mut n: u64) -> String {
-        let keynum = {
-            if !self.ordered_inserts {
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u64(n);
-                n = hasher.finish();
-            }
-            n.to_string()
-        };
-        let fill = (self.props.zero_padding as i32) - (keynum.len() as i32);
-        let mut key = "user".to_string();
-        for _ in 0..fill {
-            key.push('0');
+    pub(super) fn table(&self) -> &str {
+        &self.props.table
+    }
+
+    /// Key of an existing record picked by `requestdistribution`.
+    pub(super) fn next_key(&self) -> String {
+        self.build_key(self.key_chooser.next())
+    }
+
+    fn build_record(&self) -> (String, ValueListType) {
+        let key = self.build_key(self.key_sequence.next());
+        let values = self.build_values(&key);
+        (key, values)
+    }
+
+    fn do_transaction_read<T: DB + ?Sized>(&self, db: &mut T) {
+        let key = self.next_key();
+        let fields = self.read_fields();
+        let _ = db.read(&self.props.table, &key, fields.as_deref());
+    }
+
+    fn do_transaction_update<T: DB + ?Sized>(&self, db: &mut T) {
+        let key = self.next_key();
+        let values = self.build_update_values(&key);
+        let _ = db.update(&self.props.table, &key, values);
+    }
+
+    fn do_transaction_scan<T: DB + ?Sized>(&self, db: &mut T) {
+        let key = self.next_key();
+        let len = self.scan_length_chooser.next() as usize;
+        let fields = self.read_fields();
+        let _ = db.scan(&self.props.table, &key, len, fields.as_deref());
+    }
+
+    fn do_transaction_read_modify_write<T: DB + ?Sized>(&self, db: &mut T) {
+        let key = self.next_key();
+        let fields = self.read_fields();
+        let values = self.build_update_values(&key);
+        let _ = db.read(&self.props.table, &key, fields.as_deref());
+        let _ = db.update(&self.props.table, &key, values);
+    }
+
+    fn do_transaction_delete<T: DB + ?Sized>(&self, db: &mut T) {
+        let key = self.next_key();
+        let _ = db.delete(&self.props.table, &key);
+    }
+
+    /// Fields to project on read; `None` reads the whole record.
+    pub(super) fn read_fields(&self) -> Option<Vec<String>> {
+        if self.props.read_all_fields && self.props.read_all_fields_by_name {
+            Some(self.field_names.clone())
+        } else if self.props.read_all_fields {
+            None
+        } else {
+            let i = self.field_chooser.next() as usize;
+            Some(vec![self.field_names[i].clone()])
         }
-        key.push_str(&keynum);
-        return key;
     }
 
-    fn build_values(&self, key: &str) -> ValueListType {
+    pub(super) fn build_update_values(&self, key: &str) -> ValueListType {
+        if self.props.write_all_fields {
+            self.build_values(key)
+        } else {
+            let i = self.field_chooser.next() as usize;
+            let mut v = vec![0u8; self.field_length_generator.next() as usize];
+            thread_rng().fill_bytes(&mut v);
+            vec![(self.field_names[i].clone(), v)]
+        }
+    }
+
+    fn build_key(&self, n: u64) -> String {
+        build_key_name(n, self.props.zero_padding, self.ordered_inserts)
+    }
+
+    fn build_values(&self, _key: &str) -> ValueListType {
         let mut ret = ValueListType::with_capacity(self.field_names.len());
         let mut rng = thread_rng();
-        for i in 0..self.field_names.len() {
-            let mut v = Vec::<u8>::with_capacity(self.field_length_generator.next() as usize);
-            unsafe {
-                v.set_len(v.capacity());
-            }
+        for name in &self.field_names {
+            let mut v = vec![0u8; self.field_length_generator.next() as usize];
             rng.fill_bytes(&mut v);
-            ret.push((self.field_names[i].clone(), v));
+            ret.push((name.clone(), v));
         }
-        return ret;
+        ret
     }
 }
 
+/// Key of the record number `n`. Backends use it to predict the key space.
+pub fn build_key_name(mut n: u64, zero_padding: u32, ordered_inserts: bool) -> String {
+    let keynum = {
+        if !ordered_inserts {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u64(n);
+            n = hasher.finish();
+        }
+        n.to_string()
+    };
+    let fill = (zero_padding as i32) - (keynum.len() as i32);
+    let mut key = "user".to_string();
+    for _ in 0..fill {
+        key.push('0');
+    }
+    key.push_str(&keynum);
+    key
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "table",
+        default: "usertable",
+        description: "name of the table",
+    },
+    Property {
+        name: "fieldcount",
+        default: "10",
+        description: "number of fields in a record",
+    },
+    Property {
+        name: "fieldnameprefix",
+        default: "field",
+        description: "prefix of the field names",
+    },
+    Property {
+        name: "fieldlength",
+        default: "100",
+        description: "length of a field in bytes",
+    },
+    Property {
+        name: "minfieldlength",
+        default: "1",
+        description: "minimum length of a field in bytes, for non-constant distributions",
+    },
+    Property {
+        name: "fieldlengthdistribution",
+        default: "constant",
+        description: "distribution of field lengths (constant, uniform, zipfian)",
+    },
+    Property {
+        name: "requestdistribution",
+        default: "uniform",
+        description: "distribution of requested keys (uniform, zipfian)",
+    },
+    Property {
+        name: "minscanlength",
+        default: "1",
+        description: "minimum number of records to scan",
+    },
+    Property {
+        name: "maxscanlength",
+        default: "1000",
+        description: "maximum number of records to scan",
+    },
+    Property {
+        name: "scanlengthdistribution",
+        default: "uniform",
+        description: "distribution of scan lengths (uniform, zipfian)",
+    },
+    Property {
+        name: "insertstart",
+        default: "0",
+        description: "first key number inserted by this client",
+    },
+    Property {
+        name: "insertcount",
+        default: "recordcount - insertstart",
+        description: "number of records inserted by this client",
+    },
+    Property {
+        name: "zeropadding",
+        default: "1",
+        description: "minimum number of digits of the key number",
+    },
+    Property {
+        name: "readallfields",
+        default: "true",
+        description: "read all fields of a record, or a single field",
+    },
+    Property {
+        name: "readallfieldsbyname",
+        default: "false",
+        description:
+            "read all fields by listing their names instead of asking for the whole record",
+    },
+    Property {
+        name: "writeallfields",
+        default: "false",
+        description: "update all fields of a record, or a single field",
+    },
+    Property {
+        name: "insertorder",
+        default: "hashed",
+        description: "hashed or ordered key numbers",
+    },
+    Property {
+        name: "readproportion",
+        default: "0.95",
+        description: "proportion of reads",
+    },
+    Property {
+        name: "updateproportion",
+        default: "0.05",
+        description: "proportion of updates",
+    },
+    Property {
+        name: "insertproportion",
+        default: "0",
+        description: "proportion of inserts",
+    },
+    Property {
+        name: "scanproportion",
+        default: "0",
+        description: "proportion of scans",
+    },
+    Property {
+        name: "readmodifywriteproportion",
+        default: "0",
+        description: "proportion of read-modify-writes",
+    },
+    Property {
+        name: "deleteproportion",
+        default: "0",
+        description: "proportion of deletes",
+    },
+];
+
 #[derive(Deserialize, Debug)]
 struct Properties {
     #[serde(default = "default_table")]
@@ -163,8 +517,26 @@ struct Properties {
     #[serde(rename = "insertorder", default = "default_insert_order")]
     insert_order: String,
 
+    #[serde(rename = "readproportion", default = "default_read_proportion")]
+    read_proportion: f64,
+
+    #[serde(rename = "updateproportion", default = "default_update_proportion")]
+    update_proportion: f64,
+
     #[serde(rename = "insertproportion", default = "default_insert_proportion")]
     insert_proportion: f64,
+
+    #[serde(rename = "scanproportion", default = "default_scan_proportion")]
+    scan_proportion: f64,
+
+    #[serde(
+        rename = "readmodifywriteproportion",
+        default = "default_read_modify_write_proportion"
+    )]
+    read_modify_write_proportion: f64,
+
+    #[serde(rename = "deleteproportion", default = "default_delete_proportion")]
+    delete_proportion: f64,
 }
 
 fn default_table() -> String {
@@ -215,6 +587,45 @@ fn default_write_all_fields() -> bool {
 fn default_insert_order() -> String {
     "hashed".to_string()
 }
+fn default_read_proportion() -> f64 {
+    0.95
+}
+fn default_update_proportion() -> f64 {
+    0.05
+}
 fn default_insert_proportion() -> f64 {
     0.0
 }
+fn default_scan_proportion() -> f64 {
+    0.0
+}
+fn default_read_modify_write_proportion() -> f64 {
+    0.0
+}
+fn default_delete_proportion() -> f64 {
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::key_range;
+
+    #[test]
+    fn key_range_defaults_to_remaining_records() {
+        assert_eq!(key_range(0, None, 100).unwrap(), (0, 99));
+        assert_eq!(key_range(40, None, 100).unwrap(), (40, 99));
+        assert_eq!(key_range(40, Some(10), 100).unwrap(), (40, 49));
+    }
+
+    #[test]
+    fn key_range_rejects_empty_and_overflowing_ranges() {
+        assert!(key_range(101, None, 100).is_err());
+        assert!(key_range(100, None, 100).is_err());
+        assert!(key_range(0, Some(0), 100).is_err());
+        assert!(key_range(u64::MAX, Some(2), 100).is_err());
+        assert_eq!(
+            key_range(u64::MAX, Some(1), 0).unwrap(),
+            (u64::MAX, u64::MAX)
+        );
+    }
+}
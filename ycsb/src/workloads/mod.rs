@@ -1,14 +1,63 @@
 use toml::Table;
 
-use crate::{client::ClientProperties, CoreProperties};
+use crate::db::{DBRegistration, DBWrapper, DB};
+use crate::registry::Registration;
+use crate::{client::ClientProperties, CoreProperties, State};
 
 mod core;
-use crate::db::DB;
-pub use core::CoreWorkload;
+mod ranked;
+mod transactional;
+pub use core::{build_key_name, CoreWorkload};
+pub use ranked::RankedWorkload;
+pub use transactional::TransactionalWorkload;
 
-pub trait Workload: 'static + std::marker::Send + std::marker::Sync {
-    fn new(core_props: &CoreProperties, client_props: &ClientProperties, props: &Table) -> Self;
+pub type WorkloadRegistration = Registration<
+    fn(&CoreProperties, &ClientProperties, &Table, &DBRegistration) -> Result<State, anyhow::Error>,
+>;
+
+pub const WORKLOADS: &[WorkloadRegistration] = &[
+    Registration {
+        name: "core",
+        description: "YCSB CoreWorkload: read/update/insert/scan/read-modify-write mix",
+        properties: core::PROPERTIES,
+        init: crate::init_clients::<CoreWorkload>,
+    },
+    Registration {
+        name: "transactional",
+        description: "multi-key read/update transactions over the core workload records",
+        properties: transactional::PROPERTIES,
+        init: crate::init_clients::<TransactionalWorkload>,
+    },
+    Registration {
+        name: "ranked",
+        description: "rank and select by index over the core workload records, with trims",
+        properties: ranked::PROPERTIES,
+        init: crate::init_clients::<RankedWorkload>,
+    },
+];
+
+pub trait Workload: 'static + Sized + std::marker::Send + std::marker::Sync {
+    fn new(
+        core_props: &CoreProperties,
+        client_props: &ClientProperties,
+        props: &Table,
+    ) -> Result<Self, anyhow::Error>;
     fn init(&self, thread_idx: u32, thread_count: u32);
 
-    fn do_insert<T: DB>(&self, db: &mut T);
+    fn do_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>);
+    fn do_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>);
+
+    /// Inserts `count` records, batching them where the workload allows.
+    fn do_batch_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        for _ in 0..count {
+            self.do_insert(db);
+        }
+    }
+
+    /// Runs `count` operations, batching them where the workload allows.
+    fn do_batch_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        for _ in 0..count {
+            self.do_transaction(db);
+        }
+    }
 }
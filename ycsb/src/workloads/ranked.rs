@@ -0,0 +1,202 @@
+use anyhow::bail;
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::{DBWrapper, DB};
+use crate::generators::{self, Generator, NumberGenerator};
+use crate::registry::Property;
+use crate::workloads::{CoreWorkload, Workload};
+use crate::{client::ClientProperties, CoreProperties};
+
+/// Leaderboard operations on the core workload records: the rank of a key
+/// picked like `CoreWorkload` does, the record at an index picked by
+/// `selectdistribution`, and trims of the records past `rankmaxrecords`.
+/// The rest of the operations run as `CoreWorkload` transactions, so core
+/// inserts grow the table that trims cut back.
+pub struct RankedWorkload {
+    core: CoreWorkload,
+    max_records: usize,
+
+    operation_chooser: generators::Discrete<Operation>,
+    index_chooser: Box<dyn NumberGenerator>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    Rank,
+    Select,
+    Trim,
+    Core,
+}
+
+impl Workload for RankedWorkload {
+    fn new(
+        core_props: &CoreProperties,
+        client_props: &ClientProperties,
+        props: &Table,
+    ) -> Result<Self, anyhow::Error> {
+        let core = CoreWorkload::new(core_props, client_props, props)?;
+        let props: Properties = props.clone().try_into().unwrap();
+        let max_records = props
+            .max_records
+            .unwrap_or(client_props.record_count as usize);
+        let ranked = props.rank_proportion + props.select_proportion + props.trim_proportion;
+        // Allow for the rounding of proportions written in decimal.
+        if ranked > 1.0 + 1e-9 {
+            bail!(
+                "rankproportion + selectproportion + trimproportion ({}) is larger than 1",
+                ranked
+            );
+        }
+        let mut operation_chooser = generators::Discrete::new();
+        operation_chooser.add(props.rank_proportion, Operation::Rank);
+        operation_chooser.add(props.select_proportion, Operation::Select);
+        operation_chooser.add(props.trim_proportion, Operation::Trim);
+        if ranked < 1.0 {
+            operation_chooser.add(1.0 - ranked, Operation::Core);
+        }
+        let max_index = std::cmp::max(max_records, 1) as u64 - 1;
+        let index_chooser: Box<dyn NumberGenerator> = match &*props.select_distribution {
+            "uniform" => Box::new(generators::Uniform::new(0, max_index)),
+            "zipfian" => Box::new(generators::Zipfian::new_from_range(0, max_index)),
+            x => bail!(
+                "invalid selectdistribution \"{}\" (available: uniform, zipfian)",
+                x
+            ),
+        };
+        Ok(RankedWorkload {
+            core,
+            max_records,
+            operation_chooser,
+            index_chooser,
+        })
+    }
+
+    fn init(&self, thread_idx: u32, thread_count: u32) {
+        self.core.init(thread_idx, thread_count);
+    }
+
+    fn do_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        self.core.do_insert(db);
+    }
+
+    fn do_batch_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        self.core.do_batch_insert(db, count);
+    }
+
+    fn do_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        let table = self.core.table();
+        match self.operation_chooser.next() {
+            Operation::Rank => {
+                let key = self.core.next_key();
+                let _ = db.rank(table, &key);
+            }
+            Operation::Select => {
+                let index = self.index_chooser.next() as usize;
+                let fields = self.core.read_fields();
+                let _ = db.select(table, index, fields.as_deref());
+            }
+            Operation::Trim => {
+                if let Ok(n) = db.remove_index_range(table, self.max_records, usize::MAX) {
+                    db.measurements().count("RANKED", "Trimmed", n as u64);
+                }
+            }
+            Operation::Core => self.core.do_transaction(db),
+        }
+    }
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "rankproportion",
+        default: "0.4",
+        description: "proportion of operations looking up the rank of a key",
+    },
+    Property {
+        name: "selectproportion",
+        default: "0.4",
+        description: "proportion of operations reading the record at an index",
+    },
+    Property {
+        name: "trimproportion",
+        default: "0",
+        description: "proportion of operations removing the records past rankmaxrecords",
+    },
+    Property {
+        name: "selectdistribution",
+        default: "zipfian",
+        description: "distribution of selected indexes: uniform or zipfian, favoring the first",
+    },
+    Property {
+        name: "rankmaxrecords",
+        default: "recordcount",
+        description: "records kept by trims, and the bound of selected indexes",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "rankproportion", default = "default_rank_proportion")]
+    rank_proportion: f64,
+
+    #[serde(rename = "selectproportion", default = "default_select_proportion")]
+    select_proportion: f64,
+
+    #[serde(rename = "trimproportion", default = "default_trim_proportion")]
+    trim_proportion: f64,
+
+    #[serde(rename = "selectdistribution", default = "default_select_distribution")]
+    select_distribution: String,
+
+    #[serde(rename = "rankmaxrecords")]
+    max_records: Option<usize>,
+}
+
+fn default_rank_proportion() -> f64 {
+    0.4
+}
+fn default_select_proportion() -> f64 {
+    0.4
+}
+fn default_trim_proportion() -> f64 {
+    0.0
+}
+fn default_select_distribution() -> String {
+    "zipfian".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(props: &str) -> Result<RankedWorkload, anyhow::Error> {
+        let core_props: CoreProperties = "workload = \"ranked\""
+            .parse::<Table>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let client_props = ClientProperties::parse("recordcount = 10".parse().unwrap()).unwrap();
+        RankedWorkload::new(&core_props, &client_props, &props.parse().unwrap())
+    }
+
+    #[test]
+    fn select_distribution_must_be_known() {
+        assert!(open("selectdistribution = \"zipfian\"").is_ok());
+        let err = open("selectdistribution = \"latest\"").err().unwrap();
+        assert!(
+            err.to_string().contains("available: uniform, zipfian"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn proportions_must_not_exceed_one() {
+        assert!(open("").is_ok());
+        assert!(open("rankproportion = 0.1\nselectproportion = 0.2\ntrimproportion = 0.7").is_ok());
+        let err = open("rankproportion = 0.5\nselectproportion = 0.5\ntrimproportion = 0.1")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("larger than 1"), "{}", err);
+    }
+}
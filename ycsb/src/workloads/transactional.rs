@@ -0,0 +1,328 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::db::{DBError, DBWrapper, DB};
+use crate::generators::{self, Generator};
+use crate::registry::Property;
+use crate::workloads::{CoreWorkload, Workload};
+use crate::{client::ClientProperties, CoreProperties};
+
+/// Runs `txnoperationcount` reads and updates per transaction on keys picked
+/// like `CoreWorkload` does, retrying conflicting transactions with
+/// exponential backoff. Records and the load phase are those of
+/// `CoreWorkload`.
+pub struct TransactionalWorkload {
+    core: CoreWorkload,
+    props: Properties,
+
+    operation_chooser: generators::Discrete<Operation>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    Read,
+    Update,
+}
+
+impl Workload for TransactionalWorkload {
+    fn new(
+        core_props: &CoreProperties,
+        client_props: &ClientProperties,
+        props: &Table,
+    ) -> Result<Self, anyhow::Error> {
+        let core = CoreWorkload::new(core_props, client_props, props)?;
+        let props: Properties = props.clone().try_into().unwrap();
+        let mut operation_chooser = generators::Discrete::new();
+        operation_chooser.add(props.read_proportion, Operation::Read);
+        operation_chooser.add(1.0 - props.read_proportion, Operation::Update);
+        Ok(TransactionalWorkload {
+            core,
+            props,
+            operation_chooser,
+        })
+    }
+
+    fn init(&self, thread_idx: u32, thread_count: u32) {
+        self.core.init(thread_idx, thread_count);
+    }
+
+    fn do_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        self.core.do_insert(db);
+    }
+
+    fn do_batch_insert<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>, count: usize) {
+        self.core.do_batch_insert(db, count);
+    }
+
+    fn do_transaction<T: DB + ?Sized>(&self, db: &mut DBWrapper<T>) {
+        let plan: Vec<(Operation, String)> = (0..self.props.operation_count)
+            .map(|_| (self.operation_chooser.next(), self.core.next_key()))
+            .collect();
+
+        let start = Instant::now();
+        let mut attempts = 0;
+        let committed = loop {
+            attempts += 1;
+            match self.run(db, &plan) {
+                Ok(()) => break true,
+                Err(DBError::Conflict) if attempts <= self.props.max_retries => {
+                    self.backoff(attempts);
+                }
+                Err(_) => break false,
+            }
+        };
+
+        let measurements = db.measurements();
+        measurements.measure("TXN", start.elapsed());
+        if committed {
+            measurements.count("TXN", "Commits", 1);
+            measurements.count("TXN", "Aborts", attempts as u64 - 1);
+        } else {
+            measurements.count("TXN", "Failures", 1);
+            measurements.count("TXN", "Aborts", attempts as u64);
+        }
+        measurements.count("TXN", "Retries", attempts as u64 - 1);
+    }
+}
+
+impl TransactionalWorkload {
+    /// Runs one attempt of the transaction. A failed `commit` leaves the
+    /// transaction aborted, a failed operation is aborted here.
+    fn run<T: DB + ?Sized>(
+        &self,
+        db: &mut DBWrapper<T>,
+        plan: &[(Operation, String)],
+    ) -> Result<(), DBError> {
+        let table = self.core.table();
+        db.start()?;
+        for (op, key) in plan {
+            let ret = match op {
+                Operation::Read => {
+                    let fields = self.core.read_fields();
+                    db.read(table, key, fields.as_deref()).map(|_| ())
+                }
+                Operation::Update => {
+                    let values = self.core.build_update_values(key);
+                    db.update(table, key, values)
+                }
+            };
+            match ret {
+                Ok(()) | Err(DBError::NotFound) => {}
+                Err(e) => {
+                    let _ = db.abort();
+                    return Err(e);
+                }
+            }
+        }
+        db.commit()
+    }
+
+    fn backoff(&self, attempts: u32) {
+        let max = self.props.backoff_max_us;
+        let base = std::cmp::min(
+            self.props
+                .backoff_min_us
+                .saturating_mul(1 << std::cmp::min(attempts - 1, 20)),
+            max,
+        );
+        if base > 0 {
+            let us = thread_rng().gen_range(base / 2..=base);
+            thread::sleep(Duration::from_micros(us));
+        }
+    }
+}
+
+pub const PROPERTIES: &[Property] = &[
+    Property {
+        name: "txnoperationcount",
+        default: "4",
+        description: "number of operations per transaction",
+    },
+    Property {
+        name: "txnreadproportion",
+        default: "0.5",
+        description: "proportion of reads in a transaction, the rest are updates",
+    },
+    Property {
+        name: "txnmaxretries",
+        default: "10",
+        description: "retries of a conflicting transaction before giving up",
+    },
+    Property {
+        name: "txnbackoffmin",
+        default: "10",
+        description: "backoff before the first retry in microseconds",
+    },
+    Property {
+        name: "txnbackoffmax",
+        default: "10000",
+        description: "upper bound of the exponential backoff in microseconds",
+    },
+];
+
+#[derive(Deserialize, Debug)]
+struct Properties {
+    #[serde(rename = "txnoperationcount", default = "default_operation_count")]
+    operation_count: u32,
+
+    #[serde(rename = "txnreadproportion", default = "default_read_proportion")]
+    read_proportion: f64,
+
+    #[serde(rename = "txnmaxretries", default = "default_max_retries")]
+    max_retries: u32,
+
+    #[serde(rename = "txnbackoffmin", default = "default_backoff_min_us")]
+    backoff_min_us: u64,
+
+    #[serde(rename = "txnbackoffmax", default = "default_backoff_max_us")]
+    backoff_max_us: u64,
+}
+
+fn default_operation_count() -> u32 {
+    4
+}
+fn default_read_proportion() -> f64 {
+    0.5
+}
+fn default_max_retries() -> u32 {
+    10
+}
+fn default_backoff_min_us() -> u64 {
+    10
+}
+fn default_backoff_max_us() -> u64 {
+    10000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::db::ValueListType;
+    use crate::measurements::tests::counter;
+    use crate::measurements::Measurements;
+
+    /// Backend failing `commit` with the scripted errors before succeeding,
+    /// and every update with `update_error` if set. Reads always miss.
+    struct Stub {
+        commits: VecDeque<DBError>,
+        update_error: Option<DBError>,
+    }
+
+    impl DB for Stub {
+        fn insert(&mut self, _: &str, _: String, _: ValueListType) -> Result<(), DBError> {
+            Ok(())
+        }
+
+        fn read(
+            &mut self,
+            _: &str,
+            _: &str,
+            _: Option<&[String]>,
+        ) -> Result<ValueListType, DBError> {
+            Err(DBError::NotFound)
+        }
+
+        fn scan(
+            &mut self,
+            _: &str,
+            _: &str,
+            _: usize,
+            _: Option<&[String]>,
+        ) -> Result<Vec<(String, ValueListType)>, DBError> {
+            Err(DBError::NotImplemented)
+        }
+
+        fn update(&mut self, _: &str, _: &str, _: ValueListType) -> Result<(), DBError> {
+            match &self.update_error {
+                Some(e) => Err(e.clone()),
+                None => Ok(()),
+            }
+        }
+
+        fn commit(&mut self) -> Result<(), DBError> {
+            match self.commits.pop_front() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Runs one transaction of `props` against `stub`.
+    fn run(props: &str, stub: Stub) -> Measurements {
+        let core_props: CoreProperties = "workload = \"transactional\""
+            .parse::<Table>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let client_props = ClientProperties::parse("recordcount = 10".parse().unwrap()).unwrap();
+        let props: Table = format!("txnbackoffmin = 0\n{}", props).parse().unwrap();
+        let workload = TransactionalWorkload::new(&core_props, &client_props, &props).unwrap();
+        let mut db = DBWrapper::new(Box::new(stub));
+        workload.do_transaction(&mut db);
+        db.take_measurements()
+    }
+
+    fn conflicts(n: usize) -> Stub {
+        Stub {
+            commits: vec![DBError::Conflict; n].into(),
+            update_error: None,
+        }
+    }
+
+    #[test]
+    fn conflicts_are_retried_until_commit() {
+        let m = run("", conflicts(2));
+        assert_eq!(counter(&m, "TXN", "Commits"), 1);
+        assert_eq!(counter(&m, "TXN", "Failures"), 0);
+        assert_eq!(counter(&m, "TXN", "Aborts"), 2);
+        assert_eq!(counter(&m, "TXN", "Retries"), 2);
+        assert_eq!(counter(&m, "START", "Return=OK"), 3);
+        assert_eq!(counter(&m, "COMMIT", "Return=CONFLICT"), 2);
+        assert_eq!(counter(&m, "COMMIT", "Return=OK"), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let m = run("txnmaxretries = 3", conflicts(10));
+        assert_eq!(counter(&m, "TXN", "Commits"), 0);
+        assert_eq!(counter(&m, "TXN", "Failures"), 1);
+        assert_eq!(counter(&m, "TXN", "Aborts"), 4);
+        assert_eq!(counter(&m, "TXN", "Retries"), 3);
+        assert_eq!(counter(&m, "COMMIT", "Return=CONFLICT"), 4);
+
+        let m = run("txnmaxretries = 0", conflicts(1));
+        assert_eq!(counter(&m, "TXN", "Failures"), 1);
+        assert_eq!(counter(&m, "TXN", "Aborts"), 1);
+        assert_eq!(counter(&m, "TXN", "Retries"), 0);
+    }
+
+    #[test]
+    fn missing_keys_do_not_abort() {
+        let m = run("txnreadproportion = 1.0", conflicts(0));
+        assert_eq!(counter(&m, "READ", "Return=NOT_FOUND"), 4);
+        assert_eq!(counter(&m, "ABORT", "Return=OK"), 0);
+        assert_eq!(counter(&m, "TXN", "Commits"), 1);
+        assert_eq!(counter(&m, "TXN", "Aborts"), 0);
+    }
+
+    #[test]
+    fn other_errors_abort_without_retry() {
+        let stub = Stub {
+            commits: VecDeque::new(),
+            update_error: Some(DBError::NotImplemented),
+        };
+        let m = run("txnreadproportion = 0.0", stub);
+        assert_eq!(counter(&m, "UPDATE", "Return=NOT_IMPLEMENTED"), 1);
+        assert_eq!(counter(&m, "ABORT", "Return=OK"), 1);
+        assert_eq!(counter(&m, "COMMIT", "Return=OK"), 0);
+        assert_eq!(counter(&m, "TXN", "Failures"), 1);
+        assert_eq!(counter(&m, "TXN", "Aborts"), 1);
+        assert_eq!(counter(&m, "TXN", "Retries"), 0);
+    }
+}